@@ -55,6 +55,12 @@ impl fmt::Display for BlockHash {
     }
 }
 
+impl AsRef<[u8]> for BlockHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
 impl<'a> Visitor<'a> for HashVisitor {
     type Value = BlockHash;
 
@@ -8,16 +8,18 @@
 //!
 use std::cmp::min;
 use std::collections::HashMap;
-use std::io::{self, Seek, SeekFrom, Read};
+use std::io::{self, Seek, SeekFrom, Read, Write};
 use std::fmt;
 use std::path::{Path, PathBuf};
-use std::sync::{RwLock, Arc};
-use std::fs::{File};
+use std::sync::{RwLock, Mutex, Arc};
+use std::fs::{self, File};
 
 use failure::Backtrace;
 use futures::{Future, Async};
+use hex::FromHex;
 use futures_cpupool::{CpuPool, CpuFuture};
-use dir_signature::{HashType, v1::{self, ParseError, Hashes}};
+use dir_signature::{get_hash, HashType, v1::{self, ParseError, Hashes}};
+use id::ImageId;
 use virtual_path::VPath;
 
 pub use block_id::{BlockHash};
@@ -87,7 +89,32 @@ pub struct ThreadedBlockReader {
     blocks: Arc<RwLock<HashMap<BlockHash, BlockPointer>>>,
 }
 
-/// A future returned by `ThreadedBlockReader::read_block`
+/// A content-addressed block store
+///
+/// Unlike `ThreadedBlockReader` it copies every block of the registered
+/// directory into own directory named by block hash, so source directory
+/// doesn't need to be kept on disk. This is useful for long-running
+/// processes which serve many historic images.
+///
+/// Each registered image holds a reference to all of its blocks. When the
+/// last image referencing a block is unregistered, the block is removed.
+/// List of blocks for each image is kept on disk, so store may be reopened
+/// after restart.
+#[derive(Debug, Clone)]
+pub struct CasBlockStore {
+    pool: CpuPool,
+    dir: Arc<PathBuf>,
+    refs: Arc<Mutex<CasRefs>>,
+}
+
+#[derive(Debug)]
+struct CasRefs {
+    images: HashMap<ImageId, Vec<BlockHash>>,
+    blocks: HashMap<BlockHash, usize>,
+}
+
+/// A future returned by `ThreadedBlockReader::read_block` and
+/// `CasBlockStore::read_block`
 #[derive(Debug)]
 pub struct FutureBlock(CpuFuture<Vec<u8>, ReadError>);
 
@@ -121,6 +148,17 @@ pub enum DirError {
     #[doc(hidden)]
     #[fail(display="hash size is unsupported")]
     HashSize(Backtrace),
+    /// Filesystem error when reading source or writing into a block store
+    #[fail(display="error accessing file {:?}: {}", _0, _1)]
+    Fs(PathBuf, io::Error, Backtrace),
+    #[doc(hidden)]
+    #[fail(display="error hashing index")]
+    IndexHash(Backtrace),
+    /// File contents doesn't match the hash in the index
+    ///
+    /// Usually this means file was changed after index has been built
+    #[fail(display="block {} of file {:?} doesn't match index", _1, _0)]
+    BlockMismatch(PathBuf, BlockHash),
     #[doc(hidden)]
     #[fail(display="non-existent-error")]
     __Nonexhaustive,
@@ -195,10 +233,11 @@ impl ThreadedBlockReader {
         for (idx, hash) in hashes.iter().enumerate() {
             let id = BlockHash::from_bytes(hash)
                 .expect("block hash converted");
+            let offset = idx * block_size as usize;
             blocks.insert(id, BlockPointer::Mem {
                 data: data.clone(),
-                offset: idx * block_size as usize,
-                size: min(data.len(), (idx+1) * block_size as usize),
+                offset: offset,
+                size: min(data.len() - offset, block_size as usize),
             });
         }
     }
@@ -239,6 +278,284 @@ impl GetBlock for ThreadedBlockReader {
     }
 }
 
+fn fs_error<'a>(path: &'a Path) -> impl FnOnce(io::Error) -> DirError + 'a {
+    move |e| DirError::Fs(path.to_path_buf(), e, Backtrace::new())
+}
+
+impl CasBlockStore {
+    /// Open (or create) a block store in the specified directory
+    ///
+    /// Reads list of images registered previously. Uses default number of
+    /// threads for reading blocks (40 at the moment).
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<CasBlockStore, DirError> {
+        CasBlockStore::open_num_threads(dir, 40)
+    }
+    /// Open (or create) a block store with specified number of threads
+    pub fn open_num_threads<P: AsRef<Path>>(dir: P, num: usize)
+        -> Result<CasBlockStore, DirError>
+    {
+        let dir = dir.as_ref();
+        let images_dir = dir.join("images");
+        fs::create_dir_all(dir.join("blocks")).map_err(fs_error(dir))?;
+        fs::create_dir_all(&images_dir).map_err(fs_error(&images_dir))?;
+        let mut refs = CasRefs {
+            images: HashMap::new(),
+            blocks: HashMap::new(),
+        };
+        for entry in fs::read_dir(&images_dir)
+            .map_err(fs_error(&images_dir))?
+        {
+            let entry = entry.map_err(fs_error(&images_dir))?;
+            let image_id = match entry.file_name().to_str()
+                .and_then(|n| n.parse::<ImageId>().ok())
+            {
+                Some(image_id) => image_id,
+                // temporary and foreign files
+                None => continue,
+            };
+            let path = entry.path();
+            let mut buf = Vec::new();
+            File::open(&path)
+                .and_then(|mut f| f.read_to_end(&mut buf))
+                .map_err(fs_error(&path))?;
+            let hashes = buf.chunks(32)
+                .map(|h| BlockHash::from_bytes(h)
+                    .ok_or_else(|| DirError::HashSize(Backtrace::new())))
+                .collect::<Result<Vec<_>, _>>()?;
+            refs.add(image_id, hashes);
+        }
+        Ok(CasBlockStore {
+            pool: CpuPool::new(num),
+            dir: Arc::new(dir.to_path_buf()),
+            refs: Arc::new(Mutex::new(refs)),
+        })
+    }
+    /// Copy blocks of the directory into the store and reference them
+    ///
+    /// Returns image id of the index. Registering the same image twice is
+    /// no-op. Source directory may be removed after this method returns.
+    pub fn register_dir<P: AsRef<Path>>(&self, dir: P, index_data: &[u8])
+        -> Result<ImageId, DirError>
+    {
+        self._register_dir(dir.as_ref(), index_data)
+    }
+    fn _register_dir(&self, dir: &Path, index_data: &[u8])
+        -> Result<ImageId, DirError>
+    {
+        let image_id = ImageId::from(
+            get_hash(&mut io::Cursor::new(&index_data))
+            .map_err(|_| DirError::IndexHash(Backtrace::new()))?);
+        let mut cur = io::Cursor::new(&index_data);
+        let mut parser = v1::Parser::new(&mut cur)
+            .map_err(DirError::ParseError)?;
+        let header = parser.get_header();
+        let block_size = header.get_block_size();
+        // lock is held for the whole registration, so that `gc` can't
+        // remove blocks we have just found in the store
+        let mut refs = self.refs.lock()
+            .map_err(|_| DirError::LockError(Backtrace::new()))?;
+        if refs.images.contains_key(&image_id) {
+            return Ok(image_id);
+        }
+        let mut hashes = Vec::new();
+        let mut buf = Vec::with_capacity(block_size as usize);
+        for entry in parser.iter() {
+            let entry = entry.map_err(DirError::ParseError)?;
+            if let v1::Entry::File { ref path, hashes: ref file_hashes, .. }
+                = entry
+            {
+                let path = dir.join(
+                    path.strip_prefix("/").expect("paths are absolute"));
+                let mut file = File::open(&path).map_err(fs_error(&path))?;
+                for hash in file_hashes.iter() {
+                    let id = BlockHash::from_bytes(hash)
+                        .ok_or_else(|| DirError::HashSize(Backtrace::new()))?;
+                    buf.clear();
+                    (&mut file).take(block_size)
+                        .read_to_end(&mut buf)
+                        .map_err(fs_error(&path))?;
+                    if BlockHash::hash_bytes(&buf) != id {
+                        return Err(DirError::BlockMismatch(path, id));
+                    }
+                    if !refs.blocks.contains_key(&id) {
+                        self.write_block(&id, &buf)?;
+                    }
+                    hashes.push(id);
+                }
+            }
+        }
+        let images_dir = self.dir.join("images");
+        let refs_file = images_dir.join(image_id.to_string());
+        let tmp_file = images_dir.join(format!(".{}.tmp", image_id));
+        File::create(&tmp_file)
+            .and_then(|mut f| {
+                for hash in &hashes {
+                    f.write_all(hash.as_ref())?;
+                }
+                Ok(())
+            })
+            .and_then(|()| fs::rename(&tmp_file, &refs_file))
+            .map_err(fs_error(&refs_file))?;
+        refs.add(image_id.clone(), hashes);
+        Ok(image_id)
+    }
+    fn block_path(&self, hash: &BlockHash) -> PathBuf {
+        block_path(&self.dir, hash)
+    }
+    fn write_block(&self, hash: &BlockHash, data: &[u8])
+        -> Result<(), DirError>
+    {
+        let path = self.block_path(hash);
+        let dir = path.parent().expect("block has a parent dir");
+        if path.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(dir).map_err(fs_error(dir))?;
+        let tmp_path = dir.join(format!(".{}.tmp", hash));
+        File::create(&tmp_path)
+            .and_then(|mut f| f.write_all(data))
+            .and_then(|()| fs::rename(&tmp_path, &path))
+            .map_err(fs_error(&path))?;
+        Ok(())
+    }
+    /// Drop reference to the image blocks
+    ///
+    /// Blocks that are not referenced by any other image are removed
+    /// immediately. Returns `false` if image was not registered.
+    pub fn unregister_image(&self, image_id: &ImageId)
+        -> Result<bool, DirError>
+    {
+        let mut refs = self.refs.lock()
+            .map_err(|_| DirError::LockError(Backtrace::new()))?;
+        if !refs.images.contains_key(image_id) {
+            return Ok(false);
+        }
+        let refs_file = self.dir.join("images").join(image_id.to_string());
+        fs::remove_file(&refs_file).map_err(fs_error(&refs_file))?;
+        for hash in refs.remove(image_id) {
+            let path = self.block_path(&hash);
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(fs_error(&path)(e)),
+            }
+        }
+        Ok(true)
+    }
+    /// Returns list of images currently registered in the store
+    pub fn images(&self) -> Vec<ImageId> {
+        self.refs.lock().expect("lock not poisoned")
+            .images.keys().cloned().collect()
+    }
+    /// Remove blocks which aren't referenced by any registered image
+    ///
+    /// Normally, blocks are removed when image is unregistered, but this
+    /// method cleans up blocks left by interrupted registrations and
+    /// crashed processes. Temporary files of such registrations are
+    /// removed too. Returns number of blocks removed.
+    pub fn gc(&self) -> Result<usize, DirError> {
+        // registration holds the lock, so no temporary file is in use now
+        let refs = self.refs.lock()
+            .map_err(|_| DirError::LockError(Backtrace::new()))?;
+        let images_dir = self.dir.join("images");
+        for entry in fs::read_dir(&images_dir).map_err(fs_error(&images_dir))?
+        {
+            let path = entry.map_err(fs_error(&images_dir))?.path();
+            if is_tmp(&path) {
+                fs::remove_file(&path).map_err(fs_error(&path))?;
+            }
+        }
+        let blocks_dir = self.dir.join("blocks");
+        let mut removed = 0;
+        for sub in fs::read_dir(&blocks_dir).map_err(fs_error(&blocks_dir))? {
+            let sub = sub.map_err(fs_error(&blocks_dir))?.path();
+            for entry in fs::read_dir(&sub).map_err(fs_error(&sub))? {
+                let path = entry.map_err(fs_error(&sub))?.path();
+                if is_tmp(&path) {
+                    fs::remove_file(&path).map_err(fs_error(&path))?;
+                    continue;
+                }
+                let used = path.file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| Vec::<u8>::from_hex(n).ok())
+                    .and_then(|h| BlockHash::from_bytes(&h))
+                    .map(|h| refs.blocks.contains_key(&h))
+                    .unwrap_or(false);
+                if !used {
+                    fs::remove_file(&path).map_err(fs_error(&path))?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn is_tmp(path: &Path) -> bool {
+    path.file_name().and_then(|n| n.to_str())
+        .map(|n| n.starts_with(".") && n.ends_with(".tmp"))
+        .unwrap_or(false)
+}
+
+fn block_path(dir: &Path, hash: &BlockHash) -> PathBuf {
+    let hex = hash.to_string();
+    dir.join("blocks").join(&hex[..2]).join(&hex)
+}
+
+impl CasRefs {
+    fn add(&mut self, image_id: ImageId, hashes: Vec<BlockHash>) {
+        for hash in &hashes {
+            *self.blocks.entry(hash.clone()).or_insert(0) += 1;
+        }
+        self.images.insert(image_id, hashes);
+    }
+    /// Returns blocks which are not referenced any more
+    fn remove(&mut self, image_id: &ImageId) -> Vec<BlockHash> {
+        let mut unused = Vec::new();
+        for hash in self.images.remove(image_id).unwrap_or_default() {
+            let drop = match self.blocks.get_mut(&hash) {
+                Some(cnt) => {
+                    *cnt -= 1;
+                    *cnt == 0
+                }
+                None => false,
+            };
+            if drop {
+                self.blocks.remove(&hash);
+                unused.push(hash);
+            }
+        }
+        unused
+    }
+}
+
+impl GetBlock for CasBlockStore {
+    type Data = Vec<u8>;
+    type Error = ReadError;
+    type Future = FutureBlock;
+    fn read_block(&self, hash: BlockHash, _hint: BlockHint) -> FutureBlock {
+        let dir = self.dir.clone();
+        FutureBlock(self.pool.spawn_fn(move || {
+            let path = block_path(&dir, &hash);
+            let mut result = Vec::new();
+            match File::open(&path) {
+                Ok(mut f) => {
+                    f.read_to_end(&mut result)
+                        .map_err(|e| ReadError::Fs(path, e,
+                                                   Backtrace::new()))?;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(ReadError::NotFound(hash));
+                }
+                Err(e) => {
+                    return Err(ReadError::Fs(path, e, Backtrace::new()));
+                }
+            }
+            Ok(result)
+        }))
+    }
+}
+
 impl Future for FutureBlock {
     type Item = Vec<u8>;
     type Error = ReadError;
@@ -257,3 +574,97 @@ impl BlockHint {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    use futures::Future;
+    use tempfile::tempdir;
+    use index::scan_dir;
+    use super::{CasBlockStore, GetBlock, BlockHint, BlockHash, DirError};
+
+    fn write(path: &Path, data: &[u8]) {
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+
+    #[test]
+    fn cas_refcount() {
+        let tmp = tempdir().unwrap();
+        let src1 = tmp.path().join("src1");
+        let src2 = tmp.path().join("src2");
+        fs::create_dir(&src1).unwrap();
+        fs::create_dir(&src2).unwrap();
+        write(&src1.join("common"), b"common");
+        write(&src2.join("common"), b"common");
+        write(&src1.join("first"), b"first");
+        write(&src2.join("second"), b"second");
+        let idx1 = scan_dir(&src1);
+        let idx2 = scan_dir(&src2);
+
+        let store = CasBlockStore::open(tmp.path().join("cas")).unwrap();
+        let img1 = store.register_dir(&src1, &idx1).unwrap();
+        let img2 = store.register_dir(&src2, &idx2).unwrap();
+        fs::remove_dir_all(&src1).unwrap();
+        fs::remove_dir_all(&src2).unwrap();
+        let read = |data: &[u8]| {
+            store.read_block(BlockHash::hash_bytes(data), BlockHint::empty())
+                .wait().ok()
+        };
+        assert_eq!(read(b"common"), Some(b"common".to_vec()));
+        assert_eq!(read(b"first"), Some(b"first".to_vec()));
+
+        assert!(store.unregister_image(&img1).unwrap());
+        assert_eq!(read(b"common"), Some(b"common".to_vec()));
+        assert_eq!(read(b"first"), None);
+
+        // refcounts are restored on reopen
+        let store = CasBlockStore::open(tmp.path().join("cas")).unwrap();
+        assert_eq!(store.images(), vec![img2.clone()]);
+        assert_eq!(store.gc().unwrap(), 0);
+        assert!(store.unregister_image(&img2).unwrap());
+        assert!(!store.unregister_image(&img2).unwrap());
+        assert_eq!(store.read_block(BlockHash::hash_bytes(b"common"),
+                                    BlockHint::empty()).wait().ok(), None);
+    }
+
+    #[test]
+    fn cas_changed_file() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("src");
+        fs::create_dir(&src).unwrap();
+        write(&src.join("file"), b"original");
+        let idx = scan_dir(&src);
+        write(&src.join("file"), b"modified");
+
+        let store = CasBlockStore::open(tmp.path().join("cas")).unwrap();
+        match store.register_dir(&src, &idx) {
+            Err(DirError::BlockMismatch(ref path, ref hash))
+            if *path == src.join("file")
+                && *hash == BlockHash::hash_bytes(b"original") => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(store.images(), vec![]);
+        assert_eq!(store.read_block(BlockHash::hash_bytes(b"modified"),
+                                    BlockHint::empty()).wait().ok(), None);
+    }
+
+    #[test]
+    fn cas_gc_tmp() {
+        let tmp = tempdir().unwrap();
+        let cas = tmp.path().join("cas");
+        let store = CasBlockStore::open(&cas).unwrap();
+        let hash = BlockHash::hash_bytes(b"block");
+        let block_dir = cas.join("blocks").join(&hash.to_string()[..2]);
+        fs::create_dir_all(&block_dir).unwrap();
+        let block_tmp = block_dir.join(format!(".{}.tmp", hash));
+        let image_tmp = cas.join("images").join(".image.tmp");
+        write(&block_tmp, b"bl");
+        write(&image_tmp, b"");
+        assert_eq!(store.gc().unwrap(), 0);
+        assert!(!block_tmp.exists());
+        assert!(!image_tmp.exists());
+    }
+}
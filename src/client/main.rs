@@ -12,12 +12,14 @@ extern crate futures;
 extern crate futures_cpupool;
 extern crate hex;
 extern crate humantime;
+extern crate libc;
 extern crate ns_router;
 extern crate ns_std_threaded;
 extern crate ns_env_config;
 extern crate serde;
 extern crate serde_bytes;
 extern crate serde_cbor;
+extern crate serde_json;
 extern crate ssh_keys;
 extern crate tempfile;
 extern crate tk_bufstream;
@@ -44,6 +46,10 @@ mod upload;
 mod sync;
 mod edit;
 mod put_file;
+mod maintenance;
+mod pin;
+mod promote;
+mod rollback;
 
 // common modules for lib and daemon, we don't expose them in the lib because
 // that would mean keep backwards compatibility
@@ -53,6 +59,7 @@ mod put_file;
 #[path="../serialize/mod.rs"] mod serialize;
 #[path="../time_util.rs"] mod time_util;
 #[path="../hexlify.rs"] mod hexlify;
+#[path="../xattrs.rs"] mod xattrs;
 pub use ciruela::{VPath};
 pub use ciruela::blocks as blocks;
 pub use ciruela::index as index;
@@ -79,7 +86,9 @@ fn main() {
         ap.refer(&mut cmd)
             .add_argument("command", StoreOption, r#"
                 Command to run. Available commands:
-                `sync`, `edit`, `upload` (deprecated).
+                `sync`, `edit`, `put-file`, `maintenance`, `pin`,
+                `promote`, `rollback`,
+                `upload` (deprecated).
             "#);
         ap.refer(&mut args)
             .add_argument("args", Collect, r#"
@@ -104,6 +113,18 @@ fn main() {
         Some("put-file") => {
             put_file::cli(opt, args);
         }
+        Some("maintenance") => {
+            maintenance::cli(opt, args);
+        }
+        Some("pin") => {
+            pin::cli(opt, args);
+        }
+        Some("promote") => {
+            promote::cli(opt, args);
+        }
+        Some("rollback") => {
+            rollback::cli(opt, args);
+        }
         None => {
             writeln!(&mut stderr(), "\
                 Command argument required. Try:\n\
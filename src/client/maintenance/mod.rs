@@ -0,0 +1,90 @@
+mod network;
+
+use std::path::PathBuf;
+use std::process::exit;
+
+use structopt::StructOpt;
+
+use keys::read_keys;
+use global_options::GlobalOptions;
+use proto::MaintenanceAction;
+
+
+fn parse_action(s: &str) -> Result<MaintenanceAction, String> {
+    let all = [
+        MaintenanceAction::Cleanup,
+        MaintenanceAction::IndexGc,
+        MaintenanceAction::Restore,
+    ];
+    all.iter().find(|a| a.name() == s).cloned()
+        .ok_or_else(|| format!("unknown action {:?}, \
+            expected `cleanup`, `index-gc` or `restore`", s))
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name="ciruela maintenance", about="
+    Asks servers to run cleanup or index garbage collection right now,
+    or to restore an image removed by cleanup from the trash.
+    Request must be signed by one of the keys in server's `master.key`.
+")]
+pub struct MaintenanceOptions {
+    #[structopt(name="HOST", help="\
+        Host names of the servers to run maintenance on. All addresses \
+        of each name are contacted. \
+    ")]
+    hosts: Vec<String>,
+
+    #[structopt(short="a", long="action", name="ACTION",
+                parse(try_from_str="parse_action"),
+                help="\
+        Action to run: `cleanup`, `index-gc` or `restore`. \
+    ")]
+    action: MaintenanceAction,
+
+    #[structopt(short="d", long="dir", help="\
+        A virtual path to the base directory to clean up. By default \
+        all directories with `auto-clean` enabled are cleaned. \
+        For `restore` it's a path of the image to restore (required). \
+    ", parse(from_os_str))]
+    dir: Option<PathBuf>,
+
+    #[structopt(short="i", long="identity", name="FILENAME",
+                raw(number_of_values="1"),
+                help="\
+        Use the specified identity files (basically ssh-keys) to \
+        sign the request. By default all supported keys in \
+        `$HOME/.ssh` and a key passed in environ variable `CIRUELA_KEY` \
+        are used. Note: multiple `-i` flags may be used. \
+    ")]
+    identity: Vec<String>,
+
+    #[structopt(short="k", long="key-from-env", name="ENV_VAR",
+                raw(number_of_values="1"),
+                help="\
+        Use specified env variable to get identity (basically ssh-key). \
+        The environment variable contains actual key, not the file \
+        name. Multiple variables can be specified along with `-i`. \
+    ")]
+    key_from_env: Vec<String>,
+}
+
+pub fn cli(gopt: GlobalOptions, mut args: Vec<String>) -> ! {
+    args.insert(0, String::from("ciruela maintenance"));  // temporarily
+    let opts = MaintenanceOptions::from_iter(args);
+
+    let keys = match read_keys(&opts.identity, &opts.key_from_env) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("{}", e);
+            exit(2);
+        }
+    };
+    match network::run(gopt.destination_port, keys, opts) {
+        Ok(true) => exit(0),
+        Ok(false) => exit(1),
+        Err(e) => {
+            error!("{}", e);
+            exit(3);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+mod network;
+
+use std::path::PathBuf;
+use std::process::exit;
+
+use structopt::StructOpt;
+
+use keys::read_keys;
+use global_options::GlobalOptions;
+
+
+#[derive(StructOpt, Debug)]
+#[structopt(name="ciruela promote", about="
+    Points `current-symlink` of the base directory to the specified image,
+    which must already be present on the servers. This is useful to roll
+    back to an older image. Promote is in effect until a newer image is
+    uploaded. It's propagated across the cluster, so it's enough to send
+    request to a single server. Request must be signed by one of the
+    `upload-keys` of the directory.
+")]
+pub struct PromoteOptions {
+    #[structopt(name="HOST", help="\
+        Host names of the servers to send request to. All addresses \
+        of each name are contacted. \
+    ")]
+    hosts: Vec<String>,
+
+    #[structopt(short="d", long="dir", help="\
+        A virtual path to the directory (image) to promote. \
+    ", parse(from_os_str))]
+    dir: PathBuf,
+
+    #[structopt(short="i", long="identity", name="FILENAME",
+                raw(number_of_values="1"),
+                help="\
+        Use the specified identity files (basically ssh-keys) to \
+        sign the request. By default all supported keys in \
+        `$HOME/.ssh` and a key passed in environ variable `CIRUELA_KEY` \
+        are used. Note: multiple `-i` flags may be used. \
+    ")]
+    identity: Vec<String>,
+
+    #[structopt(short="k", long="key-from-env", name="ENV_VAR",
+                raw(number_of_values="1"),
+                help="\
+        Use specified env variable to get identity (basically ssh-key). \
+        The environment variable contains actual key, not the file \
+        name. Multiple variables can be specified along with `-i`. \
+    ")]
+    key_from_env: Vec<String>,
+}
+
+pub fn cli(gopt: GlobalOptions, mut args: Vec<String>) -> ! {
+    args.insert(0, String::from("ciruela promote"));  // temporarily
+    let opts = PromoteOptions::from_iter(args);
+
+    let keys = match read_keys(&opts.identity, &opts.key_from_env) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("{}", e);
+            exit(2);
+        }
+    };
+    match network::run(gopt.destination_port, keys, opts) {
+        Ok(true) => exit(0),
+        Ok(false) => exit(1),
+        Err(e) => {
+            error!("{}", e);
+            exit(3);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::SystemTime;
+
+use abstract_ns::{Name, HostResolve};
+use failure::Error;
+use futures::future::{Future, join_all};
+use ssh_keys::PrivateKey;
+use tk_easyloop;
+
+use name;
+use ciruela::blocks::ThreadedBlockReader;
+use ciruela::index::InMemoryIndexes;
+use promote::PromoteOptions;
+use proto::{Client, Listener, RequestClient, Promote, sign};
+use proto::message::Notification;
+use {VPath};
+
+
+struct Quiet;
+
+impl Listener for Quiet {
+    fn notification(&self, _n: Notification) {}
+    fn closed(&self) {}
+}
+
+
+pub fn run(port: u16, keys: Vec<PrivateKey>, opts: PromoteOptions)
+    -> Result<bool, Error>
+{
+    if opts.hosts.len() == 0 {
+        bail!("at least one host name is expected");
+    }
+    let names = opts.hosts.iter()
+        .map(|h| h.parse::<Name>()
+            .map_err(|e| format_err!("bad host name {:?}: {}", h, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let path = VPath::from(&opts.dir);
+    let timestamp = SystemTime::now();
+    let sigs = sign(Promote {
+        path: path.clone(),
+        timestamp: timestamp,
+        signatures: Vec::new(),
+    }.sig_data(), &keys);
+
+    let mut keep_resolver = None;
+    let results = tk_easyloop::run(|| {
+        let resolver = name::resolver(&tk_easyloop::handle());
+        keep_resolver = Some(resolver.clone());
+        join_all(names.into_iter().map(move |host| {
+            let sigs = sigs.clone();
+            let path = path.clone();
+            let host1 = host.clone();
+            resolver.resolve_host(&host)
+            .map_err(move |e| {
+                error!("Error resolving host {}: {}", host1, e)
+            })
+            .and_then(move |addr| {
+                let addrs = addr.with_port(port).at(0)
+                    .addresses().collect::<Vec<_>>();
+                join_all(addrs.into_iter().map(move |addr| {
+                    let host = host.clone();
+                    let path = path.clone();
+                    let sigs = sigs.clone();
+                    Client::spawn(addr, format!("{}:{}", host, port),
+                        ThreadedBlockReader::new(), InMemoryIndexes::new(),
+                        Quiet)
+                    .and_then(move |cli| {
+                        cli.request(Promote {
+                            path: path,
+                            timestamp: timestamp,
+                            signatures: sigs,
+                        })
+                        .map(|ack| (ack.accepted, ack.reject_reason))
+                        .map_err(move |e| {
+                            error!("Request to {} failed: {}", addr, e);
+                        })
+                    })
+                    .then(move |res| match res {
+                        Ok((true, _)) => {
+                            println!("{} / {}: promote accepted",
+                                host, addr);
+                            Ok::<_, ()>(true)
+                        }
+                        Ok((false, reason)) => {
+                            error!("{} / {}: promote rejected: {}",
+                                host, addr,
+                                reason.as_ref()
+                                    .map(|x| &x[..]).unwrap_or("(???)"));
+                            Ok(false)
+                        }
+                        Err(()) => Ok(false),
+                    })
+                }).collect::<Vec<_>>())
+            })
+            .then(|res| Ok::<_, ()>(match res {
+                Ok(results) => results.len() > 0 &&
+                    results.iter().all(|x| *x),
+                Err(()) => false,
+            }))
+        }).collect::<Vec<_>>())
+    }).expect("all errors are handled");
+    Ok(results.iter().all(|x| *x))
+}
@@ -0,0 +1,91 @@
+mod network;
+
+use std::process::exit;
+
+use ciruela::index::ImageId;
+use structopt::StructOpt;
+
+use keys::read_keys;
+use global_options::GlobalOptions;
+use {VPath};
+
+
+fn parse_target(s: &str) -> Result<(String, VPath), String> {
+    match s.find(':') {
+        Some(off) if s[off+1..].starts_with('/') => {
+            Ok((s[..off].to_string(), VPath::from(&s[off+1..])))
+        }
+        Some(_) => Err(String::from("Path must start with slash")),
+        None => Err(String::from("Target must be in format `host:/path`")),
+    }
+}
+
+fn parse_image(s: &str) -> Result<ImageId, String> {
+    s.parse().map_err(|_| format!("bad image id {:?}", s))
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name="ciruela rollback", about="
+    Replaces an image in a directory having `keep-previous-versions`
+    with one of its previous versions kept by the server. Current image
+    becomes a previous version itself. Request must be signed by one of
+    the `upload-keys` of the directory.
+")]
+pub struct RollbackOptions {
+    #[structopt(name="TARGET", parse(try_from_str="parse_target"),
+                help="\
+        Image to roll back in format `host:/path`. All addresses \
+        of the host name are contacted. \
+    ")]
+    targets: Vec<(String, VPath)>,
+
+    #[structopt(long="to", name="IMAGE_ID",
+                parse(try_from_str="parse_image"),
+                help="\
+        Image id (hex) of the previous version to restore (required). \
+        It's signed along with the request, so rollback is propagated to \
+        other servers of the cluster. Previous versions are kept in \
+        `.versions.<name>` directory on the server under their image ids. \
+    ")]
+    to: ImageId,
+
+    #[structopt(short="i", long="identity", name="FILENAME",
+                raw(number_of_values="1"),
+                help="\
+        Use the specified identity files (basically ssh-keys) to \
+        sign the request. By default all supported keys in \
+        `$HOME/.ssh` and a key passed in environ variable `CIRUELA_KEY` \
+        are used. Note: multiple `-i` flags may be used. \
+    ")]
+    identity: Vec<String>,
+
+    #[structopt(short="k", long="key-from-env", name="ENV_VAR",
+                raw(number_of_values="1"),
+                help="\
+        Use specified env variable to get identity (basically ssh-key). \
+        The environment variable contains actual key, not the file \
+        name. Multiple variables can be specified along with `-i`. \
+    ")]
+    key_from_env: Vec<String>,
+}
+
+pub fn cli(gopt: GlobalOptions, mut args: Vec<String>) -> ! {
+    args.insert(0, String::from("ciruela rollback"));  // temporarily
+    let opts = RollbackOptions::from_iter(args);
+
+    let keys = match read_keys(&opts.identity, &opts.key_from_env) {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("{}", e);
+            exit(2);
+        }
+    };
+    match network::run(gopt.destination_port, keys, opts) {
+        Ok(true) => exit(0),
+        Ok(false) => exit(1),
+        Err(e) => {
+            error!("{}", e);
+            exit(3);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::time::SystemTime;
+
+use abstract_ns::{Name, HostResolve};
+use failure::Error;
+use futures::future::{Future, join_all};
+use ssh_keys::PrivateKey;
+use tk_easyloop;
+
+use name;
+use ciruela::blocks::ThreadedBlockReader;
+use ciruela::index::InMemoryIndexes;
+use rollback::RollbackOptions;
+use proto::{Client, Listener, RequestClient, Rollback, sign};
+use proto::message::Notification;
+
+
+struct Quiet;
+
+impl Listener for Quiet {
+    fn notification(&self, _n: Notification) {}
+    fn closed(&self) {}
+}
+
+
+pub fn run(port: u16, keys: Vec<PrivateKey>, opts: RollbackOptions)
+    -> Result<bool, Error>
+{
+    if opts.targets.len() == 0 {
+        bail!("at least one target is expected");
+    }
+    let timestamp = SystemTime::now();
+    let image = opts.to;
+    let targets = opts.targets.iter()
+        .map(|&(ref host, ref path)| {
+            let name = host.parse::<Name>()
+                .map_err(|e| format_err!("bad host name {:?}: {}", host, e))?;
+            let unsigned = Rollback {
+                path: path.clone(),
+                image: image.clone(),
+                timestamp: timestamp,
+                signatures: Vec::new(),
+                replace_signatures: Vec::new(),
+            };
+            let sigs = sign(unsigned.sig_data(), &keys);
+            let replace_sigs = sign(unsigned.replace_sig_data(), &keys);
+            Ok((name, path.clone(), sigs, replace_sigs))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let mut keep_resolver = None;
+    let results = tk_easyloop::run(|| {
+        let resolver = name::resolver(&tk_easyloop::handle());
+        keep_resolver = Some(resolver.clone());
+        join_all(targets.into_iter()
+        .map(move |(host, path, sigs, replace_sigs)| {
+            let image = image.clone();
+            let host1 = host.clone();
+            resolver.resolve_host(&host)
+            .map_err(move |e| {
+                error!("Error resolving host {}: {}", host1, e)
+            })
+            .and_then(move |addr| {
+                let addrs = addr.with_port(port).at(0)
+                    .addresses().collect::<Vec<_>>();
+                join_all(addrs.into_iter().map(move |addr| {
+                    let host = host.clone();
+                    let path = path.clone();
+                    let image = image.clone();
+                    let sigs = sigs.clone();
+                    let replace_sigs = replace_sigs.clone();
+                    Client::spawn(addr, format!("{}:{}", host, port),
+                        ThreadedBlockReader::new(), InMemoryIndexes::new(),
+                        Quiet)
+                    .and_then(move |cli| {
+                        cli.request(Rollback {
+                            path: path,
+                            image: image,
+                            timestamp: timestamp,
+                            signatures: sigs,
+                            replace_signatures: replace_sigs,
+                        })
+                        .map(|ack| (ack.accepted, ack.reject_reason,
+                                    ack.image))
+                        .map_err(move |e| {
+                            error!("Request to {} failed: {}", addr, e);
+                        })
+                    })
+                    .then(move |res| match res {
+                        Ok((true, _, image)) => {
+                            println!("{} / {}: rollback accepted ({})",
+                                host, addr,
+                                image.map(|x| x.to_string())
+                                    .unwrap_or_else(|| "???".into()));
+                            Ok::<_, ()>(true)
+                        }
+                        Ok((false, reason, _)) => {
+                            error!("{} / {}: rollback rejected: {}",
+                                host, addr,
+                                reason.as_ref()
+                                    .map(|x| &x[..]).unwrap_or("(???)"));
+                            Ok(false)
+                        }
+                        Err(()) => Ok(false),
+                    })
+                }).collect::<Vec<_>>())
+            })
+            .then(|res| Ok::<_, ()>(match res {
+                Ok(results) => results.len() > 0 &&
+                    results.iter().all(|x| *x),
+                Err(()) => false,
+            }))
+        }).collect::<Vec<_>>())
+    }).expect("all errors are handled");
+    Ok(results.iter().all(|x| *x))
+}
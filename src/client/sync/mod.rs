@@ -66,6 +66,27 @@ struct SyncOptions {
                     id matches the one specified.")]
     replace: Vec<String>,
 
+    #[structopt(long="expires-in", name="EXPIRES_IN",
+                parse(try_from_str="::humantime::parse_duration"),
+                help="\
+        Servers remove directories uploaded with `--append` and \
+        `--append-weak` after this time, regardless of cleanup settings \
+        (format: http://bit.ly/durationf). Doesn't apply to `--replace`. \
+    ")]
+    expires_in: Option<Duration>,
+
+    #[structopt(long="xattrs", name="XATTR_PATTERN",
+                raw(number_of_values="1"),
+                help="\
+        Record extended attributes matching the pattern (e.g. \
+        `security.capability` or `user.*`) in the `.ciruela-xattrs` file \
+        added to the root of each image (source directory is left \
+        intact). Servers apply them \
+        if allowed by the directory config. Multiple patterns may be \
+        specified. \
+    ")]
+    xattrs: Vec<String>,
+
     #[structopt(short="i", long="identity", name="FILENAME",
                 raw(number_of_values="1"),
                 help="\
@@ -1,17 +1,24 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 use dir_signature::{v1, ScannerConfig, HashType};
 use failure::{Error, err_msg, ResultExt};
+use hex;
+use serde_json;
 use ssh_keys::PrivateKey;
 
 use ciruela::blocks::ThreadedBlockReader;
 use ciruela::index::{InMemoryIndexes, ImageId};
-use ciruela::signature::{SignedUpload, sign_upload};
+use ciruela::signature::{SignedUpload, sign_upload, sign_expiring_upload};
 use ciruela::VPath;
 
 use global_options::GlobalOptions;
 use sync::SyncOptions;
+use xattrs::{self, Manifest, MANIFEST};
 
 
 #[derive(Debug, Clone)]
@@ -46,7 +53,117 @@ fn split_replace(cli: &str)
     return Ok((src, dest, image_id));
 }
 
-fn scan(dir: &Path, threads: usize) -> Result<Vec<u8>, Error> {
+/// Collects extended attributes matching `patterns` below `base`
+fn collect_xattrs(base: &Path, dir: &Path, patterns: &[String],
+    manifest: &mut Manifest)
+    -> Result<(), Error>
+{
+    let entries = fs::read_dir(dir)
+        .context(format!("error reading dir {:?}", dir))?;
+    for entry in entries {
+        let entry = entry.context(format!("error reading dir {:?}", dir))?;
+        let path = entry.path();
+        let file_type = entry.file_type()
+            .context(format!("error reading {:?}", path))?;
+        if file_type.is_symlink() || path == base.join(MANIFEST) {
+            continue;
+        }
+        let mut attrs = BTreeMap::new();
+        for name in xattrs::list(&path)
+            .context(format!("error listing xattrs of {:?}", path))?
+        {
+            if patterns.iter().any(|p| xattrs::matches(p, &name)) {
+                let value = xattrs::get(&path, &name)
+                    .context(format!("error reading xattr {:?} of {:?}",
+                                     name, path))?;
+                attrs.insert(name, hex::encode(&value));
+            }
+        }
+        if !attrs.is_empty() {
+            let rel = path.strip_prefix(base).expect("path is in base dir");
+            manifest.insert(format!("/{}", rel.display()), attrs);
+        }
+        if file_type.is_dir() {
+            collect_xattrs(base, &path, patterns, manifest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the manifest as a file in the root of the index
+///
+/// Manifest is served from memory. A file with the same name in the source
+/// directory is replaced.
+fn add_manifest(index: &[u8], data: Vec<u8>, blocks: &ThreadedBlockReader)
+    -> Result<Vec<u8>, Error>
+{
+    let mut parser = v1::Parser::new(io::Cursor::new(index))
+        .context("error parsing index")?;
+    let header = parser.get_header();
+    let hash_type = header.get_hash_type();
+    let block_size = header.get_block_size();
+    let (manifest_size, manifest_hashes) =
+        v1::Hashes::hash_file(hash_type, block_size, &data[..])
+        .expect("can always hash memory");
+    let name = OsStr::new(MANIFEST);
+    let mut buf = Vec::with_capacity(index.len() + 1024);
+    {
+        let mut emitter = v1::Emitter::new(hash_type, block_size, &mut buf)?;
+        // manifest isn't added to the root dir yet
+        let mut pending = false;
+        for entry in parser.iter() {
+            let entry = entry.context("error parsing index")?;
+            let fname = match entry {
+                v1::Entry::Dir(ref path) => {
+                    if pending {
+                        emitter.add_file(name, false,
+                            manifest_size, &manifest_hashes)?;
+                    }
+                    emitter.start_dir(path)?;
+                    pending = path == Path::new("/");
+                    continue;
+                }
+                v1::Entry::File { ref path, .. } |
+                v1::Entry::Link(ref path, _)
+                => path.file_name().expect("file has a name"),
+            };
+            // entries are sorted by name
+            if pending && fname >= name {
+                emitter.add_file(name, false,
+                    manifest_size, &manifest_hashes)?;
+                pending = false;
+                if fname == name {
+                    continue;
+                }
+            }
+            match entry {
+                v1::Entry::File { exe, size, ref hashes, .. } => {
+                    emitter.add_file(fname, exe, size, hashes)?;
+                }
+                v1::Entry::Link(_, ref dest) => {
+                    emitter.add_symlink(fname, dest)?;
+                }
+                v1::Entry::Dir(..) => unreachable!(),
+            }
+        }
+        if pending {
+            emitter.add_file(name, false, manifest_size, &manifest_hashes)?;
+        }
+        emitter.finish()?;
+    }
+    blocks.register_memory_blocks(hash_type, block_size, data);
+    Ok(buf)
+}
+
+/// Indexes the directory and registers its blocks to be uploaded
+///
+/// Extended attributes matching `xattr_patterns` are recorded in the
+/// manifest, which is added to the index (so it's signed as a part of the
+/// image) and served from memory.
+fn scan(dir: &Path, xattr_patterns: &[String], threads: usize,
+    blocks: &ThreadedBlockReader)
+    -> Result<Vec<u8>, Error>
+{
     let mut cfg = ScannerConfig::new();
     cfg.threads(threads);
     cfg.hash(HashType::blake2b_256());
@@ -55,7 +172,18 @@ fn scan(dir: &Path, threads: usize) -> Result<Vec<u8>, Error> {
     let mut index_buf = Vec::new();
     v1::scan(&cfg, &mut index_buf)
         .context(format!("error indexing dir {:?}", dir))?;
-    return Ok(index_buf);
+    blocks.register_dir(dir, &index_buf)?;
+    if xattr_patterns.is_empty() {
+        return Ok(index_buf);
+    }
+    let mut manifest = Manifest::new();
+    collect_xattrs(dir, dir, xattr_patterns, &mut manifest)?;
+    if manifest.is_empty() {
+        return Ok(index_buf);
+    }
+    let data = serde_json::to_vec_pretty(&manifest)
+        .expect("manifest is serializable");
+    add_manifest(&index_buf, data, blocks)
 }
 
 pub(in sync) fn prepare(opts: &SyncOptions, keys: &Vec<PrivateKey>,
@@ -66,32 +194,36 @@ pub(in sync) fn prepare(opts: &SyncOptions, keys: &Vec<PrivateKey>,
     let mut result = Vec::new();
 
     let timestamp = SystemTime::now();
+    let sign_append = |dest: &VPath, image_id: &ImageId| {
+        match opts.expires_in {
+            Some(dur) => sign_expiring_upload(dest, image_id, timestamp,
+                                              timestamp + dur, keys),
+            None => sign_upload(dest, image_id, timestamp, keys),
+        }
+    };
 
     for dir in &opts.append {
         let (src, dest) = split(dir)?;
-        let index_buf = scan(&src, gopt.threads)?;
+        let index_buf = scan(&src, &opts.xattrs, gopt.threads, blocks)?;
         let image_id = indexes.register_index(&index_buf)?;
-        blocks.register_dir(&src, &index_buf)?;
 
-        let upload = sign_upload(&dest, &image_id, timestamp, &keys);
+        let upload = sign_append(&dest, &image_id);
         result.push(Upload::Append(upload));
     }
 
     for dir in &opts.append_weak {
         let (src, dest) = split(dir)?;
-        let index_buf = scan(&src, gopt.threads)?;
+        let index_buf = scan(&src, &opts.xattrs, gopt.threads, blocks)?;
         let image_id = indexes.register_index(&index_buf)?;
-        blocks.register_dir(&src, &index_buf)?;
 
-        let upload = sign_upload(&dest, &image_id, timestamp, &keys);
+        let upload = sign_append(&dest, &image_id);
         result.push(Upload::WeakAppend(upload));
     }
 
     for dir in &opts.replace {
         let (src, dest, old_image) = split_replace(dir)?;
-        let index_buf = scan(&src, gopt.threads)?;
+        let index_buf = scan(&src, &opts.xattrs, gopt.threads, blocks)?;
         let image_id = indexes.register_index(&index_buf)?;
-        blocks.register_dir(&src, &index_buf)?;
 
         let upload = sign_upload(&dest, &image_id, timestamp, &keys);
         if let Some(old) = old_image {
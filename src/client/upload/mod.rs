@@ -90,8 +90,8 @@ impl Listener for Tracker {
                 pro.check_status()
             }
             AbortedImage(img) => {
-                error!("Image download from {} aborted: {}", self.0,
-                    img.reason);
+                error!("Image download from {} aborted: {}{}", self.0,
+                    img.reason, reason_hint(&img.reason));
                 // TODO(tailhook) check image id and path
                 let mut pro = self.1.lock().expect("progress is not poisoned");
                 pro.hosts_errored.insert(self.0);
@@ -127,6 +127,18 @@ fn is_conflict_reason(reason: &Option<String>) -> bool {
     }
 }
 
+fn reason_hint(reason: &str) -> &'static str {
+    match reason {
+        "insufficient_space" => " (not enough free disk space on the \
+            server, check `free-space-reserve` of the directory)",
+        "image_too_large" => " (image is larger than `max-image-bytes` \
+            of the directory)",
+        "quota_exceeded" => " (image doesn't fit `max-total-bytes` or \
+            `max-files` of the directory)",
+        _ => "",
+    }
+}
+
 fn do_upload(gopt: GlobalOptions, opt: options::UploadOptions)
     -> Result<bool, ()>
 {
@@ -168,6 +180,8 @@ fn do_upload(gopt: GlobalOptions, opt: options::UploadOptions)
             path: &turl.path,
             image: image_id.as_ref(),
             timestamp: to_ms(timestamp),
+            expires: None,
+            action: None,
         }, &opt.private_keys));
     }
     let signatures = Arc::new(signatures);
@@ -267,6 +281,7 @@ fn do_upload(gopt: GlobalOptions, opt: options::UploadOptions)
                                             .get(&turl.path[..]).unwrap()
                                             .clone(),
                                         path: VPath::from(turl.path),
+                                        expires: None,
                                     })
                                     .map(move |resp| {
                                         info!("Response from {}: {:?}",
@@ -294,10 +309,13 @@ fn do_upload(gopt: GlobalOptions, opt: options::UploadOptions)
                                                 .unwrap_or("(???)"));
                                         Either::A(ok(true))
                                     } else {
-                                        error!("Upload rejected by {} / {}: {}",
+                                        let reason = reason.as_ref()
+                                            .map(|x| &x[..])
+                                            .unwrap_or("(???)");
+                                        error!("Upload rejected by {} / {}: \
+                                            {}{}",
                                             host, addr,
-                                            reason.as_ref().map(|x| &x[..])
-                                                .unwrap_or("(???)"));
+                                            reason, reason_hint(reason));
                                         Either::A(ok(false))
                                     }
                                 } else {
@@ -1,6 +1,8 @@
 use std::sync::Arc;
 use std::time::Duration;
 
+use failure_tracker::{RetryPolicy, default_policy};
+
 /// Configuration for clustered connection
 ///
 /// More settings will be added as needed.
@@ -12,6 +14,8 @@ pub struct Config {
     pub(crate) early_fraction: f32,
     pub(crate) early_timeout: Duration,
     pub(crate) maximum_timeout: Duration,
+    pub(crate) host_retry: Arc<dyn RetryPolicy>,
+    pub(crate) dns_retry: Arc<dyn RetryPolicy>,
 }
 
 impl Config {
@@ -24,6 +28,8 @@ impl Config {
             early_fraction: 0.75,
             early_timeout: Duration::new(10, 0),
             maximum_timeout: Duration::new(30*60, 0),
+            host_retry: default_policy(),
+            dns_retry: default_policy(),
         }
     }
     /// Set number of connections to initiate when accessing cluster
@@ -109,6 +115,25 @@ impl Config {
         self.maximum_timeout = timeout;
         self
     }
+    /// Set policy of reconnecting to a failed host
+    ///
+    /// Default: `LinearBackoff::new(Duration::from_secs(1))`, i.e. wait one
+    /// more second after each subsequent failure.
+    ///
+    /// Note: hosts are checked for retry once a second, so delays smaller
+    /// than a second are effectively rounded up.
+    pub fn host_retry<P: RetryPolicy>(&mut self, policy: P) -> &mut Self {
+        self.host_retry = Arc::new(policy);
+        self
+    }
+    /// Set policy of retrying name resolution of discovered hosts
+    ///
+    /// Default: `LinearBackoff::new(Duration::from_secs(1))`, same as for
+    /// `host_retry`.
+    pub fn dns_retry<P: RetryPolicy>(&mut self, policy: P) -> &mut Self {
+        self.dns_retry = Arc::new(policy);
+        self
+    }
     /// Finalize config and return an Arc of a config
     pub fn done(&mut self) -> Arc<Config> {
         Arc::new(self.clone())
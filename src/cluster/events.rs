@@ -0,0 +1,78 @@
+use std::net::SocketAddr;
+
+use futures::{Stream, Async};
+use futures::sync::mpsc::{UnboundedReceiver};
+use void::Void;
+
+
+/// An event happened during the upload
+///
+/// Events are sent by `Upload::events()` stream. Note: new kinds of events
+/// may be added in future.
+#[derive(Debug, Clone)]
+pub enum UploadEvent {
+    /// Host accepted the directory and is going to download it
+    Accepted {
+        /// Address of the host we are connected to
+        addr: SocketAddr,
+    },
+    /// Host rejected the directory
+    Rejected {
+        /// Address of the host we are connected to
+        addr: SocketAddr,
+        /// Reason sent by host, e.g. `no_config` or `already_exists`
+        reason: String,
+    },
+    /// New host that is going to download the directory is discovered
+    DiscoveredHost {
+        /// Hostname of the discovered host
+        hostname: String,
+    },
+    /// Host has finished downloading the image
+    ReceivedImage {
+        /// Address of the host that sent notification
+        addr: SocketAddr,
+        /// Hostname of the host that downloaded the image
+        hostname: String,
+        /// Whether notification is forwarded by `addr` from other host
+        forwarded: bool,
+    },
+    /// Host has aborted downloading the image
+    AbortedImage {
+        /// Address of the host that sent notification
+        addr: SocketAddr,
+        /// Hostname of the host that aborted the image
+        hostname: String,
+        /// Whether notification is forwarded by `addr` from other host
+        forwarded: bool,
+        /// Reason of abort
+        reason: String,
+    },
+    /// Upload is considered successful after early timeout
+    ///
+    /// See `Config::early_upload` for more info.
+    EarlySuccess,
+    /// Maximum upload timeout is reached
+    Deadline,
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+/// Stream returned from `Upload::events`
+///
+/// Stream ends when upload is finished (either successfully or not).
+#[derive(Debug)]
+pub struct UploadEvents {
+    pub(crate) inner: UnboundedReceiver<UploadEvent>,
+}
+
+impl Stream for UploadEvents {
+    type Item = UploadEvent;
+    type Error = Void;
+    fn poll(&mut self) -> Result<Async<Option<UploadEvent>>, Void> {
+        match self.inner.poll() {
+            Ok(x) => Ok(x),
+            Err(()) => Ok(Async::Ready(None)),
+        }
+    }
+}
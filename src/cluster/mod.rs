@@ -16,9 +16,12 @@ mod upload;
 mod download;
 mod future;
 mod error;
+mod events;
 
 pub use cluster::config::Config;
+pub use failure_tracker::{RetryPolicy, LinearBackoff, ExponentialBackoff};
 pub use cluster::upload::{Stats, ProgressOneLiner};
+pub use cluster::events::{UploadEvent, UploadEvents};
 pub use cluster::download::{RawIndex, MutableIndex, MaterializedIndex};
 pub use cluster::download::{IndexParseError, IndexUpdateError};
 pub use cluster::future::{UploadFuture, UploadOk, UploadFail};
@@ -27,6 +30,7 @@ pub use cluster::error::{UploadErr, ErrorKind};
 use std::sync::Arc;
 
 use abstract_ns::{Name, Resolve, HostResolve};
+use failure::err_msg;
 use futures::sync::mpsc::{UnboundedSender};
 use futures::future::{Future, Shared};
 use futures::sync::oneshot;
@@ -121,6 +125,9 @@ impl Connection {
     }
     /// Initiate a new upload (replacing a directory)
     ///
+    /// Upload fails immediately if it's expiring (see
+    /// `sign_expiring_upload`), as expiring image can't replace a directory.
+    ///
     /// # Panics
     ///
     /// If connection set is already closed
@@ -130,6 +137,9 @@ impl Connection {
 
     /// Initiate a new upload (replacing if directory hash matches)
     ///
+    /// Upload fails immediately if it's expiring (see
+    /// `sign_expiring_upload`), as expiring image can't replace a directory.
+    ///
     /// # Panics
     ///
     /// If connection set is already closed
@@ -145,6 +155,15 @@ impl Connection {
         let (tx, rx) = oneshot::channel();
         let stats = Arc::new(upload::Stats::new(
             &self.cluster_name, &upload.path, weak));
+        if replace && upload.expires.is_some() {
+            tx.send(Err(Arc::new(UploadErr::Fatal(
+                err_msg("expiring upload can't replace a directory"))))).ok();
+            stats.finish();
+            return Upload {
+                stats,
+                future: rx.shared(),
+            };
+        }
         self.chan.unbounded_send(Message::NewUpload(NewUpload {
             replace, upload, weak, old_image: old_image,
             stats: stats.clone(),
@@ -207,4 +226,51 @@ impl Upload {
     pub fn stats(&self) -> &Stats {
         &*self.stats
     }
+
+    /// Return a stream of events happening during the upload
+    ///
+    /// Only events happened after this method is called are sent. Stream
+    /// is finished when upload is done, see `future()` for the result.
+    /// This method may be called multiple times, each stream gets
+    /// all events.
+    pub fn events(&self) -> UploadEvents {
+        self.stats.subscribe()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use futures::{Future, Stream};
+    use futures::sync::mpsc::unbounded;
+
+    use VPath;
+    use cluster::{Config, Connection};
+    use id::ImageId;
+    use signature::SignedUpload;
+
+    #[test]
+    fn expiring_replace() {
+        let (tx, rx) = unbounded();
+        let conn = Connection {
+            cluster_name: vec![],
+            config: Config::new().done(),
+            chan: tx,
+        };
+        let now = SystemTime::now();
+        let up = conn.replace(SignedUpload {
+            path: VPath::from("/dir/v1"),
+            image_id: ImageId::from(vec![1; 32]),
+            timestamp: now,
+            signatures: Vec::new(),
+            expires: Some(now + Duration::from_secs(3600)),
+        });
+        assert!(up.future().wait().is_err());
+        assert_eq!(up.events().collect().wait().unwrap().len(), 0);
+        // nothing is sent to the servers
+        drop(conn);
+        assert_eq!(rx.collect().wait().unwrap().len(), 0);
+    }
 }
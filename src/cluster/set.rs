@@ -26,6 +26,7 @@ use cluster::config::Config;
 use cluster::download::{RawIndex, Location, Pointer};
 use cluster::upload;
 use cluster::error::{UploadErr, FetchErr, ErrorKind};
+use cluster::events::UploadEvent;
 use cluster::future::UploadOk;
 use signature::SignedUpload;
 use failure_tracker::{HostFailures, DnsFailures, SlowHostFailures};
@@ -160,11 +161,11 @@ impl<R, I, B> ConnectionSet<R, I, B>
             config: config.clone(),
             uploads: VecDeque::new(),
             fetches: VecDeque::new(),
-            failures: HostFailures::new_default(),
+            failures: HostFailures::new(&config.host_retry),
             pending: HashMap::new(),
             active: HashMap::new(),
             pending_addrs: HashMap::new(),
-            failed_addrs: DnsFailures::new_default(),
+            failed_addrs: DnsFailures::new(&config.dns_retry),
             addrs: HashMap::new(),
             retry: timeout(Duration::new(1, 0)),
         });
@@ -477,6 +478,7 @@ impl<R, I, B> ConnectionSet<R, I, B>
                                     timestamp: up.upload.timestamp.clone(),
                                     signatures: up.upload.signatures.clone(),
                                     path: up.upload.path.clone(),
+                                    expires: up.upload.expires,
                                 })));
                         }
                         up.connections.insert(*addr, conn.clone());
@@ -523,8 +525,9 @@ impl<R, I, B> ConnectionSet<R, I, B>
     fn poll_uploads(&mut self) {
         for _ in 0..self.uploads.len() {
             let cur = self.uploads.pop_front().unwrap();
+            let stats = cur.stats.clone();
             match self.poll_upload(cur) {
-                VAsync::Ready(()) => {},
+                VAsync::Ready(()) => stats.finish(),
                 VAsync::NotReady(cur) => self.uploads.push_back(cur),
             }
         }
@@ -618,6 +621,10 @@ impl<R, I, B> ConnectionSet<R, I, B>
                 up.candidate_hosts.is_empty());
             match check {
                 Some(Ok(result)) => {
+                    // only if some hosts are still downloading
+                    if early_timeout && !up.stats.is_complete() {
+                        up.stats.event(UploadEvent::EarlySuccess);
+                    }
                     up.resolve.send(Ok(result)).ok();
                     return VAsync::Ready(())
                 }
@@ -632,6 +639,7 @@ impl<R, I, B> ConnectionSet<R, I, B>
         }
 
         if up.deadline.poll().expect("timeout is infallible").is_ready() {
+            up.stats.event(UploadEvent::Deadline);
             let check = upload::check(&up.stats, &self.config,
                 &self.initial_addr, early_timeout,
                 up.candidate_hosts.is_empty());
@@ -813,6 +821,15 @@ impl<R, I, B> Future for ConnectionSet<R, I, B>
     }
 }
 
+impl<R, I, B> Drop for ConnectionSet<R, I, B> {
+    fn drop(&mut self) {
+        // close event streams of uploads that will never be finished
+        for up in &self.uploads {
+            up.stats.finish();
+        }
+    }
+}
+
 impl proto::Listener for Listener {
     fn notification(&self, n: Notification) {
         self.chan.unbounded_send(Message::Notification(self.addr, n)).ok();
@@ -3,12 +3,14 @@ use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, Mutex};
 use std::time::Instant;
 
 use abstract_ns::Name;
+use futures::sync::mpsc::{unbounded, UnboundedSender};
 use proto::{ReceivedImage, AbortedImage};
 use cluster::addr::AddrCell;
+use cluster::events::{UploadEvent, UploadEvents};
 use cluster::future::UploadOk;
 use cluster::error::ErrorKind;
 use cluster::config::Config;
@@ -41,6 +43,8 @@ pub struct Stats {
     weak_errors: bool,
     book: RwLock<Bookkeeping>,
     total_responses: AtomicUsize,
+    // None when upload is finished
+    subscribers: Mutex<Option<Vec<UnboundedSender<UploadEvent>>>>,
 }
 
 /// Displays some short progress of the upload
@@ -73,6 +77,41 @@ impl Stats {
                 rejected_no_config: HashSet::new(),
             }),
             total_responses: AtomicUsize::new(0),
+            subscribers: Mutex::new(Some(Vec::new())),
+        }
+    }
+    pub(crate) fn subscribe(&self) -> UploadEvents {
+        let (tx, rx) = unbounded();
+        let mut subscribers = self.subscribers.lock()
+            .expect("subscribers are not poisoned");
+        // if upload is already finished, sender is dropped here
+        // and stream is closed immediately
+        if let Some(ref mut list) = *subscribers {
+            list.push(tx);
+        }
+        UploadEvents { inner: rx }
+    }
+    pub(crate) fn event(&self, event: UploadEvent) {
+        let mut subscribers = self.subscribers.lock()
+            .expect("subscribers are not poisoned");
+        if let Some(ref mut list) = *subscribers {
+            list.retain(|s| s.unbounded_send(event.clone()).is_ok());
+        }
+    }
+    /// Closes all event streams
+    pub(crate) fn finish(&self) {
+        self.subscribers.lock()
+            .expect("subscribers are not poisoned")
+            .take();
+    }
+    fn discovered(&self, book: &mut Bookkeeping,
+        id: &MachineId, hostname: &str)
+    {
+        if !book.discovered_servers.contains_key(id) {
+            book.discovered_servers.insert(id.clone(), hostname.to_string());
+            self.event(UploadEvent::DiscoveredHost {
+                hostname: hostname.to_string(),
+            });
         }
     }
     pub(crate) fn received_image(&self, addr: SocketAddr, info: &ReceivedImage)
@@ -84,8 +123,12 @@ impl Stats {
         }
         book.done_servers.insert(
             info.machine_id.clone(), info.hostname.clone());
-        book.discovered_servers.insert(
-            info.machine_id.clone(), info.hostname.clone());
+        self.discovered(&mut book, &info.machine_id, &info.hostname);
+        self.event(UploadEvent::ReceivedImage {
+            addr,
+            hostname: info.hostname.clone(),
+            forwarded: info.forwarded,
+        });
     }
     pub(crate) fn aborted_image(&self, addr: SocketAddr, info: &AbortedImage) {
         let mut book = self.book.write()
@@ -97,6 +140,12 @@ impl Stats {
             info.machine_id.clone(), info.reason.clone());
         book.aborted_hostnames.insert(
             info.hostname.clone(), info.reason.clone());
+        self.event(UploadEvent::AbortedImage {
+            addr,
+            hostname: info.hostname.clone(),
+            forwarded: info.forwarded,
+            reason: info.reason.clone(),
+        });
     }
     pub(crate) fn add_response(&self, source: SocketAddr,
         mut accepted: bool, reject_reason: Option<String>,
@@ -114,6 +163,7 @@ impl Stats {
                 let res = book.accepted_ips.insert(source);
                 if res {
                     self.total_responses.fetch_add(1, Ordering::Relaxed);
+                    self.event(UploadEvent::Accepted { addr: source });
                 }
                 book.done_ips.insert(source);
                 // TODO(tailhook) mark other dicts as done too
@@ -123,15 +173,24 @@ impl Stats {
                 info!("Connection {} rejects directory", source);
                 if book.rejected_no_config.insert(source) {
                     self.total_responses.fetch_add(1, Ordering::Relaxed);
+                    self.event(UploadEvent::Rejected {
+                        addr: source,
+                        reason: String::from("no_config"),
+                    });
                 }
             }
             (false, _) => {
                 warn!("Rejected because of {:?} try {:?}",
                     reject_reason, hosts);
-                let res = book.rejected_ips.insert(source,
-                    reject_reason.unwrap_or_else(|| String::from("unknown")));
+                let reason = reject_reason
+                    .unwrap_or_else(|| String::from("unknown"));
+                let res = book.rejected_ips.insert(source, reason.clone());
                 if res.is_none() {
                     self.total_responses.fetch_add(1, Ordering::Relaxed);
+                    self.event(UploadEvent::Rejected {
+                        addr: source,
+                        reason,
+                    });
                 }
             }
             (true, _) => {
@@ -139,14 +198,21 @@ impl Stats {
                 let res = book.accepted_ips.insert(source);
                 if res {
                     self.total_responses.fetch_add(1, Ordering::Relaxed);
+                    self.event(UploadEvent::Accepted { addr: source });
                 }
             }
         }
         for (id, val) in hosts {
-            book.discovered_servers.insert(id, val);
+            self.discovered(&mut book, &id, &val);
         }
         return accepted;
     }
+    /// Returns true if there are no hosts still downloading the image
+    pub(crate) fn is_complete(&self) -> bool {
+        self.book.read()
+            .expect("bookkeeping is not poisoned")
+            .all_done()
+    }
     pub(crate) fn total_responses(&self) -> u32 {
         self.total_responses.load(Ordering::Relaxed) as u32
     }
@@ -244,10 +310,7 @@ pub(in cluster) fn check(stats: &Arc<Stats>, config: &Config,
                 return Some(Ok(UploadOk::new(stats)));
             }
         }
-    } else if
-        book.done_ips.is_superset(&book.accepted_ips) &&
-        is_superset(&book.done_servers, &book.discovered_servers)
-    {
+    } else if book.all_done() {
             if book.done_servers.len() > 0 {
                 if book.rejected_ips.len() > 0 || book.aborted_ips.len() > 0 {
                     return Some(Err(ErrorKind::Rejected));
@@ -318,6 +381,11 @@ impl<'a> fmt::Display for ProgressOneLiner<'a> {
 }
 
 impl Bookkeeping {
+    /// Every host that accepted or was discovered has downloaded the image
+    fn all_done(&self) -> bool {
+        self.done_ips.is_superset(&self.accepted_ips) &&
+        is_superset(&self.done_servers, &self.discovered_servers)
+    }
     fn discovered_num(&self) -> u32 {
         let explicit = self.discovered_servers.len() as u32;
         if self.accepted_ips.len() == 1 &&
@@ -332,3 +400,74 @@ impl Bookkeeping {
         }
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use futures::{Future, Stream};
+    use futures::sync::oneshot;
+
+    use VPath;
+    use cluster::Upload;
+    use cluster::events::UploadEvent;
+    use id::ImageId;
+    use machine_id::MachineId;
+    use proto::ReceivedImage;
+    use super::Stats;
+
+    fn upload() -> Upload {
+        let (_, rx) = oneshot::channel();
+        Upload {
+            stats: Arc::new(Stats::new(&vec![], &VPath::from("/dir/v1"),
+                                       false)),
+            future: rx.shared(),
+        }
+    }
+
+    fn names(events: Vec<UploadEvent>) -> Vec<&'static str> {
+        events.iter().map(|e| match *e {
+            UploadEvent::Accepted { .. } => "accepted",
+            UploadEvent::Rejected { .. } => "rejected",
+            UploadEvent::DiscoveredHost { .. } => "discovered",
+            UploadEvent::ReceivedImage { .. } => "received",
+            UploadEvent::AbortedImage { .. } => "aborted",
+            UploadEvent::EarlySuccess => "early_success",
+            UploadEvent::Deadline => "deadline",
+            UploadEvent::__Nonexhaustive => unreachable!(),
+        }).collect()
+    }
+
+    #[test]
+    fn events() {
+        let up = upload();
+        let addr = "127.0.0.1:24783".parse().unwrap();
+        let id: MachineId = "0123456789abcdef0123456789abcdef"
+            .parse().unwrap();
+        let early = up.events();
+        up.stats.add_response(addr, true, None, HashMap::new());
+        // only events after subscription are received
+        let late = up.events();
+        let mut hosts = HashMap::new();
+        hosts.insert(id.clone(), String::from("host1"));
+        up.stats.add_response(addr, true, None, hosts);
+        assert!(!up.stats.is_complete());
+        up.stats.received_image(addr, &ReceivedImage {
+            id: ImageId::from(vec![1; 32]),
+            path: VPath::from("/dir/v1"),
+            machine_id: id,
+            hostname: String::from("host1"),
+            forwarded: false,
+        });
+        assert!(up.stats.is_complete());
+        // streams are finished with the upload
+        up.stats.finish();
+        assert_eq!(names(early.collect().wait().unwrap()),
+                   vec!["accepted", "discovered", "received"]);
+        assert_eq!(names(late.collect().wait().unwrap()),
+                   vec!["discovered", "received"]);
+        assert_eq!(up.events().collect().wait().unwrap().len(), 0);
+    }
+}
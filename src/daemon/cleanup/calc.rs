@@ -1,12 +1,14 @@
-use std::cmp::min;
-use std::collections::HashSet;
+use std::cmp::{min, max};
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use database::signatures::State;
 
-use config::Directory;
+use cleanup::quota::{ImageSize, has_quota};
+use config::{Directory, CleanupPolicy};
+use index::ImageId;
 
 #[derive(Debug, PartialEq)]
 pub struct Sorted<T> {
@@ -14,6 +16,44 @@ pub struct Sorted<T> {
     pub unused: Vec<T>,
 }
 
+/// The reason why image is kept or evicted by cleanup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all="snake_case")]
+pub enum Reason {
+    /// Image is in the keep list
+    KeepList,
+    /// Image is one of the `keep_min_directories` most recent ones
+    MinDirectories,
+    /// Image was uploaded (or used) within `keep_recent`
+    Recent,
+    /// Image fits `keep_total_size`
+    FitsTotalSize,
+    /// Image was uploaded (or used) earlier than `keep_recent`
+    Outdated,
+    /// There are more than `keep_max_directories` recent images
+    MaxDirectories,
+    /// Image doesn't fit `keep_total_size`
+    TotalSize,
+    /// Image doesn't fit `max_total_bytes` or `max_files`
+    Quota,
+    /// Signed expiration time of the image has passed
+    Expired,
+    /// Image isn't expired and `auto_clean` is disabled
+    NotExpired,
+}
+
+/// Local information about images used by cleanup policies
+///
+/// Images which are not in the maps are considered empty and never
+/// accessed (except at upload).
+#[derive(Debug, Default)]
+pub struct Usage {
+    pub sizes: HashMap<ImageId, ImageSize>,
+    pub last_access: HashMap<ImageId, SystemTime>,
+}
+
+type Item<T> = (T, State, Reason);
+
 fn biggest_timestamp(state: &State) -> SystemTime {
     state.signatures.iter()
     .map(|x| x.timestamp)
@@ -21,51 +61,140 @@ fn biggest_timestamp(state: &State) -> SystemTime {
     .unwrap_or(UNIX_EPOCH)
 }
 
+impl Usage {
+    fn size(&self, state: &State) -> ImageSize {
+        self.sizes.get(&state.image).cloned().unwrap_or_default()
+    }
+    fn last_used(&self, state: &State) -> SystemTime {
+        let upload = biggest_timestamp(state);
+        self.last_access.get(&state.image)
+            .map(|&access| max(access, upload))
+            .unwrap_or(upload)
+    }
+}
+
+pub fn is_expired(state: &State, now: SystemTime) -> bool {
+    state.expires.map(|time| time <= now).unwrap_or(false)
+}
+
+/// Sorts out expired images only, used when `auto_clean` is disabled
+pub fn sort_out_expired<T>(items: Vec<(T, State)>, now: SystemTime)
+    -> Sorted<Item<T>>
+{
+    let (unused, used) = items.into_iter()
+        .partition::<Vec<_>, _>(|&(_, ref state)| is_expired(state, now));
+    Sorted {
+        used: used.into_iter()
+            .map(|(name, state)| (name, state, Reason::NotExpired))
+            .collect(),
+        unused: unused.into_iter()
+            .map(|(name, state)| (name, state, Reason::Expired))
+            .collect(),
+    }
+}
+
+/// Sorts out images into ones that should be kept and ones to delete
+///
+/// Each image is marked with the reason of decision. `usage` is used by
+/// size-based and least-recently-used policies and to enforce quotas.
+/// Expired images are deleted regardless of the policy and keep list.
 pub fn sort_out<T>(config: &Arc<Directory>, items: Vec<(T, State)>,
-                keep_list: &Vec<T>)
-    -> Sorted<(T, State)>
+                keep_list: &Vec<T>, usage: &Usage)
+    -> Sorted<Item<T>>
+    where T: PartialEq + Eq + Hash,
+{
+    let Sorted { used: items, unused: expired } =
+        sort_out_expired(items, SystemTime::now());
+    let items = items.into_iter()
+        .map(|(name, state, _)| (name, state))
+        .collect::<Vec<_>>();
+    let mut sorted = sort_policy(config, items, keep_list, usage);
+    sorted.unused.extend(expired);
+    return sorted;
+}
+
+fn sort_policy<T>(config: &Arc<Directory>, items: Vec<(T, State)>,
+                  keep_list: &Vec<T>, usage: &Usage)
+    -> Sorted<Item<T>>
     where T: PartialEq + Eq + Hash,
 {
-    let mut used = Vec::new();
-    let mut unused = Vec::new();
     let keep_list: HashSet<_> = keep_list.iter().collect();
     if items.len() <= config.keep_min_directories {
         return Sorted {
-            used: items,
+            used: items.into_iter()
+                .map(|(name, state)| (name, state, Reason::MinDirectories))
+                .collect(),
             unused: vec![],
         }
     }
+    let time: &dyn Fn(&State) -> SystemTime = match config.cleanup_policy {
+        CleanupPolicy::LeastRecentlyUsed => &|s| usage.last_used(s),
+        _ => &biggest_timestamp,
+    };
+    let mut sorted = match config.cleanup_policy {
+        CleanupPolicy::Recent | CleanupPolicy::LeastRecentlyUsed => {
+            sort_recent(config, items, &keep_list, time)
+        }
+        CleanupPolicy::TotalSize => {
+            let limit = config.keep_total_size
+                .expect("keep_total_size is validated");
+            let items = items.into_iter()
+                .map(|(name, state)| (name, state, Reason::FitsTotalSize))
+                .collect();
+            let mut unused = Vec::new();
+            let used = evict_oldest(config, items, &keep_list, usage, time,
+                |total| total.bytes <= limit, Reason::TotalSize,
+                &mut unused);
+            Sorted { used, unused }
+        }
+    };
+    if has_quota(config) {
+        let used = ::std::mem::replace(&mut sorted.used, Vec::new());
+        sorted.used = evict_oldest(config, used, &keep_list, usage, time,
+            |total| total.fits(config), Reason::Quota, &mut sorted.unused);
+    }
+    return sorted;
+}
+
+fn sort_recent<T>(config: &Directory, items: Vec<(T, State)>,
+                  keep_list: &HashSet<&T>, time: &dyn Fn(&State) -> SystemTime)
+    -> Sorted<Item<T>>
+    where T: PartialEq + Eq + Hash,
+{
+    let mut used = Vec::new();
+    let mut unused = Vec::new();
     let mut candidates = vec![];
     let min_time = SystemTime::now() - config.keep_recent;
     for (name, state) in items.into_iter() {
-        if biggest_timestamp(&state) >= min_time {
-            used.push((name, state));
+        if time(&state) >= min_time {
+            used.push((name, state, Reason::Recent));
         } else {
-            candidates.push((name, state));
+            candidates.push((name, state, Reason::Outdated));
         }
     }
     if used.len() > config.keep_max_directories {
-        used.sort_by(|&(_, ref a), &(_, ref b)| {
-            biggest_timestamp(b).cmp(&biggest_timestamp(a))
+        used.sort_by(|&(_, ref a, _), &(_, ref b, _)| {
+            time(b).cmp(&time(a))
         });
-        for pair in used.drain(config.keep_max_directories..) {
-            candidates.push(pair);
+        for (name, state, _) in used.drain(config.keep_max_directories..) {
+            candidates.push((name, state, Reason::MaxDirectories));
         }
     }
-    for (name, state) in candidates.into_iter() {
+    for (name, state, reason) in candidates.into_iter() {
         if keep_list.contains(&name) {
-            used.push((name, state));
+            used.push((name, state, Reason::KeepList));
         } else {
-            unused.push((name, state));
+            unused.push((name, state, reason));
         }
     }
     if used.len() < config.keep_min_directories {
-        unused.sort_by(|&(_, ref a), &(_, ref b)| {
-            biggest_timestamp(a).cmp(&biggest_timestamp(b))
+        unused.sort_by(|&(_, ref a, _), &(_, ref b, _)| {
+            time(a).cmp(&time(b))
         });
         let unused_len = unused.len();
         let needs = config.keep_min_directories - used.len();
-        used.extend(unused.drain(unused_len - min(unused_len, needs)..));
+        used.extend(unused.drain(unused_len - min(unused_len, needs)..)
+            .map(|(name, state, _)| (name, state, Reason::MinDirectories)));
     }
     return Sorted {
         used: used,
@@ -73,31 +202,72 @@ pub fn sort_out<T>(config: &Arc<Directory>, items: Vec<(T, State)>,
     }
 }
 
+/// Evicts oldest images into `unused` until total size `fits`
+///
+/// Images from keep list and `keep_min_directories` most recent ones are
+/// never evicted, even if the limit is exceeded.
+fn evict_oldest<T, F>(config: &Directory, mut used: Vec<Item<T>>,
+                      keep_list: &HashSet<&T>, usage: &Usage,
+                      time: &dyn Fn(&State) -> SystemTime, fits: F,
+                      reason: Reason, unused: &mut Vec<Item<T>>)
+    -> Vec<Item<T>>
+    where T: PartialEq + Eq + Hash,
+          F: Fn(ImageSize) -> bool,
+{
+    let mut total = ImageSize::default();
+    for &(ref name, ref state, _) in &used {
+        if keep_list.contains(name) {
+            total += usage.size(state);
+        }
+    }
+    used.sort_by(|&(_, ref a, _), &(_, ref b, _)| time(b).cmp(&time(a)));
+    let mut kept = Vec::with_capacity(used.len());
+    let mut full = false;
+    for (name, state, old_reason) in used {
+        if keep_list.contains(&name) {
+            kept.push((name, state, Reason::KeepList));
+            continue;
+        }
+        let mut new_total = total;
+        new_total += usage.size(&state);
+        if !full && fits(new_total) {
+            total = new_total;
+            kept.push((name, state, old_reason));
+        } else if kept.len() < config.keep_min_directories {
+            total = new_total;
+            full = true;
+            kept.push((name, state, Reason::MinDirectories));
+        } else {
+            // once one image doesn't fit, all older ones are evicted too
+            full = true;
+            unused.push((name, state, reason));
+        }
+    }
+    kept
+}
+
 #[cfg(test)]
 mod test {
-    use std::path::PathBuf;
+    use std::collections::HashMap;
     use std::sync::Arc;
-    use std::time::SystemTime;
+    use std::time::{SystemTime, Duration};
     use humantime::parse_duration;
     use rand::{thread_rng, RngCore};
-    use config::Directory;
-    use super::{sort_out, Sorted};
+    use serde_json::to_string;
+    use cleanup::ImageSize;
+    use config::{Directory, CleanupPolicy};
+    use super::{sort_out, sort_out_expired, Sorted, Usage, Reason};
     use index::{ImageId};
     use proto::Signature;
     use database::signatures::{State, SignatureEntry};
 
     fn cfg(min: usize, max: usize, rec: &str) -> Arc<Directory> {
         Arc::new(Directory {
-            directory: PathBuf::from("<nowhere>"),
-            append_only: true,  // doesn't matter
-            num_levels: 1,
-            upload_keys: Vec::new(),
-            download_keys: Vec::new(),
             auto_clean: true,
-            keep_list_file: None,  // doesn't matter
             keep_min_directories: min,
             keep_max_directories: max,
             keep_recent: parse_duration(rec).unwrap(),
+            ..Directory::test_default("<nowhere>")
         })
     }
 
@@ -118,6 +288,7 @@ mod test {
         State {
             image: id(),
             signatures: Vec::new(),
+            expires: None,
         }
     }
 
@@ -125,10 +296,21 @@ mod test {
                     keep_list: &Vec<u32>)
         -> Sorted<u32>
     {
-        let r = sort_out(config, items, keep_list);
+        let r = sort_out(config, items, keep_list, &Usage::default());
         return Sorted {
-            used: r.used.into_iter().map(|(x, _)| x).collect(),
-            unused: r.unused.into_iter().map(|(x, _)| x).collect(),
+            used: r.used.into_iter().map(|(x, _, _)| x).collect(),
+            unused: r.unused.into_iter().map(|(x, _, _)| x).collect(),
+        }
+    }
+
+    fn simple_sort_usage(config: &Arc<Directory>, items: Vec<(u32, State)>,
+                         usage: &Usage)
+        -> Sorted<u32>
+    {
+        let r = sort_out(config, items, &vec![], usage);
+        return Sorted {
+            used: r.used.into_iter().map(|(x, _, _)| x).collect(),
+            unused: r.unused.into_iter().map(|(x, _, _)| x).collect(),
         }
     }
 
@@ -140,6 +322,7 @@ mod test {
                 timestamp: time,
                 signature: sig(),
             }],
+            expires: None,
         }
     }
     #[test]
@@ -217,4 +400,139 @@ mod test {
                 unused: vec![1, 2],
             });
     }
+
+    #[test]
+    fn test_plan_reasons() {
+        // reasons are shown in `/cleanup/plan/`
+        let items = vec![
+            (1, state_at("1 week")),
+            (2, state_at("1 hour")),
+            (3, state_at("30 min")),
+            (4, state_at("2 min")),
+            (5, state_at("1 year")),
+        ];
+        let r = sort_out(&cfg(1, 2, "1 day"), items.clone(), &vec![5],
+                         &Usage::default());
+        assert_eq!(r.used.iter().map(|x| (x.0, x.2)).collect::<Vec<_>>(),
+            vec![
+                (4, Reason::Recent),
+                (3, Reason::Recent),
+                (5, Reason::KeepList),
+            ]);
+        let mut unused = r.unused.iter().map(|x| (x.0, x.2))
+            .collect::<Vec<_>>();
+        unused.sort_by_key(|&(x, _)| x);
+        assert_eq!(unused, vec![
+            (1, Reason::Outdated),
+            (2, Reason::MaxDirectories),
+        ]);
+        // plan for directories without `auto_clean`
+        let r = sort_out_expired(items, SystemTime::now());
+        assert!(r.used.iter().all(|x| x.2 == Reason::NotExpired));
+        assert_eq!(r.unused.len(), 0);
+        assert_eq!(to_string(&Reason::MaxDirectories).unwrap(),
+                   r#""max_directories""#);
+    }
+
+    #[test]
+    fn test_expired() {
+        let mut expired = state_at("1 hour");
+        expired.expires = Some(SystemTime::now() - Duration::new(60, 0));
+        let mut expiring = state_at("2 hours");
+        expiring.expires = Some(SystemTime::now() + Duration::new(60, 0));
+        let items = vec![(1, expired), (2, expiring), (3, state_at("1 day"))];
+        // expired image is removed even if in keep list and there are
+        // less than keep_min_directories
+        assert_eq!(simple_sort(&cfg(5, 10, "1 hour"), items.clone(), &vec![1]),
+            Sorted { used: vec![2, 3], unused: vec![1] });
+        let r = sort_out_expired(items, SystemTime::now());
+        assert_eq!(r.used.iter().map(|x| x.0).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(r.unused.iter().map(|x| (x.0, x.2)).collect::<Vec<_>>(),
+            vec![(1, Reason::Expired)]);
+    }
+
+    fn sizes(items: &[(u64, State)]) -> HashMap<ImageId, ImageSize> {
+        items.iter().map(|&(n, ref s)| {
+            (s.image.clone(), ImageSize { bytes: n*20, files: 1 })
+        }).collect()
+    }
+
+    #[test]
+    fn test_quota() {
+        let mut config = cfg(1, 100, "1 day");
+        Arc::get_mut(&mut config).unwrap().max_total_bytes = Some(100);
+        let items = vec![
+            (1, state_at("1 week")),
+            (2, state_at("1 hour")),
+            (3, state_at("30 min")),
+            (4, state_at("2 min")),
+            (5, state_at("1 year")),
+        ];
+        let usage = Usage { sizes: sizes(&items), ..Usage::default() };
+        let r = sort_out(&config, items, &vec![5], &usage);
+        let used = r.used.into_iter().map(|(x, _, r)| (x, r))
+            .collect::<Vec<_>>();
+        // 5 is in keep list, 4 is kept because of keep_min_directories
+        assert_eq!(used, vec![
+            (4, Reason::MinDirectories),
+            (5, Reason::KeepList),
+        ]);
+        let mut unused = r.unused.into_iter().map(|(x, _, r)| (x, r))
+            .collect::<Vec<_>>();
+        unused.sort_by_key(|&(x, _)| x);
+        assert_eq!(unused, vec![
+            (1, Reason::Outdated),
+            (2, Reason::Quota),
+            (3, Reason::Quota),
+        ]);
+    }
+
+    #[test]
+    fn test_total_size() {
+        let mut config = cfg(1, 100, "1 day");
+        {
+            let c = Arc::get_mut(&mut config).unwrap();
+            c.cleanup_policy = CleanupPolicy::TotalSize;
+            c.keep_total_size = Some(100);
+        }
+        let items = vec![
+            (1, state_at("1 week")),
+            (2, state_at("1 hour")),
+            (3, state_at("30 min")),
+            (4, state_at("2 min")),
+        ];
+        let usage = Usage { sizes: sizes(&items), ..Usage::default() };
+        let r = sort_out(&config, items, &vec![], &usage);
+        let used = r.used.into_iter().map(|(x, _, r)| (x, r))
+            .collect::<Vec<_>>();
+        assert_eq!(used, vec![(4, Reason::FitsTotalSize)]);
+        let unused = r.unused.into_iter().map(|(x, _, r)| (x, r))
+            .collect::<Vec<_>>();
+        assert_eq!(unused, vec![
+            (3, Reason::TotalSize),
+            (2, Reason::TotalSize),
+            (1, Reason::TotalSize),
+        ]);
+    }
+
+    #[test]
+    fn test_least_recently_used() {
+        let mut config = cfg(1, 100, "1 day");
+        Arc::get_mut(&mut config).unwrap().cleanup_policy =
+            CleanupPolicy::LeastRecentlyUsed;
+        let items = vec![
+            (1, state_at("1 week")),
+            (2, state_at("1 month")),
+            (3, state_at("1 year")),
+        ];
+        let mut usage = Usage::default();
+        usage.last_access.insert(items[0].1.image.clone(),
+            SystemTime::now() - parse_duration("1 min").unwrap());
+        usage.last_access.insert(items[2].1.image.clone(),
+            SystemTime::now() - parse_duration("2 days").unwrap());
+        assert_eq!(simple_sort_usage(&config, items, &usage), Sorted {
+            used: vec![1],
+            unused: vec![2, 3],
+        });
+    }
 }
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use ssh_keys::PublicKey;
+
+use proto::{Maintenance, MaintenanceAction, verify};
+use {VPath};
+
+
+/// Maximum difference between maintenance request timestamp and local time
+pub const TIME_SKEW: Duration = Duration::from_secs(600);
+
+/// Timestamps of the last accepted maintenance requests
+///
+/// A signed request is valid for `TIME_SKEW`, so the same request could be
+/// replayed within that window unless we remember what was accepted.
+#[derive(Debug, Default)]
+pub struct Accepted {
+    last: HashMap<(MaintenanceAction, Option<VPath>), SystemTime>,
+}
+
+pub fn within_skew(timestamp: SystemTime, now: SystemTime) -> bool {
+    now.duration_since(timestamp)
+        .or_else(|_| timestamp.duration_since(now))
+        .map(|skew| skew < TIME_SKEW)
+        .unwrap_or(false)
+}
+
+/// Checks the timestamp and signatures of the maintenance request
+///
+/// Request is recorded as accepted if check passes, otherwise reject reason
+/// is returned.
+pub fn check(cmd: &Maintenance, keys: &[PublicKey],
+             accepted: &mut Accepted, now: SystemTime)
+    -> Result<(), &'static str>
+{
+    if !within_skew(cmd.timestamp, now) {
+        return Err("timestamp_skew");
+    }
+    let data = cmd.sig_data();
+    if !cmd.signatures.iter().any(|sig| verify(&data, sig, keys)) {
+        return Err("signature_mismatch");
+    }
+    let key = (cmd.action, cmd.path.clone());
+    if let Some(&last) = accepted.last.get(&key) {
+        if cmd.timestamp <= last {
+            return Err("replayed");
+        }
+    }
+    // requests older than this are rejected by timestamp anyway
+    accepted.last.retain(|_, &mut time| within_skew(time, now));
+    accepted.last.insert(key, cmd.timestamp);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use crypto::ed25519::keypair;
+    use ssh_keys::{PrivateKey, PublicKey};
+
+    use proto::{Maintenance, MaintenanceAction, sign};
+    use super::{Accepted, check};
+    use {VPath};
+
+    fn key(seed: u8) -> (PrivateKey, PublicKey) {
+        let (private, _) = keypair(&[seed; 32]);
+        let private = PrivateKey::Ed25519(private);
+        let public = private.public_key();
+        (private, public)
+    }
+
+    fn request(action: MaintenanceAction, path: Option<&str>,
+               timestamp: SystemTime, key: &PrivateKey)
+        -> Maintenance
+    {
+        let mut cmd = Maintenance {
+            action: action,
+            path: path.map(VPath::from),
+            timestamp: timestamp,
+            signatures: Vec::new(),
+        };
+        cmd.signatures = sign(cmd.sig_data(), &[key.clone()]);
+        return cmd;
+    }
+
+    #[test]
+    fn signature() {
+        let (private, public) = key(1);
+        let (other, _) = key(2);
+        let now = SystemTime::now();
+        let mut acc = Accepted::default();
+        let cmd = request(MaintenanceAction::Cleanup, Some("/dir1"),
+                          now, &other);
+        assert_eq!(check(&cmd, &[public.clone()], &mut acc, now),
+                   Err("signature_mismatch"));
+        let mut cmd = request(MaintenanceAction::Cleanup, Some("/dir1"),
+                              now, &private);
+        // signature covers the action
+        cmd.action = MaintenanceAction::IndexGc;
+        assert_eq!(check(&cmd, &[public.clone()], &mut acc, now),
+                   Err("signature_mismatch"));
+        cmd.action = MaintenanceAction::Cleanup;
+        assert_eq!(check(&cmd, &[public], &mut acc, now), Ok(()));
+    }
+
+    #[test]
+    fn timestamp_skew() {
+        let (private, public) = key(1);
+        let now = SystemTime::now();
+        let mut acc = Accepted::default();
+        let cmd = request(MaintenanceAction::IndexGc, None,
+                          now - Duration::from_secs(3600), &private);
+        assert_eq!(check(&cmd, &[public.clone()], &mut acc, now),
+                   Err("timestamp_skew"));
+        let cmd = request(MaintenanceAction::IndexGc, None,
+                          now + Duration::from_secs(3600), &private);
+        assert_eq!(check(&cmd, &[public], &mut acc, now),
+                   Err("timestamp_skew"));
+    }
+
+    #[test]
+    fn replay() {
+        let (private, public) = key(1);
+        let keys = [public];
+        let now = SystemTime::now();
+        let mut acc = Accepted::default();
+        let cmd = request(MaintenanceAction::Restore, Some("/dir1/x"),
+                          now, &private);
+        assert_eq!(check(&cmd, &keys, &mut acc, now), Ok(()));
+        let later = now + Duration::from_secs(1);
+        assert_eq!(check(&cmd, &keys, &mut acc, later), Err("replayed"));
+        let older = request(MaintenanceAction::Restore, Some("/dir1/x"),
+                            now - Duration::from_secs(1), &private);
+        assert_eq!(check(&older, &keys, &mut acc, later), Err("replayed"));
+        // other path and other action are tracked separately
+        let cmd = request(MaintenanceAction::Restore, Some("/dir1/y"),
+                          now, &private);
+        assert_eq!(check(&cmd, &keys, &mut acc, later), Ok(()));
+        let cmd = request(MaintenanceAction::Cleanup, Some("/dir1/x"),
+                          now, &private);
+        assert_eq!(check(&cmd, &keys, &mut acc, later), Ok(()));
+        let newer = request(MaintenanceAction::Restore, Some("/dir1/x"),
+                            later, &private);
+        assert_eq!(check(&newer, &keys, &mut acc, later), Ok(()));
+    }
+}
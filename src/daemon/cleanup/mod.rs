@@ -1,3 +1,7 @@
 mod calc;
+mod quota;
+pub mod maintenance;
 
-pub use self::calc::sort_out;
+pub use self::calc::{sort_out, sort_out_expired, is_expired};
+pub use self::calc::{Reason, Sorted, Usage};
+pub use self::quota::{ImageSize, check_image, has_quota};
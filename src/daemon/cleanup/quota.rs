@@ -0,0 +1,58 @@
+use std::ops::AddAssign;
+
+use dir_signature::v1::Entry;
+
+use config::Directory;
+use index_cache::IndexData;
+
+
+/// Size of the image as accounted by directory quotas
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImageSize {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+impl<'a> From<&'a IndexData> for ImageSize {
+    fn from(index: &IndexData) -> ImageSize {
+        ImageSize {
+            bytes: index.bytes_total,
+            files: index.entries.iter()
+                .filter(|e| !matches!(**e, Entry::Dir(..)))
+                .count() as u64,
+        }
+    }
+}
+
+impl AddAssign for ImageSize {
+    fn add_assign(&mut self, other: ImageSize) {
+        self.bytes += other.bytes;
+        self.files += other.files;
+    }
+}
+
+impl ImageSize {
+    /// Returns true if total size of the base directory fits quotas
+    pub fn fits(&self, config: &Directory) -> bool {
+        config.max_total_bytes.map(|m| self.bytes <= m).unwrap_or(true) &&
+        config.max_files.map(|m| self.files <= m).unwrap_or(true)
+    }
+}
+
+/// Returns true if any of the base directory quotas is set
+pub fn has_quota(config: &Directory) -> bool {
+    config.max_total_bytes.is_some() || config.max_files.is_some()
+}
+
+/// Returns reject reason if the image alone can never fit quotas
+pub fn check_image(config: &Directory, size: ImageSize)
+    -> Option<&'static str>
+{
+    if config.max_image_bytes.map(|m| size.bytes > m).unwrap_or(false) {
+        Some("image_too_large")
+    } else if !size.fits(config) {
+        Some("quota_exceeded")
+    } else {
+        None
+    }
+}
@@ -1,13 +1,23 @@
 use std::collections::HashMap;
+use std::ffi::CString;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::ptr;
 use std::sync::Arc;
 use std::time::Duration;
 
+use crossbeam::sync::ArcCell;
+
 use {VPath};
 use machine_id::MachineId;
 use scan_dir::ScanDir;
 use quire::validate::{Directory as Dir, Structure, Numeric, Scalar, Sequence};
+use quire::validate::{Mapping};
 use quire::{parse_config, Options, ErrorList};
+use libc;
+use serde::{Deserialize, Deserializer};
+use serde::de::Error;
+use serde_humantime::De;
 
 
 pub struct Config {
@@ -16,12 +26,32 @@ pub struct Config {
     pub port: u16,
     pub db_dir: PathBuf,
     pub config_dir: PathBuf,
-    pub aggressive_index_gc: bool,
-    pub dirs: HashMap<String, Arc<Directory>>,
+    /// Directory configs, replaced as a whole on reload
+    pub dirs: ArcCell<HashMap<String, Arc<Directory>>>,
 }
 
+/// Names of directory configs changed by a reload
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
 
-#[derive(Debug, Deserialize)]
+
+/// Policy which decides which images are removed by `auto_clean`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="snake_case")]
+pub enum CleanupPolicy {
+    /// Keep images uploaded within `keep_recent`
+    Recent,
+    /// Keep newest images which fit `keep_total_size`
+    TotalSize,
+    /// Keep images used within `keep_recent`
+    LeastRecentlyUsed,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
 pub struct Directory {
     pub directory: PathBuf,
     pub append_only: bool,
@@ -34,6 +64,124 @@ pub struct Directory {
     pub keep_max_directories: usize,
     #[serde(with="::serde_humantime")]
     pub keep_recent: Duration,
+    pub free_space_reserve: u64,
+    pub max_total_bytes: Option<u64>,
+    pub max_image_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+    pub cleanup_policy: CleanupPolicy,
+    pub keep_total_size: Option<u64>,
+    #[serde(deserialize_with="optional_duration", default)]
+    pub trash_retention: Option<Duration>,
+    pub current_symlink: Option<String>,
+    pub keep_previous_versions: usize,
+    pub on_commit: Option<String>,
+    pub on_replace: Option<String>,
+    pub on_delete: Option<String>,
+    pub validate_command: Option<String>,
+    pub allow_absolute_symlinks: bool,
+    pub allow_escaping_symlinks: bool,
+    pub max_file_size: Option<u64>,
+    #[serde(deserialize_with="octal_mode")]
+    pub umask: u32,
+    #[serde(deserialize_with="owner", default)]
+    pub chown_to: Option<(u32, u32)>,
+    pub reset_mtime: bool,
+    pub allow_xattrs: Vec<String>,
+    #[serde(deserialize_with="optional_duration", default)]
+    pub scrub_interval: Option<Duration>,
+    pub scrub_rate: u64,
+    pub scrub_repair: bool,
+    #[serde(with="::serde_humantime")]
+    pub hook_timeout: Duration,
+    pub hook_retries: u32,
+}
+
+/// Per-host changes to a directory config, read from `overrides.yaml`
+///
+/// Only `directory` and `keep-list-file` are local to the host, other
+/// settings are included in the config hash which peers compare.
+#[derive(Debug, Deserialize)]
+pub struct Override {
+    pub directory: Option<PathBuf>,
+    pub auto_clean: Option<bool>,
+    pub keep_list_file: Option<PathBuf>,
+    pub keep_min_directories: Option<usize>,
+    pub keep_max_directories: Option<usize>,
+    #[serde(deserialize_with="optional_duration", default)]
+    pub keep_recent: Option<Duration>,
+    pub keep_total_size: Option<u64>,
+}
+
+fn optional_duration<'de, D>(d: D) -> Result<Option<Duration>, D::Error>
+    where D: Deserializer<'de>
+{
+    De::<Option<Duration>>::deserialize(d).map(De::into_inner)
+}
+
+fn octal_mode<'de, D>(d: D) -> Result<u32, D::Error>
+    where D: Deserializer<'de>
+{
+    let value = String::deserialize(d)?;
+    match u32::from_str_radix(&value, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(D::Error::custom(
+            format!("expected octal mode like 022, got {:?}", value))),
+    }
+}
+
+fn owner<'de, D>(d: D) -> Result<Option<(u32, u32)>, D::Error>
+    where D: Deserializer<'de>
+{
+    match Option::<String>::deserialize(d)? {
+        Some(value) => resolve_owner(&value).map(Some)
+            .map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Resolves `user:group` to uid and gid, numeric ids are also accepted
+fn resolve_owner(value: &str) -> Result<(u32, u32), String> {
+    let mut pair = value.splitn(2, ':');
+    let user = pair.next().expect("at least one item in split");
+    let group = pair.next()
+        .ok_or_else(|| format!("expected `user:group`, got {:?}", value))?;
+    let uid = match user.parse() {
+        Ok(uid) => uid,
+        Err(_) => {
+            let name = CString::new(user)
+                .map_err(|_| format!("bad user name {:?}", user))?;
+            let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+            let mut buf = vec![0 as libc::c_char; 16384];
+            let mut result = ptr::null_mut();
+            let rc = unsafe {
+                libc::getpwnam_r(name.as_ptr(), &mut pwd,
+                    buf.as_mut_ptr(), buf.len(), &mut result)
+            };
+            if rc != 0 || result.is_null() {
+                return Err(format!("unknown user {:?}", user));
+            }
+            pwd.pw_uid
+        }
+    };
+    let gid = match group.parse() {
+        Ok(gid) => gid,
+        Err(_) => {
+            let name = CString::new(group)
+                .map_err(|_| format!("bad group name {:?}", group))?;
+            let mut grp: libc::group = unsafe { mem::zeroed() };
+            let mut buf = vec![0 as libc::c_char; 16384];
+            let mut result = ptr::null_mut();
+            let rc = unsafe {
+                libc::getgrnam_r(name.as_ptr(), &mut grp,
+                    buf.as_mut_ptr(), buf.len(), &mut result)
+            };
+            if rc != 0 || result.is_null() {
+                return Err(format!("unknown group {:?}", group));
+            }
+            grp.gr_gid
+        }
+    };
+    Ok((uid, gid))
 }
 
 fn directory_validator<'x>() -> Structure<'x> {
@@ -41,7 +189,7 @@ fn directory_validator<'x>() -> Structure<'x> {
     .member("directory", Dir::new())
     .member("append_only", Scalar::new())
     // the limit here is just arbitrary, maybe we will lift it later
-    .member("num_levels", Numeric::new().min(1).max(16))
+    .member("num_levels", Numeric::new().min(0).max(16))
     .member("upload_keys", Sequence::new(Scalar::new()))
     .member("download_keys", Sequence::new(Scalar::new()))
     .member("auto_clean", Scalar::new().default(false))
@@ -49,35 +197,363 @@ fn directory_validator<'x>() -> Structure<'x> {
     .member("keep_min_directories", Numeric::new().min(1).default(2))
     .member("keep_max_directories", Numeric::new().min(1).default(100))
     .member("keep_recent", Scalar::new().default("2 days"))
+    .member("free_space_reserve", Numeric::new().min(0).default(0))
+    .member("max_total_bytes", Numeric::new().min(1).optional())
+    .member("max_image_bytes", Numeric::new().min(1).optional())
+    .member("max_files", Numeric::new().min(1).optional())
+    .member("cleanup_policy", Scalar::new().default("recent"))
+    .member("keep_total_size", Numeric::new().min(1).optional())
+    .member("trash_retention", Scalar::new().optional())
+    .member("current_symlink", Scalar::new().optional())
+    .member("keep_previous_versions", Numeric::new().min(0).default(0))
+    .member("on_commit", Scalar::new().optional())
+    .member("on_replace", Scalar::new().optional())
+    .member("on_delete", Scalar::new().optional())
+    .member("validate_command", Scalar::new().optional())
+    .member("allow_absolute_symlinks", Scalar::new().default(true))
+    .member("allow_escaping_symlinks", Scalar::new().default(true))
+    .member("max_file_size", Numeric::new().min(1).optional())
+    .member("umask", Scalar::new().default("022"))
+    .member("chown_to", Scalar::new().optional())
+    .member("reset_mtime", Scalar::new().default(true))
+    .member("allow_xattrs", Sequence::new(Scalar::new()))
+    .member("scrub_interval", Scalar::new().optional())
+    .member("scrub_rate", Numeric::new().min(1).default(10485760))
+    .member("scrub_repair", Scalar::new().default(false))
+    .member("hook_timeout", Scalar::new().default("1 min"))
+    .member("hook_retries", Numeric::new().min(0).default(2))
+}
+
+fn override_validator<'x>() -> Mapping<'x> {
+    Mapping::new(Scalar::new(), Structure::new()
+        .member("directory", Dir::new().optional())
+        .member("auto_clean", Scalar::new().optional())
+        .member("keep_list_file", Scalar::new().optional())
+        .member("keep_min_directories", Numeric::new().min(1).optional())
+        .member("keep_max_directories", Numeric::new().min(1).optional())
+        .member("keep_recent", Scalar::new().optional())
+        .member("keep_total_size", Numeric::new().min(1).optional()))
 }
 
-pub fn read_dirs(path: &Path)
+fn read_overrides(path: &Path) -> Result<HashMap<String, Override>, String> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    parse_config(path, &override_validator(), &Options::default())
+        .map_err(|e| e.to_string())
+}
+
+fn apply_override(cfg: &mut Directory, o: Override) {
+    if let Some(x) = o.directory { cfg.directory = x; }
+    if let Some(x) = o.auto_clean { cfg.auto_clean = x; }
+    if let Some(x) = o.keep_list_file { cfg.keep_list_file = Some(x); }
+    if let Some(x) = o.keep_min_directories { cfg.keep_min_directories = x; }
+    if let Some(x) = o.keep_max_directories { cfg.keep_max_directories = x; }
+    if let Some(x) = o.keep_recent { cfg.keep_recent = x; }
+    if let Some(x) = o.keep_total_size { cfg.keep_total_size = Some(x); }
+}
+
+/// Reads `configs/*.yaml` in `config_dir` with `overrides.yaml` applied
+pub fn read_dirs(config_dir: &Path)
     -> Result<HashMap<String, Arc<Directory>>, String>
 {
+    let path = config_dir.join("configs");
     if !path.is_dir() {
         warn!("No directory {:?} found", path);
         return Ok(HashMap::new());
     }
+    let overrides = read_overrides(&config_dir.join("overrides.yaml"))?;
     let validator = directory_validator();
-    ScanDir::files().read(path, |iter| {
+    ScanDir::files().read(&path, |iter| {
         let mut res = HashMap::new();
         let yamls = iter.filter(|&(_, ref name)| name.ends_with(".yaml"));
         for (entry, fname) in yamls {
             let name = fname[..fname.len() - 5].to_string();
-            let config = parse_config(entry.path(),
+            let config: Directory = parse_config(entry.path(),
                 &validator, &Options::default())?;
-            res.insert(name, Arc::new(config));
+            res.insert(name, config);
         }
         Ok::<_, ErrorList>(res)
     }).map_err(|e| e.to_string()).and_then(|v| v.map_err(|e| e.to_string()))
+    .and_then(|mut dirs| {
+        for (name, o) in overrides {
+            match dirs.get_mut(&name) {
+                Some(cfg) => {
+                    info!("Applying overrides to {:?}: {:?}", name, o);
+                    apply_override(cfg, o);
+                }
+                None => warn!("Overrides for unknown config {:?}", name),
+            }
+        }
+        for (name, cfg) in &dirs {
+            if cfg.cleanup_policy == CleanupPolicy::TotalSize &&
+                cfg.keep_total_size.is_none()
+            {
+                return Err(format!("{}.yaml: `keep-total-size` is required \
+                    for `total-size` cleanup policy", name));
+            }
+            if cfg.num_levels == 0 && cfg.auto_clean {
+                return Err(format!("{}.yaml: `auto-clean` is not supported \
+                    for `num-levels: 0`", name));
+            }
+            if cfg.num_levels == 0 && cfg.scrub_interval.is_some() {
+                return Err(format!("{}.yaml: `scrub-interval` is not \
+                    supported for `num-levels: 0`", name));
+            }
+            if cfg.append_only && cfg.keep_previous_versions > 0 {
+                return Err(format!("{}.yaml: `keep-previous-versions` \
+                    requires `append-only: false`", name));
+            }
+            let hooks = [&cfg.on_commit, &cfg.on_replace, &cfg.on_delete,
+                         &cfg.validate_command];
+            if hooks.iter().any(|h| {
+                h.as_ref().map(|x| x.trim().is_empty()).unwrap_or(false)
+            }) {
+                return Err(format!("{}.yaml: hook command must not be \
+                    empty", name));
+            }
+            if let Some(ref link) = cfg.current_symlink {
+                if cfg.num_levels == 0 {
+                    return Err(format!("{}.yaml: `current-symlink` is not \
+                        supported for `num-levels: 0`", name));
+                }
+                if link.is_empty() || link.starts_with('.') ||
+                    link.contains('/')
+                {
+                    return Err(format!("{}.yaml: `current-symlink` must be \
+                        a plain file name, not {:?}", name, link));
+                }
+            }
+        }
+        Ok(dirs.into_iter().map(|(k, v)| (k, Arc::new(v))).collect())
+    })
 }
 
 impl Config {
+    pub fn dir(&self, name: &str) -> Option<Arc<Directory>> {
+        self.dirs.get().get(name).cloned()
+    }
     pub fn is_valid_destination(&self, vpath: &VPath) -> bool {
-        if let Some(ref cfg) = self.dirs.get(vpath.key()) {
+        if let Some(ref cfg) = self.dirs.get().get(vpath.key()) {
             cfg.num_levels == vpath.level()
         } else {
             false
         }
     }
+    /// Replaces directory configs, returns which ones were changed
+    ///
+    /// Configs that are equal to the old ones are kept intact, so images
+    /// and base dirs referencing them continue to work as before.
+    pub fn update_dirs(&self, mut dirs: HashMap<String, Arc<Directory>>)
+        -> Changes
+    {
+        let old = self.dirs.get();
+        let mut changes = Changes::default();
+        for (name, cfg) in dirs.iter_mut() {
+            match old.get(name) {
+                Some(old_cfg) if old_cfg == cfg => *cfg = old_cfg.clone(),
+                Some(_) => changes.changed.push(name.clone()),
+                None => changes.added.push(name.clone()),
+            }
+        }
+        changes.removed.extend(old.keys()
+            .filter(|name| !dirs.contains_key(*name))
+            .cloned());
+        if !changes.is_empty() {
+            self.dirs.set(Arc::new(dirs));
+        }
+        return changes;
+    }
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() &&
+            self.removed.is_empty()
+    }
+}
+
+#[cfg(test)]
+impl Directory {
+    /// Config with the same defaults as `directory_validator`, for tests
+    pub fn test_default<P: AsRef<Path>>(directory: P) -> Directory {
+        Directory {
+            directory: directory.as_ref().to_path_buf(),
+            append_only: false,
+            num_levels: 1,
+            upload_keys: Vec::new(),
+            download_keys: Vec::new(),
+            auto_clean: false,
+            keep_list_file: None,
+            keep_min_directories: 2,
+            keep_max_directories: 100,
+            keep_recent: Duration::from_secs(2*86400),
+            free_space_reserve: 0,
+            max_total_bytes: None,
+            max_image_bytes: None,
+            max_files: None,
+            cleanup_policy: CleanupPolicy::Recent,
+            keep_total_size: None,
+            trash_retention: None,
+            current_symlink: None,
+            keep_previous_versions: 0,
+            on_commit: None,
+            on_replace: None,
+            on_delete: None,
+            validate_command: None,
+            allow_absolute_symlinks: true,
+            allow_escaping_symlinks: true,
+            max_file_size: None,
+            umask: 0o022,
+            chown_to: None,
+            reset_mtime: true,
+            allow_xattrs: Vec::new(),
+            scrub_interval: None,
+            scrub_rate: 10485760,
+            scrub_repair: false,
+            hook_timeout: Duration::from_secs(60),
+            hook_retries: 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use std::fs;
+    use std::time::Duration;
+
+    use crossbeam::sync::ArcCell;
+    use serde_json;
+    use tempfile::TempDir;
+    use super::{Config, Directory, octal_mode, resolve_owner, read_dirs};
+
+    #[derive(Deserialize)]
+    struct Umask {
+        #[serde(deserialize_with="octal_mode")]
+        umask: u32,
+    }
+
+    fn umask(value: &str) -> Option<u32> {
+        serde_json::from_str::<Umask>(&format!(r#"{{"umask": {:?}}}"#, value))
+        .ok().map(|x| x.umask)
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(umask("022"), Some(0o22));
+        assert_eq!(umask("22"), Some(0o22));
+        assert_eq!(umask("777"), Some(0o777));
+        assert_eq!(umask("0o22"), None);
+        assert_eq!(umask("999"), None);
+        assert_eq!(umask("1000"), None);
+        assert_eq!(umask(""), None);
+    }
+
+    #[test]
+    fn owners() {
+        assert_eq!(resolve_owner("1000:1001"), Ok((1000, 1001)));
+        assert_eq!(resolve_owner("root:0"), Ok((0, 0)));
+        assert_eq!(resolve_owner("0:root"), Ok((0, 0)));
+        assert!(resolve_owner("root").is_err());
+        assert!(resolve_owner("1000").is_err());
+        assert!(resolve_owner("no-such-user-here:0").is_err());
+        assert!(resolve_owner("0:no-such-group-here").is_err());
+    }
+
+    fn dirs(items: &[(&str, &str)]) -> HashMap<String, Arc<Directory>> {
+        items.iter().map(|&(name, dir)| {
+            (name.to_string(), Arc::new(Directory::test_default(dir)))
+        }).collect()
+    }
+
+    fn config(items: &[(&str, &str)]) -> Config {
+        Config {
+            machine_id: "0123456789abcdef0123456789abcdef".parse().unwrap(),
+            hostname: "localhost".into(),
+            port: 24783,
+            db_dir: Path::new("/nowhere/db").to_path_buf(),
+            config_dir: Path::new("/nowhere/config").to_path_buf(),
+            dirs: ArcCell::new(Arc::new(dirs(items))),
+        }
+    }
+
+    #[test]
+    fn update_dirs() {
+        let cfg = config(&[("same", "/srv/same"), ("changed", "/srv/old"),
+                           ("removed", "/srv/removed")]);
+        let same = cfg.dir("same").unwrap();
+        let changes = cfg.update_dirs(dirs(&[
+            ("same", "/srv/same"), ("changed", "/srv/new"),
+            ("added", "/srv/added"),
+        ]));
+        assert_eq!(changes.added, vec!["added"]);
+        assert_eq!(changes.changed, vec!["changed"]);
+        assert_eq!(changes.removed, vec!["removed"]);
+        assert!(!changes.is_empty());
+        // equal config is kept, so base dirs referencing it aren't reloaded
+        assert!(Arc::ptr_eq(&cfg.dir("same").unwrap(), &same));
+        assert_eq!(cfg.dir("changed").unwrap().directory,
+                   Path::new("/srv/new"));
+        assert!(cfg.dir("added").is_some());
+        assert!(cfg.dir("removed").is_none());
+    }
+
+    #[test]
+    fn update_dirs_unchanged() {
+        let cfg = config(&[("same", "/srv/same")]);
+        let all = cfg.dirs.get();
+        let changes = cfg.update_dirs(dirs(&[("same", "/srv/same")]));
+        assert!(changes.is_empty());
+        assert!(Arc::ptr_eq(&cfg.dirs.get(), &all));
+    }
+
+    fn write_configs(tmp: &TempDir, overrides: &str) {
+        let configs = tmp.path().join("configs");
+        fs::create_dir(&configs).unwrap();
+        fs::write(configs.join("app.yaml"),
+            "directory: /srv/app\nnum-levels: 1\nappend-only: true\n")
+            .unwrap();
+        fs::write(configs.join("whole.yaml"),
+            "directory: /srv/whole\nnum-levels: 0\nappend-only: false\n")
+            .unwrap();
+        fs::write(tmp.path().join("overrides.yaml"), overrides).unwrap();
+    }
+
+    #[test]
+    fn overrides() {
+        let tmp = TempDir::new().unwrap();
+        write_configs(&tmp, concat!(
+            "app:\n",
+            "  directory: /mnt/app\n",
+            "  auto-clean: true\n",
+            "  keep-recent: 1 hour\n",
+            "  keep-max-directories: 10\n",
+            "unknown:\n",
+            "  auto-clean: true\n",
+        ));
+        // overrides for unknown configs are ignored
+        let dirs = read_dirs(tmp.path()).unwrap();
+        assert_eq!(dirs.len(), 2);
+        let app = &dirs["app"];
+        assert_eq!(app.directory, Path::new("/mnt/app"));
+        assert_eq!(app.auto_clean, true);
+        assert_eq!(app.keep_recent, Duration::from_secs(3600));
+        assert_eq!(app.keep_max_directories, 10);
+        // not overridden
+        assert_eq!(app.keep_min_directories, 2);
+        assert_eq!(app.append_only, true);
+        assert_eq!(dirs["whole"].directory, Path::new("/srv/whole"));
+    }
+
+    #[test]
+    fn overrides_validated() {
+        let tmp = TempDir::new().unwrap();
+        write_configs(&tmp, "whole:\n  auto-clean: true\n");
+        let err = read_dirs(tmp.path()).unwrap_err();
+        assert!(err.contains("`auto-clean` is not supported"), "{}", err);
+    }
 }
@@ -1,7 +1,14 @@
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
+use libc::{statvfs, statvfs as Statvfs};
 use openat::Dir;
 
+use config::Directory;
+
 
 pub fn recover_path<P: AsRef<Path>>(dir: &Dir, path: P) -> PathBuf {
     let mut result = dir.recover_path()
@@ -12,3 +19,95 @@ pub fn recover_path<P: AsRef<Path>>(dir: &Dir, path: P) -> PathBuf {
     result.push(path.as_ref());
     result
 }
+
+/// Returns number of bytes available to unprivileged user on filesystem
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    unsafe {
+        let mut stat: Statvfs = mem::zeroed();
+        if statvfs(cpath.as_ptr(), &mut stat) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
+
+/// Number of bytes which can be written to the directory keeping reserve
+///
+/// Returns `None` if free space can't be determined.
+pub fn space_available(config: &Directory) -> Option<u64> {
+    match free_space(&config.directory) {
+        Ok(free) => Some(free.saturating_sub(config.free_space_reserve)),
+        Err(e) => {
+            warn!("Can't check free space of {:?}: {}", config.directory, e);
+            None
+        }
+    }
+}
+
+/// Checks whether `bytes` can be written to the directory keeping reserve
+///
+/// If free space can't be determined, returns `true`, so that the actual
+/// error is reported when writing.
+pub fn has_space_for(config: &Directory, bytes: u64) -> bool {
+    match space_available(config) {
+        Some(available) => {
+            let ok = bytes <= available;
+            if !ok {
+                warn!("Not enough space in {:?}: {} bytes needed, \
+                    {} bytes available, {} bytes reserved",
+                    config.directory, bytes, available,
+                    config.free_space_reserve);
+            }
+            ok
+        }
+        None => true,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::u64;
+
+    use tempfile::TempDir;
+
+    use config::Directory;
+    use super::{free_space, has_space_for, space_available};
+
+    #[test]
+    fn reserve() {
+        let tmp = TempDir::new().unwrap();
+        let free = free_space(tmp.path()).unwrap();
+        assert!(free > 4096);
+        let mut cfg = Directory::test_default(tmp.path());
+        assert!(has_space_for(&cfg, 0));
+        assert!(has_space_for(&cfg, 1024));
+        assert!(!has_space_for(&cfg, u64::MAX));
+
+        cfg.free_space_reserve = free - 1024;
+        // other processes may write or delete files in the meantime
+        let available = space_available(&cfg).unwrap();
+        assert!(available < 1024*1024, "available {}", available);
+        assert!(has_space_for(&cfg, 0));
+        assert!(!has_space_for(&cfg, 1024*1024));
+
+        cfg.free_space_reserve = u64::MAX;
+        assert_eq!(space_available(&cfg), Some(0));
+        assert!(has_space_for(&cfg, 0));
+        assert!(!has_space_for(&cfg, 1));
+    }
+
+    #[test]
+    fn unknown() {
+        let path = Path::new("/nonexistent/ciruela/dir");
+        assert!(free_space(path).is_err());
+        let mut cfg = Directory::test_default(path);
+        cfg.free_space_reserve = u64::MAX;
+        // actual error is reported when writing
+        assert_eq!(space_available(&cfg), None);
+        assert!(has_space_for(&cfg, u64::MAX));
+    }
+}
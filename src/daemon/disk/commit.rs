@@ -1,18 +1,31 @@
-use std::io;
+use std::collections::HashSet;
+use std::ffi::{CString, OsStr};
+use std::io::{self, BufReader};
 use std::fs;
-use std::os::unix::fs::{PermissionsExt};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::os::unix::io::{AsRawFd};
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Instant;
 
-use libc::{futimens, timespec};
+use libc::{futimens, timespec, fchmodat, fchownat, AT_SYMLINK_NOFOLLOW};
+use libc::{mode_t, uid_t, gid_t};
 use dir_signature::v1::Entry::*;
+use hex::FromHex;
+use openat;
+use serde_json;
 
+use config::Directory;
 use disk::dir::{ensure_path, recover_path};
+use disk::policy::{check_entry, entry_path};
+use disk::progress;
 use disk::error::Error;
 use disk::public::Image;
 use disk::dir::remove_dir_recursive;
+use disk::versions::{self, VersionInfo};
 use metrics::Counter;
+use xattrs::{self, Manifest, MANIFEST};
 
 
 lazy_static! {
@@ -43,16 +56,190 @@ pub fn reset_timestamp(f: &mut fs::File) -> Result<(), io::Error> {
 }
 
 
-pub fn commit_image(image: Arc<Image>) -> Result<(), Error> {
+/// Mode of the file in the image according to `umask` of the directory
+pub fn file_mode(config: &Directory, exe: bool) -> u32 {
+    (if exe { 0o777 } else { 0o666 }) & !config.umask
+}
+
+fn dir_mode(config: &Directory) -> u32 {
+    0o777 & !config.umask
+}
+
+/// Converts absolute path from the index to a C string relative to image
+fn relative_cstr(path: &Path) -> (&Path, CString) {
+    let path = path.strip_prefix("/").unwrap_or(path);
+    let bytes = if path.as_os_str().is_empty() {
+        &b"."[..]
+    } else {
+        path.as_os_str().as_bytes()
+    };
+    (path, CString::new(bytes).expect("paths in index have no zero bytes"))
+}
+
+fn set_mode(base: &openat::Dir, path: &Path, mode: u32)
+    -> Result<(), Error>
+{
+    let (path, cpath) = relative_cstr(path);
+    let rc = unsafe {
+        fchmodat(base.as_raw_fd(), cpath.as_ptr(), mode as mode_t, 0)
+    };
+    if rc != 0 {
+        return Err(Error::SetPermissions(recover_path(base, path),
+                                         io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Extends the file to `size` making a hole at the end
+///
+/// Blocks of zeros are not fetched, so the file may be shorter than in the
+/// index or even not exist.
+pub fn extend_file(dir: &openat::Dir, filename: &OsStr, size: u64)
+    -> Result<(), Error>
+{
+    let file = dir.update_file(filename, 0o644)
+        .map_err(|e| Error::WriteFile(recover_path(dir, filename), e))?;
+    let len = file.metadata()
+        .map_err(|e| Error::ReadFile(recover_path(dir, filename), e))?
+        .len();
+    if len > size {
+        return Err(Error::Checksum(recover_path(dir, filename)));
+    } else if len < size {
+        file.set_len(size)
+            .map_err(|e| Error::WriteFile(recover_path(dir, filename), e))?;
+    }
+    Ok(())
+}
+
+/// Changes owner of the path, symlinks are not followed
+fn set_owner(base: &openat::Dir, path: &Path, uid: u32, gid: u32)
+    -> Result<(), Error>
+{
+    let (path, cpath) = relative_cstr(path);
+    let rc = unsafe {
+        fchownat(base.as_raw_fd(), cpath.as_ptr(),
+                 uid as uid_t, gid as gid_t, AT_SYMLINK_NOFOLLOW)
+    };
+    if rc != 0 {
+        return Err(Error::SetOwner(recover_path(base, path),
+                                   io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Replaces file hardlinked from another image by its own copy
+///
+/// Extended attributes belong to the inode, so setting them on a shared
+/// file would change the other image too.
+fn unshare_file(config: &Directory, base: &openat::Dir, path: &Path)
+    -> Result<(), Error>
+{
+    let rel = path.strip_prefix("/").unwrap_or(path);
+    let fs_path = recover_path(base, rel);
+    let meta = fs::symlink_metadata(&fs_path)
+        .map_err(|e| Error::ReadFile(fs_path.clone(), e))?;
+    if !meta.is_file() || meta.nlink() <= 1 {
+        return Ok(());
+    }
+    let tmp_name = format!(".tmp.unshare.{}",
+        rel.file_name().expect("file has filename").to_string_lossy());
+    let tmp_rel = rel.with_file_name(&tmp_name);
+    let tmp_path = recover_path(base, &tmp_rel);
+    // copy keeps permissions of the file
+    fs::copy(&fs_path, &tmp_path)
+        .map_err(|e| Error::WriteFile(tmp_path.clone(), e))?;
+    if let Some((uid, gid)) = config.chown_to {
+        set_owner(base, &tmp_rel, uid, gid)?;
+    }
+    if config.reset_mtime {
+        let mut file = fs::OpenOptions::new().write(true).open(&tmp_path)
+            .map_err(|e| Error::WriteFile(tmp_path.clone(), e))?;
+        reset_timestamp(&mut file)
+            .map_err(|e| Error::SetTimestamp(tmp_path.clone(), e))?;
+    }
+    fs::rename(&tmp_path, &fs_path)
+        .map_err(|e| Error::WriteFile(fs_path.clone(), e))?;
+    Ok(())
+}
+
+/// Applies extended attributes from the manifest in the root of the image
+///
+/// Only attributes allowed by `allow-xattrs` are applied, others are
+/// skipped with a warning. Manifest may only refer to files and directories
+/// of the image. Files hardlinked from other images are copied first.
+fn apply_xattrs(config: &Directory, image: &Image) -> Result<(), Error> {
+    let manifest_path = Path::new("/").join(MANIFEST);
+    let mut paths = HashSet::new();
+    let mut has_manifest = false;
+    for entry in &image.index.entries {
+        match *entry {
+            Dir(ref path) => { paths.insert(path.as_path()); }
+            File { ref path, .. } => {
+                has_manifest = has_manifest || *path == manifest_path;
+                paths.insert(path.as_path());
+            }
+            Link(..) => {}
+        }
+    }
+    if !has_manifest || config.allow_xattrs.is_empty() {
+        return Ok(());
+    }
+    let invalid = |path: &Path| {
+        Error::Policy(path.to_path_buf(), "invalid_xattrs")
+    };
+    let file = image.temporary.open_file(MANIFEST)
+        .map_err(|e| Error::ReadFile(
+            recover_path(&image.temporary, MANIFEST), e))?;
+    let manifest: Manifest = serde_json::from_reader(BufReader::new(file))
+        .map_err(|_| invalid(&manifest_path))?;
+    for (path, attrs) in &manifest {
+        let path = Path::new(path);
+        if !paths.contains(path) {
+            return Err(invalid(path));
+        }
+        let fs_path = recover_path(&image.temporary,
+            path.strip_prefix("/").unwrap_or(path));
+        if !attrs.is_empty() {
+            unshare_file(config, &image.temporary, path)?;
+        }
+        for (name, value) in attrs {
+            if !config.allow_xattrs.iter().any(|p| xattrs::matches(p, name)) {
+                warn!("{:?}: xattr {:?} of {:?} is not allowed, skipping",
+                    image.virtual_path, name, path);
+                continue;
+            }
+            let value: Vec<u8> = FromHex::from_hex(value)
+                .map_err(|_| invalid(path))?;
+            xattrs::set(&fs_path, name, &value)
+                .map_err(|e| Error::SetXattr(fs_path.clone(), name.clone(),
+                                             e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves image in place, replaced image is kept in `previous` versions
+///
+/// If `previous` is `None`, replaced image is removed.
+///
+/// Every entry is checked against the policy of the directory `config`.
+pub fn commit_image(config: &Directory, image: Arc<Image>,
+    previous: Option<(VersionInfo, usize)>)
+    -> Result<(), Error>
+{
     debug!("Preparing commit {:?}", image.virtual_path);
     let start = Instant::now();
     // TODO(tailhook) maybe throttle
     let mut dir = None;
     for entry in &image.index.entries {
+        if let Some(reason) = check_entry(config, entry) {
+            return Err(Error::Policy(entry_path(entry).to_path_buf(),
+                                     reason));
+        }
         match *entry {
             Dir(ref path) => {
                 dir = Some((ensure_path(&image.temporary, path)?, path));
-                // TODO(tailhook) check permissions?
+                set_mode(&image.temporary, path, dir_mode(config))?;
             }
             File { ref path, exe, size, .. } if size == 0 => {
                 let &(ref dir, ref dpath) = dir.as_ref().unwrap();
@@ -62,12 +249,10 @@ pub fn commit_image(image: Arc<Image>) -> Result<(), Error> {
                 let filename = path.file_name().expect("file has filename");
                 match dir.new_file(filename, 0o644) {
                     Ok(file) => {
-                        if exe {
-                            file.set_permissions(
-                                PermissionsExt::from_mode(0o755))
-                            .map_err(|e| Error::SetPermissions(
-                                recover_path(dir, filename), e))?;
-                        } // TODO(tailhook) else check permissions
+                        file.set_permissions(
+                            PermissionsExt::from_mode(file_mode(config, exe)))
+                        .map_err(|e| Error::SetPermissions(
+                            recover_path(dir, filename), e))?;
                     }
                     Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
                         let file = dir.open_file(filename)
@@ -95,25 +280,26 @@ pub fn commit_image(image: Arc<Image>) -> Result<(), Error> {
                 debug_assert!(*dpath == path.parent().unwrap());
                 // ... and having filenames
                 let filename = path.file_name().expect("file has filename");
+                extend_file(dir, filename, size)?;
                 let mut file = dir.open_file(filename)
                     .map_err(|e| Error::ReadFile(
                         recover_path(dir, filename), e))?;
-                reset_timestamp(&mut file)
-                    .map_err(|e| Error::SetTimestamp(
-                        recover_path(dir, filename), e))?;
+                if config.reset_mtime {
+                    reset_timestamp(&mut file)
+                        .map_err(|e| Error::SetTimestamp(
+                            recover_path(dir, filename), e))?;
+                }
                 let ok = hashes.check_file(&mut file)
                     .map_err(|e| Error::ReadFile(
                         recover_path(dir, filename), e))?;
-                // TODO(tailhook) recheck filesize
                 if !ok {
                     return Err(
                         Error::Checksum(recover_path(dir, filename)));
                 }
-                if exe {
-                    file.set_permissions(PermissionsExt::from_mode(0o755))
-                    .map_err(|e| Error::SetPermissions(
-                        recover_path(dir, filename), e))?;
-                }
+                file.set_permissions(
+                    PermissionsExt::from_mode(file_mode(config, exe)))
+                .map_err(|e| Error::SetPermissions(
+                    recover_path(dir, filename), e))?;
             }
             Link(ref link, ref dest) => {
                 let &(ref dir, ref dpath) = dir.as_ref().unwrap();
@@ -125,13 +311,18 @@ pub fn commit_image(image: Arc<Image>) -> Result<(), Error> {
                         recover_path(dir, filename), e))?;
             }
         }
+        if let Some((uid, gid)) = config.chown_to {
+            set_owner(&image.temporary, entry_path(entry), uid, gid)?;
+        }
     }
+    // after chown, as changing owner clears file capabilities
+    apply_xattrs(config, &image)?;
     // TODO(tailhook) check extra files and directories
     info!("{:?}: Checked in {}. Commiting...",
         image.virtual_path,
         (Instant::now() - start).as_secs());
 
-    let fname = image.virtual_path.final_name();
+    let fname = &image.name[..];
 
     // Note: we rely on `Metadata::writing` lock to avoid race conditions here
     let exists = match image.parent.metadata(fname) {
@@ -148,12 +339,33 @@ pub fn commit_image(image: Arc<Image>) -> Result<(), Error> {
         image.parent.local_exchange(&image.temporary_name, fname)
             .map_err(|e| Error::Commit(
                 recover_path(&image.parent, fname), e))?;
-        remove_dir_recursive(&image.parent, &image.temporary_name)?;
+        let kept = match previous {
+            Some((ref info, limit)) => {
+                // new image is in place already, so don't fail the commit
+                match versions::keep(&image.parent, fname,
+                                     &image.temporary_name, info, limit)
+                {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!("{:?}: can't keep previous version {}: {}",
+                            image.virtual_path, info.state.image, e);
+                        false
+                    }
+                }
+            }
+            None => false,
+        };
+        if !kept {
+            remove_dir_recursive(&image.parent, &image.temporary_name)?;
+        }
     } else {
         image.parent.local_rename(&image.temporary_name, fname)
             .map_err(|e| Error::Commit(
                 recover_path(&image.parent, fname), e))?;
     }
+    if let Err(e) = progress::remove(&image.parent, &image.temporary_name) {
+        warn!("{:?}: {}", image.virtual_path, e);
+    }
     IMAGES.incr(1);
     BYTES.incr(image.index.bytes_total);
     BLOCKS.incr(image.index.blocks_total);
@@ -1,6 +1,6 @@
 use std::io;
 use std::ops;
-use std::path::{Path, PathBuf};
+use std::path::{Path, PathBuf, Component};
 
 use libc;
 use openat::{Dir, SimpleType, Entry};
@@ -77,8 +77,12 @@ pub fn ensure_path<P: AsRef<Path>>(dir: &Dir, path: P)
         path
     };
     let mut dir = DirBorrow::Borrow(dir);
-    for component in path.iter() {
-        // TODO(tailhook) check component validity
+    for component in path.components() {
+        let component = match component {
+            Component::Normal(name) => name,
+            Component::CurDir => continue,
+            _ => return Err(Error::InvalidPath(path.to_path_buf())),
+        };
         dir = DirBorrow::Owned(ensure_subdir(&*dir, component)?);
     }
     Ok(dir)
@@ -94,14 +98,32 @@ pub fn open_path<P: AsRef<Path>>(dir: &Dir, path: P)
         path
     };
     let mut dir = DirBorrow::Borrow(dir);
-    for component in path.iter() {
-        // TODO(tailhook) check component validity
+    for component in path.components() {
+        let component = match component {
+            Component::Normal(name) => name,
+            Component::CurDir => continue,
+            _ => return Err(Error::InvalidPath(path.to_path_buf())),
+        };
         dir = DirBorrow::Owned(dir.sub_dir(component)
             .map_err(|e| Error::OpenDir(recover_path(&*dir, component), e))?);
     }
     Ok(dir)
 }
 
+/// Opens parent of the base directory, returns it and the name of the base
+///
+/// This is where level 0 image is written, as it replaces base directory.
+pub fn open_base_parent(base_dir: &Path) -> Result<(Dir, String), Error> {
+    let name = base_dir.file_name().and_then(|x| x.to_str())
+        .ok_or_else(|| Error::OpenBase(base_dir.to_path_buf(),
+            io::Error::new(io::ErrorKind::InvalidInput,
+                "directory must have a valid name")))?;
+    let parent = base_dir.parent().expect("directory has a name");
+    let dir = Dir::open(parent)
+        .map_err(|e| Error::OpenBase(parent.to_path_buf(), e))?;
+    Ok((dir, name.to_string()))
+}
+
 pub fn ensure_virtual_parent<'x>(dir: &'x Dir, path: &VPath)
     -> Result<DirBorrow<'x>, Error>
 {
@@ -1,6 +1,8 @@
 use std::io;
 use std::path::{PathBuf};
 
+use serde_cbor::error::Error as CborError;
+
 use {VPath};
 
 
@@ -66,11 +68,37 @@ quick_error! {
             display("error setting permissions on {:?}: {}", path, e)
             cause(e)
         }
+        SetOwner(path: PathBuf, e: io::Error) {
+            description("error changing owner")
+            display("error changing owner of {:?}: {}", path, e)
+            cause(e)
+        }
+        SetXattr(path: PathBuf, name: String, e: io::Error) {
+            description("error setting extended attribute")
+            display("error setting xattr {:?} on {:?}: {}", name, path, e)
+            cause(e)
+        }
         CreateSymlink(path: PathBuf, e: io::Error) {
             description("error creating symlink")
             display("error creating symlink {:?}: {}", path, e)
             cause(e)
         }
+        InvalidPath(path: PathBuf) {
+            description("invalid path")
+            display("invalid path {:?}", path)
+        }
+        Policy(path: PathBuf, reason: &'static str) {
+            description("path is not allowed by directory config")
+            display("path {:?} is not allowed: {}", path, reason)
+        }
+        OwnerMismatch(dir: VPath) {
+            description("files have different owner")
+            display("files in {:?} have different owner", dir)
+        }
+        HasXattrs(path: PathBuf) {
+            description("file has extended attributes")
+            display("file {:?} has extended attributes", path)
+        }
         Checksum(path: PathBuf) {
             description("error verifing checksum")
             display("error verifing checksum {:?}", path)
@@ -90,5 +118,14 @@ quick_error! {
             description("path already exists but not a directory")
             display("path {:?} already exists but not a directory", path)
         }
+        BadTrashInfo(path: PathBuf, e: CborError) {
+            description("can't read or write trash info")
+            display("can't read or write trash info {:?}: {}", path, e)
+        }
+        BadVersionInfo(path: PathBuf, e: CborError) {
+            description("can't read or write state of previous version")
+            display("can't read or write state of previous version {:?}: {}",
+                path, e)
+        }
     }
 }
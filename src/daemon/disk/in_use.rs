@@ -0,0 +1,88 @@
+use std::fs::{File, read_dir, read_link};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+
+/// Returns pids of processes which use files in the directory
+///
+/// Current and root directories, executable, open files and memory mapped
+/// files are checked for all the processes we have access to. The `dir`
+/// must be canonical path, as this is what kernel reports.
+pub fn find_users(dir: &Path) -> Vec<u32> {
+    let mut res = Vec::new();
+    let procs = match read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(e) => {
+            warn!("Can't list processes: {}", e);
+            return res;
+        }
+    };
+    for entry in procs.filter_map(|e| e.ok()) {
+        let pid = match entry.file_name().to_str()
+            .and_then(|x| x.parse::<u32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if uses_dir(&entry.path(), dir) {
+            res.push(pid);
+        }
+    }
+    res
+}
+
+fn uses_dir(proc_dir: &Path, dir: &Path) -> bool {
+    for name in &["cwd", "root", "exe"] {
+        if link_in(&proc_dir.join(name), dir) {
+            return true;
+        }
+    }
+    if let Ok(fds) = read_dir(proc_dir.join("fd")) {
+        for fd in fds.filter_map(|e| e.ok()) {
+            if link_in(&fd.path(), dir) {
+                return true;
+            }
+        }
+    }
+    if let Ok(f) = File::open(proc_dir.join("maps")) {
+        for line in BufReader::new(f).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            // address, perms, offset, dev, inode and padded pathname
+            if let Some(path) = line.splitn(6, ' ').nth(5) {
+                if Path::new(path.trim_start()).starts_with(dir) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+fn link_in(link: &Path, dir: &Path) -> bool {
+    read_link(link).map(|target| target.starts_with(dir)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use tempfile::TempDir;
+    use super::find_users;
+
+    #[test]
+    #[cfg(target_os="linux")]
+    fn child_in_dir() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().canonicalize().unwrap();
+        let mut child = Command::new("sleep").arg("10")
+            .current_dir(&path)
+            .spawn().unwrap();
+        let users = find_users(&path);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(users.contains(&child.id()));
+        assert!(!find_users(&path).contains(&child.id()));
+    }
+}
@@ -1,10 +1,19 @@
 mod commit;
 mod dir;
 mod error;
+mod in_use;
+mod policy;
+mod progress;
 mod public;
+mod reflink;
+mod scrub;
+mod trash;
+mod versions;
 
 pub use self::public::{Disk, Image, start};
 pub use self::error::Error;
+pub use self::scrub::{ScrubReport, DamagedFile};
+pub use self::trash::TrashInfo;
 
 use metrics::{List, Metric};
 
@@ -13,13 +22,21 @@ pub struct Init {
 
 pub fn metrics() -> List {
     let hlinks = "disk.hardlinks";
+    let local = "disk.local_blocks";
     let comm = "disk.committed";
+    let scrub = "disk.scrub";
     vec![
         (Metric(hlinks, "files"), &*self::public::HARDLINKED_FILES),
         (Metric(hlinks, "bytes"), &*self::public::HARDLINKED_BYTES),
+        (Metric(local, "copied"), &*self::public::COPIED_BLOCKS),
+        (Metric(local, "bytes"), &*self::public::COPIED_BYTES),
         (Metric(comm, "images"), &*self::commit::IMAGES),
         (Metric(comm, "bytes"), &*self::commit::BYTES),
         (Metric(comm, "blocks"), &*self::commit::BLOCKS),
         (Metric(comm, "paths"), &*self::commit::PATHS),
+        (Metric(scrub, "bytes"), &*self::scrub::BYTES),
+        (Metric(scrub, "bad_blocks"), &*self::scrub::BAD_BLOCKS),
+        (Metric(scrub, "missing_files"), &*self::scrub::MISSING_FILES),
+        (Metric(scrub, "repaired_blocks"), &*self::scrub::REPAIRED_BLOCKS),
     ]
 }
@@ -0,0 +1,130 @@
+use std::path::{Path, Component};
+
+use dir_signature::v1::Entry;
+
+use config::Directory;
+
+
+/// Checks that path in the index is absolute and has no `..`
+fn valid_path(path: &Path) -> bool {
+    let mut components = path.components();
+    if components.next() != Some(Component::RootDir) {
+        return false;
+    }
+    components.all(|c| matches!(c, Component::Normal(..)))
+}
+
+/// Returns true if relative symlink `link -> target` may point outside of
+/// the image
+///
+/// Parent dirs of the symlink are real directories, so `..` is allowed only
+/// at the start of the target (at most the depth of the symlink). Each
+/// symlink is checked separately, so chains of symlinks can't escape too.
+fn escapes(link: &Path, target: &Path) -> bool {
+    let mut depth = link.parent().map(|p| {
+        p.components().filter(|c| matches!(*c, Component::Normal(..))).count()
+    }).unwrap_or(0);
+    let mut descended = false;
+    for component in target.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if descended || depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+            }
+            Component::Normal(..) => descended = true,
+            Component::RootDir | Component::Prefix(..) => return true,
+        }
+    }
+    return false;
+}
+
+/// Checks entry of the index against the policy of the directory
+///
+/// Returns reason if the entry is not allowed.
+pub fn check_entry(config: &Directory, entry: &Entry)
+    -> Option<&'static str>
+{
+    match *entry {
+        Entry::Dir(ref path) => {
+            if !valid_path(path) {
+                return Some("invalid_path");
+            }
+        }
+        Entry::File { ref path, size, .. } => {
+            if !valid_path(path) || path.parent().is_none() {
+                return Some("invalid_path");
+            }
+            if config.max_file_size.map(|max| size > max).unwrap_or(false) {
+                return Some("file_too_large");
+            }
+        }
+        Entry::Link(ref path, ref target) => {
+            if !valid_path(path) || path.parent().is_none() {
+                return Some("invalid_path");
+            }
+            if target.is_absolute() {
+                if !config.allow_absolute_symlinks {
+                    return Some("absolute_symlink");
+                }
+            } else if !config.allow_escaping_symlinks &&
+                escapes(path, target)
+            {
+                return Some("escaping_symlink");
+            }
+        }
+    }
+    None
+}
+
+pub fn entry_path(entry: &Entry) -> &Path {
+    match *entry {
+        Entry::Dir(ref path) => path,
+        Entry::File { ref path, .. } => path,
+        Entry::Link(ref path, _) => path,
+    }
+}
+
+/// Checks all entries of the index, returns first violation
+pub fn check_index<'x>(config: &Directory, entries: &'x [Entry])
+    -> Option<(&'x Path, &'static str)>
+{
+    for entry in entries {
+        if let Some(reason) = check_entry(config, entry) {
+            return Some((entry_path(entry), reason));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::{escapes, valid_path};
+
+    fn esc(link: &str, target: &str) -> bool {
+        escapes(Path::new(link), Path::new(target))
+    }
+
+    #[test]
+    fn paths() {
+        assert!(valid_path(Path::new("/")));
+        assert!(valid_path(Path::new("/a/b")));
+        assert!(!valid_path(Path::new("a/b")));
+        assert!(!valid_path(Path::new("/a/../b")));
+    }
+
+    #[test]
+    fn escaping() {
+        assert!(!esc("/link", "file"));
+        assert!(!esc("/link", "./dir/file"));
+        assert!(!esc("/dir/link", "../file"));
+        assert!(!esc("/a/b/link", "../../file"));
+        assert!(esc("/link", "../file"));
+        assert!(esc("/dir/link", "../../file"));
+        assert!(esc("/link", "dir/../file"));
+        assert!(esc("/link", "/etc/passwd"));
+    }
+}
@@ -0,0 +1,293 @@
+//! Progress of the image download, kept to resume it after restart
+//!
+//! Progress file `.tmp.<name>.progress` lives next to the temporary dir of
+//! the image. First line is the image id, every next line is the number of
+//! the entry in the index and the offset of the block written.
+use std::cmp::min;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+
+use dir_signature::v1::Entry;
+use openat::{Dir, SimpleType};
+
+use blocks::BlockHash;
+use disk::Error;
+use disk::dir::{ensure_subdir, open_path, recover_path};
+use disk::dir::{remove_dir_recursive};
+use tracking::Index;
+
+
+pub fn file_name(temporary_name: &str) -> String {
+    format!("{}.progress", temporary_name)
+}
+
+/// Reads blocks recorded in the progress file if it's for the same image
+fn read(parent: &Dir, name: &str, index: &Index)
+    -> Result<Option<Vec<(usize, u64)>>, Error>
+{
+    let mut buf = String::new();
+    match parent.open_file(name) {
+        Ok(mut f) => {
+            f.read_to_string(&mut buf)
+                .map_err(|e| Error::ReadFile(recover_path(parent, name), e))?;
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::ReadFile(recover_path(parent, name), e)),
+    }
+    let mut lines = buf.lines();
+    if lines.next() != Some(&index.id.to_string()[..]) {
+        return Ok(None);
+    }
+    // last line may be incomplete, it's skipped like any malformed one
+    Ok(Some(lines.filter_map(|line| {
+        let mut pair = line.split(' ');
+        match (pair.next().and_then(|x| x.parse().ok()),
+               pair.next().and_then(|x| x.parse().ok()))
+        {
+            (Some(entry), Some(offset)) => Some((entry, offset)),
+            _ => None,
+        }
+    }).collect()))
+}
+
+/// Checks that the block is written, holes are read as zeros
+fn check_block(dir: &Dir, index: &Index, entry: usize, offset: u64)
+    -> Result<bool, Error>
+{
+    let (path, size, hashes) = match index.entries.get(entry) {
+        Some(&Entry::File { ref path, size, ref hashes, .. }) => {
+            (path, size, hashes)
+        }
+        _ => return Ok(false),
+    };
+    if offset >= size || offset % index.block_size != 0 {
+        return Ok(false);
+    }
+    let expected = hashes.iter()
+        .nth((offset / index.block_size) as usize)
+        .and_then(BlockHash::from_bytes);
+    let path = path.strip_prefix("/").expect("path is absolute");
+    let dir = open_path(dir, path.parent().expect("path is never root"))?;
+    let fname = path.file_name().expect("path has a filename");
+    let file = match dir.open_file(fname) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(Error::ReadFile(recover_path(&*dir, fname), e)),
+    };
+    let mut data = vec![0u8; min(index.block_size, size - offset) as usize];
+    let mut pos = 0;
+    while pos < data.len() {
+        match file.read_at(&mut data[pos..], offset + pos as u64) {
+            Ok(0) => break,  // the rest is zeros
+            Ok(n) => pos += n,
+            Err(e) => {
+                return Err(Error::ReadFile(recover_path(&*dir, fname), e));
+            }
+        }
+    }
+    Ok(expected == Some(BlockHash::hash_bytes(&data)))
+}
+
+/// Prepares temporary dir for the image
+///
+/// If there is a progress file for the same image, the dir is kept and
+/// blocks recorded there are verified. Returns temporary dir, progress
+/// file opened for appending and blocks which don't need to be fetched.
+pub fn start(parent: &Dir, temporary_name: &str, index: &Index)
+    -> Result<(Dir, File, HashSet<(usize, u64)>), Error>
+{
+    let name = file_name(temporary_name);
+    let recorded = read(parent, &name, index)?;
+    if recorded.is_none() {
+        remove_dir_recursive(parent, temporary_name)?;
+        let path = &|| recover_path(parent, &name);
+        let mut file = parent.write_file(&name, 0o644)
+            .map_err(|e| Error::WriteFile(path(), e))?;
+        file.write_all(format!("{}\n", index.id).as_bytes())
+            .map_err(|e| Error::WriteFile(path(), e))?;
+    }
+    let dir = ensure_subdir(parent, temporary_name)?;
+    let mut written = HashSet::new();
+    if let Some(blocks) = recorded {
+        let total = blocks.len();
+        for (entry, offset) in blocks {
+            match check_block(&dir, index, entry, offset) {
+                Ok(true) => { written.insert((entry, offset)); }
+                Ok(false) => {}
+                Err(e) => warn!("Can't check written block: {}", e),
+            }
+        }
+        info!("Resuming {}: {} of {} recorded blocks are valid",
+            index.id, written.len(), total);
+    }
+    let file = parent.append_file(&name, 0o644)
+        .map_err(|e| Error::WriteFile(recover_path(parent, &name), e))?;
+    Ok((dir, file, written))
+}
+
+/// Records that the block is written
+///
+/// Each record is written by a single `write` to the file opened in append
+/// mode, so blocks may be recorded from multiple threads.
+pub fn record(mut file: &File, entry: usize, offset: u64)
+    -> io::Result<()>
+{
+    file.write_all(format!("{} {}\n", entry, offset).as_bytes())
+}
+
+/// Removes progress file after image is committed
+pub fn remove(parent: &Dir, temporary_name: &str) -> Result<(), Error> {
+    let name = file_name(temporary_name);
+    match parent.remove_file(&name) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::Delete(recover_path(parent, &name), e)),
+    }
+}
+
+/// Removes temporary dirs and progress files of images not in `keep`
+///
+/// If `only` is set, other images are never touched. It's used for level 0
+/// images, which are written next to unrelated files of the parent dir.
+pub fn remove_stale(dir: &Dir, only: Option<&str>, keep: &HashSet<String>)
+    -> Result<(), Error>
+{
+    let err = |e| Error::ReadFile(recover_path(dir, "."), e);
+    for entry in dir.list_dir(".").map_err(&err)? {
+        let entry = entry.map_err(&err)?;
+        let fname = match entry.file_name().to_str() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        // these are removed by the code which creates them
+        if !fname.starts_with(".tmp.") || fname.starts_with(".tmp.old.") ||
+            fname.starts_with(".tmp.rollback.")
+        {
+            continue;
+        }
+        let is_dir = entry.simple_type() == Some(SimpleType::Dir);
+        let image = if is_dir {
+            &fname[".tmp.".len()..]
+        } else if fname.ends_with(".progress") {
+            &fname[".tmp.".len()..fname.len() - ".progress".len()]
+        } else {
+            continue;
+        };
+        if only.map(|name| name != image).unwrap_or(false) ||
+            keep.contains(image)
+        {
+            continue;
+        }
+        info!("Removing stale {:?}", recover_path(dir, &fname));
+        if is_dir {
+            remove_dir_recursive(dir, &fname)?;
+        } else {
+            dir.remove_file(&fname[..])
+                .map_err(|e| Error::Delete(recover_path(dir, &fname), e))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::io::Write;
+
+    use openat::Dir;
+    use tempfile::TempDir;
+
+    use index::ImageId;
+    use index_cache::IndexData;
+    use tracking::Index;
+    use super::{read, remove_stale};
+
+    fn index(id: u8) -> Index {
+        Index::from_data(IndexData::from_files(
+            &ImageId::from(vec![id; 32]), 4096, &[("file", b"hello")]))
+    }
+
+    fn write(dir: &Dir, name: &str, data: &str) {
+        dir.write_file(name, 0o644).unwrap()
+            .write_all(data.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn read_other_image() {
+        let tmp = TempDir::new().unwrap();
+        let dir = Dir::open(tmp.path()).unwrap();
+        write(&dir, ".tmp.app.progress",
+              &format!("{}\n1 0\n", ImageId::from(vec![2; 32])));
+        assert_eq!(read(&dir, ".tmp.app.progress", &index(1)).unwrap(),
+                   None);
+        assert_eq!(read(&dir, ".tmp.none.progress", &index(1)).unwrap(),
+                   None);
+    }
+
+    #[test]
+    fn read_partial_line() {
+        let tmp = TempDir::new().unwrap();
+        let dir = Dir::open(tmp.path()).unwrap();
+        let idx = index(1);
+        write(&dir, ".tmp.app.progress",
+              &format!("{}\n1 0\n1 4096\n3 ", idx.id));
+        assert_eq!(read(&dir, ".tmp.app.progress", &idx).unwrap(),
+                   Some(vec![(1, 0), (1, 4096)]));
+        write(&dir, ".tmp.app.progress", &format!("{}\n1", idx.id));
+        assert_eq!(read(&dir, ".tmp.app.progress", &idx).unwrap(),
+                   Some(vec![]));
+    }
+
+    #[test]
+    fn stale() {
+        let tmp = TempDir::new().unwrap();
+        let dir = Dir::open(tmp.path()).unwrap();
+        for name in &["app", ".tmp.app", ".tmp.stale", ".tmp.old.app",
+                      ".tmp.rollback.app"]
+        {
+            dir.create_dir(*name, 0o755).unwrap();
+        }
+        dir.write_file(".tmp.stale/file", 0o644).unwrap();
+        write(&dir, ".tmp.app.progress", "");
+        write(&dir, ".tmp.stale.progress", "");
+        let keep = vec!["app".to_string()].into_iter()
+            .collect::<HashSet<_>>();
+        remove_stale(&dir, None, &keep).unwrap();
+        let mut names = dir.list_dir(".").unwrap()
+            .map(|e| e.unwrap().file_name().to_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec![".tmp.app", ".tmp.app.progress",
+                               ".tmp.old.app", ".tmp.rollback.app", "app"]);
+    }
+
+    #[test]
+    fn stale_level_zero() {
+        let tmp = TempDir::new().unwrap();
+        let dir = Dir::open(tmp.path()).unwrap();
+        for name in &["nginx", ".tmp.nginx", ".tmp.other"] {
+            dir.create_dir(*name, 0o755).unwrap();
+        }
+        dir.write_file(".tmp.nginx/file", 0o644).unwrap();
+        write(&dir, ".tmp.nginx.progress", "");
+        write(&dir, ".tmp.other.progress", "");
+        let names = || {
+            let mut names = dir.list_dir(".").unwrap()
+                .map(|e| e.unwrap().file_name().to_str().unwrap().to_string())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        let keep = vec!["nginx".to_string()].into_iter()
+            .collect::<HashSet<_>>();
+        remove_stale(&dir, Some("nginx"), &keep).unwrap();
+        assert_eq!(names(), vec![".tmp.nginx", ".tmp.nginx.progress",
+                                 ".tmp.other", ".tmp.other.progress",
+                                 "nginx"]);
+        remove_stale(&dir, Some("nginx"), &HashSet::new()).unwrap();
+        assert_eq!(names(), vec![".tmp.other", ".tmp.other.progress",
+                                 "nginx"]);
+    }
+}
@@ -1,9 +1,10 @@
-use std::collections::HashSet;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
 use std::io::{self, Seek, SeekFrom, Write, BufReader, BufRead, Read};
-use std::os::unix::fs::{PermissionsExt};
+use std::os::unix::fs::{FileExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use futures::{Future, Stream};
 use futures::stream::iter_ok;
@@ -11,40 +12,64 @@ use futures_cpupool::{self, CpuPool, CpuFuture};
 use openat::{Dir, SimpleType, hardlink};
 use regex::Regex;
 use self_meter_http::Meter;
+use serde_json;
 use void::Void;
 
 use {VPath};
+use blocks::BlockHash;
 use config::{Config, Directory};
-use disk::commit::commit_image;
+use dir_util::has_space_for;
+use disk::commit::{commit_image, file_mode};
 use disk::dir::{ensure_virtual_parent, ensure_path, open_path};
-use disk::dir::{ensure_subdir, recover_path, DirBorrow};
+use disk::dir::{open_base_parent};
+use disk::dir::{recover_path, DirBorrow};
 use disk::dir::{remove_dir_recursive};
 use disk::{Init, Error};
+use disk::in_use::find_users;
+use disk::policy;
+use disk::progress;
+use disk::reflink::copy_range;
+use disk::scrub::{self, ScrubReport};
+use disk::trash::{self, TrashInfo};
+use disk::versions::{self, VersionInfo};
+use database::signatures::State;
+use index::ImageId;
 use tracking::Index;
-use metadata::{Meta, Hardlink};
+use metadata::{Meta, Hardlink, LocalBlock};
 use tracking::BlockData;
 use metrics::Counter;
+use xattrs::{Manifest, MANIFEST};
 
 
 
 lazy_static! {
     pub static ref HARDLINKED_FILES: Counter = Counter::new();
     pub static ref HARDLINKED_BYTES: Counter = Counter::new();
+    pub static ref COPIED_BLOCKS: Counter = Counter::new();
+    pub static ref COPIED_BYTES: Counter = Counter::new();
 }
 
 
 #[derive(Clone)]
 pub struct Disk {
     pool: CpuPool,
+    /// Single thread for scrubbing, so it doesn't delay downloads
+    scrub_pool: CpuPool,
     config: Arc<Config>,
 }
 
 pub struct Image {
     pub virtual_path: VPath,
     pub parent: Dir,
+    /// Name of the image in `parent`, differs from `virtual_path` for
+    /// level 0 images which replace base directory itself
+    pub name: String,
     pub temporary_name: String,
     pub temporary: Dir,
     pub index: Index,
+    /// Blocks written before restart as `(entry number, offset)` pairs
+    pub written: HashSet<(usize, u64)>,
+    progress: File,
 }
 
 fn check_exists(dir: &Dir, name: &str) -> Result<(), Error> {
@@ -65,6 +90,8 @@ impl Disk {
     {
         let m1 = meter.clone();
         let m2 = meter.clone();
+        let m3 = meter.clone();
+        let m4 = meter.clone();
         Ok((Disk {
             pool: futures_cpupool::Builder::new()
                 .pool_size(num_threads)
@@ -72,6 +99,12 @@ impl Disk {
                 .after_start(move || m1.track_current_thread_by_name())
                 .before_stop(move || m2.untrack_current_thread())
                 .create(),
+            scrub_pool: futures_cpupool::Builder::new()
+                .pool_size(1)
+                .name_prefix("scrub-")
+                .after_start(move || m3.track_current_thread_by_name())
+                .before_stop(move || m4.untrack_current_thread())
+                .create(),
             config: config.clone(),
         }, Init {
         }))
@@ -81,25 +114,81 @@ impl Disk {
         -> CpuFuture<Image, Error>
     {
         self.pool.spawn_fn(move || {
-            let dir = Dir::open(&base_dir)
-                .map_err(|e| Error::OpenBase(base_dir.to_path_buf(), e))?;
-            let dir = match ensure_virtual_parent(&dir, &virtual_path)? {
-                DirBorrow::Borrow(_) => dir,
-                DirBorrow::Owned(dir) => dir,
+            let (dir, name) = if virtual_path.level() == 0 {
+                open_base_parent(&base_dir)?
+            } else {
+                let dir = Dir::open(&base_dir)
+                    .map_err(|e| Error::OpenBase(base_dir.to_path_buf(), e))?;
+                let dir = match ensure_virtual_parent(&dir, &virtual_path)? {
+                    DirBorrow::Borrow(_) => dir,
+                    DirBorrow::Owned(dir) => dir,
+                };
+                (dir, virtual_path.final_name().to_string())
             };
 
-            let tmp_name = format!(".tmp.{}", virtual_path.final_name());
-            remove_dir_recursive(&dir, &tmp_name)?;
-            let temp_dir = ensure_subdir(&dir, &tmp_name)?;
+            let tmp_name = format!(".tmp.{}", name);
+            let (temp_dir, progress_file, written) =
+                progress::start(&dir, &tmp_name, &index)?;
             Ok(Image {
                 virtual_path: virtual_path,
                 parent: dir,
+                name: name,
                 temporary_name: tmp_name,
                 temporary: temp_dir,
                 index: index,
+                written: written,
+                progress: progress_file,
             })
         })
     }
+    /// Removes temporary dirs of images in `base_dir` except ones in `keep`
+    ///
+    /// They are left by downloads which are not resumed after restart.
+    pub fn remove_stale_temporary(&self, config: &Arc<Directory>,
+        base_dir: &VPath, keep: HashSet<String>)
+        -> CpuFuture<(), Error>
+    {
+        let cfg = config.clone();
+        let base_dir = base_dir.clone();
+        self.pool.spawn_fn(move || {
+            if cfg.num_levels == 0 {
+                // temporary dir is next to the base dir itself
+                let (parent, name) = open_base_parent(&cfg.directory)?;
+                let keep = if keep.contains("") {
+                    vec![name.clone()].into_iter().collect()
+                } else {
+                    HashSet::new()
+                };
+                return progress::remove_stale(&parent, Some(&name), &keep);
+            }
+            let root = Dir::open(&cfg.directory)
+                .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+            let dir = match open_path(&root, base_dir.suffix()) {
+                Ok(dir) => dir,
+                Err(Error::OpenDir(_, ref e))
+                if e.kind() == io::ErrorKind::NotFound
+                => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            progress::remove_stale(&*dir, None, &keep)
+        })
+    }
+    /// Returns pids of processes which use the image
+    pub fn find_users(&self, config: &Arc<Directory>, path: PathBuf)
+        -> CpuFuture<Vec<u32>, Error>
+    {
+        let cfg = config.clone();
+        self.pool.spawn_fn(move || {
+            let full = cfg.directory.join(&path);
+            match full.canonicalize() {
+                Ok(dir) => Ok(find_users(&dir)),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    Ok(Vec::new())
+                }
+                Err(e) => Err(Error::OpenDir(full, e)),
+            }
+        })
+    }
     pub fn remove_image(&self, config: &Arc<Directory>, path: PathBuf)
         -> CpuFuture<(), Error>
     {
@@ -111,9 +200,196 @@ impl Disk {
             let filename = path.file_name().and_then(|x| x.to_str())
                 .expect("valid path");
             remove_dir_recursive(&dir, filename)?;
+            match dir.remove_file(&format!(".access.{}", filename)) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Can't remove access marker of {:?}: {}",
+                    path, e),
+            }
             Ok(())
         })
     }
+    /// Moves image to the trash dir instead of removing it
+    ///
+    /// Also removes `.access.<name>` marker, similarly to `remove_image`.
+    pub fn trash_image(&self, config: &Arc<Directory>, path: PathBuf,
+                       info: TrashInfo)
+        -> CpuFuture<(), Error>
+    {
+        let cfg = config.clone();
+        self.pool.spawn_fn(move || {
+            let base = Dir::open(&cfg.directory)
+                .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+            let dir = open_path(&base,
+                path.parent().expect("valid parent"))?;
+            let filename = path.file_name().and_then(|x| x.to_str())
+                .expect("valid path");
+            trash::move_to_trash(&base, &dir, filename, &info)?;
+            match dir.remove_file(&format!(".access.{}", filename)) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Can't remove access marker of {:?}: {}",
+                    path, e),
+            }
+            Ok(())
+        })
+    }
+    /// Removes images which are in trash longer than `trash_retention`
+    pub fn purge_trash(&self, config: &Arc<Directory>)
+        -> CpuFuture<usize, Error>
+    {
+        let cfg = config.clone();
+        self.pool.spawn_fn(move || {
+            let retention = match cfg.trash_retention {
+                Some(retention) => retention,
+                None => return Ok(0),
+            };
+            let base = Dir::open(&cfg.directory)
+                .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+            trash::purge(&base, retention)
+        })
+    }
+    /// Lists images in the trash of all directories
+    pub fn list_trash(&self) -> CpuFuture<Vec<TrashInfo>, Error> {
+        let config = self.config.clone();
+        self.pool.spawn_fn(move || {
+            let mut result = Vec::new();
+            for cfg in config.dirs.get().values() {
+                if cfg.trash_retention.is_none() {
+                    continue;
+                }
+                let base = Dir::open(&cfg.directory)
+                    .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+                result.extend(trash::list(&base)?
+                    .into_iter().map(|(_, info)| info));
+            }
+            Ok(result)
+        })
+    }
+    /// Returns state of the most recently removed image at `path`
+    pub fn trash_state(&self, config: &Arc<Directory>, path: &VPath)
+        -> CpuFuture<Option<State>, Error>
+    {
+        let cfg = config.clone();
+        let path = path.clone();
+        self.pool.spawn_fn(move || {
+            let base = Dir::open(&cfg.directory)
+                .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+            Ok(trash::newest(&base, &path)?.map(|(_, info)| info.state))
+        })
+    }
+    /// Moves most recently removed `image` at `path` back from the trash
+    ///
+    /// Returns state of the image, `None` if there is no such image in the
+    /// trash.
+    pub fn restore_image(&self, config: &Arc<Directory>, path: &VPath,
+        image: &ImageId)
+        -> CpuFuture<Option<State>, Error>
+    {
+        let cfg = config.clone();
+        let path = path.clone();
+        let image = image.clone();
+        self.pool.spawn_fn(move || {
+            let base = Dir::open(&cfg.directory)
+                .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+            let dir = ensure_path(&base,
+                path.suffix().parent().expect("valid path"))?;
+            trash::restore(&base, &dir, &path, &image)
+        })
+    }
+    /// Exchanges image at `path` with its previous version `image`
+    ///
+    /// `current` is the state of the image being rolled back, it's kept as
+    /// a previous version. Returns state of the restored image, or `None`
+    /// if there is no such version.
+    pub fn rollback_image(&self, config: &Arc<Directory>, path: &VPath,
+        image: ImageId, current: State)
+        -> CpuFuture<Option<State>, Error>
+    {
+        let cfg = config.clone();
+        let path = path.clone();
+        self.pool.spawn_fn(move || {
+            let limit = cfg.keep_previous_versions;
+            let current = VersionInfo {
+                path: path.clone(),
+                state: current,
+                replaced: SystemTime::now(),
+            };
+            if path.level() == 0 {
+                let (dir, name) = open_base_parent(&cfg.directory)?;
+                return versions::restore(&dir, &name, &image,
+                                         &current, limit);
+            }
+            let base = Dir::open(&cfg.directory)
+                .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+            let dir = open_path(&base,
+                path.suffix().parent().expect("valid path"))?;
+            versions::restore(&dir, path.final_name(), &image,
+                              &current, limit)
+        })
+    }
+    /// Lists previous versions of images in all directories
+    pub fn list_versions(&self) -> CpuFuture<Vec<VersionInfo>, Error> {
+        let config = self.config.clone();
+        self.pool.spawn_fn(move || {
+            let mut result = Vec::new();
+            for cfg in config.dirs.get().values() {
+                if cfg.keep_previous_versions == 0 {
+                    continue;
+                }
+                if cfg.num_levels == 0 {
+                    let (dir, name) = open_base_parent(&cfg.directory)?;
+                    result.extend(versions::list(&dir, &name)?
+                        .into_iter().map(|(_, info)| info));
+                    continue;
+                }
+                let base = match Dir::open(&cfg.directory) {
+                    Ok(dir) => dir,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        continue;
+                    }
+                    Err(e) => {
+                        return Err(Error::OpenBase(cfg.directory.clone(), e));
+                    }
+                };
+                result.extend(versions::all(&base, cfg.num_levels)?);
+            }
+            Ok(result)
+        })
+    }
+    /// Checks files of the committed image against its index
+    pub fn scrub_image(&self, config: &Arc<Directory>, vpath: &VPath,
+        index: Index)
+        -> CpuFuture<ScrubReport, Error>
+    {
+        let cfg = config.clone();
+        let vpath = vpath.clone();
+        self.scrub_pool.spawn_fn(move || {
+            let dir = Dir::open(&cfg.directory)
+                .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+            let dir = open_path(&dir, vpath.suffix())?;
+            scrub::check_image(&*dir, &index, cfg.scrub_rate)
+        })
+    }
+    /// Writes blocks fetched from peers into the damaged file of the image
+    ///
+    /// `blocks` are `(offset, data)` pairs, `size` is the size of the file
+    /// in the index.
+    pub fn repair_file(&self, config: &Arc<Directory>, vpath: &VPath,
+        path: PathBuf, size: u64, blocks: Vec<(u64, BlockData)>)
+        -> CpuFuture<(), Error>
+    {
+        let cfg = config.clone();
+        let vpath = vpath.clone();
+        self.pool.spawn_fn(move || {
+            info!("Repairing {:?} in {:?}: {} blocks",
+                path, vpath, blocks.len());
+            let dir = Dir::open(&cfg.directory)
+                .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+            let dir = open_path(&dir, vpath.suffix())?;
+            scrub::repair_file(&*dir, &cfg, &path, size, &blocks)
+        })
+    }
     /// Fetch block at path and offset
     ///
     /// If `writing` is `true` then it looks in `.tmp.dirname`.
@@ -133,12 +409,21 @@ impl Disk {
         self.pool.spawn_fn(move || {
             trace!("Reading block {:?}/{:?}:{}", vpath, path, offset);
 
-            let cfg = config.dirs.get(vpath.key())
+            let cfg = config.dir(vpath.key())
                 .ok_or_else(|| Error::NoDir(vpath.clone()))?;
-            let dir = Dir::open(&cfg.directory)
-                .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+            let dir = if vpath.level() == 0 && writing {
+                // level 0 image is written next to the base directory
+                let (parent, name) = open_base_parent(&cfg.directory)?;
+                let tmp_name = format!(".tmp.{}", name);
+                parent.sub_dir(&tmp_name[..])
+                    .map_err(|e| Error::OpenDir(
+                        recover_path(&parent, &tmp_name), e))?
+            } else {
+                Dir::open(&cfg.directory)
+                    .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?
+            };
             let dir = open_path(&dir, vpath.parent().suffix())?;
-            let dir = if writing {
+            let dir = if writing && vpath.level() > 0 {
                 let tmp_name = format!(".tmp.{}", vpath.final_name());
                 open_path(&dir, tmp_name)?
             } else {
@@ -160,7 +445,10 @@ impl Disk {
             Ok(buf)
         })
     }
-    pub fn write_block(&self, image: Arc<Image>,
+    /// Writes block and records it in the progress file of the image
+    ///
+    /// `entry` is the number of the file in the index.
+    pub fn write_block(&self, image: Arc<Image>, entry: usize,
                        path: Arc<PathBuf>, offset: u64,
                        block: BlockData)
         -> CpuFuture<(), Error>
@@ -176,12 +464,33 @@ impl Disk {
                 .expect("path has a filename");
             write_block(&*dir, Path::new(fname), offset, block)
                 .map_err(|e| Error::WriteFile(recover_path(&*dir, fname), e))?;
+            progress::record(&image.progress, entry, offset)
+                .map_err(|e| Error::WriteFile(recover_path(&image.parent,
+                    progress::file_name(&image.temporary_name)), e))?;
             Ok(())
         })
     }
-    pub fn commit_image(&self, image: Arc<Image>) -> CpuFuture<(), Error> {
+    /// Commits image, `previous` is the state of the image being replaced
+    ///
+    /// Replaced image is kept if `keep-previous-versions` is set.
+    pub fn commit_image(&self, config: &Arc<Directory>, image: Arc<Image>,
+        previous: Option<State>)
+        -> CpuFuture<(), Error>
+    {
+        let config = config.clone();
         self.pool.spawn_fn(move || {
-            commit_image(image)
+            let limit = config.keep_previous_versions;
+            let previous = previous.and_then(|state| {
+                if limit == 0 {
+                    return None;
+                }
+                Some((VersionInfo {
+                    path: image.virtual_path.clone(),
+                    state: state,
+                    replaced: SystemTime::now(),
+                }, limit))
+            });
+            commit_image(&config, image, previous)
         })
     }
     pub fn read_keep_list(&self, dir: &Arc<Directory>)
@@ -282,6 +591,116 @@ impl Disk {
             }
         })
     }
+    /// Reads modification times of `.access.<name>` markers
+    ///
+    /// Markers are touched by local consumers of the images and are used by
+    /// `least-recently-used` cleanup policy. Missing markers are skipped.
+    pub fn read_access_times(&self, config: &Arc<Directory>, path: PathBuf,
+        names: Vec<String>)
+        -> CpuFuture<HashMap<String, SystemTime>, Error>
+    {
+        let cfg = config.clone();
+        self.pool.spawn_fn(move || {
+            let mut res = HashMap::new();
+            let dir = match Dir::open(&cfg.directory) {
+                Ok(dir) => dir,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Ok(res);
+                }
+                Err(e) => {
+                    return Err(Error::OpenBase(cfg.directory.clone(), e));
+                }
+            };
+            let dir = match open_path(&dir, &path) {
+                Ok(dir) => dir,
+                Err(Error::OpenDir(_, ref e))
+                if e.kind() == io::ErrorKind::NotFound
+                => return Ok(res),
+                Err(e) => return Err(e),
+            };
+            for name in names {
+                let marker = format!(".access.{}", name);
+                match dir.metadata(&marker) {
+                    Ok(meta) => {
+                        let mtime = meta.stat().st_mtime.max(0) as u64;
+                        res.insert(name,
+                            UNIX_EPOCH + Duration::from_secs(mtime));
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        warn!("Can't read access marker {:?}: {}",
+                            recover_path(&*dir, &marker), e);
+                    }
+                }
+            }
+            Ok(res)
+        })
+    }
+    /// Points `current-symlink` of the base dir to the image `name`
+    ///
+    /// The symlink is replaced atomically by renaming a temporary one over
+    /// it. Returns `false` if the symlink already points to the image.
+    pub fn update_current_symlink(&self, config: &Arc<Directory>,
+        path: PathBuf, name: String)
+        -> CpuFuture<bool, Error>
+    {
+        let cfg = config.clone();
+        self.pool.spawn_fn(move || {
+            let link = match cfg.current_symlink {
+                Some(ref link) => link.clone(),
+                None => return Ok(false),
+            };
+            let full = cfg.directory.join(&path).join(&link);
+            match fs::read_link(&full) {
+                Ok(ref target) if target == Path::new(&name) => {
+                    return Ok(false);
+                }
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::ReadFile(full, e)),
+            }
+            let base = Dir::open(&cfg.directory)
+                .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+            let dir = open_path(&base, &path)?;
+            let tmp_name = format!(".tmp.{}", link);
+            match dir.remove_file(&tmp_name) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(Error::Delete(recover_path(&dir, &tmp_name), e))
+                }
+            }
+            dir.symlink(&tmp_name, &name)
+                .map_err(|e| Error::CreateSymlink(
+                    recover_path(&dir, &tmp_name), e))?;
+            dir.local_rename(&tmp_name, &link)
+                .map_err(|e| Error::CreateSymlink(full, e))?;
+            Ok(true)
+        })
+    }
+    /// Checks index against the policy of the directory
+    ///
+    /// Returns reason if the image is not allowed.
+    pub fn check_index(&self, config: &Arc<Directory>, index: &Index)
+        -> CpuFuture<Option<&'static str>, Void>
+    {
+        let cfg = config.clone();
+        let index = index.clone();
+        self.pool.spawn_fn(move || {
+            Ok(policy::check_index(&cfg, &index.entries)
+                .map(|(path, reason)| {
+                    error!("Image {} contains {:?} which is not allowed: {}",
+                        index.id, path, reason);
+                    reason
+                }))
+        })
+    }
+    pub fn check_free_space(&self, config: &Arc<Directory>, bytes: u64)
+        -> CpuFuture<bool, Void>
+    {
+        let cfg = config.clone();
+        self.pool.spawn_fn(move || Ok(has_space_for(&cfg, bytes)))
+    }
     pub fn check_and_hardlink(&self, hardlinks: Vec<Hardlink>,
         image: &Arc<Image>)
         -> Box<Future<Item=HashSet<PathBuf>, Error=Void>>
@@ -303,18 +722,123 @@ impl Disk {
             .filter_map(|x| x.ok())
             .collect().map(|x| x.into_iter().collect()))
     }
+    /// Copies blocks from existing images, returns hashes of copied ones
+    pub fn copy_local_blocks(&self, blocks: Vec<LocalBlock>,
+        image: &Arc<Image>)
+        -> Box<Future<Item=HashSet<BlockHash>, Error=Void>>
+    {
+        let pool = self.pool.clone();
+        let config = self.config.clone();
+        let image = image.clone();
+        Box::new(iter_ok(blocks)
+            .map(move |block| {
+                let config = config.clone();
+                let image = image.clone();
+                pool.spawn_fn(move || {
+                    Ok(copy_block(&config, &block, &image)
+                        .map_err(|e| error!("Error copying block: {}", e))
+                        .map(move |()| block.hash))
+                })
+            })
+            .buffer_unordered(8)
+            .filter_map(|x| x.ok())
+            .collect().map(|x| x.into_iter().collect()))
+    }
+}
+
+/// Copies block from an existing image to all its places in the new image
+///
+/// Block is checked against its hash first, as files in the image could be
+/// modified on disk.
+fn copy_block(config: &Arc<Config>, block: &LocalBlock, img: &Arc<Image>)
+    -> Result<(), Error>
+{
+    let cfg = match config.dir(block.source.key()) {
+        Some(cfg) => cfg,
+        None => return Err(Error::NoDir(block.source.clone())),
+    };
+    let src_path = block.source_path.strip_prefix("/")
+        .expect("path is absolute");
+    let epath = &|| cfg.directory.join(block.source.suffix()).join(src_path);
+    let dir = Dir::open(&cfg.directory)
+        .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
+    let dir = open_path(&dir, block.source.suffix())?;
+    let dir = open_path(&dir, src_path.parent().expect("path is never root"))?;
+    let src = dir.open_file(src_path.file_name().expect("file has filename"))
+        .map_err(|e| Error::ReadFile(epath(), e))?;
+    let mut data = vec![0u8; block.size as usize];
+    let mut pos = 0;
+    while pos < data.len() {
+        let n = src.read_at(&mut data[pos..], block.source_offset + pos as u64)
+            .map_err(|e| Error::ReadFile(epath(), e))?;
+        if n == 0 {
+            return Err(Error::Checksum(epath()));
+        }
+        pos += n;
+    }
+    if BlockHash::hash_bytes(&data) != block.hash {
+        return Err(Error::Checksum(epath()));
+    }
+    for &(ref path, offset) in &block.targets {
+        let path = path.strip_prefix("/").expect("path is absolute");
+        let parent = path.parent().expect("path is never root");
+        let fname = path.file_name().expect("path has a filename");
+        let dest_dir = ensure_path(&img.temporary, parent)?;
+        let dpath = &|| recover_path(&*dest_dir, fname);
+        let dest = dest_dir.update_file(fname, 0o644)
+            .map_err(|e| Error::WriteFile(dpath(), e))?;
+        copy_range(&src, block.source_offset, &dest, offset, &data)
+            .map_err(|e| Error::WriteFile(dpath(), e))?;
+        COPIED_BLOCKS.incr(1);
+        COPIED_BYTES.incr(block.size);
+    }
+    Ok(())
+}
+
+/// Checks whether manifest of the image has attributes for the file
+fn has_xattrs(dir: &Dir, path: &Path) -> Result<bool, Error> {
+    let file = match dir.open_file(MANIFEST) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(Error::ReadFile(recover_path(dir, MANIFEST), e)),
+    };
+    let manifest: Manifest = match serde_json::from_reader(
+        BufReader::new(file))
+    {
+        Ok(manifest) => manifest,
+        // it's not applied on commit, but the file may still have attributes
+        // from a previous version of the manifest
+        Err(_) => return Ok(true),
+    };
+    let path = Path::new("/").join(path);
+    Ok(path.to_str().and_then(|p| manifest.get(p))
+        .map(|attrs| !attrs.is_empty())
+        .unwrap_or(false))
 }
 
 fn try_hardlink(config: &Arc<Config>, hlink: &Hardlink, img: &Arc<Image>)
     -> Result<(), Error>
 {
-    let cfg = match config.dirs.get(hlink.source.key()) {
+    let cfg = match config.dir(hlink.source.key()) {
         Some(cfg) => cfg,
         None => return Err(Error::NoDir(hlink.source.clone())),
     };
+    let target_cfg = match config.dir(img.virtual_path.key()) {
+        Some(cfg) => cfg,
+        None => return Err(Error::NoDir(img.virtual_path.clone())),
+    };
+    if cfg.chown_to != target_cfg.chown_to {
+        return Err(Error::OwnerMismatch(hlink.source.clone()));
+    }
     let dir = Dir::open(&cfg.directory)
         .map_err(|e| Error::OpenBase(cfg.directory.clone(), e))?;
     let dir = open_path(&dir, hlink.source.suffix())?;
+    // hardlinked files share extended attributes, attributes of the new
+    // image are applied to its own copy of the file on commit
+    if !cfg.allow_xattrs.is_empty() && has_xattrs(&*dir, &hlink.path)? {
+        return Err(Error::HasXattrs(
+            cfg.directory.join(hlink.source.suffix()).join(&hlink.path)));
+    }
     let parent = hlink.path.parent().expect("path is never root");
     let dir = open_path(&dir, parent)?;
     let epath = &|| cfg.directory.join(hlink.source.suffix()).join(&hlink.path);
@@ -328,9 +852,9 @@ fn try_hardlink(config: &Arc<Config>, hlink: &Hardlink, img: &Arc<Image>)
     }
     let meta = file.metadata()
         .map_err(|e| Error::ReadFile(epath(), e))?;
-    let target_perm = if hlink.exe { 0o755 } else { 0o644 };
+    let target_perm = file_mode(&target_cfg, hlink.exe);
     // Throwing out S_IFREG flag
-    if meta.permissions().mode() & 0o777 != target_perm {
+    if meta.permissions().mode() & 0o7777 != target_perm {
         // note: entry permissions are checked when comparing, so this check
         // is as useful as checksum check: i.e. if file was modified on
         // the disk
@@ -338,6 +862,14 @@ fn try_hardlink(config: &Arc<Config>, hlink: &Hardlink, img: &Arc<Image>)
     }
     debug!("Hardlinking {:?}/{:?} -> {:?}", hlink.source, hlink.path, epath());
     let dest = ensure_path(&img.temporary, parent)?;
+    // partially written file may be left by a download before restart
+    match dest.remove_file(file_name) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(Error::Delete(recover_path(&*dest, file_name), e));
+        }
+    }
     hardlink(&dir, file_name, &dest, file_name)
         .map_err(|e| Error::Hardlink(epath(), e))?;
     HARDLINKED_FILES.incr(1);
@@ -349,6 +881,10 @@ fn write_block(dir: &Dir, filename: &Path, offset: u64, block: BlockData)
     -> io::Result<()>
 {
     let mut file = dir.update_file(filename, 0o644)?;
+    // leave a hole, the file is extended to its full size on commit
+    if block.iter().all(|&b| b == 0) {
+        return Ok(());
+    }
     file.seek(SeekFrom::Start(offset))?;
     file.write_all(&block[..])?;
     Ok(())
@@ -357,3 +893,62 @@ fn write_block(dir: &Dir, filename: &Path, offset: u64, block: BlockData)
 pub fn start(_: Init, _: &Meta) -> Result<(), Error> {
    Ok(())
 }
+
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use dir_signature::v1::{Entry, Hashes};
+    use openat::Dir;
+    use tempfile::TempDir;
+
+    use disk::commit::extend_file;
+    use index::ImageId;
+    use index_cache::IndexData;
+    use super::write_block;
+
+    const BLOCK: usize = 65536;
+
+    #[test]
+    fn zero_blocks() {
+        // zero block in the middle, partial zero block at the end
+        let mut data = vec![1u8; BLOCK];
+        data.extend(vec![0u8; BLOCK]);
+        data.extend(vec![2u8; BLOCK]);
+        data.extend(vec![0u8; 100]);
+        let index = IndexData::from_files(&ImageId::from(vec![1; 32]),
+            BLOCK as u64, &[("file", &data[..])]);
+        let tmp = TempDir::new().unwrap();
+        let dir = Dir::open(tmp.path()).unwrap();
+        for (i, chunk) in data.chunks(BLOCK).enumerate() {
+            write_block(&dir, Path::new("file"), (i*BLOCK) as u64,
+                        Arc::new(chunk.to_vec())).unwrap();
+        }
+        let path = tmp.path().join("file");
+        // trailing zeros are not written
+        assert_eq!(fs::metadata(&path).unwrap().len(), 3*BLOCK as u64);
+
+        extend_file(&dir, OsStr::new("file"), data.len() as u64).unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.len(), data.len() as u64);
+        // zero block in the middle is a hole
+        assert!(meta.blocks()*512 < meta.len(),
+            "{} blocks allocated", meta.blocks());
+        let contents = fs::read(&path).unwrap();
+        assert!(contents == data);
+        let (size, hashes) = Hashes::hash_file(index.hash_type,
+            BLOCK as u64, &contents[..]).unwrap();
+        match index.entries[1] {
+            Entry::File { size: isize, hashes: ref ihashes, .. } => {
+                assert_eq!(size, isize);
+                assert_eq!(&hashes, ihashes);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
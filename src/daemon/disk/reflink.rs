@@ -0,0 +1,88 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use libc::{self, loff_t};
+
+
+/// Shares extents between files using `FICLONERANGE` (btrfs and xfs)
+fn clone_range(src: &File, src_offset: u64, dest: &File, dest_offset: u64,
+    len: u64)
+    -> io::Result<()>
+{
+    let arg = libc::file_clone_range {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset: src_offset,
+        src_length: len,
+        dest_offset: dest_offset,
+    };
+    let rc = unsafe {
+        libc::ioctl(dest.as_raw_fd(), libc::FICLONERANGE, &arg)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn copy_file_range(src: &File, src_offset: u64, dest: &File,
+    dest_offset: u64, len: u64)
+    -> io::Result<()>
+{
+    let mut src_off = src_offset as loff_t;
+    let mut dest_off = dest_offset as loff_t;
+    let mut left = len as usize;
+    while left > 0 {
+        let rc = unsafe {
+            libc::copy_file_range(src.as_raw_fd(), &mut src_off,
+                dest.as_raw_fd(), &mut dest_off, left, 0)
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        } else if rc == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        left -= rc as usize;
+    }
+    Ok(())
+}
+
+/// Errors which mean that data should be copied in userspace
+fn unsupported(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::ENOSYS) | Some(libc::EXDEV) |
+        Some(libc::EINVAL) | Some(libc::EOPNOTSUPP) => true,
+        _ => false,
+    }
+}
+
+/// Copies `data` which is the range of `src` to `dest` at `dest_offset`
+///
+/// Extents are shared if filesystem supports that, otherwise data is copied
+/// in kernel. Data is written directly only if neither is supported (or
+/// files are on different filesystems).
+pub fn copy_range(src: &File, src_offset: u64, dest: &File, dest_offset: u64,
+    data: &[u8])
+    -> io::Result<()>
+{
+    let len = data.len() as u64;
+    if clone_range(src, src_offset, dest, dest_offset, len).is_ok() {
+        return Ok(());
+    }
+    match copy_file_range(src, src_offset, dest, dest_offset, len) {
+        Ok(()) => return Ok(()),
+        Err(ref e) if unsupported(e) => {}
+        Err(e) => return Err(e),
+    }
+    let mut written = 0;
+    while written < data.len() {
+        let n = dest.write_at(&data[written..],
+                              dest_offset + written as u64)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        written += n;
+    }
+    Ok(())
+}
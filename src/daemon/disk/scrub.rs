@@ -0,0 +1,267 @@
+//! Verification of committed images against their indexes
+use std::cmp::min;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use dir_signature::v1::Entry;
+use libc;
+use openat::{Dir, SimpleType};
+
+use blocks::BlockHash;
+use config::Directory;
+use disk::Error;
+use disk::commit::reset_timestamp;
+use disk::dir::{open_path, recover_path};
+use metrics::Counter;
+use tracking::{Index, BlockData};
+
+
+lazy_static! {
+    pub static ref BYTES: Counter = Counter::new();
+    pub static ref BAD_BLOCKS: Counter = Counter::new();
+    pub static ref MISSING_FILES: Counter = Counter::new();
+    pub static ref REPAIRED_BLOCKS: Counter = Counter::new();
+}
+
+
+/// File of the image which doesn't match the index
+#[derive(Debug, Clone)]
+pub struct DamagedFile {
+    /// Absolute path in the image
+    pub path: PathBuf,
+    /// Size of the file in the index
+    pub size: u64,
+    /// Size of the file on disk
+    pub actual_size: u64,
+    /// Offsets and hashes of mismatched blocks
+    pub blocks: Vec<(u64, BlockHash)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScrubReport {
+    /// Number of bytes checked
+    pub bytes: u64,
+    pub damaged: Vec<DamagedFile>,
+    /// Files which don't exist or aren't regular files
+    pub missing: Vec<PathBuf>,
+}
+
+/// Limits reading to `rate` bytes per second by sleeping
+struct Throttle {
+    started: Instant,
+    bytes: u64,
+    rate: u64,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Throttle {
+        Throttle {
+            started: Instant::now(),
+            bytes: 0,
+            rate: rate,
+        }
+    }
+    fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let target = Duration::from_millis(self.bytes * 1000 / self.rate);
+        let elapsed = self.started.elapsed();
+        if target > elapsed {
+            sleep(target - elapsed);
+        }
+    }
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Opens regular file of the image, `None` if there is no such file
+fn open_file(dir: &Dir, path: &Path) -> Result<Option<File>, Error> {
+    let path = path.strip_prefix("/").expect("path is absolute");
+    let dir = match open_path(dir, path.parent().expect("path is never root"))
+    {
+        Ok(dir) => dir,
+        Err(Error::OpenDir(_, ref e))
+        if e.kind() == io::ErrorKind::NotFound ||
+           e.raw_os_error() == Some(libc::ENOTDIR)
+        => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let fname = path.file_name().expect("path has a filename");
+    match dir.metadata(fname) {
+        Ok(ref m) if m.simple_type() == SimpleType::File => {}
+        Ok(_) => return Ok(None),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::ReadFile(recover_path(&*dir, fname), e)),
+    }
+    dir.open_file(fname).map(Some)
+        .map_err(|e| Error::ReadFile(recover_path(&*dir, fname), e))
+}
+
+/// Reads the block, the part after the end of file is filled with zeros
+fn read_block(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match file.read_at(&mut buf[pos..], offset + pos as u64)? {
+            0 => break,
+            n => pos += n,
+        }
+    }
+    for b in &mut buf[pos..] {
+        *b = 0;
+    }
+    Ok(())
+}
+
+/// Rehashes files of the image reading at most `rate` bytes per second
+///
+/// Only contents and sizes of the files are checked.
+pub fn check_image(dir: &Dir, index: &Index, rate: u64)
+    -> Result<ScrubReport, Error>
+{
+    let mut throttle = Throttle::new(rate);
+    let mut report = ScrubReport::default();
+    let mut buf = vec![0u8; index.block_size as usize];
+    for entry in &index.entries {
+        let (path, size, hashes) = match *entry {
+            Entry::File { ref path, size, ref hashes, .. } => {
+                (path, size, hashes)
+            }
+            _ => continue,
+        };
+        let file = match open_file(dir, path)? {
+            Some(file) => file,
+            None => {
+                MISSING_FILES.incr(1);
+                report.missing.push(path.clone());
+                continue;
+            }
+        };
+        let epath = &|| {
+            recover_path(dir, path.strip_prefix("/").expect("absolute path"))
+        };
+        let actual_size = file.metadata()
+            .map_err(|e| Error::ReadFile(epath(), e))?
+            .len();
+        let mut blocks = Vec::new();
+        for (num, hash) in hashes.iter().enumerate() {
+            let offset = num as u64 * index.block_size;
+            let len = min(index.block_size, size - offset);
+            let data = &mut buf[..len as usize];
+            read_block(&file, data, offset)
+                .map_err(|e| Error::ReadFile(epath(), e))?;
+            throttle.consume(len);
+            BYTES.incr(len);
+            report.bytes += len;
+            let hash = BlockHash::from_bytes(hash)
+                .ok_or_else(|| Error::Checksum(epath()))?;
+            if BlockHash::hash_bytes(data) != hash {
+                BAD_BLOCKS.incr(1);
+                blocks.push((offset, hash));
+            }
+        }
+        if actual_size != size || !blocks.is_empty() {
+            report.damaged.push(DamagedFile {
+                path: path.clone(),
+                size: size,
+                actual_size: actual_size,
+                blocks: blocks,
+            });
+        }
+    }
+    Ok(report)
+}
+
+/// Writes blocks fetched from peers into the file and fixes its size
+///
+/// The file is changed in place, so it's also fixed in all the images
+/// hardlinked to it.
+pub fn repair_file(dir: &Dir, config: &Directory, path: &Path, size: u64,
+    blocks: &[(u64, BlockData)])
+    -> Result<(), Error>
+{
+    let path = path.strip_prefix("/").expect("path is absolute");
+    let dir = open_path(dir, path.parent().expect("path is never root"))?;
+    let fname = path.file_name().expect("path has a filename");
+    let epath = &|| recover_path(&*dir, fname);
+    // make sure the file isn't created if it's removed in the meantime
+    match dir.metadata(fname) {
+        Ok(ref m) if m.simple_type() == SimpleType::File => {}
+        Ok(_) => return Err(Error::InvalidPath(epath())),
+        Err(e) => return Err(Error::ReadFile(epath(), e)),
+    }
+    let mut file = dir.update_file(fname, 0o644)
+        .map_err(|e| Error::WriteFile(epath(), e))?;
+    file.set_len(size)
+        .map_err(|e| Error::WriteFile(epath(), e))?;
+    for &(offset, ref data) in blocks {
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|e| Error::WriteFile(epath(), e))?;
+        file.write_all(&data[..])
+            .map_err(|e| Error::WriteFile(epath(), e))?;
+        REPAIRED_BLOCKS.incr(1);
+    }
+    if config.reset_mtime {
+        reset_timestamp(&mut file)
+            .map_err(|e| Error::SetTimestamp(epath(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use openat::Dir;
+    use tempfile::TempDir;
+
+    use index::ImageId;
+    use index_cache::IndexData;
+    use tracking::Index;
+    use super::check_image;
+
+    #[test]
+    fn damaged() {
+        let tmp = TempDir::new().unwrap();
+        let dir = Dir::open(tmp.path()).unwrap();
+        let corrupt = vec![1u8; 10000];
+        let truncated = vec![2u8; 6000];
+        let index = Index::from_data(IndexData::from_files(
+            &ImageId::from(vec![1; 32]), 4096, &[
+                ("corrupt", &corrupt),
+                ("intact", b"hello"),
+                ("missing", b"world"),
+                ("truncated", &truncated),
+            ]));
+        let mut data = corrupt.clone();
+        data[5000] = 0;
+        dir.write_file("corrupt", 0o644).unwrap()
+            .write_all(&data).unwrap();
+        dir.write_file("intact", 0o644).unwrap()
+            .write_all(b"hello").unwrap();
+        dir.write_file("truncated", 0o644).unwrap()
+            .write_all(&truncated[..3000]).unwrap();
+
+        let report = check_image(&dir, &index, 1 << 30).unwrap();
+        assert_eq!(report.bytes, 10000 + 5 + 6000);
+        assert_eq!(report.missing, vec![PathBuf::from("/missing")]);
+        assert_eq!(report.damaged.len(), 2);
+        let file = &report.damaged[0];
+        assert_eq!(file.path, PathBuf::from("/corrupt"));
+        assert_eq!((file.size, file.actual_size), (10000, 10000));
+        assert_eq!(file.blocks.iter().map(|&(off, _)| off)
+                   .collect::<Vec<_>>(), vec![4096]);
+        let file = &report.damaged[1];
+        assert_eq!(file.path, PathBuf::from("/truncated"));
+        assert_eq!((file.size, file.actual_size), (6000, 3000));
+        assert_eq!(file.blocks.iter().map(|&(off, _)| off)
+                   .collect::<Vec<_>>(), vec![0, 4096]);
+    }
+}
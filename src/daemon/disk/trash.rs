@@ -0,0 +1,202 @@
+use std::io::{self, BufReader, BufWriter};
+use std::time::{Duration, SystemTime};
+
+use openat::{Dir, rename};
+use serde::Serialize;
+use serde_cbor::de::from_reader as read_cbor;
+use serde_cbor::ser::Serializer as Cbor;
+
+use {VPath};
+use database::signatures::State;
+use disk::Error;
+use disk::dir::{ensure_subdir, recover_path, remove_dir_recursive};
+use index::ImageId;
+use time_util::to_ms;
+
+
+/// Name of the trash directory in the root of a configured `directory`
+pub const TRASH_DIR: &'static str = ".ciruela-trash";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashInfo {
+    pub path: VPath,
+    pub state: State,
+    #[serde(with="::serialize::timestamp")]
+    pub deleted: SystemTime,
+}
+
+
+fn open_trash(base: &Dir) -> Result<Option<Dir>, Error> {
+    match base.sub_dir(TRASH_DIR) {
+        Ok(dir) => Ok(Some(dir)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::OpenDir(recover_path(base, TRASH_DIR), e)),
+    }
+}
+
+/// Returns a list of `(entry_name, info)` pairs of the trash
+pub fn list(base: &Dir) -> Result<Vec<(String, TrashInfo)>, Error> {
+    let trash = match open_trash(base)? {
+        Some(trash) => trash,
+        None => return Ok(Vec::new()),
+    };
+    let mut result = Vec::new();
+    let err = |e| Error::ReadFile(recover_path(&trash, "."), e);
+    for entry in trash.list_dir(".").map_err(&err)? {
+        let entry = entry.map_err(&err)?;
+        let name = match entry.file_name().to_str() {
+            Some(name) if name.ends_with(".info") => {
+                name[..name.len() - ".info".len()].to_string()
+            }
+            _ => continue,
+        };
+        let info = trash.open_file(entry.file_name())
+            .map_err(|e| Error::ReadFile(recover_path(&trash, &name), e))
+            .and_then(|f| read_cbor(&mut BufReader::new(f))
+                .map_err(|e| Error::BadTrashInfo(
+                    recover_path(&trash, &name), e)));
+        match info {
+            Ok(info) => result.push((name, info)),
+            Err(e) => warn!("Skipping trash entry: {}", e),
+        }
+    }
+    Ok(result)
+}
+
+/// Moves image `name` from the `parent` directory to the trash
+pub fn move_to_trash(base: &Dir, parent: &Dir, name: &str, info: &TrashInfo)
+    -> Result<(), Error>
+{
+    let trash = ensure_subdir(base, TRASH_DIR)?;
+    let entry = format!("{}.{}", name, to_ms(info.deleted));
+    let info_file = format!("{}.info", entry);
+    let tmp_file = format!(".tmp.{}", info_file);
+    let file = trash.write_file(&tmp_file, 0o644)
+        .map_err(|e| Error::WriteFile(recover_path(&trash, &tmp_file), e))?;
+    info.serialize(&mut Cbor::new(BufWriter::new(file)))
+        .map_err(|e| {
+            Error::BadTrashInfo(recover_path(&trash, &tmp_file), e)
+        })?;
+    rename(parent, name, &trash, &entry)
+        .map_err(|e| Error::RenameDir(recover_path(parent, name), e))?;
+    trash.local_rename(&tmp_file, &info_file)
+        .map_err(|e| Error::RenameDir(recover_path(&trash, &tmp_file), e))?;
+    Ok(())
+}
+
+/// Removes trash entries older than `retention`, returns number removed
+pub fn purge(base: &Dir, retention: Duration) -> Result<usize, Error> {
+    let trash = match open_trash(base)? {
+        Some(trash) => trash,
+        None => return Ok(0),
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for (entry, info) in list(base)? {
+        if info.deleted + retention > now {
+            continue;
+        }
+        info!("Purging {:?} ({}) from trash", info.path, info.state.image);
+        remove_dir_recursive(&trash, &entry)?;
+        trash.remove_file(&format!("{}.info", entry))
+            .map_err(|e| Error::Delete(recover_path(&trash, &entry), e))?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Returns the most recently removed image for `path`
+pub fn newest(base: &Dir, path: &VPath)
+    -> Result<Option<(String, TrashInfo)>, Error>
+{
+    Ok(list(base)?.into_iter()
+        .filter(|&(_, ref info)| &info.path == path)
+        .max_by_key(|&(_, ref info)| info.deleted))
+}
+
+/// Moves the most recently removed `image` for `path` back to `parent`
+///
+/// Returns `None` if there is no such image in the trash.
+pub fn restore(base: &Dir, parent: &Dir, path: &VPath, image: &ImageId)
+    -> Result<Option<State>, Error>
+{
+    let trash = match open_trash(base)? {
+        Some(trash) => trash,
+        None => return Ok(None),
+    };
+    let newest = list(base)?.into_iter()
+        .filter(|&(_, ref info)| {
+            &info.path == path && &info.state.image == image
+        })
+        .max_by_key(|&(_, ref info)| info.deleted);
+    let (entry, info) = match newest {
+        Some(pair) => pair,
+        None => return Ok(None),
+    };
+    match parent.metadata(path.final_name()) {
+        Ok(_) => return Err(Error::AlreadyExists),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(Error::OpenDir(
+                recover_path(parent, path.final_name()), e));
+        }
+    }
+    rename(&trash, &entry, parent, path.final_name())
+        .map_err(|e| Error::RenameDir(recover_path(&trash, &entry), e))?;
+    trash.remove_file(&format!("{}.info", entry))
+        .map_err(|e| Error::Delete(recover_path(&trash, &entry), e))?;
+    Ok(Some(info.state))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use openat::Dir;
+    use tempfile::TempDir;
+
+    use {VPath};
+    use index::ImageId;
+    use database::signatures::State;
+    use super::{TrashInfo, move_to_trash, list, newest, restore, purge};
+
+    #[test]
+    fn trash_and_restore() {
+        let tmp = TempDir::new().unwrap();
+        let base = Dir::open(tmp.path()).unwrap();
+        base.create_dir("v1", 0o755).unwrap();
+        let path = VPath::from("/dir/v1");
+        let info = TrashInfo {
+            path: path.clone(),
+            state: State {
+                image: ImageId::from(vec![7u8; 32]),
+                signatures: Vec::new(),
+                expires: None,
+            },
+            deleted: SystemTime::now(),
+        };
+        move_to_trash(&base, &base, "v1", &info).unwrap();
+        assert!(base.metadata("v1").is_err());
+        assert_eq!(list(&base).unwrap().len(), 1);
+        // not expired yet
+        assert_eq!(purge(&base, Duration::new(3600, 0)).unwrap(), 0);
+
+        assert_eq!(newest(&base, &path).unwrap().unwrap().1.state.image,
+                   info.state.image);
+        // only the image which is expected is restored
+        let other = ImageId::from(vec![8u8; 32]);
+        assert!(restore(&base, &base, &path, &other).unwrap().is_none());
+        let state = restore(&base, &base, &path, &info.state.image)
+            .unwrap().unwrap();
+        assert_eq!(state.image, info.state.image);
+        assert!(base.metadata("v1").is_ok());
+        assert_eq!(list(&base).unwrap().len(), 0);
+        assert!(newest(&base, &path).unwrap().is_none());
+        assert!(restore(&base, &base, &path, &info.state.image)
+            .unwrap().is_none());
+
+        move_to_trash(&base, &base, "v1", &info).unwrap();
+        assert_eq!(purge(&base, Duration::new(0, 0)).unwrap(), 1);
+        assert_eq!(list(&base).unwrap().len(), 0);
+    }
+}
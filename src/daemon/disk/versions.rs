@@ -0,0 +1,224 @@
+use std::io::{self, BufReader, BufWriter};
+use std::time::SystemTime;
+
+use libc;
+use openat::{Dir, rename};
+use serde::Serialize;
+use serde_cbor::de::from_reader as read_cbor;
+use serde_cbor::ser::Serializer as Cbor;
+
+use {VPath};
+use index::ImageId;
+use database::signatures::State;
+use disk::Error;
+use disk::dir::{ensure_subdir, recover_path, remove_dir_recursive};
+
+
+/// Information about previous version stored along with the image
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VersionInfo {
+    pub path: VPath,
+    pub state: State,
+    #[serde(with="::serialize::timestamp")]
+    pub replaced: SystemTime,
+}
+
+/// Name of the hidden sibling where previous versions of `name` are kept
+fn versions_dir(name: &str) -> String {
+    format!(".versions.{}", name)
+}
+
+fn open_versions(parent: &Dir, name: &str) -> Result<Option<Dir>, Error> {
+    let dir_name = versions_dir(name);
+    match parent.sub_dir(&dir_name) {
+        Ok(dir) => Ok(Some(dir)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::OpenDir(recover_path(parent, &dir_name), e)),
+    }
+}
+
+/// Returns previous versions of image `name`, most recent first
+pub fn list(parent: &Dir, name: &str)
+    -> Result<Vec<(String, VersionInfo)>, Error>
+{
+    let versions = match open_versions(parent, name)? {
+        Some(versions) => versions,
+        None => return Ok(Vec::new()),
+    };
+    let mut result: Vec<(String, VersionInfo)> = Vec::new();
+    let err = |e| Error::ReadFile(recover_path(&versions, "."), e);
+    for entry in versions.list_dir(".").map_err(&err)? {
+        let entry = entry.map_err(&err)?;
+        let name = match entry.file_name().to_str() {
+            Some(name) if name.ends_with(".state") => {
+                name[..name.len() - ".state".len()].to_string()
+            }
+            _ => continue,
+        };
+        let info = versions.open_file(entry.file_name())
+            .map_err(|e| Error::ReadFile(recover_path(&versions, &name), e))
+            .and_then(|f| read_cbor(&mut BufReader::new(f))
+                .map_err(|e| Error::BadVersionInfo(
+                    recover_path(&versions, &name), e)));
+        match info {
+            Ok(info) => result.push((name, info)),
+            Err(e) => warn!("Skipping previous version: {}", e),
+        }
+    }
+    result.sort_by(|&(_, ref a), &(_, ref b)| b.replaced.cmp(&a.replaced));
+    Ok(result)
+}
+
+/// Moves replaced image `from` into versions of `name`
+///
+/// Only `limit` most recent versions are kept, older ones are removed.
+pub fn keep(parent: &Dir, name: &str, from: &str, info: &VersionInfo,
+    limit: usize)
+    -> Result<(), Error>
+{
+    let versions = ensure_subdir(parent, versions_dir(name))?;
+    let entry = info.state.image.to_string();
+    let state_file = format!("{}.state", entry);
+    let tmp_file = format!(".tmp.{}", state_file);
+    // the same image may be uploaded again and replaced again
+    remove_dir_recursive(&versions, &entry)?;
+    let file = versions.write_file(&tmp_file, 0o644)
+        .map_err(|e| Error::WriteFile(recover_path(&versions, &tmp_file), e))?;
+    info.serialize(&mut Cbor::new(BufWriter::new(file)))
+        .map_err(|e| {
+            Error::BadVersionInfo(recover_path(&versions, &tmp_file), e)
+        })?;
+    rename(parent, from, &versions, &entry)
+        .map_err(|e| Error::RenameDir(recover_path(parent, from), e))?;
+    versions.local_rename(&tmp_file, &state_file)
+        .map_err(|e| Error::RenameDir(recover_path(&versions, &tmp_file), e))?;
+    for (old, old_info) in list(parent, name)?.into_iter().skip(limit) {
+        info!("Removing old version {} of {:?}",
+            old_info.state.image, old_info.path);
+        remove(&versions, &old)?;
+    }
+    Ok(())
+}
+
+/// Lists previous versions of all images `levels` deep below `dir`
+pub fn all(dir: &Dir, levels: usize) -> Result<Vec<VersionInfo>, Error> {
+    let mut result = Vec::new();
+    let err = |e| Error::ReadFile(recover_path(dir, "."), e);
+    for entry in dir.list_dir(".").map_err(&err)? {
+        let entry = entry.map_err(&err)?;
+        let name = match entry.file_name().to_str() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        if levels <= 1 {
+            if name.starts_with(".versions.") {
+                let image = &name[".versions.".len()..];
+                result.extend(list(dir, image)?.into_iter()
+                    .map(|(_, info)| info));
+            }
+        } else if !name.starts_with('.') {
+            match dir.sub_dir(&name[..]) {
+                Ok(sub) => result.extend(all(&sub, levels - 1)?),
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOTDIR) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(Error::OpenDir(recover_path(dir, &name), e));
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn remove(versions: &Dir, entry: &str) -> Result<(), Error> {
+    versions.remove_file(&format!("{}.state", entry))
+        .map_err(|e| Error::Delete(recover_path(versions, entry), e))?;
+    remove_dir_recursive(versions, entry)
+}
+
+/// Atomically exchanges image `name` with its previous version `image`
+///
+/// Current image is kept as a previous version itself, described by
+/// `current`. Returns state of the restored image or `None` if there is
+/// no such version.
+pub fn restore(parent: &Dir, name: &str, image: &ImageId,
+    current: &VersionInfo, limit: usize)
+    -> Result<Option<State>, Error>
+{
+    let versions = match open_versions(parent, name)? {
+        Some(versions) => versions,
+        None => return Ok(None),
+    };
+    let found = list(parent, name)?.into_iter()
+        .find(|&(_, ref info)| &info.state.image == image);
+    let (entry, info) = match found {
+        Some(pair) => pair,
+        None => return Ok(None),
+    };
+    let tmp_name = format!(".tmp.rollback.{}", name);
+    remove_dir_recursive(parent, &tmp_name)?;
+    rename(&versions, &entry, parent, &tmp_name)
+        .map_err(|e| Error::RenameDir(recover_path(&versions, &entry), e))?;
+    versions.remove_file(&format!("{}.state", entry))
+        .map_err(|e| Error::Delete(recover_path(&versions, &entry), e))?;
+    parent.local_exchange(&tmp_name, name)
+        .map_err(|e| Error::Commit(recover_path(parent, name), e))?;
+    keep(parent, name, &tmp_name, current, limit)?;
+    Ok(Some(info.state))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use openat::Dir;
+    use tempfile::TempDir;
+
+    use {VPath};
+    use index::ImageId;
+    use database::signatures::State;
+    use super::{VersionInfo, keep, list, restore};
+
+    fn info(id: u8, replaced: SystemTime) -> VersionInfo {
+        VersionInfo {
+            path: VPath::from("/dir/app"),
+            state: State {
+                image: ImageId::from(vec![id; 32]),
+                signatures: Vec::new(),
+                expires: None,
+            },
+            replaced: replaced,
+        }
+    }
+
+    #[test]
+    fn keep_and_restore() {
+        let tmp = TempDir::new().unwrap();
+        let base = Dir::open(tmp.path()).unwrap();
+        let time = SystemTime::now();
+        base.create_dir("app", 0o755).unwrap();
+        for id in 1..4 {
+            base.create_dir(".tmp.app", 0o755).unwrap();
+            base.write_file(".tmp.app/marker", 0o644).unwrap();
+            keep(&base, "app", ".tmp.app", &info(id,
+                time + Duration::from_secs(id as u64)), 2).unwrap();
+        }
+        let all = list(&base, "app").unwrap();
+        assert_eq!(all.iter().map(|&(_, ref i)| i.state.image.clone())
+            .collect::<Vec<_>>(),
+            vec![ImageId::from(vec![3; 32]), ImageId::from(vec![2; 32])]);
+
+        let cur = info(4, time + Duration::from_secs(10));
+        let two = ImageId::from(vec![2; 32]);
+        let state = restore(&base, "app", &two, &cur, 2)
+            .unwrap().unwrap();
+        assert_eq!(state.image, two);
+        assert!(base.metadata("app/marker").is_ok());
+        let all = list(&base, "app").unwrap();
+        assert_eq!(all.iter().map(|&(_, ref i)| i.state.image.clone())
+            .collect::<Vec<_>>(),
+            vec![ImageId::from(vec![4; 32]), ImageId::from(vec![3; 32])]);
+        assert!(restore(&base, "app", &two, &cur, 2)
+            .unwrap().is_none());
+    }
+}
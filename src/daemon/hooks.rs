@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio, ExitStatus};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use dir_signature::v1::Entry;
+use futures::sync::oneshot::{channel, Receiver};
+use serde_json;
+
+use {VPath};
+use config::Directory;
+use index::ImageId;
+use metrics::{List, Metric, Counter, Integer};
+use named_mutex::Mutex;
+use tracking::Index;
+
+
+/// Number of finished hooks listed at `/hooks/`
+const HISTORY: usize = 100;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    pub static ref RUNNING: Integer = Integer::new();
+    pub static ref SUCCEEDED: Counter = Counter::new();
+    pub static ref FAILED: Counter = Counter::new();
+    pub static ref REJECTED: Counter = Counter::new();
+}
+
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all="snake_case")]
+pub enum Hook {
+    OnCommit,
+    OnReplace,
+    OnDelete,
+}
+
+/// Result of the hook, as shown at `/hooks/` HTTP endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct HookRun {
+    pub hook: Hook,
+    pub path: VPath,
+    pub image_id: String,
+    pub command: Vec<String>,
+    #[serde(with="::serialize::timestamp")]
+    pub started: SystemTime,
+    #[serde(with="::serialize::timestamp")]
+    pub finished: SystemTime,
+    pub attempts: u32,
+    pub exit_status: Option<i32>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Hooks(Arc<Mutex<VecDeque<HookRun>>>);
+
+/// Index of the image as passed to `validate-command`
+#[derive(Serialize)]
+struct ValidateInput<'a> {
+    path: &'a VPath,
+    image_id: String,
+    block_size: u64,
+    bytes_total: u64,
+    entries: Vec<ValidateEntry<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag="kind", rename_all="snake_case")]
+enum ValidateEntry<'a> {
+    Dir { path: &'a Path },
+    File { path: &'a Path, exe: bool, size: u64 },
+    Symlink { path: &'a Path, target: &'a Path },
+}
+
+
+impl Hook {
+    /// Command template of the hook
+    ///
+    /// `on-replace` falls back to `on-commit` if not configured.
+    fn command(self, config: &Directory) -> Option<&str> {
+        match self {
+            Hook::OnCommit => config.on_commit.as_ref(),
+            Hook::OnReplace => config.on_replace.as_ref()
+                .or(config.on_commit.as_ref()),
+            Hook::OnDelete => config.on_delete.as_ref(),
+        }.map(|x| &x[..])
+    }
+}
+
+/// Splits command template by whitespace and substitutes placeholders
+fn expand(template: &str, config: &Directory, path: &VPath,
+    image_id: &ImageId)
+    -> Vec<String>
+{
+    let fs_path = config.directory.join(path.suffix());
+    template.split_whitespace()
+        .map(|word| word
+            .replace("{path}", &fs_path.to_string_lossy())
+            .replace("{vpath}", &path.to_string())
+            .replace("{image_id}", &image_id.to_string()))
+        .collect()
+}
+
+fn run_once(cmd: &[String], input: Option<Vec<u8>>, timeout: Duration)
+    -> Result<ExitStatus, String>
+{
+    let mut child = Command::new(&cmd[0])
+        .args(&cmd[1..])
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .spawn()
+        .map_err(|e| format!("can't run {:?}: {}", cmd[0], e))?;
+    if let (Some(data), Some(mut stdin)) = (input, child.stdin.take()) {
+        // in a thread, so timeout works even if process doesn't read stdin
+        thread::spawn(move || {
+            // process may exit without reading everything, that's fine
+            stdin.write_all(&data).ok();
+        });
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(status),
+            Ok(None) => {}
+            Err(e) => return Err(format!("error waiting for process: {}", e)),
+        }
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(format!("timed out after {:?}", timeout));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+impl Hooks {
+    pub fn new() -> Hooks {
+        Hooks(Arc::new(Mutex::new(VecDeque::new(), "hooks")))
+    }
+    /// Runs the hook in a separate thread if it's configured for the dir
+    ///
+    /// Failed hooks are retried `hook-retries` times, each attempt is killed
+    /// after `hook-timeout`.
+    pub fn run(&self, hook: Hook, config: &Arc<Directory>, path: &VPath,
+        image_id: &ImageId)
+    {
+        let cmd = match hook.command(config) {
+            Some(template) => expand(template, config, path, image_id),
+            None => return,
+        };
+        let mut run = HookRun {
+            hook: hook,
+            path: path.clone(),
+            image_id: image_id.to_string(),
+            command: cmd,
+            started: SystemTime::now(),
+            finished: SystemTime::now(),
+            attempts: 0,
+            exit_status: None,
+            error: None,
+        };
+        let timeout = config.hook_timeout;
+        let retries = config.hook_retries;
+        let hooks = self.clone();
+        RUNNING.incr(1);
+        let result = thread::Builder::new()
+            .name(String::from("hook"))
+            .spawn(move || {
+                while run.attempts <= retries {
+                    if run.attempts > 0 {
+                        thread::sleep(RETRY_INTERVAL);
+                    }
+                    run.attempts += 1;
+                    match run_once(&run.command, None, timeout) {
+                        Ok(status) => {
+                            run.exit_status = status.code();
+                            run.error = if status.success() {
+                                None
+                            } else {
+                                Some(format!("{}", status))
+                            };
+                        }
+                        Err(e) => {
+                            run.exit_status = None;
+                            run.error = Some(e);
+                        }
+                    }
+                    if run.error.is_none() {
+                        break;
+                    }
+                    warn!("Hook {:?} for {:?} failed (attempt {}): {}",
+                        run.hook, run.path, run.attempts,
+                        run.error.as_ref().unwrap());
+                }
+                run.finished = SystemTime::now();
+                if run.error.is_none() {
+                    info!("Hook {:?} for {:?} succeeded",
+                        run.hook, run.path);
+                    SUCCEEDED.incr(1);
+                } else {
+                    error!("Hook {:?} for {:?} failed after {} attempts",
+                        run.hook, run.path, run.attempts);
+                    FAILED.incr(1);
+                }
+                RUNNING.decr(1);
+                hooks.finished(run);
+            });
+        if let Err(e) = result {
+            error!("Can't start hook thread: {}", e);
+            RUNNING.decr(1);
+        }
+    }
+    fn finished(&self, run: HookRun) {
+        let mut history = self.0.lock();
+        if history.len() >= HISTORY {
+            history.pop_front();
+        }
+        history.push_back(run);
+    }
+    /// Recently finished hooks, most recent first
+    pub fn get_history(&self) -> Vec<HookRun> {
+        self.0.lock().iter().rev().cloned().collect()
+    }
+}
+
+fn index_json(path: &VPath, index: &Index) -> Vec<u8> {
+    serde_json::to_vec(&ValidateInput {
+        path: path,
+        image_id: index.id.to_string(),
+        block_size: index.block_size,
+        bytes_total: index.bytes_total,
+        entries: index.entries.iter().map(|e| match *e {
+            Entry::Dir(ref path) => ValidateEntry::Dir { path: path },
+            Entry::File { ref path, exe, size, .. } => {
+                ValidateEntry::File { path: path, exe: exe, size: size }
+            }
+            Entry::Link(ref path, ref target) => {
+                ValidateEntry::Symlink { path: path, target: target }
+            }
+        }).collect(),
+    }).expect("index is serializable")
+}
+
+/// Runs `validate-command` of the directory with the index on stdin
+///
+/// Resolves to the reason if the image must be rejected, or `None` if
+/// command exits successfully (or is not configured).
+pub fn validate(config: &Arc<Directory>, path: &VPath, index: &Index)
+    -> Receiver<Option<&'static str>>
+{
+    let (tx, rx) = channel();
+    let cmd = match config.validate_command {
+        Some(ref template) => expand(template, config, path, &index.id),
+        None => {
+            tx.send(None).ok();
+            return rx;
+        }
+    };
+    let timeout = config.hook_timeout;
+    let path = path.clone();
+    let index = index.clone();
+    let result = thread::Builder::new()
+        .name(String::from("validate"))
+        .spawn(move || {
+            let input = index_json(&path, &index);
+            let reason = match run_once(&cmd, Some(input), timeout) {
+                Ok(ref status) if status.success() => None,
+                Ok(status) => {
+                    error!("Image {} for {:?} rejected by {:?}: {}",
+                        index.id, path, cmd[0], status);
+                    Some("validation_failed")
+                }
+                Err(e) => {
+                    error!("Can't validate image {} for {:?}: {}",
+                        index.id, path, e);
+                    Some("validation_error")
+                }
+            };
+            if reason.is_some() {
+                REJECTED.incr(1);
+            }
+            tx.send(reason).ok();
+        });
+    if let Err(e) = result {
+        // sender is dropped, so receiver is canceled
+        error!("Can't start validation thread: {}", e);
+    }
+    rx
+}
+
+pub fn metrics() -> List {
+    let hooks = "hooks";
+    vec![
+        (Metric(hooks, "running"), &*RUNNING),
+        (Metric(hooks, "succeeded"), &*SUCCEEDED),
+        (Metric(hooks, "failed"), &*FAILED),
+        (Metric(hooks, "images_rejected"), &*REJECTED),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use {VPath};
+    use config::Directory;
+    use index::ImageId;
+    use super::{Hook, expand};
+
+    fn cfg(on_commit: Option<&str>, on_replace: Option<&str>) -> Directory {
+        Directory {
+            on_commit: on_commit.map(String::from),
+            on_replace: on_replace.map(String::from),
+            ..Directory::test_default("/srv/my images")
+        }
+    }
+
+    #[test]
+    fn fallback() {
+        let both = cfg(Some("commit"), Some("replace"));
+        assert_eq!(Hook::OnCommit.command(&both), Some("commit"));
+        assert_eq!(Hook::OnReplace.command(&both), Some("replace"));
+        assert_eq!(Hook::OnDelete.command(&both), None);
+        let commit = cfg(Some("commit"), None);
+        assert_eq!(Hook::OnReplace.command(&commit), Some("commit"));
+        let none = cfg(None, None);
+        assert_eq!(Hook::OnReplace.command(&none), None);
+    }
+
+    #[test]
+    fn placeholders() {
+        let id = ImageId::from(vec![0xab; 32]);
+        let cmd = expand("  /bin/reload  --path={path} {vpath}\t{image_id} ",
+            &cfg(None, None), &VPath::from("/dir/my app"), &id);
+        assert_eq!(cmd, vec![
+            String::from("/bin/reload"),
+            String::from("--path=/srv/my images/my app"),
+            String::from("/dir/my app"),
+            id.to_string(),
+        ]);
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::{Duration, SystemTime};
 
 use futures::Async;
@@ -23,6 +24,8 @@ use tokio_core::net::TcpListener;
 use tokio_io::{AsyncRead, AsyncWrite};
 
 use ciruela::VPath;
+use cleanup::Reason;
+use database::signatures::State;
 use machine_id::MachineId;
 use mask::Mask;
 use metrics;
@@ -68,6 +71,11 @@ enum Route {
     Watching,
     Watched,
     Peers,
+    InUse,
+    CleanupPlan,
+    Trash,
+    Scrub,
+    Hooks,
     ListDir(VPath),
 }
 
@@ -181,13 +189,15 @@ impl<S> server::Codec<S> for HttpCodec
                     pub num_downloading: usize,
                     #[serde(with="::serialize::timestamp")]
                     pub last_scan: SystemTime,
+                    pub pinned: Vec<String>,
                 }
                 Either::A(ok(serve_json(e, &self.tracking.get_base_dirs()
-                    .iter().map(|(path, d)| (path, BaseDir {
+                    .into_iter().map(|(path, d)| (path, BaseDir {
                         hash: format!("{}", d.hash),
                         num_subdirs: d.num_subdirs,
                         num_downloading: d.num_downloading,
                         last_scan: d.last_scan,
+                        pinned: d.pinned.into_iter().collect(),
                     })).collect::<BTreeMap<_, _>>())))
             }
             Route::Configs => {
@@ -197,7 +207,7 @@ impl<S> server::Codec<S> for HttpCodec
                     num_levels: usize,
                     auto_clean: bool,
                 }
-                Either::A(ok(serve_json(e, &self.tracking.config().dirs
+                Either::A(ok(serve_json(e, &self.tracking.config().dirs.get()
                     .iter().map(|(path, d)| (path, Config {
                         append_only: d.append_only,
                         num_levels: d.num_levels,
@@ -220,6 +230,107 @@ impl<S> server::Codec<S> for HttpCodec
                 Either::A(ok(serve_json(e,
                     &self.tracking.peers().get_peers())))
             }
+            Route::InUse => {
+                #[derive(Serialize)]
+                pub struct InUse {
+                    pub image_id: String,
+                    pub pids: Vec<u32>,
+                    #[serde(with="::serialize::timestamp")]
+                    pub checked: SystemTime,
+                }
+                Either::A(ok(serve_json(e, &self.tracking.get_in_use()
+                    .into_iter().map(|(path, u)| (path, InUse {
+                        image_id: u.image_id.to_string(),
+                        pids: u.pids,
+                        checked: u.checked,
+                    })).collect::<BTreeMap<_, _>>())))
+            }
+            Route::CleanupPlan => {
+                #[derive(Serialize)]
+                pub struct Item {
+                    pub name: String,
+                    pub image_id: String,
+                    pub reason: Reason,
+                }
+                #[derive(Serialize)]
+                pub struct Plan {
+                    pub used: Vec<Item>,
+                    pub unused: Vec<Item>,
+                }
+                fn items(lst: Vec<(PathBuf, State, Reason)>) -> Vec<Item> {
+                    lst.into_iter().map(|(path, state, reason)| Item {
+                        name: path.file_name()
+                            .map(|x| x.to_string_lossy().into_owned())
+                            .unwrap_or_else(String::new),
+                        image_id: state.image.to_string(),
+                        reason: reason,
+                    }).collect()
+                }
+                Either::B(Box::new(self.tracking.get_cleanup_plan()
+                    .map_err(|e| Error::custom(e.to_string()))
+                    .and_then(|data| {
+                        Ok(serve_json(e, &data.into_iter()
+                            .map(|(path, plan)| (path, Plan {
+                                used: items(plan.used),
+                                unused: items(plan.unused),
+                            })).collect::<BTreeMap<_, _>>()))
+                    })))
+            }
+            Route::Trash => {
+                #[derive(Serialize)]
+                pub struct Item {
+                    pub path: VPath,
+                    pub image_id: String,
+                    #[serde(with="::serialize::timestamp")]
+                    pub deleted: SystemTime,
+                }
+                Either::B(Box::new(self.tracking.disk().list_trash()
+                    .map_err(|e| Error::custom(e.to_string()))
+                    .and_then(|data| {
+                        Ok(serve_json(e, &data.into_iter()
+                            .map(|info| Item {
+                                path: info.path,
+                                image_id: info.state.image.to_string(),
+                                deleted: info.deleted,
+                            }).collect::<Vec<_>>()))
+                    })))
+            }
+            Route::Scrub => {
+                #[derive(Serialize)]
+                pub struct File {
+                    pub path: PathBuf,
+                    pub size: u64,
+                    pub actual_size: u64,
+                    pub bad_blocks: Vec<u64>,
+                }
+                #[derive(Serialize)]
+                pub struct Damaged {
+                    pub image_id: String,
+                    pub files: Vec<File>,
+                    pub missing: Vec<PathBuf>,
+                    #[serde(with="::serialize::timestamp")]
+                    pub checked: SystemTime,
+                    pub repaired: bool,
+                }
+                Either::A(ok(serve_json(e, &self.tracking.get_damaged()
+                    .into_iter().map(|(path, d)| (path, Damaged {
+                        image_id: d.image_id.to_string(),
+                        files: d.files.into_iter().map(|f| File {
+                            path: f.path,
+                            size: f.size,
+                            actual_size: f.actual_size,
+                            bad_blocks: f.blocks.into_iter()
+                                .map(|(offset, _)| offset).collect(),
+                        }).collect(),
+                        missing: d.missing,
+                        checked: d.checked,
+                        repaired: d.repaired,
+                    })).collect::<BTreeMap<_, _>>())))
+            }
+            Route::Hooks => {
+                Either::A(ok(serve_json(e,
+                    &self.tracking.hooks().get_history())))
+            }
             Route::ListDir(path) => {
                 Either::B(Box::new(self.tracking.meta().scan_dir(&path)
                     .map_err(|e| Error::custom(e.to_string()))
@@ -366,6 +477,16 @@ impl Route {
             return Route::Watched;
         } else if path == "/peers/" {
             return Route::Peers;
+        } else if path == "/cleanup/in-use/" {
+            return Route::InUse;
+        } else if path == "/cleanup/plan/" {
+            return Route::CleanupPlan;
+        } else if path == "/cleanup/trash/" {
+            return Route::Trash;
+        } else if path == "/scrub/" {
+            return Route::Scrub;
+        } else if path == "/hooks/" {
+            return Route::Hooks;
         } else if path.starts_with("/list-dir/") {
             let subpath = &path["/list-dir".len()..];
             match VPath::try_from(subpath) {
@@ -1,10 +1,13 @@
+use std::collections::HashSet;
 use std::fmt;
 use std::io::BufRead;
+use std::path::PathBuf;
 
 
 use dir_signature::v1::{Entry};
 use dir_signature::HashType;
 use dir_signature::v1::{Parser, ParseError as IndexError};
+#[cfg(test)] use dir_signature::v1::Hashes;
 
 use index::{ImageId};
 
@@ -63,4 +66,46 @@ impl IndexData {
             entries: items,
         })
     }
+    /// Number of bytes in files except the specified ones
+    ///
+    /// Used to find out how much data should be written given the set of
+    /// files which will be hardlinked.
+    pub fn bytes_except(&self, paths: &HashSet<PathBuf>) -> u64 {
+        self.entries.iter()
+            .filter(|e| match **e {
+                Entry::File { ref path, .. } => !paths.contains(path),
+                _ => false,
+            })
+            .map(bytes)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+impl IndexData {
+    /// Index of the image having only files in the root directory
+    pub fn from_files(id: &ImageId, block_size: u64, files: &[(&str, &[u8])])
+        -> IndexData
+    {
+        let hash_type = HashType::blake2b_256();
+        let mut entries = vec![Entry::Dir(PathBuf::from("/"))];
+        for &(name, data) in files {
+            let (size, hashes) = Hashes::hash_file(hash_type, block_size, data)
+                .expect("hashing memory never fails");
+            entries.push(Entry::File {
+                path: PathBuf::from("/").join(name),
+                exe: false,
+                size: size,
+                hashes: hashes,
+            });
+        }
+        IndexData {
+            id: id.clone(),
+            hash_type: hash_type,
+            block_size: block_size,
+            bytes_total: entries.iter().map(bytes).sum(),
+            blocks_total: entries.iter().map(|x| blocks(x, block_size)).sum(),
+            entries: entries,
+        }
+    }
 }
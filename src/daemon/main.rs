@@ -1,5 +1,5 @@
 #![allow(dead_code)]  // temporarily
-#![recursion_limit="100"]
+#![recursion_limit="200"]
 extern crate abstract_ns;
 extern crate argparse;
 extern crate atomic;
@@ -43,6 +43,7 @@ extern crate tokio_io;
 extern crate valuable_futures;
 extern crate void;
 extern crate libcantal;
+#[cfg(test)] extern crate tempfile;
 
 #[macro_use] extern crate log;
 #[macro_use] extern crate lazy_static;
@@ -59,6 +60,7 @@ use std::process::exit;
 use std::sync::Arc;
 
 use abstract_ns::{HostResolve, Resolve};
+use crossbeam::sync::ArcCell;
 use argparse::{ArgumentParser, Parse, Store, Print, StoreTrue, StoreOption};
 use ns_std_threaded::ThreadedResolver;
 use ns_router::{Router, Config};
@@ -69,6 +71,7 @@ mod cleanup;
 mod config;
 mod dir_util;
 mod disk;
+mod hooks;
 mod http;
 mod index_cache;
 mod mask;
@@ -76,6 +79,7 @@ mod metadata;
 mod metrics;
 mod named_mutex;
 mod peers;
+mod reload;
 mod remote;
 mod tracking;
 
@@ -90,6 +94,7 @@ mod tracking;
 #[path="../failure_tracker.rs"] mod failure_tracker;
 #[path="../signature.rs"] mod signature;
 #[path="../block_id.rs"] mod block_id;
+#[path="../xattrs.rs"] mod xattrs;
 pub use ciruela::{VPath};
 pub use ciruela::blocks as blocks;
 pub use ciruela::index as index;
@@ -128,7 +133,6 @@ fn main() {
     let mut hostname = hostname::get_hostname();
     let mut log_machine_id = false;
     let mut cantal: bool = false;
-    let mut aggressive_index_gc: bool = false;
     {
         let mut ap = ArgumentParser::new();
         ap.refer(&mut config_dir)
@@ -175,14 +179,6 @@ fn main() {
             .add_option(&["--log-machine-id"], StoreTrue, "
                 Adds machine id to the logs, useful for local multi-node
                 testing such as `vagga trio`.");
-        ap.refer(&mut aggressive_index_gc)
-            .add_option(&["--aggressive-index-gc"], StoreTrue, "
-                Run Index GC after every cleanup
-                (usually run every 100 images deleted or once a day,
-                whichever comes first).
-                This should be used only for debugging, or for the short
-                period of time after DoS attack has happened.
-            ");
         ap.add_option(&["--version"],
             Print(env!("CARGO_PKG_VERSION").to_string()),
             "Show version");
@@ -205,13 +201,13 @@ fn main() {
         env!("CARGO_PKG_VERSION"), machine_id);
 
     let addr = (ip, port).to_socket_addrs().unwrap().next().unwrap();
-    let config = match config::read_dirs(&config_dir.join("configs")) {
+    let config = match config::read_dirs(&config_dir) {
         Ok(configs) => {
             Arc::new(config::Config {
                 machine_id: machine_id.clone(),
                 hostname: hostname.clone(),
-                dirs: configs,
-                port, db_dir, config_dir, aggressive_index_gc,
+                dirs: ArcCell::new(Arc::new(configs)),
+                port, db_dir, config_dir,
             })
         }
         Err(e) => {
@@ -277,6 +273,7 @@ fn main() {
         http::start(addr, &tracking, &meter)?;
         disk::start(disk_init, &meta)?;
         tracking::start(tracking_init)?;
+        reload::start(&config, &tracking)?;
         peers::start(peers_init, addr, &config, &disk, &router, &tracking)?;
 
         Ok(())
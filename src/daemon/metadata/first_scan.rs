@@ -24,13 +24,18 @@ fn scan_dir<F>(dir: &Dir, virtual_path: PathBuf, level: usize,
     Ok(())
 }
 
-pub fn scan<F>(meta: &Meta, mut add_dir: F)
+/// Finds base dirs of configs `names` or of all configs if `names` is `None`
+pub fn scan<F>(meta: &Meta, names: Option<Vec<String>>, mut add_dir: F)
     -> Result<(), Error>
     where F: FnMut(VPath),
 {
     // TODO(tailhook) throttle maybe?
     let root = meta.signatures()?;
-    for (virtual_name, ref cfg) in &meta.0.config.dirs {
+    for (virtual_name, ref cfg) in meta.0.config.dirs.get().iter() {
+        if names.as_ref().map(|n| !n.contains(virtual_name)).unwrap_or(false)
+        {
+            continue;
+        }
         if let Some(dir) = root.dir_if_exists(virtual_name)? {
             let vpath = Path::new("/").join(virtual_name);
             scan_dir(&dir, vpath, cfg.num_levels, &mut add_dir)?;
@@ -1,5 +1,5 @@
-use std::cmp::Reverse;
-use std::collections::{HashSet, BTreeMap};
+use std::cmp::{Reverse, min};
+use std::collections::{HashMap, HashSet, BTreeMap};
 use std::fs::File;
 use std::io::{self, BufReader};
 use std::path::PathBuf;
@@ -9,10 +9,12 @@ use dir_signature::v1::merge::MergedSignatures;
 use dir_signature::v1::{Entry, EntryKind, Hashes, Parser};
 
 use {VPath};
-use metadata::{Meta, Error};
+use blocks::BlockHash;
+use index::ImageId;
+use metadata::{Meta, Error, state_name};
 use metadata::{read_index, scan};
 use metadata::upload;
-use tracking::Index;
+use index_cache::IndexData;
 
 
 #[derive(Debug)]
@@ -24,37 +26,43 @@ pub struct Hardlink {
     pub hashes: Hashes,
 }
 
-pub fn replace_mode(index: Index, path: VPath, meta: Meta)
-    -> Result<Vec<Hardlink>, Error>
+/// Block of an existing image which is copied locally instead of fetching
+#[derive(Debug)]
+pub struct LocalBlock {
+    pub hash: BlockHash,
+    pub source: VPath,
+    pub source_path: PathBuf,
+    pub source_offset: u64,
+    pub size: u64,
+    /// Paths and offsets of the block in the new image
+    pub targets: Vec<(PathBuf, u64)>,
+}
+
+/// Image being replaced, if any
+fn replace_candidates(path: &VPath, meta: &Meta)
+    -> Result<Vec<(VPath, ImageId)>, Error>
 {
     let dir = meta.signatures()?.ensure_dir(path.parent_rel())?;
-    let old_image = if let Some(state) = dir.read_file(
-        &format!("{}.state", path.final_name()),
+    if let Some(state) = dir.read_file(
+        &format!("{}.state", state_name(path)),
         upload::read_state)?
     {
-        state.image
+        Ok(vec![(path.clone(), state.image)])
     } else {
         // No old dir
         // TODO(tailhook) maybe make normal scan like in append_mode?
         //                are there any real use-cases where it's useful?
         debug!("no old dir for {:?}", path);
-        return Ok(Vec::new());
-    };
-    let reader = match read_index::open(&old_image, &meta) {
-        Ok(index) => index,
-        Err(ref e) => {
-            warn!("Error reading index {:?} from file: {}", old_image, e);
-            return Ok(Vec::new());
-        }
-    };
-    return scan_links(index, vec![(path, reader)]);
+        Ok(Vec::new())
+    }
 }
 
-pub fn append_mode(index: Index, base_dir: VPath, meta: Meta)
-    -> Result<Vec<Hardlink>, Error>
+/// Most recent images in the `base_dir`, except ones being written
+fn append_candidates(base_dir: &VPath, meta: &Meta)
+    -> Result<Vec<(VPath, ImageId)>, Error>
 {
-    let all_states = match meta.signatures()?.open_vpath(&base_dir) {
-        Ok(open_dir) => scan::all_states(&meta, &base_dir, &open_dir)?,
+    let all_states = match meta.signatures()?.open_vpath(base_dir) {
+        Ok(open_dir) => scan::all_states(meta, base_dir, &open_dir)?,
 
         Err(Error::Open(_, ref e))
         if e.kind() == io::ErrorKind::NotFound
@@ -83,28 +91,123 @@ pub fn append_mode(index: Index, base_dir: VPath, meta: Meta)
                 continue;
             }
             visited.insert(s.image.clone());
-            selected.push((cur_dir, s));
+            selected.push((base_dir.join(cur_dir), s.image));
             if selected.len() > 36 {  // TODO(tailhook) make tweakable
                 break;
             }
         }
     }
+    Ok(selected)
+}
+
+fn candidates(path: &VPath, replacing: bool, meta: &Meta)
+    -> Result<Vec<(VPath, ImageId)>, Error>
+{
+    if replacing {
+        replace_candidates(path, meta)
+    } else {
+        append_candidates(&path.parent(), meta)
+    }
+}
+
+fn open_indexes(candidates: Vec<(VPath, ImageId)>, meta: &Meta)
+    -> Vec<(VPath, Parser<BufReader<File>>)>
+{
     let mut files = Vec::new();
-    for (dir_name, s) in selected {
+    for (path, image) in candidates {
         // TODO(tailhook) look in cache
-        match read_index::open(&s.image, &meta) {
-            Ok(index) => {
-                files.push((base_dir.join(dir_name), index));
+        match read_index::open(&image, meta) {
+            Ok(index) => files.push((path, index)),
+            Err(ref e) => {
+                warn!("Error reading index {:?} from file: {}", image, e);
             }
+        }
+    }
+    files
+}
+
+pub fn replace_mode(index: &IndexData, path: VPath, meta: &Meta)
+    -> Result<Vec<Hardlink>, Error>
+{
+    let candidates = replace_candidates(&path, meta)?;
+    return scan_links(index, open_indexes(candidates, meta));
+}
+
+pub fn append_mode(index: &IndexData, base_dir: VPath, meta: &Meta)
+    -> Result<Vec<Hardlink>, Error>
+{
+    let candidates = append_candidates(&base_dir, meta)?;
+    return scan_links(index, open_indexes(candidates, meta));
+}
+
+/// Finds blocks of the image which exist in the same images which are
+/// used as hardlink sources
+///
+/// Files which are `hardlinked` and full blocks of zeros (which are never
+/// fetched) are skipped.
+pub fn local_blocks(index: &IndexData, path: &VPath, replacing: bool,
+    hardlinked: &HashSet<PathBuf>, meta: &Meta)
+    -> Result<Vec<LocalBlock>, Error>
+{
+    let zero_block = BlockHash::hash_bytes(
+        &vec![0u8; index.block_size as usize]);
+    let mut needed = HashMap::new();
+    for entry in &index.entries {
+        if let Entry::File { ref path, size, ref hashes, .. } = *entry {
+            if hardlinked.contains(path) {
+                continue;
+            }
+            for (i, hash) in hashes.iter().enumerate() {
+                let hash = BlockHash::from_bytes(hash)
+                    .expect("valid hash type");
+                let offset = (i as u64)*index.block_size;
+                if offset + index.block_size <= size && hash == zero_block {
+                    continue;
+                }
+                needed.entry(hash).or_insert_with(Vec::new)
+                    .push((path.clone(), offset));
+            }
+        }
+    }
+    let mut result = Vec::new();
+    for (source, image) in candidates(path, replacing, meta)? {
+        if needed.is_empty() {
+            break;
+        }
+        let source_index = match read_index::read(&image, meta) {
+            Ok(index) => index,
             Err(ref e) => {
-                warn!("Error reading index {:?} from file: {}", s.image, e);
+                warn!("Error reading index {:?} from file: {}", image, e);
+                continue;
+            }
+        };
+        let block_size = source_index.block_size;
+        for entry in &source_index.entries {
+            if let Entry::File { ref path, size, ref hashes, .. } = *entry {
+                for (i, hash) in hashes.iter().enumerate() {
+                    let hash = match BlockHash::from_bytes(hash) {
+                        Some(hash) => hash,
+                        None => break,  // other hash type
+                    };
+                    if let Some(targets) = needed.remove(&hash) {
+                        let offset = (i as u64)*block_size;
+                        result.push(LocalBlock {
+                            hash: hash,
+                            source: source.clone(),
+                            source_path: path.clone(),
+                            source_offset: offset,
+                            size: min(block_size, size - offset),
+                            targets: targets,
+                        });
+                    }
+                }
             }
         }
     }
-    return scan_links(index, files);
+    Ok(result)
 }
 
-fn scan_links(index: Index, files: Vec<(VPath, Parser<BufReader<File>>)>)
+fn scan_links(index: &IndexData, files: Vec<(VPath, Parser<BufReader<File>>)>)
     -> Result<Vec<Hardlink>, Error>
 {
     let mut result = Vec::new();
@@ -151,3 +254,118 @@ fn scan_links(index: Index, files: Vec<(VPath, Parser<BufReader<File>>)>)
     }
     Ok(result)
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io::{BufWriter, Cursor};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crossbeam::sync::ArcCell;
+    use dir_signature::HashType;
+    use dir_signature::v1::{Emitter, Hashes};
+    use self_meter_http::Meter;
+    use serde::Serialize;
+    use serde_cbor::ser::Serializer as Cbor;
+    use tempfile::TempDir;
+
+    use {VPath};
+    use blocks::BlockHash;
+    use config::{Config, read_dirs};
+    use database::signatures::{State, SignatureEntry};
+    use index::ImageId;
+    use index_cache::IndexData;
+    use metadata::{Meta, store_index};
+    use proto::Signature;
+    use super::local_blocks;
+
+    fn meta(tmp: &TempDir) -> Meta {
+        let configs = tmp.path().join("config/configs");
+        fs::create_dir_all(&configs).unwrap();
+        fs::create_dir(tmp.path().join("db")).unwrap();
+        fs::write(configs.join("app.yaml"),
+            "directory: /nowhere\nnum-levels: 0\nappend-only: false\n")
+            .unwrap();
+        let config = Arc::new(Config {
+            machine_id: "0123456789abcdef0123456789abcdef".parse().unwrap(),
+            hostname: "localhost".into(),
+            port: 24783,
+            db_dir: tmp.path().join("db"),
+            config_dir: tmp.path().join("config"),
+            dirs: ArcCell::new(Arc::new(
+                read_dirs(&tmp.path().join("config")).unwrap())),
+        });
+        Meta::new(1, &config, &Meter::new()).unwrap()
+    }
+
+    fn index(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        {
+            let mut emitter = Emitter::new(HashType::blake2b_256(), 4096,
+                                           &mut buf).unwrap();
+            emitter.start_dir(Path::new("/")).unwrap();
+            for &(name, data) in files {
+                let (size, hashes) = Hashes::hash_file(
+                    HashType::blake2b_256(), 4096, data).unwrap();
+                emitter.add_file(OsStr::new(name), false, size, &hashes)
+                    .unwrap();
+            }
+            emitter.finish().unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn blocks() {
+        let tmp = TempDir::new().unwrap();
+        let meta = meta(&tmp);
+        // every block of the file is different
+        let old = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut changed = old[..8192].to_vec();
+        changed.extend(b"new tail");
+
+        let old_id = ImageId::from(vec![1; 32]);
+        store_index::write(&old_id, index(&[("old", &old)]), &meta)
+            .unwrap();
+        let dir = meta.signatures().unwrap().ensure_dir("app").unwrap();
+        dir.replace_file("app.state", |file| {
+            State {
+                image: old_id.clone(),
+                signatures: vec![SignatureEntry {
+                    timestamp: UNIX_EPOCH + Duration::from_secs(1),
+                    signature: Signature::SshEd25519([1; 64]),
+                }],
+                expires: None,
+            }.serialize(&mut Cbor::new(BufWriter::new(file)))
+        }).unwrap();
+
+        let new = IndexData::parse(&ImageId::from(vec![2; 32]),
+            Cursor::new(index(&[
+                ("changed", &changed),
+                ("copy", &old),
+                ("linked", &old),
+            ]))).unwrap();
+        let hardlinked = vec![PathBuf::from("/linked")].into_iter()
+            .collect::<HashSet<_>>();
+        let blocks = local_blocks(&new, &VPath::from("/app"), true,
+            &hardlinked, &meta).unwrap();
+
+        let path = |p: &str| PathBuf::from(p);
+        assert_eq!(blocks.iter().map(|b| {
+            (b.source.clone(), b.source_path.clone(), b.source_offset,
+             b.size, b.targets.clone())
+        }).collect::<Vec<_>>(), vec![
+            (VPath::from("/app"), path("/old"), 0, 4096,
+             vec![(path("/changed"), 0), (path("/copy"), 0)]),
+            (VPath::from("/app"), path("/old"), 4096, 4096,
+             vec![(path("/changed"), 4096), (path("/copy"), 4096)]),
+            (VPath::from("/app"), path("/old"), 8192, 1808,
+             vec![(path("/copy"), 8192)]),
+        ]);
+        assert_eq!(blocks[2].hash, BlockHash::hash_bytes(&old[8192..]));
+    }
+}
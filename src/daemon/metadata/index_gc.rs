@@ -58,7 +58,7 @@ fn scan_dir(meta: &Meta, dir: &Dir, virtual_path: PathBuf, level: usize)
 fn scan(meta: &Meta) -> Result<(), Error>
 {
     let root = meta.signatures()?;
-    for (virtual_name, ref cfg) in &meta.0.config.dirs {
+    for (virtual_name, ref cfg) in meta.0.config.dirs.get().iter() {
         if let Some(dir) = root.dir_if_exists(virtual_name)? {
             let vpath = Path::new("/").join(virtual_name);
             scan_dir(meta, &dir, vpath, cfg.num_levels)?;
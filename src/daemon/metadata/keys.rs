@@ -67,3 +67,11 @@ pub fn read_upload_keys(cfg: &Arc<Directory>, meta: &Meta)
     }
     Ok(res)
 }
+
+pub fn read_master_keys(meta: &Meta) -> Result<Vec<PublicKey>, Error> {
+    let mut res = Vec::new();
+    let cfg_dir = Dir::open(&meta.0.config.config_dir)
+        .map_err(|e| Error::ReadKey(meta.0.config.config_dir.clone(), e))?;
+    read_keys(&cfg_dir, "master.key", &mut res, true);
+    Ok(res)
+}
@@ -4,6 +4,8 @@ mod first_scan;
 mod hardlink_sources;
 mod index_gc;
 mod keys;
+mod pins;
+mod promotes;
 mod read_index;
 mod scan;
 mod store_index;
@@ -11,6 +13,7 @@ mod upload;
 
 use std::io;
 use std::collections::{HashMap, HashSet, BTreeMap};
+use std::path::PathBuf;
 use std::sync::{Arc};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -20,8 +23,11 @@ use self_meter_http::Meter;
 
 use database::signatures::{State, SignatureEntry};
 use proto::{AppendDir};
-use proto::{ReplaceDir};
+use proto::{ReplaceDir, Rollback};
+use proto::{Maintenance, PinState, PromoteState};
 use {VPath};
+use cleanup::ImageSize;
+use cleanup::maintenance;
 use config::Config;
 use tracking::Index;
 use index::{ImageId};
@@ -33,7 +39,7 @@ use void::Void;
 use self::dir::Dir;
 pub use self::upload::{Upload, Accept};
 pub use self::error::Error;
-pub use self::hardlink_sources::Hardlink;
+pub use self::hardlink_sources::{Hardlink, LocalBlock};
 
 
 #[derive(Clone)]
@@ -42,6 +48,7 @@ pub struct Meta(Arc<Inner>);
 struct Writing {
     pub image: ImageId,
     pub signatures: Vec<SignatureEntry>,
+    pub expires: Option<SystemTime>,
     pub replacing: bool,
 }
 
@@ -50,6 +57,7 @@ struct Inner {
     config: Arc<Config>,
     writing: Mutex<HashMap<VPath, Writing>>,
     collecting: Mutex<Option<HashSet<ImageId>>>,
+    maintenance: Mutex<maintenance::Accepted>,
     base_dir: Dir,
 }
 
@@ -58,6 +66,26 @@ fn is_fresher(meta: &Metadata, time: SystemTime) -> bool {
     return meta.stat().st_mtime as u64 > dur.as_secs() - 1;
 }
 
+/// Name of the state file of the image without `.state` suffix
+///
+/// Level 0 image is named by its key, because hidden files are skipped when
+/// state files are listed.
+fn state_name(vpath: &VPath) -> &str {
+    if vpath.level() == 0 {
+        vpath.key()
+    } else {
+        vpath.final_name()
+    }
+}
+
+/// Name of the image in `base_dir` by its state file name (see `state_name`)
+fn image_name<'x>(meta: &Meta, base_dir: &VPath, name: &'x str) -> &'x str {
+    match meta.0.config.dir(base_dir.key()) {
+        Some(ref cfg) if cfg.num_levels == 0 => "",
+        _ => name,
+    }
+}
+
 impl Meta {
     pub fn new(num_threads: usize, config: &Arc<Config>, meter: &Meter)
         -> Result<Meta, Error>
@@ -77,6 +105,8 @@ impl Meta {
             base_dir: dir,
             writing: Mutex::new(HashMap::new(), "metadata_writing"),
             collecting: Mutex::new(None, "metadata_collecting"),
+            maintenance: Mutex::new(maintenance::Accepted::default(),
+                                    "metadata_maintenance"),
         })))
     }
     pub fn get_image_id(&self, vpath: &VPath)
@@ -87,7 +117,7 @@ impl Meta {
         self.0.cpu_pool.spawn_fn(move || {
             let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
             if let Some(state) = dir.read_file(
-                &format!("{}.state", vpath.final_name()),
+                &format!("{}.state", state_name(&vpath)),
                 upload::read_state)?
             {
                 Ok(state.image)
@@ -96,6 +126,18 @@ impl Meta {
             }
         })
     }
+    /// Reads state of the image, `None` if there is no image at `vpath`
+    pub fn read_state(&self, vpath: &VPath)
+        -> CpuFuture<Option<State>, Error>
+    {
+        let meta = self.clone();
+        let vpath = vpath.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
+            dir.read_file(&format!("{}.state", state_name(&vpath)),
+                          upload::read_state)
+        })
+    }
     pub fn append_dir(&self, params: AppendDir)
         -> CpuFuture<Upload, Error>
     {
@@ -121,6 +163,19 @@ impl Meta {
             upload::resume_upload(&path, &meta)
         })
     }
+    /// Registers downloads of `base_dir` interrupted by restart
+    ///
+    /// Returns them (with `replacing` flag) and names of all images being
+    /// written in `base_dir`.
+    pub fn resume_pending(&self, base_dir: &VPath)
+        -> CpuFuture<(Vec<(VPath, ImageId, bool)>, HashSet<String>), Error>
+    {
+        let meta = self.clone();
+        let base_dir = base_dir.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            upload::resume_pending(&base_dir, &meta)
+        })
+    }
     pub fn dir_aborted(&self, path: &VPath) -> CpuFuture<(), Void> {
         let meta = self.clone();
         let path: VPath = path.clone();
@@ -177,7 +232,7 @@ impl Meta {
                 return Err(Error::CleanupCanceled(path));
             }
             let parent = meta.signatures()?.open_path(path.parent_rel())?;
-            let state = format!("{}.state", path.final_name());
+            let state = format!("{}.state", state_name(&path));
             if let Some(meta) = parent.file_meta(&state)? {
                 if is_fresher(&meta, at) {
                     return Err(Error::CleanupCanceled(path));
@@ -186,6 +241,52 @@ impl Meta {
                 return Err(Error::FileWasVanished(parent.path(state)));
             }
             parent.remove_file(&state)?;
+            // pin and promote are meaningless without the image
+            parent.remove_file(&format!("{}.pin", path.final_name()))?;
+            parent.remove_file(&format!("{}.promote", path.final_name()))?;
+            Ok(())
+        })
+    }
+    pub fn reserve_restore(&self, path: &VPath, state: State)
+        -> CpuFuture<bool, Error>
+    {
+        let meta = self.clone();
+        let path = path.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            upload::reserve_restore(&path, state, &meta)
+        })
+    }
+    pub fn check_rollback(&self, params: Rollback)
+        -> CpuFuture<(Rollback, Result<State, &'static str>), Error>
+    {
+        let meta = self.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            upload::check_rollback(&params, &meta)
+                .map(|result| (params, result))
+        })
+    }
+    /// Stores state of the image at the reserved path and releases it
+    ///
+    /// Path is reserved by `check_rollback` or `reserve_restore`.
+    pub fn commit_reserved(&self, path: &VPath) -> CpuFuture<(), Error> {
+        let meta = self.clone();
+        let path: VPath = path.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            let mut lock = meta.writing();
+            match lock.remove(&path) {
+                Some(wr) => upload::commit_reserved(&path, wr, &meta),
+                None => Err(Error::PathNotFound(path)),
+            }
+        })
+    }
+    /// Releases the path reserved by `check_rollback` or `reserve_restore`
+    pub fn abort_reserved(&self, path: &VPath) -> CpuFuture<(), Void> {
+        let meta = self.clone();
+        let path: VPath = path.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            if meta.writing().remove(&path).is_none() {
+                error!("Spurious release of {:?}", path);
+            }
             Ok(())
         })
     }
@@ -224,6 +325,31 @@ impl Meta {
             res
         })
     }
+    /// Reads sizes of images for quota accounting
+    ///
+    /// Images which index can't be read are skipped.
+    pub fn image_sizes(&self, images: Vec<ImageId>)
+        -> CpuFuture<HashMap<ImageId, ImageSize>, Error>
+    {
+        let meta = self.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            let mut res = HashMap::new();
+            for id in images {
+                match read_index::read(&id, &meta) {
+                    Ok(index) => {
+                        res.insert(id, ImageSize::from(&index));
+                    }
+                    Err(Error::IndexNotFound) => {
+                        debug!("No index {:?} to find out size", id);
+                    }
+                    Err(e) => {
+                        warn!("Error reading index {:?}: {}", id, e);
+                    }
+                }
+            }
+            Ok(res)
+        })
+    }
     pub fn store_index(&self, id: &ImageId, data: Vec<u8>)
     {
         let meta = self.clone();
@@ -243,7 +369,17 @@ impl Meta {
     {
         let meta = self.clone();
         self.0.cpu_pool.spawn_fn(move || {
-            first_scan::scan(&meta, add_dir)
+            first_scan::scan(&meta, None, add_dir)
+        })
+    }
+    /// Same as `scan_base_dirs` but only for specified directory configs
+    pub fn scan_base_dirs_of<F>(&self, names: Vec<String>, add_dir: F)
+        -> CpuFuture<(), Error>
+        where F: FnMut(VPath) + Send + 'static
+    {
+        let meta = self.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            first_scan::scan(&meta, Some(names), add_dir)
         })
     }
     pub fn scan_dir(&self, path: &VPath)
@@ -263,6 +399,71 @@ impl Meta {
             }
         })
     }
+    /// Same as `scan_dir` but skips images which are being written
+    pub fn scan_committed(&self, path: &VPath)
+        -> CpuFuture<BTreeMap<String, State>, Error>
+    {
+        let path = path.clone();
+        let meta = self.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            match meta.signatures()?.open_vpath(&path) {
+                Ok(dir) => scan::committed_states(&meta, &path, &dir),
+                Err(Error::Open(_, ref e))
+                if e.kind() == io::ErrorKind::NotFound
+                => Ok(BTreeMap::new()),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+    pub fn read_pins(&self, path: &VPath)
+        -> CpuFuture<BTreeMap<String, PinState>, Error>
+    {
+        let path = path.clone();
+        let meta = self.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            match meta.signatures()?.open_vpath(&path) {
+                Ok(dir) => pins::all_pins(&dir),
+                Err(Error::Open(_, ref e))
+                if e.kind() == io::ErrorKind::NotFound
+                => Ok(BTreeMap::new()),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+    pub fn set_pin(&self, path: &VPath, pin: PinState, require_image: bool)
+        -> CpuFuture<Upload, Error>
+    {
+        let path = path.clone();
+        let meta = self.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            pins::set_pin(&path, pin, require_image, &meta)
+        })
+    }
+    pub fn read_promotes(&self, path: &VPath)
+        -> CpuFuture<BTreeMap<String, PromoteState>, Error>
+    {
+        let path = path.clone();
+        let meta = self.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            match meta.signatures()?.open_vpath(&path) {
+                Ok(dir) => promotes::all_promotes(&dir),
+                Err(Error::Open(_, ref e))
+                if e.kind() == io::ErrorKind::NotFound
+                => Ok(BTreeMap::new()),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+    pub fn set_promote(&self, path: &VPath, promote: PromoteState,
+        require_image: bool)
+        -> CpuFuture<Upload, Error>
+    {
+        let path = path.clone();
+        let meta = self.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            promotes::set_promote(&path, promote, require_image, &meta)
+        })
+    }
     pub fn files_to_hardlink(&self, dir: &VPath, index: &Index,
         replacing: bool)
         -> CpuFuture<Vec<Hardlink>, Error>
@@ -272,12 +473,37 @@ impl Meta {
         let index = index.clone();
         self.0.cpu_pool.spawn_fn(move || {
             if replacing {
-                hardlink_sources::replace_mode(index, dir, meta)
+                hardlink_sources::replace_mode(&index, dir, &meta)
             } else {
-                hardlink_sources::append_mode(index, dir.parent(), meta)
+                hardlink_sources::append_mode(&index, dir.parent(), &meta)
             }
         })
     }
+    /// Blocks of the image which can be copied from local images
+    pub fn local_blocks(&self, dir: &VPath, index: &Index, replacing: bool,
+        hardlinked: HashSet<PathBuf>)
+        -> CpuFuture<Vec<LocalBlock>, Error>
+    {
+        let dir = dir.clone();
+        let meta = self.clone();
+        let index = index.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            hardlink_sources::local_blocks(&index, &dir, replacing,
+                                           &hardlinked, &meta)
+        })
+    }
+    pub fn check_quota(&self, dir: &VPath, index: &Index)
+        -> CpuFuture<Option<&'static str>, Error>
+    {
+        let dir = dir.clone();
+        let meta = self.clone();
+        let index = index.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            let config = meta.0.config.dir(dir.key())
+                .ok_or_else(|| Error::PathNotFound(dir.clone()))?;
+            upload::check_quota(&index, &dir, &config, &meta)
+        })
+    }
     pub fn is_writing(&self, dir: &VPath)
         -> CpuFuture<bool, Error>
     {
@@ -288,6 +514,21 @@ impl Meta {
             Ok(wr.contains_key(&dir))
         })
     }
+    /// Checks that maintenance request is signed by one of master keys
+    /// and is not a replay of already accepted one
+    ///
+    /// Returns reject reason if request should not be run.
+    pub fn check_maintenance(&self, cmd: Maintenance)
+        -> CpuFuture<(Maintenance, Option<&'static str>), Error>
+    {
+        let meta = self.clone();
+        self.0.cpu_pool.spawn_fn(move || {
+            let keys = keys::read_master_keys(&meta)?;
+            let result = maintenance::check(&cmd, &keys,
+                &mut *meta.0.maintenance.lock(), SystemTime::now());
+            Ok((cmd, result.err()))
+        })
+    }
     fn signatures(&self) -> Result<Dir, Error> {
         self.0.base_dir.ensure_dir("signatures")
     }
@@ -299,13 +540,16 @@ impl Meta {
             set.insert(image_id.clone());
         }
     }
-    pub fn index_gc(&self)
+    /// Removes unused indexes, `extra` images are ones kept outside of
+    /// metadata (i.e. previous versions of images)
+    pub fn index_gc(&self, extra: Vec<ImageId>)
         -> CpuFuture<(), Error>
     {
         let meta = self.clone();
         self.0.cpu_pool.spawn_fn(move || {
             let writing = meta.writing().values()
                 .map(|w| w.image.clone())
+                .chain(extra)
                 .collect();
             *meta.0.collecting.lock() = Some(writing);
             index_gc::full_collection(&meta)
@@ -0,0 +1,203 @@
+use std::io::{BufReader, BufWriter};
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_cbor::de::from_reader as read_cbor;
+use serde_cbor::ser::Serializer as Cbor;
+
+use {VPath};
+use proto::{PinState, verify};
+use metadata::keys::read_upload_keys;
+use metadata::{Meta, Error, Dir, Upload, Accept, state_name};
+
+
+pub fn all_pins(dir: &Dir) -> Result<BTreeMap<String, PinState>, Error> {
+    let mut res = BTreeMap::new();
+    for mut name in dir.list_files(".pin")? {
+        let read: Result<Option<PinState>, _>;
+        read = dir.read_file(&name, |f| read_cbor(&mut BufReader::new(f)));
+        match read {
+            Ok(Some(pin)) => {
+                let nlen = name.len() - ".pin".len();
+                name.truncate(nlen);
+                res.insert(name, pin);
+            }
+            Ok(None) => {}
+            Err(e @ Error::Decode(..)) => {
+                dir.rename_broken_file(&name,
+                    format_args!("Scan error: {}", e));
+            }
+            Err(e) => {
+                error!("Scan error: {}", e);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Stores pin (or unpin) of the directory if it's newer than the stored one
+///
+/// When `require_image` is set, pin is rejected unless the image is present
+/// locally. Pins received from peers are stored regardless, as the image
+/// may be downloaded later.
+pub fn set_pin(vpath: &VPath, pin: PinState, require_image: bool,
+    meta: &Meta)
+    -> Result<Upload, Error>
+{
+    let config = if let Some(cfg) = meta.0.config.dir(vpath.key()) {
+        if vpath.level() != cfg.num_levels {
+            return Ok(Upload::Rejected("config_level_mismatch", None));
+        }
+        cfg
+    } else {
+        return Err(Error::PathNotFound(vpath.clone()));
+    };
+    let keys = read_upload_keys(&config, meta)?;
+    let sig_data = pin.sig_data(vpath);
+    if !pin.signatures.iter().any(|sig| verify(&sig_data, sig, &keys)) {
+        warn!("Pin of {:?} has no valid signatures. Upload-keys: {:?}",
+              vpath, config.upload_keys);
+        return Ok(Upload::Rejected("signature_mismatch", None));
+    }
+    // pins are not written during upload, but lock is useful to serialize
+    // concurrent pin requests
+    let _writing = meta.writing();
+    let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
+    let state_file = format!("{}.state", state_name(vpath));
+    if require_image && dir.file_meta(&state_file)?.is_none() {
+        return Ok(Upload::Rejected("image_not_found", None));
+    }
+    let pin_file = format!("{}.pin", vpath.final_name());
+    let old = dir.read_file(&pin_file,
+        |f| read_cbor::<PinState, _>(&mut BufReader::new(f)))?;
+    if let Some(old) = old {
+        if old.timestamp >= pin.timestamp {
+            return Ok(Upload::Accepted(Accept::AlreadyDone));
+        }
+    }
+    dir.replace_file(&pin_file, |file| {
+        pin.serialize(&mut Cbor::new(BufWriter::new(file)))
+    })?;
+    Ok(Upload::Accepted(Accept::New))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::BufWriter;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crossbeam::sync::ArcCell;
+    use crypto::ed25519::keypair;
+    use futures::Future;
+    use self_meter_http::Meter;
+    use serde::Serialize;
+    use serde_cbor::ser::Serializer as Cbor;
+    use ssh_keys::PrivateKey;
+    use tempfile::TempDir;
+
+    use {VPath};
+    use config::{Config, read_dirs};
+    use database::signatures::State;
+    use index::ImageId;
+    use metadata::{Meta, Error, Upload, Accept};
+    use proto::{PinState, sign};
+    use super::{all_pins, set_pin};
+
+    fn meta(tmp: &TempDir, key: &PrivateKey) -> Meta {
+        let configs = tmp.path().join("config/configs");
+        fs::create_dir_all(&configs).unwrap();
+        fs::create_dir(tmp.path().join("db")).unwrap();
+        fs::write(configs.join("app.yaml"),
+            "directory: /nowhere\nnum-levels: 1\nappend-only: false\n")
+            .unwrap();
+        fs::write(tmp.path().join("config/master.key"),
+            format!("{}\n", key.public_key())).unwrap();
+        let config = Arc::new(Config {
+            machine_id: "0123456789abcdef0123456789abcdef".parse().unwrap(),
+            hostname: "localhost".into(),
+            port: 24783,
+            db_dir: tmp.path().join("db"),
+            config_dir: tmp.path().join("config"),
+            dirs: ArcCell::new(Arc::new(
+                read_dirs(&tmp.path().join("config")).unwrap())),
+        });
+        Meta::new(1, &config, &Meter::new()).unwrap()
+    }
+
+    fn pin(vpath: &VPath, pinned: bool, secs: u64, key: &PrivateKey)
+        -> PinState
+    {
+        let mut pin = PinState {
+            pinned: pinned,
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            signatures: Vec::new(),
+        };
+        pin.signatures = sign(pin.sig_data(vpath), &[key.clone()]);
+        return pin;
+    }
+
+    fn outcome(result: Result<Upload, Error>) -> &'static str {
+        match result.unwrap() {
+            Upload::Accepted(Accept::New) => "new",
+            Upload::Accepted(Accept::InProgress) => "in_progress",
+            Upload::Accepted(Accept::AlreadyDone) => "already_done",
+            Upload::Rejected(reason, _) => reason,
+        }
+    }
+
+    #[test]
+    fn ordering() {
+        let tmp = TempDir::new().unwrap();
+        let key = PrivateKey::Ed25519(keypair(&[1; 32]).0);
+        let meta = meta(&tmp, &key);
+        let v1 = VPath::from("/app/v1");
+        let v2 = VPath::from("/app/v2");
+        let dir = meta.signatures().unwrap().ensure_dir("app").unwrap();
+        dir.replace_file("v1.state", |file| {
+            State {
+                image: ImageId::from(vec![1; 32]),
+                signatures: Vec::new(),
+                expires: None,
+            }.serialize(&mut Cbor::new(BufWriter::new(file)))
+        }).unwrap();
+
+        // clients can't pin unknown images, peers can
+        assert_eq!(outcome(set_pin(&v2, pin(&v2, true, 10, &key),
+                                   true, &meta)),
+                   "image_not_found");
+        assert_eq!(outcome(set_pin(&v2, pin(&v2, true, 10, &key),
+                                   false, &meta)),
+                   "new");
+
+        let other = PrivateKey::Ed25519(keypair(&[2; 32]).0);
+        assert_eq!(outcome(set_pin(&v1, pin(&v1, true, 10, &other),
+                                   true, &meta)),
+                   "signature_mismatch");
+        assert_eq!(outcome(set_pin(&v1, pin(&v1, true, 10, &key),
+                                   true, &meta)),
+                   "new");
+        // older (or same) timestamp doesn't override the newer one
+        assert_eq!(outcome(set_pin(&v1, pin(&v1, false, 5, &key),
+                                   false, &meta)),
+                   "already_done");
+        assert_eq!(outcome(set_pin(&v1, pin(&v1, false, 10, &key),
+                                   false, &meta)),
+                   "already_done");
+        let pins = all_pins(&dir).unwrap();
+        assert_eq!(pins.keys().collect::<Vec<_>>(), vec!["v1", "v2"]);
+        assert_eq!(pins["v1"], pin(&v1, true, 10, &key));
+
+        assert_eq!(outcome(set_pin(&v1, pin(&v1, false, 20, &key),
+                                   false, &meta)),
+                   "new");
+        assert_eq!(all_pins(&dir).unwrap()["v1"], pin(&v1, false, 20, &key));
+
+        // pin is removed together with the image
+        meta.remove_state_file(v1, SystemTime::now() + Duration::from_secs(5))
+            .wait().unwrap();
+        assert_eq!(all_pins(&dir).unwrap().keys().collect::<Vec<_>>(),
+                   vec!["v2"]);
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::{BufReader, BufWriter};
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_cbor::de::from_reader as read_cbor;
+use serde_cbor::ser::Serializer as Cbor;
+
+use {VPath};
+use proto::{PromoteState, verify};
+use metadata::keys::read_upload_keys;
+use metadata::{Meta, Error, Dir, Upload, Accept, state_name};
+
+
+pub fn all_promotes(dir: &Dir)
+    -> Result<BTreeMap<String, PromoteState>, Error>
+{
+    let mut res = BTreeMap::new();
+    for mut name in dir.list_files(".promote")? {
+        let read: Result<Option<PromoteState>, _>;
+        read = dir.read_file(&name, |f| read_cbor(&mut BufReader::new(f)));
+        match read {
+            Ok(Some(promote)) => {
+                let nlen = name.len() - ".promote".len();
+                name.truncate(nlen);
+                res.insert(name, promote);
+            }
+            Ok(None) => {}
+            Err(e @ Error::Decode(..)) => {
+                dir.rename_broken_file(&name,
+                    format_args!("Scan error: {}", e));
+            }
+            Err(e) => {
+                error!("Scan error: {}", e);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Stores promote of the image if it's newer than the stored one
+///
+/// When `require_image` is set, promote is rejected unless the image is
+/// present locally. Promotes received from peers are stored regardless,
+/// as the image may be downloaded later.
+pub fn set_promote(vpath: &VPath, promote: PromoteState,
+    require_image: bool, meta: &Meta)
+    -> Result<Upload, Error>
+{
+    let config = if let Some(cfg) = meta.0.config.dir(vpath.key()) {
+        if vpath.level() != cfg.num_levels || cfg.num_levels == 0 {
+            return Ok(Upload::Rejected("config_level_mismatch", None));
+        }
+        cfg
+    } else {
+        return Err(Error::PathNotFound(vpath.clone()));
+    };
+    let keys = read_upload_keys(&config, meta)?;
+    let sig_data = promote.sig_data(vpath);
+    if !promote.signatures.iter().any(|sig| verify(&sig_data, sig, &keys)) {
+        warn!("Promote of {:?} has no valid signatures. Upload-keys: {:?}",
+              vpath, config.upload_keys);
+        return Ok(Upload::Rejected("signature_mismatch", None));
+    }
+    // serializes promotes with uploads, so image can't vanish in between
+    let _writing = meta.writing();
+    let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
+    let state_file = format!("{}.state", state_name(vpath));
+    if require_image && dir.file_meta(&state_file)?.is_none() {
+        return Ok(Upload::Rejected("image_not_found", None));
+    }
+    let promote_file = format!("{}.promote", vpath.final_name());
+    let old = dir.read_file(&promote_file,
+        |f| read_cbor::<PromoteState, _>(&mut BufReader::new(f)))?;
+    if let Some(old) = old {
+        if old.timestamp >= promote.timestamp {
+            return Ok(Upload::Accepted(Accept::AlreadyDone));
+        }
+    }
+    dir.replace_file(&promote_file, |file| {
+        promote.serialize(&mut Cbor::new(BufWriter::new(file)))
+    })?;
+    Ok(Upload::Accepted(Accept::New))
+}
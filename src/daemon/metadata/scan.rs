@@ -1,7 +1,7 @@
 use std::io::{BufReader};
 use std::collections::BTreeMap;
 
-use metadata::{Meta, Error, Dir};
+use metadata::{Meta, Error, Dir, image_name};
 
 use {VPath};
 use database::signatures::State;
@@ -15,7 +15,8 @@ pub fn all_states(meta: &Meta, vpath: &VPath, dir: &Dir)
     for mut name in dir.list_files(".state")? {
         if name.ends_with(".new.state") {
             let nlen = name.len() - ".new.state".len();
-            if !meta.writing().contains_key(&vpath.join(&name[..nlen])) {
+            let image = image_name(meta, vpath, &name[..nlen]);
+            if !meta.writing().contains_key(&vpath.join(image)) {
                 match dir.remove_file(&name) {
                     Ok(()) => {
                         info!("Removed stale file {:?} in {:?}",
@@ -26,40 +27,146 @@ pub fn all_states(meta: &Meta, vpath: &VPath, dir: &Dir)
                 continue;
             }
         }
-        let read: Result<Option<State>, _>;
-        read = dir.read_file(&name, |f| read_cbor(&mut BufReader::new(f)));
-        match read {
-            Ok(Some(ref state)) if state.signatures.len() < 1 => {
-                dir.rename_broken_file(&name,
-                    format_args!("Scan error: state has no signatures"));
-            }
-            Ok(Some(state)) => {
-                let nlen = name.len() - ".state".len();
-                name.truncate(nlen);
-                if name.ends_with(".new") {
-                    let nlen = nlen - 4;
-                    name.truncate(nlen);
-                    res.insert(name, state);
-                } else if !res.contains_key(&name) {
-                    // We must not replace `.new` file, if it visited earlier
-                    // Since the only way to have duplicate entries is to
-                    // have both `.new` and non-new file, we replace when
-                    // visit `.new` and insert if not exists for non-new file
-                    res.insert(name, state);
-                }
-            }
-            Ok(None) => {
-                warn!("Scan error: {}",
-                    Error::FileWasVanished(dir.path(&name)));
-            }
-            Err(e @ Error::Decode(..)) => {
-                dir.rename_broken_file(&name,
-                    format_args!("Scan error: {}", e));
-            }
-            Err(e) => {
-                error!("Scan error: {}", e);
-            }
+        let state = match read_state(dir, &name) {
+            Some(state) => state,
+            None => continue,
+        };
+        let nlen = name.len() - ".state".len();
+        name.truncate(nlen);
+        if name.ends_with(".new") {
+            let nlen = nlen - 4;
+            name.truncate(nlen);
+            res.insert(image_name(meta, vpath, &name).to_string(), state);
+        } else if !res.contains_key(image_name(meta, vpath, &name)) {
+            // We must not replace `.new` file, if it visited earlier
+            // Since the only way to have duplicate entries is to
+            // have both `.new` and non-new file, we replace when
+            // visit `.new` and insert if not exists for non-new file
+            res.insert(image_name(meta, vpath, &name).to_string(), state);
+        }
+    }
+    Ok(res)
+}
+
+/// Returns states of committed images only, i.e. skips `.new.state` files
+pub fn committed_states(meta: &Meta, vpath: &VPath, dir: &Dir)
+    -> Result<BTreeMap<String, State>, Error>
+{
+    let mut res = BTreeMap::new();
+    for name in dir.list_files(".state")? {
+        if name.ends_with(".new.state") {
+            continue;
+        }
+        if let Some(state) = read_state(dir, &name) {
+            let stem = &name[..name.len() - ".state".len()];
+            res.insert(image_name(meta, vpath, stem).to_string(), state);
         }
     }
     Ok(res)
 }
+
+/// Reads state file, broken files are renamed and skipped
+fn read_state(dir: &Dir, name: &str) -> Option<State> {
+    let read: Result<Option<State>, _>;
+    read = dir.read_file(name, |f| read_cbor(&mut BufReader::new(f)));
+    match read {
+        Ok(Some(ref state)) if state.signatures.len() < 1 => {
+            dir.rename_broken_file(name,
+                format_args!("Scan error: state has no signatures"));
+        }
+        Ok(Some(state)) => return Some(state),
+        Ok(None) => {
+            warn!("Scan error: {}",
+                Error::FileWasVanished(dir.path(name)));
+        }
+        Err(e @ Error::Decode(..)) => {
+            dir.rename_broken_file(name,
+                format_args!("Scan error: {}", e));
+        }
+        Err(e) => {
+            error!("Scan error: {}", e);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::BufWriter;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crossbeam::sync::ArcCell;
+    use self_meter_http::Meter;
+    use serde::Serialize;
+    use serde_cbor::ser::Serializer as Cbor;
+    use tempfile::TempDir;
+
+    use {VPath};
+    use config::{Config, read_dirs};
+    use database::signatures::{State, SignatureEntry};
+    use index::ImageId;
+    use metadata::{Meta, upload};
+    use proto::Signature;
+    use super::{all_states, committed_states};
+
+    fn meta(tmp: &TempDir) -> Meta {
+        let configs = tmp.path().join("config/configs");
+        fs::create_dir_all(&configs).unwrap();
+        fs::create_dir(tmp.path().join("db")).unwrap();
+        fs::write(configs.join("app.yaml"),
+            "directory: /nowhere\nnum-levels: 0\nappend-only: false\n")
+            .unwrap();
+        let config = Arc::new(Config {
+            machine_id: "0123456789abcdef0123456789abcdef".parse().unwrap(),
+            hostname: "localhost".into(),
+            port: 24783,
+            db_dir: tmp.path().join("db"),
+            config_dir: tmp.path().join("config"),
+            dirs: ArcCell::new(Arc::new(
+                read_dirs(&tmp.path().join("config")).unwrap())),
+        });
+        Meta::new(1, &config, &Meter::new()).unwrap()
+    }
+
+    fn state(id: u8) -> State {
+        State {
+            image: ImageId::from(vec![id; 32]),
+            signatures: vec![SignatureEntry {
+                timestamp: UNIX_EPOCH + Duration::from_secs(id as u64),
+                signature: Signature::SshEd25519([id; 64]),
+            }],
+            expires: None,
+        }
+    }
+
+    #[test]
+    fn level_zero() {
+        let tmp = TempDir::new().unwrap();
+        let meta = meta(&tmp);
+        let vpath = VPath::from("/app");
+        let dir = meta.signatures().unwrap().ensure_dir("app").unwrap();
+        dir.replace_file("app.new.state", |file| {
+            state(1).serialize(&mut Cbor::new(BufWriter::new(file)))
+        }).unwrap();
+
+        // download interrupted by restart is found
+        let (resumed, writing) = upload::resume_pending(&vpath, &meta)
+            .unwrap();
+        assert_eq!(resumed, vec![(vpath.clone(), state(1).image, false)]);
+        assert!(writing.contains(""));
+        let states = all_states(&meta, &vpath, &dir).unwrap();
+        assert_eq!(states.keys().collect::<Vec<_>>(), vec![""]);
+        assert!(committed_states(&meta, &vpath, &dir).unwrap().is_empty());
+
+        let wr = meta.writing().remove(&vpath).unwrap();
+        upload::commit_dir(&vpath, wr, &meta).unwrap();
+        assert!(dir.file_meta("app.state").unwrap().is_some());
+        let committed = committed_states(&meta, &vpath, &dir).unwrap();
+        assert_eq!(committed.keys().collect::<Vec<_>>(), vec![""]);
+        assert_eq!(committed[""].image, state(1).image);
+        let states = all_states(&meta, &vpath, &dir).unwrap();
+        assert_eq!(states[""].image, state(1).image);
+    }
+}
@@ -1,7 +1,9 @@
-use std::io::{BufReader, BufWriter};
+use std::io::{self, BufReader, BufWriter};
 use std::fs::File;
+use std::collections::{BTreeMap, HashSet};
 use std::collections::hash_map::Entry;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use serde::Serialize;
 use serde_cbor::de::from_reader as read_cbor;
@@ -12,11 +14,16 @@ use {VPath};
 use index::ImageId;
 use database::signatures::{State, SignatureEntry};
 use proto::{AppendDir};
-use proto::{ReplaceDir};
+use proto::{ReplaceDir, Rollback};
 use proto::{SigData, Signature, verify};
+use cleanup::{ImageSize, check_image, has_quota};
 use config::Directory;
+use dir_util::{has_space_for, space_available};
+use index_cache::IndexData;
+use metadata::hardlink_sources;
 use metadata::keys::read_upload_keys;
-use metadata::{Meta, Error, Writing};
+use metadata::{read_index, scan};
+use metadata::{Meta, Error, Writing, state_name, image_name};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Accept {
@@ -46,6 +53,13 @@ fn append_signatures(old: &mut Vec<SignatureEntry>,
     sort_signatures(old);
 }
 
+/// Image can't have the same name as the `current-symlink`
+fn is_current_symlink(vpath: &VPath, config: &Directory) -> bool {
+    config.current_symlink.as_ref()
+        .map(|name| name == vpath.final_name())
+        .unwrap_or(false)
+}
+
 pub fn read_state(f: File) -> Result<State, CborError> {
     read_cbor(&mut BufReader::new(f))
 }
@@ -62,26 +76,122 @@ fn check_keys(sigdata: &SigData, signatures: &Vec<Signature>,
     Ok(signatures.iter().any(|sig| verify(sigdata, sig, &keys)))
 }
 
+/// Returns reason to reject image if it doesn't fit directory quotas
+///
+/// Total size is only checked when `auto_clean` is disabled, otherwise
+/// older images are evicted by cleanup to fit new one.
+pub fn check_quota(index: &IndexData, vpath: &VPath, config: &Arc<Directory>,
+                   meta: &Meta)
+    -> Result<Option<&'static str>, Error>
+{
+    let size = ImageSize::from(index);
+    if let Some(reason) = check_image(config, size) {
+        return Ok(Some(reason));
+    }
+    if config.auto_clean || !has_quota(config) {
+        return Ok(None);
+    }
+    let base = vpath.parent();
+    let states = match meta.signatures()?.open_vpath(&base) {
+        Ok(dir) => scan::all_states(meta, &base, &dir)?,
+        Err(Error::Open(_, ref e)) if e.kind() == io::ErrorKind::NotFound
+        => BTreeMap::new(),
+        Err(e) => return Err(e),
+    };
+    let mut total = size;
+    for (name, state) in states {
+        if name == vpath.final_name() {
+            // image is replaced, so it's size doesn't matter
+            continue;
+        }
+        match read_index::read(&state.image, meta) {
+            Ok(index) => total += ImageSize::from(&index),
+            Err(Error::IndexNotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if total.fits(config) {
+        Ok(None)
+    } else {
+        warn!("Image {} doesn't fit quota of {:?}: {:?}",
+            index.id, base, total);
+        Ok(Some("quota_exceeded"))
+    }
+}
+
+/// Checks whether image fits quotas and free disk space if index is known
+///
+/// Files which can be hardlinked are not counted. If index isn't stored
+/// locally yet, this check is skipped and the real one is done by
+/// tracking subsystem after fetching index.
+///
+/// Must be called without `writing` lock, as hardlink scan reads indexes
+/// of many images. The scan is skipped if the whole image fits anyway.
+fn check_limits(image: &ImageId, vpath: &VPath, replacing: bool,
+                config: &Arc<Directory>, meta: &Meta)
+    -> Result<Option<&'static str>, Error>
+{
+    let index = match read_index::read(image, meta) {
+        Ok(index) => index,
+        Err(Error::IndexNotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if let Some(reason) = check_quota(&index, vpath, config, meta)? {
+        return Ok(Some(reason));
+    }
+    let available = match space_available(config) {
+        Some(available) => available,
+        None => return Ok(None),
+    };
+    if index.bytes_total <= available {
+        return Ok(None);
+    }
+    let links = if replacing {
+        hardlink_sources::replace_mode(&index, vpath.clone(), meta)?
+    } else {
+        hardlink_sources::append_mode(&index, vpath.parent(), meta)?
+    };
+    let paths = links.into_iter().map(|h| h.path).collect::<HashSet<_>>();
+    if has_space_for(config, index.bytes_except(&paths)) {
+        Ok(None)
+    } else {
+        Ok(Some("insufficient_space"))
+    }
+}
+
 pub fn start_append(params: AppendDir, meta: &Meta)
     -> Result<Upload, Error>
 {
     meta.mark_used(&params.image);
     let vpath = params.path.clone();
-    let config = if let Some(cfg) = meta.0.config.dirs.get(vpath.key()) {
+    let config = if let Some(cfg) = meta.0.config.dir(vpath.key()) {
         if vpath.level() != cfg.num_levels {
             return Ok(Upload::Rejected("config_level_mismatch", None));
         }
+        if is_current_symlink(&vpath, &cfg) {
+            return Ok(Upload::Rejected("reserved_name", None));
+        }
         cfg
     } else {
         return Err(Error::PathNotFound(vpath));
     };
 
-    if !check_keys(&params.sig_data(), &params.signatures, config, meta)? {
+    if !check_keys(&params.sig_data(), &params.signatures, &config, meta)? {
         warn!("{:?} has no valid signatures. Upload-keys: {:?}",
               params, config.upload_keys);
         return Ok(Upload::Rejected("signature_mismatch", None));
     }
 
+    if params.expires.map(|t| t <= SystemTime::now()).unwrap_or(false) {
+        return Ok(Upload::Rejected("already_expired", None));
+    }
+
+    if let Some(reason) = check_limits(&params.image, &vpath, false,
+                                       &config, meta)?
+    {
+        return Ok(Upload::Rejected(reason, None));
+    }
+
     let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
 
     let timestamp = params.timestamp;
@@ -91,15 +201,19 @@ pub fn start_append(params: AppendDir, meta: &Meta)
             signature: sig,
         }).collect::<Vec<_>>();
     sort_signatures(&mut signatures);
-    let state_file = format!("{}.state", vpath.final_name());
-    let new_state_file = format!("{}.new.state", vpath.final_name());
+    let state_file = format!("{}.state", state_name(&vpath));
+    let new_state_file = format!("{}.new.state", state_name(&vpath));
 
     let mut writing = meta.writing();
     let (state, new) = match writing.entry(vpath.clone()) {
         Entry::Vacant(e) => {
             if let Some(mut state) = dir.read_file(&state_file, read_state)?
             {
-                if state.image == params.image {
+                if state.image == params.image &&
+                    state.expires != params.expires
+                {
+                    return Ok(Upload::Rejected("expires_mismatch", None));
+                } else if state.image == params.image {
                     append_signatures(&mut state.signatures, signatures);
                     dir.replace_file(&state_file, |file| {
                         state.serialize(&mut Cbor::new(BufWriter::new(file)))
@@ -113,10 +227,12 @@ pub fn start_append(params: AppendDir, meta: &Meta)
                 let state = State {
                     image: params.image.clone(),
                     signatures: signatures,
+                    expires: params.expires,
                 };
                 e.insert(Writing {
                     image: state.image.clone(),
                     signatures: state.signatures.clone(),
+                    expires: state.expires,
                     replacing: false,
                 });
                 (state, Accept::New)
@@ -124,13 +240,18 @@ pub fn start_append(params: AppendDir, meta: &Meta)
         }
         Entry::Occupied(mut e) => {
             let old_state = e.get_mut();
-            if old_state.image == params.image {
+            if old_state.image == params.image &&
+                old_state.expires != params.expires
+            {
+                return Ok(Upload::Rejected("expires_mismatch", None));
+            } else if old_state.image == params.image {
                 if signatures != old_state.signatures {
                     append_signatures(&mut old_state.signatures, signatures);
                 }
                 (State {
                     image: old_state.image.clone(),
                     signatures: old_state.signatures.clone(),
+                    expires: old_state.expires,
                 }, Accept::InProgress)
             } else {
                 return Ok(Upload::Rejected(
@@ -150,10 +271,13 @@ pub fn start_replace(params: ReplaceDir, meta: &Meta)
 {
     meta.mark_used(&params.image);
     let vpath = params.path.clone();
-    let config = if let Some(cfg) = meta.0.config.dirs.get(vpath.key()) {
+    let config = if let Some(cfg) = meta.0.config.dir(vpath.key()) {
         if vpath.level() != cfg.num_levels {
             return Ok(Upload::Rejected("config_level_mismatch", None));
         }
+        if is_current_symlink(&vpath, &cfg) {
+            return Ok(Upload::Rejected("reserved_name", None));
+        }
         cfg
     } else {
         return Err(Error::PathNotFound(vpath));
@@ -162,10 +286,16 @@ pub fn start_replace(params: ReplaceDir, meta: &Meta)
         return Ok(Upload::Rejected("dir_is_append_only", None));
     }
 
-    if !check_keys(&params.sig_data(), &params.signatures, config, meta)? {
+    if !check_keys(&params.sig_data(), &params.signatures, &config, meta)? {
         return Ok(Upload::Rejected("signature_mismatch", None));
     }
 
+    if let Some(reason) = check_limits(&params.image, &vpath, true,
+                                       &config, meta)?
+    {
+        return Ok(Upload::Rejected(reason, None));
+    }
+
     let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
 
     let timestamp = params.timestamp;
@@ -175,14 +305,16 @@ pub fn start_replace(params: ReplaceDir, meta: &Meta)
             signature: sig,
         }).collect::<Vec<_>>();
     sort_signatures(&mut signatures);
-    let state_file = format!("{}.state", vpath.final_name());
-    let new_state_file = format!("{}.new.state", vpath.final_name());
+    let state_file = format!("{}.state", state_name(&vpath));
+    let new_state_file = format!("{}.new.state", state_name(&vpath));
 
     let mut writing = meta.writing();
     let (state, new) = match writing.entry(vpath.clone()) {
         Entry::Vacant(e) => {
             if let Some(mut state) = dir.read_file(&state_file, read_state)? {
-                if state.image == params.image {
+                if state.image == params.image && state.expires.is_some() {
+                    return Ok(Upload::Rejected("expires_mismatch", None));
+                } else if state.image == params.image {
                     append_signatures(&mut state.signatures, signatures);
                     dir.replace_file(&state_file, |file| {
                         state.serialize(&mut Cbor::new(BufWriter::new(file)))
@@ -198,10 +330,12 @@ pub fn start_replace(params: ReplaceDir, meta: &Meta)
                     let state = State {
                         image: params.image.clone(),
                         signatures: signatures,
+                        expires: None,
                     };
                     e.insert(Writing {
                         image: state.image.clone(),
                         signatures: state.signatures.clone(),
+                        expires: None,
                         replacing: true,
                     });
                     (state, Accept::New)
@@ -210,10 +344,12 @@ pub fn start_replace(params: ReplaceDir, meta: &Meta)
                 let state = State {
                     image: params.image.clone(),
                     signatures: signatures,
+                    expires: None,
                 };
                 e.insert(Writing {
                     image: state.image.clone(),
                     signatures: state.signatures.clone(),
+                    expires: None,
                     replacing: false,
                 });
                 (state, Accept::New)
@@ -221,13 +357,18 @@ pub fn start_replace(params: ReplaceDir, meta: &Meta)
         }
         Entry::Occupied(mut e) => {
             let old_state = e.get_mut();
-            if old_state.image == params.image {
+            if old_state.image == params.image &&
+                old_state.expires.is_some()
+            {
+                return Ok(Upload::Rejected("expires_mismatch", None));
+            } else if old_state.image == params.image {
                 if signatures != old_state.signatures {
                     append_signatures(&mut old_state.signatures, signatures);
                 }
                 (State {
                     image: old_state.image.clone(),
                     signatures: old_state.signatures.clone(),
+                    expires: None,
                 }, Accept::InProgress)
             } else if params.old_image.is_some() &&
                       params.old_image.as_ref() != Some(&old_state.image)
@@ -251,10 +392,122 @@ pub fn start_replace(params: ReplaceDir, meta: &Meta)
     Ok(Upload::Accepted(new))
 }
 
+/// Writes back a state file of the image restored from trash
+///
+/// Returns `false` if there is already an image (or upload) at the path.
+/// Reserves the path for the image restored from trash
+///
+/// Returns `false` if there is an image at the path or it's being uploaded.
+/// Reserved path is held in `writing` until `commit_reserved` or
+/// `abort_reserved`.
+pub fn reserve_restore(vpath: &VPath, state: State, meta: &Meta)
+    -> Result<bool, Error>
+{
+    let mut writing = meta.writing();
+    if writing.contains_key(vpath) {
+        return Ok(false);
+    }
+    let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
+    let state_file = format!("{}.state", state_name(vpath));
+    if dir.file_meta(&state_file)?.is_some() {
+        return Ok(false);
+    }
+    writing.insert(vpath.clone(), Writing {
+        image: state.image,
+        signatures: state.signatures,
+        expires: state.expires,
+        replacing: false,
+    });
+    Ok(true)
+}
+
+/// Checks rollback request, returns state of the current image
+///
+/// Returns reason if rollback is rejected. Accepted rollback holds the path
+/// in `writing` until `commit_reserved` or `abort_reserved`, so no upload
+/// can start while images are exchanged.
+pub fn check_rollback(params: &Rollback, meta: &Meta)
+    -> Result<Result<State, &'static str>, Error>
+{
+    let vpath = &params.path;
+    let config = if let Some(cfg) = meta.0.config.dir(vpath.key()) {
+        if vpath.level() != cfg.num_levels {
+            return Ok(Err("config_level_mismatch"));
+        }
+        cfg
+    } else {
+        return Ok(Err("no_config"));
+    };
+    if config.append_only {
+        return Ok(Err("dir_is_append_only"));
+    }
+    if config.keep_previous_versions == 0 {
+        return Ok(Err("no_previous_versions"));
+    }
+    if !check_keys(&params.sig_data(), &params.signatures, &config, meta)? ||
+       !check_keys(&params.replace_sig_data(), &params.replace_signatures,
+                   &config, meta)?
+    {
+        warn!("Rollback of {:?} has no valid signatures. Upload-keys: {:?}",
+              vpath, config.upload_keys);
+        return Ok(Err("signature_mismatch"));
+    }
+    let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
+    let state_file = format!("{}.state", state_name(vpath));
+    let mut writing = meta.writing();
+    match writing.entry(vpath.clone()) {
+        Entry::Vacant(e) => {
+            let state = match dir.read_file(&state_file, read_state)? {
+                Some(state) => state,
+                None => return Ok(Err("path_not_found")),
+            };
+            if state.image == params.image {
+                return Ok(Err("already_current"));
+            }
+            let timestamp = params.timestamp;
+            let mut signatures = params.replace_signatures.iter()
+                .map(|sig| SignatureEntry {
+                    timestamp: timestamp,
+                    signature: sig.clone(),
+                }).collect::<Vec<_>>();
+            sort_signatures(&mut signatures);
+            e.insert(Writing {
+                image: params.image.clone(),
+                signatures: signatures,
+                expires: None,
+                replacing: true,
+            });
+            Ok(Ok(state))
+        }
+        Entry::Occupied(_) => Ok(Err("already_uploading")),
+    }
+}
+
+/// Writes state of the image restored by rollback
+///
+/// Restored image is signed by the replace signatures of the rollback
+/// request.
+pub(in metadata) fn commit_reserved(vpath: &VPath, wr: Writing, meta: &Meta)
+    -> Result<(), Error>
+{
+    // WARNING: no meta.writing() here, it's already locked
+    let state = State {
+        image: wr.image,
+        signatures: wr.signatures,
+        expires: wr.expires,
+    };
+    let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
+    let state_file = format!("{}.state", state_name(vpath));
+    dir.replace_file(&state_file, |file| {
+        state.serialize(&mut Cbor::new(BufWriter::new(file)))
+    })?;
+    Ok(())
+}
+
 pub fn resume_upload(vpath: &VPath, meta: &Meta)
     -> Result<ImageId, Error>
 {
-    let _config = if let Some(cfg) = meta.0.config.dirs.get(vpath.key()) {
+    let _config = if let Some(cfg) = meta.0.config.dir(vpath.key()) {
         if vpath.level() != cfg.num_levels {
             return Err(Error::LevelMismatch(vpath.level(), cfg.num_levels));
         }
@@ -263,8 +516,8 @@ pub fn resume_upload(vpath: &VPath, meta: &Meta)
         return Err(Error::PathNotFound(vpath.clone()));
     };
     let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
-    let state_file = format!("{}.state", vpath.final_name());
-    let new_state_file = format!("{}.new.state", vpath.final_name());
+    let state_file = format!("{}.state", state_name(vpath));
+    let new_state_file = format!("{}.new.state", state_name(vpath));
     let mut writing = meta.writing();
     match writing.entry(vpath.clone()) {
         Entry::Vacant(e) => {
@@ -273,6 +526,7 @@ pub fn resume_upload(vpath: &VPath, meta: &Meta)
                 e.insert(Writing {
                     image: state.image.clone(),
                     signatures: state.signatures.clone(),
+                    expires: state.expires,
                     replacing: false,
                 });
                 return Ok(state.image)
@@ -280,6 +534,7 @@ pub fn resume_upload(vpath: &VPath, meta: &Meta)
                 e.insert(Writing {
                     image: state.image.clone(),
                     signatures: state.signatures.clone(),
+                    expires: state.expires,
                     replacing: false,
                 });
                 return Ok(state.image)
@@ -293,11 +548,54 @@ pub fn resume_upload(vpath: &VPath, meta: &Meta)
     }
 }
 
+/// Registers downloads which were in progress before restart
+///
+/// Returns images in `base_dir` having `.new.state` file which are not
+/// being written yet, and names of all images being written in `base_dir`.
+pub fn resume_pending(base_dir: &VPath, meta: &Meta)
+    -> Result<(Vec<(VPath, ImageId, bool)>, HashSet<String>), Error>
+{
+    let dir = match meta.signatures()?.open_vpath(base_dir) {
+        Ok(dir) => Some(dir),
+        Err(Error::Open(_, ref e))
+        if e.kind() == io::ErrorKind::NotFound
+        => None,
+        Err(e) => return Err(e),
+    };
+    let mut writing = meta.writing();
+    let mut resumed = Vec::new();
+    if let Some(dir) = dir {
+        for name in dir.list_files(".new.state")? {
+            let stem = &name[..name.len() - ".new.state".len()];
+            let vpath = base_dir.join(image_name(meta, base_dir, stem));
+            if writing.contains_key(&vpath) {
+                continue;
+            }
+            if let Some(state) = dir.read_file(&name, read_state)? {
+                let replacing = dir.file_meta(
+                    &format!("{}.state", stem))?.is_some();
+                writing.insert(vpath.clone(), Writing {
+                    image: state.image.clone(),
+                    signatures: state.signatures,
+                    expires: state.expires,
+                    replacing: replacing,
+                });
+                resumed.push((vpath, state.image, replacing));
+            }
+        }
+    }
+    let names = writing.keys()
+        .filter(|path| path.parent() == *base_dir)
+        .map(|path| path.final_name().to_string())
+        .collect();
+    Ok((resumed, names))
+}
+
 pub(in metadata) fn abort_dir(vpath: &VPath, _wr: Writing, meta: &Meta)
     -> Result<(), Error>
 {
     // WARNING: no meta.writing() here, it's already locked
-    let new_state_file = format!("{}.new.state", vpath.final_name());
+    let new_state_file = format!("{}.new.state", state_name(vpath));
     let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
     dir.remove_file(&new_state_file)?;
     Ok(())
@@ -307,8 +605,8 @@ pub(in metadata) fn commit_dir(vpath: &VPath, _wr: Writing, meta: &Meta)
     -> Result<(), Error>
 {
     // WARNING: no meta.writing() here, it's already locked
-    let state_file = format!("{}.state", vpath.final_name());
-    let new_state_file = format!("{}.new.state", vpath.final_name());
+    let state_file = format!("{}.state", state_name(vpath));
+    let new_state_file = format!("{}.new.state", state_name(vpath));
     let dir = meta.signatures()?.ensure_dir(vpath.parent_rel())?;
     dir.rename(&new_state_file, &state_file)?;
     Ok(())
@@ -27,5 +27,6 @@ pub fn all() -> Vec<Box<Collection>> {
         Box::new(::metadata::metrics()),
         Box::new(::disk::metrics()),
         Box::new(::peers::metrics()),
+        Box::new(::hooks::metrics()),
     ]
 }
@@ -3,7 +3,7 @@ use std::time::Duration;
 
 use serialize;
 use proto::Hash;
-use config::Directory;
+use config::{Directory, CleanupPolicy};
 
 
 #[derive(Serialize)]
@@ -14,6 +14,14 @@ pub struct RemoteConfig {
     pub keep_max_directories: usize,
     #[serde(with="serialize::duration")]
     pub keep_recent: Duration,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub max_total_bytes: Option<u64>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub max_files: Option<u64>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub cleanup_policy: Option<CleanupPolicy>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub keep_total_size: Option<u64>,
 }
 
 pub fn get_hash(cfg: &Arc<Directory>) -> Hash {
@@ -23,5 +31,13 @@ pub fn get_hash(cfg: &Arc<Directory>) -> Hash {
         keep_min_directories: cfg.keep_min_directories,
         keep_max_directories: cfg.keep_max_directories,
         keep_recent: cfg.keep_recent,
+        max_total_bytes: cfg.max_total_bytes,
+        max_files: cfg.max_files,
+        cleanup_policy: if cfg.cleanup_policy == CleanupPolicy::Recent {
+            None
+        } else {
+            Some(cfg.cleanup_policy)
+        },
+        keep_total_size: cfg.keep_total_size,
     })
 }
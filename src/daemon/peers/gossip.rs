@@ -16,7 +16,7 @@ use serde::Deserialize;
 use serde_cbor::de::Deserializer;
 
 use {VPath};
-use config::Config;
+use config::{Config, Directory};
 use index::{ImageId};
 use machine_id::{MachineId};
 use mask::Mask;
@@ -62,6 +62,8 @@ struct Gossip {
     interval: Interval,
     machine_id: MachineId,
     config: Arc<Config>,
+    /// Directory configs `config_list` and `config_hash` are built from
+    config_dirs: Arc<HashMap<String, Arc<Directory>>>,
     config_hash: Hash,
     config_list: BTreeSet<VPath>,
     messages: UnboundedReceiver<Message>,
@@ -75,6 +77,7 @@ struct Gossip {
 
 impl Gossip {
     fn poll_forever(&mut self) {
+        self.check_config();
         self.write_messages();
         self.read_messages();
         while self.interval.poll().expect("interval never fails").is_ready()
@@ -82,6 +85,19 @@ impl Gossip {
             self.send_gossips();
         }
     }
+    /// Updates config list if directory configs were reloaded
+    ///
+    /// Peers notice the new hash in `your_config` of our packets and
+    /// request the new list.
+    fn check_config(&mut self) {
+        let dirs = self.config.dirs.get();
+        if !Arc::ptr_eq(&dirs, &self.config_dirs) {
+            self.config_list = config_list(&dirs);
+            self.config_hash = Hash::for_object(&self.config_list);
+            self.config_dirs = dirs;
+            debug!("Config list changed: {:?}", self.config_list);
+        }
+    }
     fn read_messages(&mut self) {
         let mut buf = [0u8; MAX_GOSSIP_PACKET];
         loop {
@@ -492,16 +508,21 @@ pub fn start(addr: SocketAddr,
     tracking: &Tracking, future_peers: HashMap<SocketAddr, String>)
     -> Result<(), io::Error>
 {
-    let config_list = config.dirs.keys()
-        .map(|x| VPath::from(format!("/{}", x)))
-        .collect();
+    let config_dirs = config.dirs.get();
+    let config_list = config_list(&config_dirs);
     let config_hash = Hash::for_object(&config_list);
     spawn(Gossip {
         interval: interval(Duration::from_millis(GOSSIP_INTERVAL)),
         socket: UdpSocket::bind(&addr, &handle())?,
         tracking: tracking.clone(),
         peers, machine_id, future_peers, by_host, messages,
-        config, config_list, config_hash, configs,
+        config, config_dirs, config_list, config_hash, configs,
     });
     Ok(())
 }
+
+fn config_list(dirs: &HashMap<String, Arc<Directory>>) -> BTreeSet<VPath> {
+    dirs.keys()
+        .map(|x| VPath::from(format!("/{}", x)))
+        .collect()
+}
@@ -0,0 +1,130 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use futures::Stream;
+use libc;
+use tk_easyloop::{spawn, interval};
+
+use config::{Config, read_dirs};
+use tracking::Tracking;
+
+
+/// Interval of checking whether reload is needed
+///
+/// This also works as a debounce for editors which write a file in
+/// multiple steps.
+const CHECK_INTERVAL: u64 = 1000;
+
+static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// Inotify watch of the directories with configs
+struct Watch(libc::c_int);
+
+impl Watch {
+    fn new() -> Result<Watch, io::Error> {
+        let fd = unsafe {
+            libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Watch(fd))
+    }
+    fn add(&self, dir: &Path) -> Result<(), io::Error> {
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let rc = unsafe {
+            libc::inotify_add_watch(self.0, path.as_ptr(),
+                libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO |
+                libc::IN_MOVED_FROM | libc::IN_DELETE)
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    /// Drains pending events, returns `true` if there were any
+    fn changed(&self) -> bool {
+        let mut buf = [0u8; 4096];
+        let mut changed = false;
+        loop {
+            let bytes = unsafe {
+                libc::read(self.0,
+                    buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+            };
+            if bytes <= 0 {
+                return changed;
+            }
+            changed = true;
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+fn reload(config_dir: &Path, config: &Config, tracking: &Tracking) {
+    match read_dirs(config_dir) {
+        Ok(dirs) => {
+            let changes = config.update_dirs(dirs);
+            if changes.is_empty() {
+                debug!("Configs are unchanged");
+            } else {
+                warn!("Configs reloaded, added: {:?}, changed: {:?}, \
+                    removed: {:?}",
+                    changes.added, changes.changed, changes.removed);
+                tracking.config_changed(&changes);
+            }
+        }
+        Err(e) => {
+            error!("Error reloading configs, keeping old ones: {}", e);
+        }
+    }
+}
+
+pub fn start(config: &Arc<Config>, tracking: &Tracking)
+    -> Result<(), io::Error>
+{
+    let dir = config.config_dir.clone();
+    // base dir is watched for `overrides.yaml`
+    let watch = Watch::new()
+        .and_then(|w| w.add(&dir).map(|()| w))
+        .and_then(|w| w.add(&dir.join("configs")).map(|()| w));
+    let watch = match watch {
+        Ok(watch) => Some(watch),
+        Err(e) => {
+            warn!("Can't watch {:?}: {}. Configs are reloaded on SIGHUP only",
+                dir, e);
+            None
+        }
+    };
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as libc::sighandler_t);
+    }
+    let config = config.clone();
+    let tracking = tracking.clone();
+    spawn(interval(Duration::from_millis(CHECK_INTERVAL))
+        .map_err(|_| unreachable!())
+        .for_each(move |()| {
+            let changed = watch.as_ref().map(|w| w.changed())
+                .unwrap_or(false);
+            if SIGHUP_RECEIVED.swap(false, Ordering::SeqCst) || changed {
+                reload(&dir, &config, &tracking);
+            }
+            Ok(())
+        }));
+    Ok(())
+}
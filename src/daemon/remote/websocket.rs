@@ -200,6 +200,24 @@ impl websocket::Dispatcher for Dispatcher {
                             self.tracking.get_base_dir(gb,
                                 Responder::new(rid, self));
                         }
+                        Maintenance(m) => {
+                            self.tracking.maintenance(m,
+                                Responder::new(rid, self));
+                        }
+                        Pin(p) => {
+                            self.tracking.pin(p, Responder::new(rid, self));
+                        }
+                        Unpin(p) => {
+                            self.tracking.unpin(p, Responder::new(rid, self));
+                        }
+                        Promote(p) => {
+                            self.tracking.promote(p,
+                                Responder::new(rid, self));
+                        }
+                        Rollback(r) => {
+                            self.tracking.rollback(r,
+                                Responder::new(rid, self));
+                        }
                     }
                 }
                 Ok(Message::Response(request_id, resp)) => {
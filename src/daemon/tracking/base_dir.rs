@@ -1,22 +1,24 @@
 use std::collections::{HashMap, BTreeSet, BTreeMap};
 use std::collections::hash_map::Entry;
 use std::sync::{Arc};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
 use std::time::{Instant, Duration};
 use std::path::PathBuf;
 
 use futures::Future;
+use futures::future::{Either, ok};
 
 use atomic::Atomic;
-use config::Directory;
-use cleanup::{sort_out};
+use config::{Directory, CleanupPolicy};
+use cleanup::{sort_out, has_quota, Usage};
 use disk::{self, Disk};
 use metadata::{self, Meta};
 use metrics::Integer;
 use named_mutex::{Mutex, MutexGuard};
 use peers::config::get_hash;
-use proto::{Hash, BaseDirState};
+use proto::{Hash, BaseDirState, PinState, PromoteState};
 use database::signatures::State;
+use index::ImageId;
 use tracking::Subsystem;
 use {VPath};
 
@@ -57,6 +59,8 @@ pub struct BaseDir {
     num_subdirs: AtomicUsize,
     num_downloading: AtomicUsize,
     recon_table: Mutex<HashMap<Hash, Instant>>,
+    pinned: Mutex<BTreeSet<String>>,
+    has_expiring: AtomicBool,
 }
 
 impl BaseDir {
@@ -89,6 +93,14 @@ impl BaseDir {
     pub fn last_scan(&self) -> Instant {
         self.last_scan.load(Ordering::SeqCst)
     }
+    /// Whether there are images with expiration time in the directory
+    pub fn has_expiring(&self) -> bool {
+        self.has_expiring.load(Ordering::SeqCst)
+    }
+    /// Names of subdirectories which are pinned currently
+    pub fn pinned(&self) -> BTreeSet<String> {
+        self.pinned.lock().clone()
+    }
     fn recon_table(&self) -> MutexGuard<HashMap<Hash, Instant>> {
         self.recon_table.lock()
     }
@@ -106,7 +118,9 @@ impl BaseDir {
     {
         let state = &mut *sys.state();
         let ref mut lst = state.base_dir_list;
-        let hash = Hash::for_object(&dir_data.dirs);
+        let hash = dir_data.hash();
+        let pinned = pinned_names(&dir_data.pins);
+        let expiring = dir_data.dirs.values().any(|s| s.expires.is_some());
         let down = state.in_progress.keys()
             .filter(|path| path.parent() == dir_data.path)
             .count();
@@ -121,6 +135,8 @@ impl BaseDir {
                     num_subdirs: AtomicUsize::new(dir_data.dirs.len()),
                     num_downloading: AtomicUsize::new(down.into()),
                     recon_table: Mutex::new(HashMap::new(), "base_dir"),
+                    pinned: Mutex::new(pinned, "base_dir_pins"),
+                    has_expiring: AtomicBool::new(expiring),
                 });
                 lst.push(new.clone());
                 e.insert(new);
@@ -130,6 +146,8 @@ impl BaseDir {
             Entry::Occupied(e) => {
                 let val = e.get();
                 val.last_scan.store(scan_time, Ordering::SeqCst);
+                *val.pinned.lock() = pinned;
+                val.has_expiring.store(expiring, Ordering::SeqCst);
                 let old_hash = val.hash();
                 if old_hash != hash {
                     debug!("Updated base dir {:?}: {}",
@@ -147,6 +165,87 @@ impl BaseDir {
     }
 }
 
+fn pinned_names(pins: &BTreeMap<String, PinState>) -> BTreeSet<String> {
+    pins.iter()
+        .filter(|&(_, pin)| pin.pinned)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Adds pinned directories to the keep list, so cleanup skips them
+pub fn add_pinned(path: &VPath, keep_list: &mut Vec<PathBuf>,
+    pins: &BTreeMap<String, PinState>)
+{
+    keep_list.extend(pinned_names(pins).into_iter()
+        .map(|name| path.suffix().join(name)));
+}
+
+/// Name of the image `current-symlink` should point to
+///
+/// It's the most recently uploaded image unless some other image was
+/// promoted after that upload. Only `committed` images are candidates, the
+/// ones being downloaded aren't on disk yet.
+fn current_image(committed: &BTreeMap<String, State>,
+    promotes: &BTreeMap<String, PromoteState>)
+    -> Option<String>
+{
+    let newest = committed.iter()
+        .filter_map(|(name, state)| {
+            state.signatures.iter().map(|s| s.timestamp).max()
+            .map(|ts| (ts, name))
+        })
+        .max();
+    let promoted = promotes.iter()
+        .filter(|&(name, _)| committed.contains_key(name))
+        .map(|(name, promote)| (promote.timestamp, name))
+        .max();
+    match (newest, promoted) {
+        (Some((uploaded, _)), Some((ts, name))) if ts > uploaded => {
+            Some(name.clone())
+        }
+        (Some((_, name)), _) => Some(name.clone()),
+        (None, _) => None,
+    }
+}
+
+/// Adds image `current-symlink` points to, so cleanup doesn't remove it
+pub fn add_current(path: &VPath, config: &Directory,
+    keep_list: &mut Vec<PathBuf>, committed: &BTreeMap<String, State>,
+    promotes: &BTreeMap<String, PromoteState>)
+{
+    if config.current_symlink.is_some() {
+        if let Some(name) = current_image(committed, promotes) {
+            keep_list.push(path.suffix().join(name));
+        }
+    }
+}
+
+/// Updates `current-symlink` of the base dir, errors are only logged
+fn update_symlink(path: &VPath, config: &Arc<Directory>, disk: &Disk,
+    committed: &BTreeMap<String, State>,
+    promotes: &BTreeMap<String, PromoteState>)
+    -> Box<Future<Item=(), Error=Error>>
+{
+    let name = match current_image(committed, promotes) {
+        Some(name) if config.current_symlink.is_some() => name,
+        _ => return Box::new(ok(())),
+    };
+    let path = path.clone();
+    Box::new(disk.update_current_symlink(config,
+            path.suffix().to_path_buf(), name.clone())
+        .then(move |res| {
+            match res {
+                Ok(true) => info!("Current image of {:?} is {:?}", path, name),
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Error updating current symlink of {:?}: {}",
+                        path, e);
+                }
+            }
+            Ok(())
+        }))
+}
+
 fn short_list(input: impl IntoIterator<Item=(PathBuf, State)>)
     -> BTreeMap<String, State>
 {
@@ -160,20 +259,103 @@ fn short_list(input: impl IntoIterator<Item=(PathBuf, State)>)
     .collect()
 }
 
+/// Reads local usage of images needed by cleanup policy of the directory
+///
+/// `extra` images are the ones not in `dirs` (i.e. images of peers).
+pub fn read_usage(path: &VPath, config: &Arc<Directory>,
+    dirs: &BTreeMap<String, State>, extra: Vec<ImageId>,
+    meta: &Meta, disk: &Disk)
+    -> Box<Future<Item=Usage, Error=Error>>
+{
+    let sizes = if has_quota(config) ||
+        config.cleanup_policy == CleanupPolicy::TotalSize
+    {
+        let ids = dirs.values().map(|s| s.image.clone())
+            .chain(extra).collect();
+        Either::A(meta.image_sizes(ids).map_err(Error::Meta))
+    } else {
+        Either::B(ok(HashMap::new()))
+    };
+    let access = if config.cleanup_policy == CleanupPolicy::LeastRecentlyUsed
+    {
+        let ids = dirs.iter()
+            .map(|(name, s)| (name.clone(), s.image.clone()))
+            .collect::<HashMap<_, _>>();
+        Either::A(disk.read_access_times(config,
+                path.suffix().to_path_buf(), ids.keys().cloned().collect())
+            .map_err(Error::Disk)
+            .map(move |times| {
+                times.into_iter()
+                    .filter_map(|(name, time)| {
+                        ids.get(&name).map(|id| (id.clone(), time))
+                    })
+                    .collect()
+            }))
+    } else {
+        Either::B(ok(HashMap::new()))
+    };
+    Box::new(sizes.join(access)
+        .map(|(sizes, last_access)| Usage { sizes, last_access }))
+}
+
+/// Finds downloads of the base dir interrupted by restart
+///
+/// Temporary dirs which are not used by any download are removed. Returns
+/// images which should be fetched again.
+pub fn resume_pending(path: &VPath, config: &Arc<Directory>, sys: &Subsystem)
+    -> Box<Future<Item=Vec<(VPath, ImageId, bool)>, Error=Error>>
+{
+    let path = path.clone();
+    let config = config.clone();
+    let disk = sys.disk.clone();
+    Box::new(sys.meta.resume_pending(&path).map_err(Error::Meta)
+        .and_then(move |(resumed, writing)| {
+            disk.remove_stale_temporary(&config, &path, writing)
+            .then(move |res| -> Result<_, Error> {
+                if let Err(e) = res {
+                    error!("Can't remove stale temporary dirs of {:?}: {}",
+                        path, e);
+                }
+                Ok(resumed)
+            })
+        }))
+}
+
+/// Scans the base dir and updates its `current-symlink`
+///
+/// Images which cleanup would remove are excluded from the state only for
+/// the `recent` policy. Other policies depend on local usage of images, so
+/// all the images are listed to keep the hash equal on all peers.
 pub fn scan(path: &VPath, config: &Arc<Directory>, meta: &Meta, disk: &Disk)
     -> Box<Future<Item=BaseDirState, Error=Error>>
 {
     let path = path.clone();
     let config = config.clone();
+    let disk = disk.clone();
     Box::new(
         meta.scan_dir(&path).map_err(Error::Meta)
-        .join(disk.read_keep_list(&config).map_err(Error::Disk))
-        .map(move |(dirs, keep_list)| {
+        .join5(meta.scan_committed(&path).map_err(Error::Meta),
+               disk.read_keep_list(&config).map_err(Error::Disk),
+               meta.read_pins(&path).map_err(Error::Meta),
+               meta.read_promotes(&path).map_err(Error::Meta))
+        .and_then(move |(dirs, committed, keep_list, pins, promotes)| {
+            let mut keep = keep_list.clone();
+            add_pinned(&path, &mut keep, &pins);
+            add_current(&path, &config, &mut keep, &committed, &promotes);
+            update_symlink(&path, &config, &disk, &committed, &promotes)
+            .map(move |()| {
+                (dirs, keep_list, keep, pins, promotes, path, config)
+            })
+        })
+        .map(move |(dirs, keep_list, keep, pins, promotes, path, config)| {
             let images = dirs.into_iter().map(|(name, state)| {
                 (path.suffix().join(name), state)
             }).collect();
-            let dirs = if config.auto_clean {
-                short_list(sort_out(&config, images, &keep_list).used)
+            let dirs = if config.auto_clean &&
+                config.cleanup_policy == CleanupPolicy::Recent
+            {
+                short_list(sort_out(&config, images, &keep, &Usage::default())
+                    .used.into_iter().map(|(name, state, _)| (name, state)))
             } else {
                 short_list(images)
             };
@@ -192,6 +374,63 @@ pub fn scan(path: &VPath, config: &Arc<Directory>, meta: &Meta, disk: &Disk)
                 config_hash: get_hash(&config),
                 keep_list_hash: Hash::for_object(&kl),
                 dirs: dirs,
+                pins: pins,
+                promotes: promotes,
             }
         }))
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use index::ImageId;
+    use proto::{PromoteState, Signature};
+    use database::signatures::{State, SignatureEntry};
+    use super::current_image;
+
+    fn state(id: u8, secs: u64) -> State {
+        State {
+            image: ImageId::from(vec![id; 32]),
+            signatures: vec![SignatureEntry {
+                timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+                signature: Signature::SshEd25519([id; 64]),
+            }],
+            expires: None,
+        }
+    }
+
+    fn promote(secs: u64) -> PromoteState {
+        PromoteState {
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            signatures: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn newest() {
+        let mut dirs = BTreeMap::new();
+        dirs.insert("v1".to_string(), state(1, 100));
+        dirs.insert("v2".to_string(), state(2, 200));
+        assert_eq!(current_image(&dirs, &BTreeMap::new()),
+                   Some("v2".to_string()));
+        assert_eq!(current_image(&BTreeMap::new(), &BTreeMap::new()), None);
+    }
+
+    #[test]
+    fn promoted() {
+        let mut dirs = BTreeMap::new();
+        dirs.insert("v1".to_string(), state(1, 100));
+        dirs.insert("v2".to_string(), state(2, 200));
+        let mut promotes = BTreeMap::new();
+        promotes.insert("v1".to_string(), promote(300));
+        assert_eq!(current_image(&dirs, &promotes), Some("v1".to_string()));
+        // newer upload overrides promote
+        dirs.insert("v3".to_string(), state(3, 400));
+        assert_eq!(current_image(&dirs, &promotes), Some("v3".to_string()));
+        // promote of the image which isn't committed is ignored
+        promotes.insert("v4".to_string(), promote(500));
+        assert_eq!(current_image(&dirs, &promotes), Some("v3".to_string()));
+    }
+}
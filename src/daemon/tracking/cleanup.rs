@@ -1,4 +1,3 @@
-use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
@@ -9,29 +8,72 @@ use futures::stream::iter_ok;
 use futures::sync::mpsc::{UnboundedReceiver};
 use tk_easyloop::{timeout, spawn};
 
-use cleanup::{sort_out};
-use tracking::{Subsystem, BaseDir};
+use {VPath};
+use cleanup::{sort_out, sort_out_expired, Reason, Sorted};
+use config::Directory;
 use database::signatures::State;
+use disk::{Disk, TrashInfo};
+use hooks::Hook;
+use metadata::Meta;
+use proto::MaintenanceAction;
+use tracking::{Subsystem, BaseDir};
+use tracking::base_dir::{read_usage, add_pinned, add_current};
 
 
+pub type Plan = Sorted<(PathBuf, State, Reason)>;
+
 pub enum Command {
     Base(Arc<BaseDir>),
     IndexGc,
+    PurgeTrash(Arc<Directory>),
     Reschedule,
 }
 
-fn find_unused(sys: &Subsystem, dir: &Arc<BaseDir>,
-    all: BTreeMap<String, State>, keep_list: Vec<PathBuf>)
+/// Sorts out images in the base dir according to it's cleanup policy
+///
+/// Only expired images are sorted out if `auto_clean` is disabled.
+pub fn plan(dir: &Arc<BaseDir>, meta: &Meta, disk: &Disk)
+    -> Box<Future<Item=Plan, Error=Box<::std::error::Error + Send>>>
+{
+    let dir = dir.clone();
+    let meta = meta.clone();
+    let disk = disk.clone();
+    if !dir.config.auto_clean {
+        return Box::new(meta.scan_dir(&dir.path).map_err(boxerr)
+            .map(move |all| {
+                let images = all.into_iter().map(|(name, state)| {
+                    (dir.path.suffix().join(name), state)
+                }).collect();
+                sort_out_expired(images, SystemTime::now())
+            }));
+    }
+    Box::new(meta.scan_dir(&dir.path).map_err(boxerr)
+        .join5(meta.scan_committed(&dir.path).map_err(boxerr),
+               disk.read_keep_list(&dir.config).map_err(boxerr),
+               meta.read_pins(&dir.path).map_err(boxerr),
+               meta.read_promotes(&dir.path).map_err(boxerr))
+        .and_then(move |(all, committed, mut keep_list, pins, promotes)| {
+            add_pinned(&dir.path, &mut keep_list, &pins);
+            add_current(&dir.path, &dir.config, &mut keep_list,
+                        &committed, &promotes);
+            read_usage(&dir.path, &dir.config, &all,
+                       Vec::new(), &meta, &disk)
+                .map_err(boxerr)
+                .map(move |usage| {
+                    let images = all.into_iter().map(|(name, state)| {
+                        (dir.path.suffix().join(name), state)
+                    }).collect();
+                    sort_out(&dir.config, images, &keep_list, &usage)
+                })
+        }))
+}
+
+fn find_unused(sys: &Subsystem, dir: &Arc<BaseDir>, sorted: Plan)
     -> Vec<(PathBuf, State)>
 {
-    let images = all.into_iter().map(|(name, state)| {
-        (dir.path.suffix().join(name), state)
-    }).collect();
-    // TODO(tailhook) read keep list
-    let sorted = sort_out(&dir.config, images, &keep_list);
     if sorted.unused.len() > 0 {
-        info!("Sorted out {:?}, used {}, unused {}, keep_list: {}. {}",
-            dir.path, sorted.used.len(), sorted.unused.len(), keep_list.len(),
+        info!("Sorted out {:?}, used {}, unused {}. {}",
+            dir.path, sorted.used.len(), sorted.unused.len(),
             if sys.dry_cleanup() {
                 "Dry run... \
                  Will issue a cleanup in 10 minutes after startup."
@@ -39,11 +81,50 @@ fn find_unused(sys: &Subsystem, dir: &Arc<BaseDir>,
                 "Cleaning..."
             });
     } else {
-        debug!("Sorted out {:?}, used {}, unused {}, keep_list: {}. {}",
-            dir.path, sorted.used.len(), sorted.unused.len(), keep_list.len(),
+        debug!("Sorted out {:?}, used {}, unused {}. {}",
+            dir.path, sorted.used.len(), sorted.unused.len(),
             "Nothing to do.");
     }
-    sorted.unused
+    for &(ref path, ref state, reason) in &sorted.unused {
+        debug!("Evicting {:?} ({}) from {:?}: {:?}",
+            path, state.image, dir.path, reason);
+    }
+    sorted.unused.into_iter()
+        .map(|(path, state, _)| (path, state))
+        .collect()
+}
+
+/// Schedules cleanup or index gc requested by administrator
+pub fn trigger(sys: &Subsystem, action: MaintenanceAction,
+               path: Option<VPath>)
+{
+    let mut state = sys.state();
+    match action {
+        MaintenanceAction::IndexGc => {
+            info!("Index GC requested by administrator");
+            sys.cleanup.unbounded_send(Command::IndexGc)
+                .expect("can always send in cleanup channel");
+            state.deleted_since_index_gc = 0;
+        }
+        MaintenanceAction::Restore => {
+            // restore is run by the request itself, as its result is
+            // returned to the client
+            error!("Restore of {:?} can't be scheduled, ignoring", path);
+        }
+        MaintenanceAction::Cleanup => {
+            info!("Cleanup of {:?} requested by administrator", path);
+            for dir in state.base_dirs.values() {
+                if !dir.config.auto_clean {
+                    continue;
+                }
+                if path.as_ref().map(|p| p != &dir.path).unwrap_or(false) {
+                    continue;
+                }
+                sys.cleanup.unbounded_send(Command::Base(dir.clone()))
+                    .expect("can always send in cleanup channel");
+            }
+        }
+    }
 }
 
 fn boxerr<E: ::std::error::Error + Send + 'static>(e: E)
@@ -66,26 +147,57 @@ pub fn spawn_loop(rx: UnboundedReceiver<Command>, sys: &Subsystem) {
                     let sys3 = sys.clone();
                     let time = SystemTime::now();
                     Either::A(Either::A(
-                        sys.meta.scan_dir(&dir.path).map_err(boxerr)
-                        .join(sys.disk.read_keep_list(&dir.config)
-                              .map_err(boxerr))
-                        .and_then(move |(lst, keep_list)| {
-                            let u = find_unused(&sys1, &dir1, lst, keep_list);
+                        plan(dir, &sys.meta, &sys.disk)
+                        .and_then(move |sorted| {
+                            let u = find_unused(&sys1, &dir1, sorted);
+                            sys1.clear_in_use(&dir1.path);
                             iter_ok(u.into_iter())
                             .for_each(move |(path, state)| {
                                 let vpath = dir1.path.join(
                                         &path.file_name()
                                         .expect("valid image path"));
-                                warn!("Removing {:?}", vpath);
-                                sys2.dir_deleted(&vpath,
-                                    &state.image);
                                 let cfg = dir2.config.clone();
                                 let sys = sys2.clone();
-                                sys.meta.remove_state_file(vpath, time)
+                                sys.disk.find_users(&cfg, path.clone())
                                 .map_err(boxerr)
-                                .and_then(move |()| {
-                                    sys.disk.remove_image(&cfg, path)
-                                    .map_err(boxerr)
+                                .and_then(move |pids| {
+                                    if !pids.is_empty() {
+                                        warn!("Not removing {:?}, it's used \
+                                            by processes {:?}", vpath, pids);
+                                        sys.image_in_use(&vpath, &state.image,
+                                                         pids);
+                                        return Either::A(ok(()));
+                                    }
+                                    warn!("Removing {:?}", vpath);
+                                    sys.dir_deleted(&vpath, &state.image);
+                                    let image_id = state.image.clone();
+                                    let info = TrashInfo {
+                                        path: vpath.clone(),
+                                        state: state,
+                                        deleted: time,
+                                    };
+                                    let sys1 = sys.clone();
+                                    let cfg1 = cfg.clone();
+                                    Either::B(sys.meta
+                                        .remove_state_file(vpath.clone(),
+                                                           time)
+                                        .map_err(boxerr)
+                                        .and_then(move |()| {
+                                            if cfg.trash_retention.is_some() {
+                                                Either::A(sys.disk
+                                                    .trash_image(&cfg, path,
+                                                                 info)
+                                                    .map_err(boxerr))
+                                            } else {
+                                                Either::B(sys.disk
+                                                    .remove_image(&cfg, path)
+                                                    .map_err(boxerr))
+                                            }
+                                        })
+                                        .map(move |()| {
+                                            sys1.hooks.run(Hook::OnDelete,
+                                                &cfg1, &vpath, &image_id);
+                                        }))
                                 })
                                 // TODO(tailhook) clean the image itself
                             })
@@ -107,27 +219,62 @@ pub fn spawn_loop(rx: UnboundedReceiver<Command>, sys: &Subsystem) {
                 Command::Reschedule => {
                     let mut state = sys.state();
                     debug!("Rescheduling {} base dirs", state.base_dirs.len());
-                    if sys.config.aggressive_index_gc ||
-                        state.should_run_index_gc()
-                    {
+                    if state.should_run_index_gc() {
                         sys.cleanup.unbounded_send(Command::IndexGc)
                             .expect("can always send in cleanup channel");
                         state.deleted_since_index_gc = 0;
                     }
                     for dir in state.base_dirs.values() {
-                        if dir.config.auto_clean {
+                        // level 0 image is the base dir, it's never removed
+                        if dir.config.num_levels == 0 {
+                            continue;
+                        }
+                        if dir.config.auto_clean || dir.has_expiring() {
                             sys.cleanup
                                 .unbounded_send(Command::Base(dir.clone()))
                                 .expect("can always send in cleanup channel");
                         }
                     }
+                    for cfg in sys.config.dirs.get().values() {
+                        if cfg.trash_retention.is_some() {
+                            sys.cleanup
+                                .unbounded_send(
+                                    Command::PurgeTrash(cfg.clone()))
+                                .expect("can always send in cleanup channel");
+                        }
+                    }
                     sys.cleanup.unbounded_send(Command::Reschedule)
                         .expect("can always send in cleanup channel");
                     Either::A(Either::B(ok(())))
                 }
+                Command::PurgeTrash(ref cfg) => {
+                    let dir = cfg.directory.clone();
+                    Either::B(Either::B(sys.disk.purge_trash(cfg)
+                        .then(move |res| {
+                            match res {
+                                Ok(0) => {}
+                                Ok(n) => {
+                                    info!("Purged {} images from trash \
+                                           of {:?}", n, dir);
+                                }
+                                Err(e) => {
+                                    error!("Purging trash of {:?} failed: {}",
+                                        dir, e);
+                                }
+                            }
+                            Ok(())
+                        })))
+                }
                 Command::IndexGc => {
                     let sys = sys.clone();
-                    Either::B(sys.meta.index_gc()
+                    let meta = sys.meta.clone();
+                    Either::B(Either::A(sys.disk.list_versions()
+                        .map_err(boxerr)
+                        .and_then(move |versions| {
+                            meta.index_gc(versions.into_iter()
+                                .map(|v| v.state.image).collect())
+                            .map_err(boxerr)
+                        })
                         .then(move |res| {
                             match res {
                                 Ok(()) => {}
@@ -138,7 +285,7 @@ pub fn spawn_loop(rx: UnboundedReceiver<Command>, sys: &Subsystem) {
                             let mut state = sys.state();
                             state.last_index_gc = SystemTime::now();
                             Ok(())
-                        }))
+                        })))
                 }
             }
         })
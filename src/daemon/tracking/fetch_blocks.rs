@@ -85,6 +85,7 @@ impl StateMachine for FetchBlock {
                                 ctx.downloading.report_block(&data);
                                 Writing(ctx.sys.disk.write_block(
                                     ctx.image.clone(),
+                                    blk.entry,
                                     blk.path.clone(),
                                     blk.offset,
                                     data))
@@ -1,12 +1,16 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use futures::{Future};
+use futures::future::{Either, ok};
 use tk_easyloop::spawn;
 use void::unreachable;
 
-use disk::{Image};
+use disk::{self, Image};
+use hooks::validate;
 use metrics::Counter;
-use tracking::{Subsystem, Downloading, DOWNLOADING};
+use tracking::{Subsystem, Downloading, Index, DOWNLOADING};
 use tracking::fetch_blocks::FetchBlocks;
 
 
@@ -44,30 +48,26 @@ pub fn start(sys: &Subsystem, cmd: Downloading) {
                 sys2.dir_aborted(&cmd2, "cant_fetch_index")
             }));
     })
-    .and_then(|index| {
+    .and_then(move |index| {
         debug!("Got index {:?}", cmd.image_id);
-        spawn(sys.disk.start_image(
-                cmd.config.directory.clone(),
-                index.clone(),
-                cmd.virtual_path.clone())
+        spawn(sys.meta.check_quota(&cmd.virtual_path, &index)
             .then(move |res| -> Result<(), ()> {
                 match res {
-                    Ok(img) => {
-                        let img = Arc::new(img);
-                        debug!("Created dir for {:?}", cmd.virtual_path);
-                        cmd.index_fetched(&img.index);
-                        sys.peers.notify_progress(&cmd.virtual_path,
-                            &cmd.image_id, cmd.mask.get(),
-                            sys.remote.has_image_source(&cmd.image_id));
-                        hardlink_blocks(sys.clone(), img, cmd);
+                    Ok(None) => check_policy(sys, index, cmd),
+                    Ok(Some(reason)) => {
+                        error!("Image {} doesn't fit quota of {:?}: {}",
+                            cmd.image_id, cmd.virtual_path, reason);
+                        spawn(sys.meta.dir_aborted(&cmd.virtual_path)
+                            .map_err(|e| unreachable(e))
+                            .map(move |()| sys.dir_aborted(&cmd, reason)));
                     }
                     Err(e) => {
-                        error!("Can't start image {:?}: {}",
+                        error!("Can't check quota of {:?}: {}",
                             cmd.virtual_path, e);
                         spawn(sys.meta.dir_aborted(&cmd.virtual_path)
                             .map_err(|e| unreachable(e))
                             .map(move |()| {
-                                sys.dir_aborted(&cmd, "cant_create_directory")
+                                sys.dir_aborted(&cmd, "internal_error")
                             }));
                     }
                 }
@@ -77,6 +77,70 @@ pub fn start(sys: &Subsystem, cmd: Downloading) {
     }));
 }
 
+fn check_policy(sys: Subsystem, index: Index, cmd: Arc<Downloading>) {
+    spawn(sys.disk.check_index(&cmd.config, &index)
+        .map_err(|e| unreachable(e))
+        .map(move |reject| match reject {
+            None => validate_image(sys, index, cmd),
+            Some(reason) => {
+                spawn(sys.meta.dir_aborted(&cmd.virtual_path)
+                    .map_err(|e| unreachable(e))
+                    .map(move |()| sys.dir_aborted(&cmd, reason)));
+            }
+        }));
+}
+
+fn validate_image(sys: Subsystem, index: Index, cmd: Arc<Downloading>) {
+    if cmd.config.validate_command.is_none() {
+        return start_image(sys, index, cmd);
+    }
+    spawn(validate(&cmd.config, &cmd.virtual_path, &index)
+        .then(move |res| -> Result<(), ()> {
+            let reason = match res {
+                Ok(None) => {
+                    start_image(sys, index, cmd);
+                    return Ok(());
+                }
+                Ok(Some(reason)) => reason,
+                Err(_) => "validation_error",
+            };
+            spawn(sys.meta.dir_aborted(&cmd.virtual_path)
+                .map_err(|e| unreachable(e))
+                .map(move |()| sys.dir_aborted(&cmd, reason)));
+            Ok(())
+        }));
+}
+
+fn start_image(sys: Subsystem, index: Index, cmd: Arc<Downloading>) {
+    spawn(sys.disk.start_image(
+            cmd.config.directory.clone(),
+            index.clone(),
+            cmd.virtual_path.clone())
+        .then(move |res| -> Result<(), ()> {
+            match res {
+                Ok(img) => {
+                    let img = Arc::new(img);
+                    debug!("Created dir for {:?}", cmd.virtual_path);
+                    cmd.index_fetched(&img.index);
+                    sys.peers.notify_progress(&cmd.virtual_path,
+                        &cmd.image_id, cmd.mask.get(),
+                        sys.remote.has_image_source(&cmd.image_id));
+                    hardlink_blocks(sys.clone(), img, cmd);
+                }
+                Err(e) => {
+                    error!("Can't start image {:?}: {}",
+                        cmd.virtual_path, e);
+                    spawn(sys.meta.dir_aborted(&cmd.virtual_path)
+                        .map_err(|e| unreachable(e))
+                        .map(move |()| {
+                            sys.dir_aborted(&cmd, "cant_create_directory")
+                        }));
+                }
+            }
+            Ok(())
+        }));
+}
+
 fn hardlink_blocks(sys: Subsystem, image: Arc<Image>, cmd: Arc<Downloading>) {
     let sys2 = sys.clone();
     let sys3 = sys.clone();
@@ -98,13 +162,51 @@ fn hardlink_blocks(sys: Subsystem, image: Arc<Image>, cmd: Arc<Downloading>) {
             sys3.disk.check_and_hardlink(sources, &image2)
                 .map_err(|e| unreachable(e))
         })
-        .map(move |hardlink_paths| {
-            cmd.fill_blocks(&image.index, hardlink_paths);
-            fetch_blocks(sys.clone(), image, cmd);
+        .and_then(move |hardlink_paths| {
+            let bytes = image.index.bytes_except(&hardlink_paths);
+            sys.disk.check_free_space(&cmd.config, bytes)
+                .map_err(|e| unreachable(e))
+                .map(move |fits| {
+                    if fits {
+                        copy_blocks(sys.clone(), image, cmd, hardlink_paths);
+                    } else {
+                        error!("Not enough space for {} to {:?}",
+                            cmd.image_id, cmd.virtual_path);
+                        // TODO(tailhook) remove temporary directory
+                        spawn(sys.meta.dir_aborted(&cmd.virtual_path)
+                            .map_err(|e| unreachable(e))
+                            .map(move |()| {
+                                sys.dir_aborted(&cmd, "insufficient_space")
+                            }));
+                    }
+                })
         })
         );
 }
 
+/// Copies blocks which exist in local images instead of fetching them
+fn copy_blocks(sys: Subsystem, image: Arc<Image>, cmd: Arc<Downloading>,
+    hardlink_paths: HashSet<PathBuf>)
+{
+    let disk = sys.disk.clone();
+    let image2 = image.clone();
+    spawn(sys.meta.local_blocks(&cmd.virtual_path, &image.index,
+                                cmd.replacing, hardlink_paths.clone())
+        .or_else(|e| -> Result<_, ()> {
+            error!("Error finding local blocks: {}", e);
+            Ok(Vec::new())
+        })
+        .and_then(move |blocks| {
+            disk.copy_local_blocks(blocks, &image2)
+                .map_err(|e| unreachable(e))
+        })
+        .map(move |copied| {
+            cmd.fill_blocks(&image.index, hardlink_paths, copied,
+                            &image.written);
+            fetch_blocks(sys, image, cmd);
+        }));
+}
+
 fn fetch_blocks(sys: Subsystem, image: Arc<Image>, cmd: Arc<Downloading>)
 {
     let sys1 = sys.clone();
@@ -125,14 +227,35 @@ fn fetch_blocks(sys: Subsystem, image: Arc<Image>, cmd: Arc<Downloading>)
                 }));
         })
         .and_then(move |()| {
-            sys1.disk.commit_image(image)
+            let previous = if cmd1.replacing &&
+                cmd1.config.keep_previous_versions > 0
+            {
+                let path = cmd1.virtual_path.clone();
+                Either::A(sys1.meta.read_state(&cmd1.virtual_path)
+                    .or_else(move |e| {
+                        error!("Can't read state of {:?}, previous version \
+                            will not be kept: {}", path, e);
+                        Ok(None)
+                    }))
+            } else {
+                Either::B(ok(None))
+            };
+            let disk = sys1.disk.clone();
+            let config = cmd1.config.clone();
+            previous.and_then(move |previous| {
+                disk.commit_image(&config, image, previous)
+            })
             .map_err(move |e| {
                 error!("Error commiting image: {}", e);
+                let reason = match e {
+                    disk::Error::Policy(_, reason) => reason,
+                    _ => "commit_error",
+                };
                 // TODO(tailhook) remove temporary directory
                 spawn(sys1.meta.dir_aborted(&cmd1.virtual_path)
                     .map_err(|e| unreachable(e))
                     .map(move |()| {
-                        sys1.dir_aborted(&cmd1, "commit_error")
+                        sys1.dir_aborted(&cmd1, reason)
                     }));
             })
         })
@@ -222,6 +222,17 @@ impl Deref for Index {
     }
 }
 
+#[cfg(test)]
+impl Index {
+    /// Wraps the data without registering it in any `Indexes`
+    pub fn from_data(data: IndexData) -> Index {
+        Index(Arc::new(Inner {
+            data: data,
+            registry: Arc::new(Mutex::new(Registry::new(), "image_registry")),
+        }))
+    }
+}
+
 impl Drop for Inner {
     fn drop(&mut self) {
         let mut lock = self.registry.lock();
@@ -6,6 +6,7 @@ mod fetch_index;
 mod progress;
 mod reconciliation;
 mod rpc;
+mod scrub;
 
 use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet};
 use std::net::SocketAddr;
@@ -15,20 +16,21 @@ use std::sync::{Arc};
 use std::time::{Instant, SystemTime, Duration};
 
 use futures::{Future, Stream, Async};
-use futures::future::{Either, ok};
+use futures::future::{Either, ok, join_all};
 use futures::stream::iter_ok;
 use futures::sync::mpsc::{unbounded, UnboundedSender, UnboundedReceiver};
 use futures_cpupool::CpuFuture;
 use tk_easyloop::{spawn, timeout, interval};
 
-use proto::{Hash};
+use proto::{Hash, MaintenanceAction};
 use index::{ImageId};
 use {VPath};
 use machine_id::{MachineId};
 use metrics::{List, Metric, Integer, Counter};
-use config::{Config};
+use config::{Config, Changes};
 use disk::Disk;
-use failure_tracker::{Failures, Policy};
+use failure_tracker::{Failures};
+use hooks::{Hooks, Hook};
 use mask::{AtomicMask, Mask};
 use metadata::{self, Meta};
 use named_mutex::{Mutex, MutexGuard};
@@ -40,6 +42,8 @@ use tracking::reconciliation::ReconPush;
 pub use self::fetch_index::Index;
 pub use self::progress::{Downloading, Slices};
 pub use self::base_dir::BaseDir;
+pub use self::cleanup::Plan;
+pub use self::scrub::Damaged;
 
 const DELETED_RETENTION: u64 = 300_000;  // 5 min
 const AVOID_DOWNLOAD: u64 = 120_000;  // do not try delete image again in 2 min
@@ -84,6 +88,18 @@ pub struct State {
     /// gossip subsystem.
     recently_received: HashMap<VPath, HashMap<SocketAddr, Instant>>,
     watched: HashMap<VPath, WatchedStatus>,
+    in_use: HashMap<VPath, InUse>,
+    /// Next scrub pass of each directory config
+    scrub_due: HashMap<String, Instant>,
+    damaged: HashMap<VPath, Damaged>,
+}
+
+/// Image which cleanup didn't delete because processes use it
+#[derive(Clone)]
+pub struct InUse {
+    pub image_id: ImageId,
+    pub pids: Vec<u32>,
+    pub checked: SystemTime,
 }
 
 pub struct ShortProgress {
@@ -98,6 +114,7 @@ pub struct ShortBasedir {
     pub num_subdirs: usize,
     pub num_downloading: usize,
     pub last_scan: SystemTime,
+    pub pinned: BTreeSet<String>,
 }
 
 #[derive(Clone)]
@@ -113,6 +130,7 @@ struct Inner {
     disk: Disk,
     remote: Remote,
     peers: Peers,
+    hooks: Hooks,
 }
 
 #[derive(Clone)]
@@ -130,6 +148,7 @@ pub struct Data {
     remote: Remote,
     tracking: Tracking,
     peers: Peers,
+    hooks: Hooks,
     dry_cleanup: AtomicBool,
 }
 
@@ -142,6 +161,7 @@ pub struct TrackingInit {
 pub enum Command {
     FetchDir(Downloading),
     Reconcile(ReconPush),
+    Maintenance(MaintenanceAction, Option<VPath>),
 }
 
 fn index_gc_at_start() -> Duration {
@@ -169,11 +189,15 @@ impl Tracking {
                 reconciling: HashMap::new(),
                 recently_received: HashMap::new(),
                 watched: HashMap::new(),
+                in_use: HashMap::new(),
+                scrub_due: HashMap::new(),
+                damaged: HashMap::new(),
             }, "tracking_state")),
             meta: meta.clone(),
             disk: disk.clone(),
             remote: remote.clone(),
             peers: peers.clone(),
+            hooks: Hooks::new(),
             cmd_chan: ctx,
             rescan_chan: rtx,
         }));
@@ -187,7 +211,7 @@ impl Tracking {
     pub fn reconcile_dir(&self, path: VPath, hash: Hash,
         peer_addr: SocketAddr, peer_id: MachineId)
     {
-        if self.0.config.dirs.get(path.key()).is_none() {
+        if self.0.config.dir(path.key()).is_none() {
             return;
         }
         let mut state = self.state();
@@ -224,13 +248,19 @@ impl Tracking {
         }
     }
     pub fn fetch_dir(&self, path: VPath, image: ImageId, replace: bool) {
-        let cfg = self.0.config.dirs.get(path.key())
-            .expect("config should exist");
+        let cfg = match self.0.config.dir(path.key()) {
+            Some(cfg) => cfg,
+            None => {
+                warn!("Config for {:?} is removed, not fetching {}",
+                    path, image);
+                return;
+            }
+        };
         self.send(Command::FetchDir(Downloading {
             replacing: replace,
             virtual_path: path,
             image_id: image,
-            config: cfg.clone(),
+            config: cfg,
             mask: AtomicMask::new(),
             slices: Slices::new(),
             index_fetched: AtomicBool::new(false),
@@ -254,6 +284,45 @@ impl Tracking {
             }
         }
     }
+    /// Applies reloaded directory configs
+    ///
+    /// Base dirs of changed and removed configs are forgotten, changed ones
+    /// are rescanned with the new config. Downloads which are already
+    /// running continue with the config they were started with.
+    pub fn config_changed(&self, changes: &Changes) {
+        let forget = |path: &VPath| {
+            changes.changed.iter().chain(&changes.removed)
+                .any(|name| name == path.key())
+        };
+        let forgotten = {
+            let mut state = self.state();
+            let (dropped, kept) = state.base_dir_list.drain(..)
+                .partition::<Vec<_>, _>(|b| forget(&b.path));
+            state.base_dir_list = kept;
+            for bdir in &dropped {
+                state.base_dirs.remove(&bdir.path);
+                base_dir::NUM_DIRS.decr(bdir.subdirs() as i64);
+            }
+            state.in_use.retain(|path, _| !forget(path));
+            base_dir::BASE_DIRS.set(state.base_dirs.len() as i64);
+            dropped
+        };
+        for name in &changes.removed {
+            warn!("Config {:?} is removed, new uploads are rejected", name);
+        }
+        for bdir in forgotten {
+            if changes.changed.iter().any(|name| name == bdir.path.key()) {
+                self.rescan_dir(bdir.path.clone());
+            }
+        }
+        if !changes.added.is_empty() {
+            info!("New configs: {:?}", changes.added);
+            let trk = self.clone();
+            spawn(self.0.meta.scan_base_dirs_of(changes.added.clone(),
+                    move |dir| trk.scan_dir(dir))
+                .map_err(|e| error!("Error scanning new base dirs: {}", e)));
+        }
+    }
     pub fn pick_random_dir(&self) -> Option<(VPath, Hash)> {
         let state = self.state();
         thread_rng().choose(&state.base_dir_list)
@@ -271,6 +340,11 @@ impl Tracking {
         self.0.rescan_chan.unbounded_send((path, Instant::now(), true))
             .expect("rescan thread is alive");
     }
+    fn rescan_dir(&self, path: VPath) {
+        SCAN_QUEUE.incr(1);
+        self.0.rescan_chan.unbounded_send((path, Instant::now(), false))
+            .expect("rescan thread is alive");
+    }
     pub fn remote(&self) -> &Remote {
         &self.0.remote
     }
@@ -279,6 +353,10 @@ impl Tracking {
         &self.0.peers
     }
     // only for http
+    pub fn disk(&self) -> &Disk {
+        &self.0.disk
+    }
+    // only for http
     pub fn meta(&self) -> &Meta {
         &self.0.meta
     }
@@ -286,6 +364,10 @@ impl Tracking {
     pub fn config(&self) -> &Arc<Config> {
         &self.0.config
     }
+    // only for http
+    pub fn hooks(&self) -> &Hooks {
+        &self.0.hooks
+    }
     pub fn get_in_progress(&self) -> BTreeMap<VPath, ShortProgress> {
         let mut res = BTreeMap::new();
         for inp in self.state().in_progress.values() {
@@ -308,6 +390,7 @@ impl Tracking {
                 num_downloading: inp.downloading(),
                 last_scan: SystemTime::now() +
                     Instant::now().duration_since(inp.last_scan()),
+                pinned: inp.pinned(),
             });
         }
         return res;
@@ -315,6 +398,33 @@ impl Tracking {
     pub fn get_deleted(&self) -> Vec<(VPath, ImageId)> {
         self.state().recently_deleted.keys().cloned().collect()
     }
+    pub fn get_in_use(&self) -> BTreeMap<VPath, InUse> {
+        self.state().in_use.iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+    // only for http
+    pub fn get_damaged(&self) -> BTreeMap<VPath, Damaged> {
+        self.state().damaged.iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+    // only for http
+    pub fn get_cleanup_plan(&self)
+        -> Box<Future<Item=BTreeMap<VPath, Plan>,
+                      Error=Box<::std::error::Error + Send>>>
+    {
+        let dirs = self.state().base_dirs.values()
+            .filter(|d| d.config.auto_clean)
+            .cloned()
+            .collect::<Vec<_>>();
+        let meta = self.0.meta.clone();
+        let disk = self.0.disk.clone();
+        Box::new(join_all(dirs.into_iter().map(move |dir| {
+            cleanup::plan(&dir, &meta, &disk)
+                .map(move |plan| (dir.path.clone(), plan))
+        }).collect::<Vec<_>>()).map(|v| v.into_iter().collect()))
+    }
     pub fn get_watching(&self) -> BTreeSet<VPath> {
         self.remote().get_watching()
     }
@@ -346,9 +456,9 @@ impl Tracking {
                 }
             }).collect()
     }
-    pub fn get_connection_by_mask<P: Policy>(&self,
+    pub fn get_connection_by_mask(&self,
         vpath: &VPath, id: &ImageId, mask: Mask,
-        failures: &Failures<SocketAddr, P>)
+        failures: &Failures<SocketAddr>)
         -> Option<Connection>
     {
         // First try hosts we certainly know has needed bit, but in our
@@ -445,9 +555,39 @@ impl Subsystem {
         self.0.rescan_chan.unbounded_send((path, Instant::now(), false))
             .expect("can always send in rescan channel");
     }
+    fn image_in_use(&self, path: &VPath, image_id: &ImageId,
+        pids: Vec<u32>)
+    {
+        self.state().in_use.insert(path.clone(), InUse {
+            image_id: image_id.clone(),
+            pids: pids,
+            checked: SystemTime::now(),
+        });
+    }
+    fn clear_in_use(&self, base_dir: &VPath) {
+        self.state().in_use.retain(|path, _| &path.parent() != base_dir);
+    }
+    fn image_damaged(&self, path: &VPath, damaged: Damaged) {
+        let mut state = self.state();
+        state.damaged.insert(path.clone(), damaged);
+        scrub::DAMAGED.set(state.damaged.len() as i64);
+    }
+    fn image_repaired(&self, path: &VPath) {
+        if let Some(damaged) = self.state().damaged.get_mut(path) {
+            damaged.repaired = true;
+        }
+    }
+    fn image_intact(&self, path: &VPath) {
+        let mut state = self.state();
+        state.damaged.remove(path);
+        scrub::DAMAGED.set(state.damaged.len() as i64);
+    }
     pub fn dir_deleted(&self, path: &VPath, image_id: &ImageId) {
         let mut state = self.state();
         state.deleted_since_index_gc += 1;
+        state.in_use.remove(path);
+        state.damaged.remove(path);
+        scrub::DAMAGED.set(state.damaged.len() as i64);
         state.recently_deleted
             .insert((path.clone(), image_id.clone()), Instant::now());
     }
@@ -481,6 +621,8 @@ impl Subsystem {
         {
             let mut state = self.state();
             state.in_progress.remove(&cmd.virtual_path);
+            state.damaged.remove(&cmd.virtual_path);
+            scrub::DAMAGED.set(state.damaged.len() as i64);
             state.watched
                 .insert(cmd.virtual_path.clone(),
                     WatchedStatus::Complete(cmd.image_id.clone()));
@@ -492,6 +634,9 @@ impl Subsystem {
             &cmd.image_id, &cmd.virtual_path);
         self.peers.notify_complete(
             &cmd.virtual_path, &cmd.image_id);
+        self.hooks.run(
+            if cmd.replacing { Hook::OnReplace } else { Hook::OnCommit },
+            &cmd.config, &cmd.virtual_path, &cmd.image_id);
         self.rescan_dir(cmd.virtual_path.parent());
     }
 }
@@ -508,6 +653,7 @@ pub fn start(init: TrackingInit) -> Result<(), String> // actually void
         state: tracking.0.state.clone(),
         remote: tracking.0.remote.clone(),
         peers: tracking.0.peers.clone(),
+        hooks: tracking.0.hooks.clone(),
         tracking: tracking.clone(),
         cleanup: ctx,
         rescan_chan: tracking.0.rescan_chan.clone(),
@@ -524,12 +670,16 @@ pub fn start(init: TrackingInit) -> Result<(), String> // actually void
         .map(move |()| sys4.start_cleanup())
         .map_err(|e| error!("Error during first scan: {}", e)));
     cleanup::spawn_loop(crx, &sys);
+    scrub::spawn_loop(&sys);
     spawn(cmd_chan
         .for_each(move |command| {
             use self::Command::*;
             match command {
                 FetchDir(info) => fetch_dir::start(&sys2, info),
                 Reconcile(info) => reconciliation::start(&sys2, info),
+                Maintenance(action, path) => {
+                    cleanup::trigger(&sys2, action, path)
+                }
             }
             Ok(())
         }));
@@ -542,18 +692,51 @@ pub fn start(init: TrackingInit) -> Result<(), String> // actually void
                 // race condition between scan and enqueue. just skip it
                 return Either::A(ok(()))
             }
-            let config = sys.config.dirs.get(path.key())
-                .expect("only scan configured dirs")
-                .clone();
+            let config = match sys.config.dir(path.key()) {
+                Some(config) => config,
+                None => {
+                    // config is removed by reload, base dir is forgotten
+                    debug!("No config for {:?}, skipping scan", path);
+                    return Either::A(ok(()));
+                }
+            };
             let sys = sys.clone();
             let scan_time = Instant::now();
+            // pending downloads must be registered before scan, otherwise
+            // their `.new.state` files are removed as stale
+            let pending = if first_time {
+                Either::A(base_dir::resume_pending(&path, &config, &sys))
+            } else {
+                Either::B(ok(Vec::new()))
+            };
+            let scan_path = path.clone();
+            let scan_config = config.clone();
+            let meta = meta.clone();
+            let disk = sys.disk.clone();
             Either::B(
-                base_dir::scan(&path, &config, &meta, &sys.disk)
+                pending.and_then(move |pending| {
+                    base_dir::scan(&scan_path, &scan_config, &meta, &disk)
+                    .map(move |bdir| (bdir, pending))
+                })
                 .then(move |res| match res {
-                    Ok(bdir) => {
+                    Ok((bdir, pending)) => {
                         if first_time {
-                            let dirs = bdir.dirs.clone();
+                            let dirs = bdir.dirs.iter()
+                                .filter(|&(name, _)| {
+                                    let vpath = path.join(name);
+                                    !pending.iter().any(|p| p.0 == vpath)
+                                })
+                                .map(|(name, state)| {
+                                    (name.clone(), state.clone())
+                                })
+                                .collect::<Vec<_>>();
                             BaseDir::commit_scan(bdir, &config, scan_time, &sys);
+                            for (vpath, image, replacing) in pending {
+                                warn!("Resuming download of {:?}: {}",
+                                    vpath, image);
+                                sys.tracking.fetch_dir(
+                                    vpath, image, replacing);
+                            }
                             Either::B(iter_ok(dirs)
                                 .for_each(move |(dir, state)| {
                                     let path = path.join(dir);
@@ -681,6 +864,7 @@ pub fn metrics() -> List {
     let images = "tracking.images";
     let recon = "tracking.reconciliation";
     let scan = "tracking.scan";
+    let scrub = "tracking.scrub";
     vec![
         (Metric(indexes, "cached"), &*fetch_index::INDEXES),
         (Metric(indexes, "fetched"), &*fetch_index::FETCHED),
@@ -689,6 +873,7 @@ pub fn metrics() -> List {
         (Metric(images, "downloading"), &*DOWNLOADING),
         (Metric(images, "download_failed"), &*FAILED),
         (Metric(images, "blocks_failed"), &*fetch_dir::BLOCK_FAILURES),
+        (Metric(images, "zero_blocks_skipped"), &*progress::ZERO_BLOCKS),
         (Metric(images, "base_dirs"), &*base_dir::BASE_DIRS),
         (Metric(images, "tracking"), &*base_dir::NUM_DIRS),
         (Metric(recon, "hashes_processed"), &*reconciliation::PROCESSED),
@@ -698,5 +883,9 @@ pub fn metrics() -> List {
         (Metric(recon, "append_rejected"), &*reconciliation::APPEND_REJECTED),
         (Metric(recon, "replace_rejected"), &*reconciliation::REPLACE_REJECTED),
         (Metric(scan, "queue_size"), &*SCAN_QUEUE),
+        (Metric(scrub, "images"), &*scrub::IMAGES),
+        (Metric(scrub, "damaged"), &*scrub::DAMAGED),
+        (Metric(scrub, "repaired"), &*scrub::REPAIRED),
+        (Metric(scrub, "repair_failed"), &*scrub::REPAIR_FAILED),
     ]
 }
@@ -9,6 +9,7 @@ use std::sync::{Arc};
 use rand::{thread_rng, Rng};
 
 use index::{ImageId};
+use metrics::Counter;
 use {VPath};
 use blocks::BlockHash;
 use config::Directory;
@@ -21,10 +22,16 @@ use tracking::{BlockData};
 
 pub const MAX_SLICES: usize = 15;
 
+lazy_static! {
+    pub static ref ZERO_BLOCKS: Counter = Counter::new();
+}
+
 
 #[derive(Debug, Clone)]
 pub struct Block {
     pub hash: BlockHash,
+    /// Number of the file in the index
+    pub entry: usize,
     pub path: Arc<PathBuf>,
     pub offset: u64,
 }
@@ -126,14 +133,24 @@ impl Downloading {
         self.blocks_total.store(index.blocks_total as usize, Relaxed);
         self.index_fetched.store(true, Relaxed);
     }
-    pub fn fill_blocks(&self, index: &Index, hardlinks: HashSet<PathBuf>) {
-        let blocks = index.entries.iter()
-            .flat_map(|entry| {
+    /// Splits blocks which need to be fetched into slices
+    ///
+    /// Full blocks of zeros are skipped, they are left as holes in the file
+    /// and the file is extended to its size on commit. Blocks which are
+    /// already copied from local images or `written` before restart are
+    /// skipped too.
+    pub fn fill_blocks(&self, index: &Index, hardlinks: HashSet<PathBuf>,
+        copied: HashSet<BlockHash>, written: &HashSet<(usize, u64)>)
+    {
+        let zero_block = BlockHash::hash_bytes(
+            &vec![0u8; index.block_size as usize]);
+        let blocks = index.entries.iter().enumerate()
+            .flat_map(|(entry_no, entry)| {
                 use dir_signature::v1::Entry::*;
                 match *entry {
                     Dir(..) => Vec::new(),
                     Link(..) => Vec::new(),
-                    File { ref hashes, ref path, .. } => {
+                    File { ref hashes, ref path, size, .. } => {
                         if hardlinks.contains(path) {
                             return Vec::new();
                         }
@@ -142,10 +159,22 @@ impl Downloading {
                         for (i, hash) in hashes.iter().enumerate() {
                             let hash = BlockHash::from_bytes(hash)
                                 .expect("valid hash type");
+                            let end = (i as u64 + 1)*index.block_size;
+                            if end <= size && hash == zero_block {
+                                ZERO_BLOCKS.incr(1);
+                                continue;
+                            }
+                            let offset = (i as u64)*index.block_size;
+                            if copied.contains(&hash) ||
+                                written.contains(&(entry_no, offset))
+                            {
+                                continue;
+                            }
                             result.push(Block {
                                 hash: hash,
+                                entry: entry_no,
                                 path: arc.clone(),
-                                offset: (i as u64)*index.block_size,
+                                offset: offset,
                             });
                         }
                         result
@@ -180,3 +209,80 @@ impl Downloading {
         self.slices.slices()
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+    use {VPath};
+    use blocks::BlockHash;
+    use config::Directory;
+    use index::ImageId;
+    use index_cache::IndexData;
+    use mask::AtomicMask;
+    use tracking::fetch_index::Index;
+    use super::{Downloading, Slices};
+
+    const BLOCK: usize = 65536;
+
+    fn downloading() -> Downloading {
+        Downloading {
+            virtual_path: VPath::from("/dir/v1"),
+            replacing: false,
+            image_id: ImageId::from(vec![1; 32]),
+            config: Arc::new(Directory::test_default("/nowhere")),
+            slices: Slices::new(),
+            mask: AtomicMask::new(),
+            index_fetched: AtomicBool::new(false),
+            bytes_total: AtomicUsize::new(0),
+            bytes_fetched: AtomicUsize::new(0),
+            blocks_total: AtomicUsize::new(0),
+            blocks_fetched: AtomicUsize::new(0),
+            stalled: AtomicBool::new(false),
+        }
+    }
+
+    fn offsets(down: &Downloading) -> Vec<(PathBuf, u64)> {
+        let mut result = down.slices().iter()
+            .flat_map(|s| s.blocks.iter())
+            .map(|b| ((*b.path).clone(), b.offset))
+            .collect::<Vec<_>>();
+        result.sort();
+        return result;
+    }
+
+    #[test]
+    fn skip_zero_blocks() {
+        // zero block in the middle, partial zero block at the end
+        let mut data = vec![1u8; BLOCK];
+        data.extend(vec![0u8; BLOCK]);
+        data.extend(vec![2u8; BLOCK]);
+        data.extend(vec![0u8; 100]);
+        let index = Index::from_data(IndexData::from_files(
+            &ImageId::from(vec![1; 32]), BLOCK as u64,
+            &[("file", &data[..])]));
+        let down = downloading();
+        down.fill_blocks(&index, HashSet::new(), HashSet::new(),
+                         &HashSet::new());
+        let file = PathBuf::from("/file");
+        // partial zero block is fetched, disk skips writing it though
+        assert_eq!(offsets(&down), vec![
+            (file.clone(), 0),
+            (file.clone(), 2*BLOCK as u64),
+            (file.clone(), 3*BLOCK as u64),
+        ]);
+
+        // copied and already written blocks are skipped too
+        let down = downloading();
+        let mut copied = HashSet::new();
+        copied.insert(BlockHash::hash_bytes(&vec![2u8; BLOCK]));
+        let mut written = HashSet::new();
+        written.insert((1, 0));
+        down.fill_blocks(&index, HashSet::new(), copied, &written);
+        assert_eq!(offsets(&down), vec![(file.clone(), 3*BLOCK as u64)]);
+    }
+}
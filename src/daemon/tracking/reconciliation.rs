@@ -1,18 +1,20 @@
-use std::collections::{HashMap};
+use std::collections::{HashMap, BTreeMap};
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 
-use cleanup::{sort_out};
+use cleanup::{sort_out, is_expired, Usage};
 use machine_id::MachineId;
 use proto::Hash;
-use proto::{BaseDirState, AppendDir, ReplaceDir, GetBaseDir};
+use proto::{BaseDirState, AppendDir, ReplaceDir, GetBaseDir, PinState};
+use proto::{PromoteState};
 use proto::{RequestClient};
 use proto::Error;
 use tracking::Subsystem;
+use tracking::base_dir::{read_usage, add_pinned};
 use metrics::{Counter, Integer};
 use {VPath};
 
-use futures::future::{Future, Loop, loop_fn};
+use futures::future::{Future, Loop, loop_fn, Either, ok};
 use tk_easyloop::spawn;
 use metadata::{Upload, Accept};
 use tracking::RECONCILE_BASEDIR_THROTTLE;
@@ -34,12 +36,85 @@ pub struct ReconPush {
     pub initial_machine_id: MachineId,
 }
 
+/// Stores pins of the peer which are newer than ours
+///
+/// Local `pins` are updated with the ones that are going to be stored.
+fn merge_pins(sys: &Subsystem, path: &VPath,
+    pins: &mut BTreeMap<String, PinState>,
+    remote: &BTreeMap<String, PinState>)
+{
+    for (name, rpin) in remote {
+        if let Some(pin) = pins.get(name) {
+            if pin.timestamp >= rpin.timestamp {
+                continue;
+            }
+        }
+        pins.insert(name.clone(), rpin.clone());
+        let sys = sys.clone();
+        let vpath = path.join(name);
+        let pinned = rpin.pinned;
+        spawn(sys.meta.set_pin(&vpath, rpin.clone(), false)
+            .then(move |result| {
+                match result {
+                    Ok(Upload::Accepted(Accept::New)) => {
+                        info!("{} {:?} by peer",
+                            if pinned { "Pinned" } else { "Unpinned" },
+                            vpath);
+                        sys.rescan_dir(vpath.parent());
+                    }
+                    Ok(Upload::Accepted(_)) => {}
+                    Ok(Upload::Rejected(reason, _)) => {
+                        error!("Error reconciling pin {:?}: {}",
+                            vpath, reason);
+                    }
+                    Err(e) => {
+                        error!("Error reconciling pin {:?}: {}", vpath, e);
+                    }
+                }
+                Ok(())
+            }));
+    }
+}
+
+/// Stores promotes of the peer, older ones are skipped by metadata
+///
+/// Promoted image doesn't have to be present yet, it may be downloaded
+/// later in the same reconciliation.
+fn merge_promotes(sys: &Subsystem, path: &VPath,
+    remote: &BTreeMap<String, PromoteState>)
+{
+    for (name, promote) in remote {
+        let sys = sys.clone();
+        let vpath = path.join(name);
+        spawn(sys.meta.set_promote(&vpath, promote.clone(), false)
+            .then(move |result| {
+                match result {
+                    Ok(Upload::Accepted(Accept::New)) => {
+                        info!("Promoted {:?} by peer", vpath);
+                        sys.rescan_dir(vpath.parent());
+                    }
+                    Ok(Upload::Accepted(_)) => {}
+                    Ok(Upload::Rejected(reason, _)) => {
+                        error!("Error reconciling promote {:?}: {}",
+                            vpath, reason);
+                    }
+                    Err(e) => {
+                        error!("Error reconciling promote {:?}: {}",
+                            vpath, e);
+                    }
+                }
+                Ok(())
+            }));
+    }
+}
+
 pub fn start(sys: &Subsystem, info: ReconPush) {
     debug!("Reconciling {:?} to hash {} from {}/{}",
         info.path, info.hash, info.initial_addr, info.initial_machine_id);
     let sys = sys.clone();
     let sys2 = sys.clone();
     let sys3 = sys.clone();
+    let sys4 = sys.clone();
     let sys_drop = sys.clone();
     let ReconPush {
         path,
@@ -47,6 +122,17 @@ pub fn start(sys: &Subsystem, info: ReconPush) {
         initial_addr: addr,
         initial_machine_id: mid
     } = info;
+    let config = match sys.config.dir(path.key()) {
+        Some(config) => config,
+        None => {
+            // config is removed by reload since reconciliation is queued
+            debug!("No config for {:?}, skipping reconciliation", path);
+            sys.state().reconciling.remove(&(path, hash));
+            return;
+        }
+    };
+    let config2 = config.clone();
+    let config3 = config.clone();
     // TODO(tailhook) allow Remote subsystem to pick a connection, so
     // it can choose one, already available when multiple choices are there
     let pair = (path.clone(), hash);
@@ -66,8 +152,10 @@ pub fn start(sys: &Subsystem, info: ReconPush) {
                             config_hash: dir.config_hash,
                             keep_list_hash: dir.keep_list_hash,
                             dirs: dir.dirs,
+                            pins: dir.pins,
+                            promotes: dir.promotes,
                         };
-                        let dir_hash = Hash::for_object(&dir_state.dirs);
+                        let dir_hash = dir_state.hash();
                         if dir_hash == hash {
                             return Ok(Loop::Break((addr, dir_state)))
                         } else {
@@ -106,20 +194,41 @@ pub fn start(sys: &Subsystem, info: ReconPush) {
             })
     })
     .and_then(move |(addr, remote)| {
-        let config = sys2.config.dirs.get(remote.path.key())
-            .expect("only configured dirs are reconciled");
         let path = remote.path.clone();
         let p1 = remote.path.clone();
         let p2 = remote.path.clone();
+        let p3 = remote.path.clone();
         sys2.meta.scan_dir(&path)
             .map_err(move |e| error!("Scanning base-dir {:?}: {}", p1, e))
-        .join(sys2.disk.read_keep_list(config)
-            .map_err(move |e| error!("Reading keep_list {:?}: {}", p2, e)))
-        .map(move |(local, keep_list)| (addr, remote, local, keep_list))
+        .join3(sys2.disk.read_keep_list(&config)
+            .map_err(move |e| error!("Reading keep_list {:?}: {}", p2, e)),
+            sys2.meta.read_pins(&path)
+            .map_err(move |e| error!("Reading pins {:?}: {}", p3, e)))
+        .map(move |(local, mut keep_list, mut pins)| {
+            merge_pins(&sys2, &path, &mut pins, &remote.pins);
+            merge_promotes(&sys2, &path, &remote.promotes);
+            add_pinned(&path, &mut keep_list, &pins);
+            (addr, remote, local, keep_list)
+        })
     })
-    .map(move |(_addr, remote, mut local_dirs, keep_list)| {
-        let config = sys3.config.dirs.get(remote.path.key())
-            .expect("only configured dirs are reconciled");
+    .and_then(move |(addr, remote, local, keep_list)| {
+        let config = &config2;
+        if config.auto_clean {
+            // indexes of remote images are usually unknown yet, so they are
+            // accounted as empty, cleanup will evict them if needed
+            let extra = remote.dirs.values()
+                .map(|s| s.image.clone()).collect();
+            let p = remote.path.clone();
+            Either::A(read_usage(&remote.path, config, &local, extra,
+                                 &sys4.meta, &sys4.disk)
+                .map_err(move |e| error!("Reading usage {:?}: {}", p, e))
+                .map(move |usage| (addr, remote, local, keep_list, usage)))
+        } else {
+            Either::B(ok((addr, remote, local, keep_list, Usage::default())))
+        }
+    })
+    .map(move |(_addr, remote, mut local_dirs, keep_list, usage)| {
+        let config = &config3;
         let path = remote.path.clone();
         let mut possible_dirs = local_dirs.iter().map(|(name, state)| {
             (path.suffix().join(name), state.clone())
@@ -154,13 +263,15 @@ pub fn start(sys: &Subsystem, info: ReconPush) {
             }
             possible_dirs.insert(path.suffix().join(name), rstate.clone());
         }
-        let sorted = if config.auto_clean {
+        let sorted: Vec<_> = if config.auto_clean {
             sort_out(config,
-                possible_dirs.into_iter().collect(), &keep_list).used
+                possible_dirs.into_iter().collect(), &keep_list, &usage).used
+                .into_iter().map(|(name, state, _)| (name, state)).collect()
         } else {
             possible_dirs.into_iter().collect()
         };
 
+        let now = SystemTime::now();
         let mut count = sys3.state().downloading_in_basedir(&path);
         let mut sorted_remote = remote.dirs.into_iter().collect::<Vec<_>>();
         let mut full_reconciliation = true;
@@ -176,6 +287,11 @@ pub fn start(sys: &Subsystem, info: ReconPush) {
                         again", vpath, rstate.image);
                 continue;
             }
+            if is_expired(&rstate, now) {
+                debug!("Not updating {:?} to {} because it's expired",
+                    vpath, rstate.image);
+                continue;
+            }
             let sys = sys3.clone();
             // TODO(tailhook) consume multiple signatures
             let sig = match rstate.signatures.drain(..).next() {
@@ -186,11 +302,17 @@ pub fn start(sys: &Subsystem, info: ReconPush) {
                 }
             };
             let image_id = rstate.image;
+            let expires = rstate.expires;
             if let Some(old_state) = local_dirs.remove(&name) {
                 if old_state.image == image_id {
                     // TODO(tailhook) maybe update timestamp
                     continue;
                 }
+                if expires.is_some() {
+                    debug!("Not replacing {:?} with expiring image {}",
+                        vpath, image_id);
+                    continue;
+                }
                 if old_state.signatures.iter()
                     .any(|old_s| old_s.timestamp >= sig.timestamp)
                 {
@@ -273,6 +395,7 @@ pub fn start(sys: &Subsystem, info: ReconPush) {
                         image: image_id.clone(),
                         timestamp: sig.timestamp,
                         signatures: vec![sig.signature],
+                        expires: expires,
                     }).then(move |result| {
                         match result {
                             Ok(Upload::Accepted(Accept::New)) => {
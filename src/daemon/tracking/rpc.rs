@@ -1,4 +1,8 @@
+use std::time::{Duration, SystemTime};
+
 use futures::Future;
+use futures::future::{Either, ok};
+use void::unreachable;
 
 use proto::{AppendDir, AppendDirAck};
 use proto::{ReplaceDir, ReplaceDirAck};
@@ -6,9 +10,22 @@ use proto::{GetIndex, GetIndexResponse};
 use proto::{GetIndexAt, GetIndexAtResponse};
 use proto::{GetBlock, GetBlockResponse};
 use proto::{GetBaseDir, GetBaseDirResponse};
-use tracking::{Tracking, base_dir, WatchedStatus};
+use proto::{Maintenance, MaintenanceAck, MaintenanceAction};
+use proto::{Pin, PinAck, Unpin, UnpinAck, PinState};
+use proto::{Promote, PromoteAck, PromoteState};
+use proto::{Rollback, RollbackAck};
+use database::signatures::State;
+use cleanup::maintenance;
+use hooks::Hook;
+use index::ImageId;
+use tracking::{Tracking, Command, base_dir, WatchedStatus};
 use remote::websocket::Responder;
 use {metadata, disk};
+use {VPath};
+
+
+/// Maximum difference between rollback request timestamp and local time
+const ROLLBACK_TIME_SKEW: Duration = Duration::from_secs(600);
 
 
 quick_error! {
@@ -35,7 +52,7 @@ impl Tracking {
         use metadata::Upload::*;
         use metadata::Accept::*;
 
-        if !self.0.config.dirs.contains_key(cmd.path.key()) {
+        if self.0.config.dir(cmd.path.key()).is_none() {
             resp.respond_now(AppendDirAck {
                 accepted: false,
                 reject_reason: Some("no_config".into()),
@@ -74,7 +91,7 @@ impl Tracking {
         use metadata::Upload::*;
         use metadata::Accept::*;
 
-        if !self.0.config.dirs.contains_key(cmd.path.key()) {
+        if self.0.config.dir(cmd.path.key()).is_none() {
             resp.respond_now(ReplaceDirAck {
                 accepted: false,
                 reject_reason: Some("no_config".into()),
@@ -166,8 +183,8 @@ impl Tracking {
     pub fn get_base_dir(&self, cmd: GetBaseDir,
         resp: Responder<GetBaseDirResponse>)
     {
-        let cfg = match self.0.config.dirs.get(cmd.path.key()) {
-            Some(cfg) => cfg.clone(),
+        let cfg = match self.0.config.dir(cmd.path.key()) {
+            Some(cfg) => cfg,
             None => {
                 resp.error_now("No such config");
                 return;
@@ -182,7 +199,286 @@ impl Tracking {
                     keep_list_hash:
                         value.keep_list_hash,
                     dirs: value.dirs,
+                    pins: value.pins,
+                    promotes: value.promotes,
+                }
+            }));
+    }
+    pub fn pin(&self, cmd: Pin, resp: Responder<PinAck>) {
+        let pin = PinState::from(&cmd);
+        resp.respond_with_future(self.set_pin(cmd.path, pin, true)
+            .map(|reason| PinAck {
+                accepted: reason.is_none(),
+                reject_reason: reason.map(String::from),
+            }));
+    }
+    pub fn unpin(&self, cmd: Unpin, resp: Responder<UnpinAck>) {
+        let pin = PinState::from(&cmd);
+        // unpin of removed image is allowed to clean up stale pins
+        resp.respond_with_future(self.set_pin(cmd.path, pin, false)
+            .map(|reason| UnpinAck {
+                accepted: reason.is_none(),
+                reject_reason: reason.map(String::from),
+            }));
+    }
+    /// Stores pin state, returns reason if it's rejected
+    fn set_pin(&self, path: VPath, pin: PinState, require_image: bool)
+        -> Box<Future<Item=Option<&'static str>, Error=Error>>
+    {
+        use metadata::Upload::*;
+        use metadata::Accept::*;
+
+        if self.0.config.dir(path.key()).is_none() {
+            return Box::new(ok(Some("no_config")));
+        }
+        let tracking = self.clone();
+        let pinned = pin.pinned;
+        Box::new(self.0.meta.set_pin(&path, pin, require_image)
+            .map_err(Error::Meta)
+            .map(move |result| match result {
+                Accepted(New) => {
+                    info!("{} {:?}",
+                        if pinned { "Pinned" } else { "Unpinned" }, path);
+                    tracking.rescan_dir(path.parent());
+                    None
+                }
+                Accepted(_) => None,
+                Rejected(reason, _) => Some(reason),
+            }))
+    }
+    pub fn promote(&self, cmd: Promote, resp: Responder<PromoteAck>) {
+        use metadata::Upload::*;
+        use metadata::Accept::*;
+
+        let reject = |reason: &str| PromoteAck {
+            accepted: false,
+            reject_reason: Some(reason.into()),
+        };
+        match self.0.config.dir(cmd.path.key()) {
+            Some(ref cfg) if cfg.current_symlink.is_some() => {}
+            Some(_) => return resp.respond_now(reject("no_current_symlink")),
+            None => return resp.respond_now(reject("no_config")),
+        }
+        let tracking = self.clone();
+        let path = cmd.path.clone();
+        resp.respond_with_future(self.0.meta
+            .set_promote(&cmd.path, PromoteState::from(&cmd), true)
+            .map_err(Error::Meta)
+            .map(move |result| match result {
+                Accepted(New) => {
+                    info!("Promoted {:?}", path);
+                    tracking.rescan_dir(path.parent());
+                    PromoteAck { accepted: true, reject_reason: None }
+                }
+                Accepted(_) => {
+                    PromoteAck { accepted: true, reject_reason: None }
+                }
+                Rejected(reason, _) => reject(reason),
+            }));
+    }
+    pub fn maintenance(&self, cmd: Maintenance,
+        resp: Responder<MaintenanceAck>)
+    {
+        let reject = |reason: &str| MaintenanceAck {
+            accepted: false,
+            reject_reason: Some(reason.into()),
+        };
+        if !maintenance::within_skew(cmd.timestamp, SystemTime::now()) {
+            resp.respond_now(reject("timestamp_skew"));
+            return;
+        }
+        match (cmd.action, cmd.path.as_ref()) {
+            (MaintenanceAction::Cleanup, Some(path)) => {
+                match self.state().base_dirs.get(path) {
+                    Some(dir) if dir.config.auto_clean => {}
+                    Some(_) => {
+                        resp.respond_now(reject("auto_clean_disabled"));
+                        return;
+                    }
+                    None => {
+                        resp.respond_now(reject("no_base_dir"));
+                        return;
+                    }
+                }
+            }
+            (MaintenanceAction::Restore, Some(path)) => {
+                if !self.0.config.is_valid_destination(path) {
+                    resp.respond_now(reject("no_config"));
+                    return;
+                }
+            }
+            (MaintenanceAction::Restore, None) => {
+                resp.respond_now(reject("path_required"));
+                return;
+            }
+            _ => {}
+        }
+        let tracking = self.clone();
+        resp.respond_with_future(self.0.meta.check_maintenance(cmd)
+            .map_err(Error::Meta)
+            .and_then(move |(cmd, reject_reason)| {
+                if let Some(reason) = reject_reason {
+                    return Either::A(ok(reject(reason)));
+                }
+                match (cmd.action, cmd.path) {
+                    (MaintenanceAction::Restore, Some(path)) => {
+                        Either::B(tracking.restore(path)
+                            .map(move |reason| match reason {
+                                Some(reason) => reject(reason),
+                                None => MaintenanceAck {
+                                    accepted: true,
+                                    reject_reason: None,
+                                },
+                            }))
+                    }
+                    (action, path) => {
+                        tracking.send(Command::Maintenance(action, path));
+                        Either::A(ok(MaintenanceAck {
+                            accepted: true,
+                            reject_reason: None,
+                        }))
+                    }
                 }
             }));
     }
+    pub fn rollback(&self, cmd: Rollback, resp: Responder<RollbackAck>) {
+        let reject = |reason: &str| RollbackAck {
+            accepted: false,
+            reject_reason: Some(reason.into()),
+            image: None,
+        };
+        let now = SystemTime::now();
+        let skew = now.duration_since(cmd.timestamp)
+            .or_else(|_| cmd.timestamp.duration_since(now))
+            .unwrap_or(ROLLBACK_TIME_SKEW);
+        if skew >= ROLLBACK_TIME_SKEW {
+            resp.respond_now(reject("timestamp_skew"));
+            return;
+        }
+        let tracking = self.clone();
+        resp.respond_with_future(self.0.meta.check_rollback(cmd)
+            .map_err(Error::Meta)
+            .and_then(move |(cmd, current)| match current {
+                Ok(current) => Either::B(tracking.do_rollback(cmd, current)
+                    .map(move |result| match result {
+                        Ok(image) => RollbackAck {
+                            accepted: true,
+                            reject_reason: None,
+                            image: Some(image),
+                        },
+                        Err(reason) => reject(reason),
+                    })),
+                Err(reason) => Either::A(ok(reject(reason))),
+            }));
+    }
+    /// Exchanges image with its previous version and stores new state
+    ///
+    /// Restored image is signed by replace signatures of the rollback
+    /// request. The path is reserved by `check_rollback`, it's released
+    /// here whatever the result is.
+    fn do_rollback(&self, cmd: Rollback, current: State)
+        -> Box<Future<Item=Result<ImageId, &'static str>, Error=Error>>
+    {
+        let meta = self.0.meta.clone();
+        let path = cmd.path.clone();
+        // config might be removed by reload since request is validated
+        let cfg = match self.0.config.dir(path.key()) {
+            Some(cfg) => cfg,
+            None => {
+                return Box::new(meta.abort_reserved(&path)
+                    .map(|()| Err("no_config"))
+                    .map_err(|e| unreachable(e)));
+            }
+        };
+        let tracking = self.clone();
+        Box::new(self.0.disk
+            .rollback_image(&cfg, &path, cmd.image, current)
+            .map_err(Error::Disk)
+            .then(move |result| {
+                let image = match result {
+                    Ok(Some(state)) => state.image,
+                    Ok(None) => {
+                        return Either::A(meta.abort_reserved(&path)
+                            .map(|()| Err("version_not_found"))
+                            .map_err(|e| unreachable(e)));
+                    }
+                    Err(e) => {
+                        return Either::B(Either::A(meta.abort_reserved(&path)
+                            .then(move |_| Err(e))));
+                    }
+                };
+                Either::B(Either::B(meta.commit_reserved(&path)
+                    .map_err(Error::Meta)
+                    .map(move |()| {
+                        info!("Rolled back {:?} to {}", path, image);
+                        tracking.0.hooks.run(Hook::OnReplace, &cfg,
+                                             &path, &image);
+                        tracking.rescan_dir(path.parent());
+                        Ok(image)
+                    })))
+            }))
+    }
+    /// Restores image from trash, returns reason if it can't be restored
+    fn restore(&self, path: VPath)
+        -> Box<Future<Item=Option<&'static str>, Error=Error>>
+    {
+        // config might be removed by reload since request is validated
+        let cfg = match self.0.config.dir(path.key()) {
+            Some(cfg) => cfg,
+            None => return Box::new(ok(Some("no_config"))),
+        };
+        if cfg.num_levels == 0 {
+            // level 0 images are never cleaned up, so never in trash
+            return Box::new(ok(Some("not_in_trash")));
+        }
+        let tracking = self.clone();
+        let meta = self.0.meta.clone();
+        let disk = self.0.disk.clone();
+        Box::new(disk.trash_state(&cfg, &path).map_err(Error::Disk)
+            .and_then(move |state| {
+                let state = match state {
+                    Some(state) => state,
+                    None => return Either::A(ok(Err("not_in_trash"))),
+                };
+                let image = state.image.clone();
+                // path is held until image is in place, so no upload can
+                // start meanwhile
+                Either::B(meta.reserve_restore(&path, state)
+                    .map_err(Error::Meta)
+                    .map(move |reserved| if reserved {
+                        Ok((path, image))
+                    } else {
+                        Err("already_exists")
+                    }))
+            })
+            .and_then(move |reserved| {
+                let (path, image) = match reserved {
+                    Ok(pair) => pair,
+                    Err(reason) => return Either::A(ok(Some(reason))),
+                };
+                let meta = tracking.0.meta.clone();
+                Either::B(tracking.0.disk.restore_image(&cfg, &path, &image)
+                    .then(move |result| {
+                        let reason = match result {
+                            Ok(Some(_)) => {
+                                return Either::A(meta.commit_reserved(&path)
+                                    .map_err(Error::Meta)
+                                    .map(move |()| {
+                                        warn!("Restored {:?} from trash",
+                                              path);
+                                        tracking.rescan_dir(path.parent());
+                                        None
+                                    }));
+                            }
+                            Ok(None) => Ok(Some("not_in_trash")),
+                            Err(disk::Error::AlreadyExists) => {
+                                Ok(Some("already_exists"))
+                            }
+                            Err(e) => Err(Error::Disk(e)),
+                        };
+                        Either::B(meta.abort_reserved(&path)
+                            .then(move |_| reason))
+                    }))
+            }))
+    }
 }
@@ -0,0 +1,269 @@
+//! Periodic verification of committed images, see `scrub-interval`
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::{Future, Stream};
+use futures::future::{Either, Loop, loop_fn, ok, err};
+use futures::stream::iter_ok;
+use rand::{thread_rng, Rng};
+use tk_easyloop::{spawn, interval};
+
+use {VPath};
+use blocks::BlockHash;
+use config::Directory;
+use disk::{ScrubReport, DamagedFile};
+use failure_tracker::HostFailures;
+use index::ImageId;
+use mask::Mask;
+use metrics::{Counter, Integer};
+use proto::{GetBlock, RequestClient};
+use tracking::{Subsystem, BaseDir, BlockData, Index};
+
+
+/// How often configs are checked for due scrub passes
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of peers tried to fetch each damaged block
+const REPAIR_ATTEMPTS: usize = 5;
+
+lazy_static! {
+    pub static ref IMAGES: Counter = Counter::new();
+    pub static ref DAMAGED: Integer = Integer::new();
+    pub static ref REPAIRED: Counter = Counter::new();
+    pub static ref REPAIR_FAILED: Counter = Counter::new();
+}
+
+
+/// Image which scrubbing found not matching its index
+#[derive(Clone)]
+pub struct Damaged {
+    pub image_id: ImageId,
+    pub files: Vec<DamagedFile>,
+    pub missing: Vec<PathBuf>,
+    pub checked: SystemTime,
+    pub repaired: bool,
+}
+
+/// Returns time of the first pass, so that passes of different directories
+/// and hosts are spread over the interval
+fn first_pass(interval: Duration) -> Instant {
+    let secs = interval.as_secs();
+    Instant::now() + Duration::new(thread_rng().gen_range(0, secs + 1), 0)
+}
+
+/// Returns base dirs which are due to be scrubbed and schedules next pass
+fn due_dirs(sys: &Subsystem) -> Vec<Arc<BaseDir>> {
+    let now = Instant::now();
+    let configs = sys.config.dirs.get();
+    let mut guard = sys.state();
+    let state = &mut *guard;
+    state.scrub_due.retain(|name, _| {
+        configs.get(name)
+            .map(|cfg| cfg.scrub_interval.is_some())
+            .unwrap_or(false)
+    });
+    let mut result = Vec::new();
+    for (name, cfg) in configs.iter() {
+        let interval = match cfg.scrub_interval {
+            Some(interval) => interval,
+            None => continue,
+        };
+        let due = *state.scrub_due.entry(name.clone())
+            .or_insert_with(|| first_pass(interval));
+        if due > now {
+            continue;
+        }
+        info!("Starting scrub of {:?}", name);
+        state.scrub_due.insert(name.clone(), now + interval);
+        result.extend(state.base_dirs.values()
+            .filter(|dir| dir.path.key() == name)
+            .cloned());
+    }
+    return result;
+}
+
+fn scrub_dir(sys: &Subsystem, dir: Arc<BaseDir>)
+    -> Box<Future<Item=(), Error=()>>
+{
+    let sys = sys.clone();
+    let path = dir.path.clone();
+    Box::new(sys.meta.scan_dir(&dir.path)
+        .map_err(move |e| error!("Can't list images of {:?}: {}", path, e))
+        .and_then(move |images| {
+            iter_ok(images).for_each(move |(name, state)| {
+                scrub_image(&sys, &dir.config,
+                    dir.path.join(name), state.image)
+            })
+        }))
+}
+
+/// Checks single image, never fails
+fn scrub_image(sys: &Subsystem, config: &Arc<Directory>, vpath: VPath,
+    image_id: ImageId)
+    -> Box<Future<Item=(), Error=()>>
+{
+    if sys.state().in_progress.contains_key(&vpath) {
+        debug!("Not scrubbing {:?}, it's being downloaded", vpath);
+        return Box::new(ok(()));
+    }
+    let sys = sys.clone();
+    let config = config.clone();
+    let disk = sys.disk.clone();
+    let cfg = config.clone();
+    let vpath1 = vpath.clone();
+    let vpath2 = vpath.clone();
+    Box::new(sys.images.get(&sys.tracking, &vpath, &image_id)
+        .map_err(|e| format!("can't get index: {}", e))
+        .and_then(move |index| {
+            disk.scrub_image(&cfg, &vpath1, index.clone())
+            .map(move |report| (index, report))
+            .map_err(|e| e.to_string())
+        })
+        .and_then(move |(index, report)| {
+            // image could be replaced or removed while it was checked
+            sys.meta.read_state(&vpath)
+            .map_err(|e| e.to_string())
+            .and_then(move |state| {
+                match state {
+                    Some(ref state) if state.image == image_id => {
+                        IMAGES.incr(1);
+                        Either::A(check_done(&sys, &config, vpath,
+                                             index, report))
+                    }
+                    _ => {
+                        debug!("Image {:?} is changed while scrubbing",
+                            vpath);
+                        Either::B(ok(()))
+                    }
+                }
+            })
+        })
+        .map_err(move |e| error!("Error scrubbing {:?}: {}", vpath2, e)))
+}
+
+fn check_done(sys: &Subsystem, config: &Arc<Directory>, vpath: VPath,
+    index: Index, report: ScrubReport)
+    -> Box<Future<Item=(), Error=String>>
+{
+    if report.is_clean() {
+        debug!("Image {:?} is intact, checked {} bytes",
+            vpath, report.bytes);
+        sys.image_intact(&vpath);
+        return Box::new(ok(()));
+    }
+    error!("Image {:?} is damaged: {} files mismatch, {} files missing",
+        vpath, report.damaged.len(), report.missing.len());
+    sys.image_damaged(&vpath, Damaged {
+        image_id: index.id.clone(),
+        files: report.damaged.clone(),
+        missing: report.missing,
+        checked: SystemTime::now(),
+        repaired: false,
+    });
+    if !config.scrub_repair || report.damaged.is_empty() {
+        return Box::new(ok(()));
+    }
+    let sys = sys.clone();
+    Box::new(repair(&sys, config, &vpath, &index.id, report.damaged)
+        .map(move |repaired| {
+            if repaired {
+                REPAIRED.incr(1);
+                sys.image_repaired(&vpath);
+            } else {
+                REPAIR_FAILED.incr(1);
+            }
+        })
+        .map_err(|()| unreachable!()))
+}
+
+/// Repairs files from peers, returns whether all of them are repaired
+fn repair(sys: &Subsystem, config: &Arc<Directory>, vpath: &VPath,
+    image_id: &ImageId, files: Vec<DamagedFile>)
+    -> Box<Future<Item=bool, Error=()>>
+{
+    let sys = sys.clone();
+    let config = config.clone();
+    let vpath = vpath.clone();
+    let image_id = image_id.clone();
+    Box::new(iter_ok(files).fold(true, move |all_ok, file| {
+        let sys = sys.clone();
+        let config = config.clone();
+        let vpath = vpath.clone();
+        let image_id = image_id.clone();
+        let DamagedFile { path, size, blocks, .. } = file;
+        let sys1 = sys.clone();
+        let vpath1 = vpath.clone();
+        let path1 = path.clone();
+        iter_ok(blocks)
+        .and_then(move |(offset, hash)| {
+            fetch_block(&sys1, &vpath1, &image_id, &path1, offset, hash)
+        })
+        .collect()
+        .and_then(move |blocks| {
+            sys.disk.repair_file(&config, &vpath, path, size, blocks)
+            .map_err(|e| error!("Error repairing file: {}", e))
+        })
+        .then(move |res| Ok::<_, ()>(all_ok && res.is_ok()))
+    }))
+}
+
+/// Fetches block from peers having the image
+fn fetch_block(sys: &Subsystem, vpath: &VPath, image_id: &ImageId,
+    path: &PathBuf, offset: u64, hash: BlockHash)
+    -> Box<Future<Item=(u64, BlockData), Error=()>>
+{
+    let sys = sys.clone();
+    let vpath = vpath.clone();
+    let image_id = image_id.clone();
+    let path = path.clone();
+    Box::new(loop_fn((HostFailures::new_default(), 0),
+        move |(mut failures, attempt)| {
+            let conn = if attempt < REPAIR_ATTEMPTS {
+                sys.tracking.get_connection_by_mask(
+                    &vpath, &image_id, Mask::full(), &failures)
+            } else {
+                None
+            };
+            let conn = match conn {
+                Some(conn) => conn,
+                None => {
+                    error!("Can't fetch block {:?}:{} of {:?} for repair",
+                        path, offset, vpath);
+                    return Either::A(err(()));
+                }
+            };
+            let addr = conn.addr();
+            let hash = hash.clone();
+            Either::B(conn.request(GetBlock {
+                hash: hash.clone(),
+                hint: Some((vpath.clone(), path.clone(), offset)),
+            }).then(move |res| -> Result<_, ()> {
+                match res {
+                    Ok(resp) => {
+                        if BlockHash::hash_bytes(&resp.data) == hash {
+                            return Ok(Loop::Break(
+                                (offset, Arc::new(resp.data))));
+                        }
+                        warn!("Wrong checksum of block from {}", addr);
+                    }
+                    Err(e) => {
+                        warn!("Block fetch error from {}: {}", addr, e);
+                    }
+                }
+                failures.add_failure(addr);
+                Ok(Loop::Continue((failures, attempt + 1)))
+            }))
+        }))
+}
+
+pub fn spawn_loop(sys: &Subsystem) {
+    let sys = sys.clone();
+    spawn(interval(CHECK_INTERVAL)
+        .map_err(|_| unreachable!())
+        .for_each(move |()| {
+            let sys = sys.clone();
+            iter_ok(due_dirs(&sys)).for_each(move |dir| {
+                scrub_dir(&sys, dir).then(|_| Ok(()))
+            })
+        }));
+}
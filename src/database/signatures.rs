@@ -20,6 +20,10 @@ pub struct SignatureEntry {
 pub struct State {
     pub image: ImageId,
     pub signatures: Vec<SignatureEntry>,
+    /// Signed expiration time of the image, see `AppendDir`
+    #[serde(with="::serialize::optional_timestamp", default,
+            skip_serializing_if="Option::is_none")]
+    pub expires: Option<SystemTime>,
 }
 
 impl Serialize for SignatureEntry {
@@ -1,90 +1,183 @@
+use std::cmp::min;
 use std::fmt;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::hash::Hash;
+use std::sync::Arc;
 use std::time::{Instant, Duration};
 
 use abstract_ns::Name;
+use rand::{thread_rng, Rng};
 
 
 const RETRY_TIME: Duration = Duration::from_secs(1);
 const SLOW_RETRY_TIME: Duration = Duration::from_secs(10);
 
 
-pub type HostFailures = Failures<SocketAddr, DefaultPolicy>;
-pub type SlowHostFailures = Failures<SocketAddr, SlowerPolicy>;
-pub type DnsFailures = Failures<Name, DefaultPolicy>;
+pub type HostFailures = Failures<SocketAddr>;
+pub type SlowHostFailures = Failures<SocketAddr>;
+pub type DnsFailures = Failures<Name>;
 
-#[derive(Debug)]
-pub struct DefaultPolicy;
+/// Policy which determines how long to wait before retrying failed host
+///
+/// The policy is consulted once on each failure, so it's fine to return
+/// randomized values (i.e. to add jitter).
+pub trait RetryPolicy: fmt::Debug + Send + Sync + 'static {
+    /// Returns a delay before the next attempt after `subsequent` failures
+    ///
+    /// `subsequent` is at least `1`, it's reset when connection succeeds.
+    fn retry_delay(&self, subsequent: u32) -> Duration;
+}
 
-#[derive(Debug)]
-pub struct SlowerPolicy;
+/// Linear backoff, i.e. wait `step * subsequent_failures`
+///
+/// This is a default policy with the step of 1 second.
+#[derive(Debug, Clone)]
+pub struct LinearBackoff {
+    step: Duration,
+}
+
+/// Exponential backoff with a cap and random jitter
+///
+/// Delay starts at `initial`, is doubled on each subsequent failure and
+/// capped at `max`. Then delay is randomly decreased by up to `jitter`
+/// fraction of it, to spread retries of many clients over time.
+#[derive(Debug, Clone)]
+pub struct ExponentialBackoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+}
 
 #[derive(Debug)]
-pub struct Failures<K: Eq + Hash, P> {
+pub struct Failures<K: Eq + Hash> {
     items: HashMap<K, Failure>,
-    policy: P,
+    policy: Arc<dyn RetryPolicy>,
 }
 
 #[derive(Debug)]
 pub struct Failure {
     subsequent: u32,
-    last: Instant,
+    next_try: Instant,
 }
 
-pub trait Policy: fmt::Debug {
-    fn can_try(&self, entry: &Failure) -> bool;
+impl LinearBackoff {
+    /// Create a policy which increases delay by `step` on each failure
+    pub fn new(step: Duration) -> LinearBackoff {
+        LinearBackoff { step }
+    }
 }
 
-impl Policy for DefaultPolicy {
-    fn can_try(&self, entry: &Failure) -> bool {
-        let since = Instant::now() - entry.last;
-        return since > RETRY_TIME * entry.subsequent;
+impl RetryPolicy for LinearBackoff {
+    fn retry_delay(&self, subsequent: u32) -> Duration {
+        self.step * subsequent
     }
 }
 
-impl Policy for SlowerPolicy {
-    fn can_try(&self, entry: &Failure) -> bool {
-        let since = Instant::now() - entry.last;
-        return since > SLOW_RETRY_TIME * entry.subsequent;
+impl ExponentialBackoff {
+    /// Create an exponential backoff policy
+    ///
+    /// `jitter` is a fraction of the delay, in range `0.0..1.0`, which is
+    /// randomly subtracted from the delay.
+    ///
+    /// # Panics
+    ///
+    /// If jitter is outside of the range
+    pub fn new(initial: Duration, max: Duration, jitter: f64)
+        -> ExponentialBackoff
+    {
+        assert!((0.0..1.0).contains(&jitter), "jitter must be in 0.0..1.0");
+        ExponentialBackoff { initial, max, jitter }
     }
 }
 
-impl<K: Clone + Eq + Hash> Failures<K, DefaultPolicy> {
-    pub fn new_default() -> Failures<K, DefaultPolicy> {
-        Failures {
-            policy: DefaultPolicy,
-            items: HashMap::new(),
+impl RetryPolicy for ExponentialBackoff {
+    fn retry_delay(&self, subsequent: u32) -> Duration {
+        let mut delay = self.initial;
+        for _ in 1..subsequent {
+            delay *= 2;
+            if delay >= self.max {
+                break;
+            }
         }
+        let delay = min(delay, self.max);
+        let ms = delay.as_secs()*1000 + u64::from(delay.subsec_millis());
+        let dec = (ms as f64 * self.jitter * thread_rng().gen::<f64>()) as u64;
+        Duration::from_millis(ms - min(dec, ms))
     }
 }
 
-impl<K: Clone + Eq + Hash> Failures<K, SlowerPolicy> {
-    pub fn new_slow() -> Failures<K, SlowerPolicy> {
+impl<K: Clone + Eq + Hash> Failures<K> {
+    pub fn new(policy: &Arc<dyn RetryPolicy>) -> Failures<K> {
         Failures {
-            policy: SlowerPolicy,
+            policy: policy.clone(),
+            items: HashMap::new(),
+        }
+    }
+    pub fn new_default() -> Failures<K> {
+        Failures::new(&default_policy())
+    }
+    pub fn new_slow() -> Failures<K> {
+        Failures {
+            policy: Arc::new(LinearBackoff::new(SLOW_RETRY_TIME)),
             items: HashMap::new(),
         }
     }
-}
-
-impl<K: Clone + Eq + Hash, P: Policy> Failures<K, P> {
     pub fn add_failure(&mut self, name: K) {
         let entry = self.items.entry(name)
             .or_insert(Failure {
                 subsequent: 0,
-                last: Instant::now(),
+                next_try: Instant::now(),
             });
         entry.subsequent = entry.subsequent.saturating_add(1);
-        entry.last = Instant::now();
+        entry.next_try = Instant::now() +
+            self.policy.retry_delay(entry.subsequent);
     }
     pub fn reset(&mut self, name: &K) {
         self.items.remove(name);
     }
     pub fn can_try(&self, name: &K) -> bool {
         self.items.get(name)
-            .map(|f| self.policy.can_try(f))
+            .map(|f| Instant::now() > f.next_try)
             .unwrap_or(true)
     }
 }
+
+pub fn default_policy() -> Arc<dyn RetryPolicy> {
+    Arc::new(LinearBackoff::new(RETRY_TIME))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::{ExponentialBackoff, RetryPolicy};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn exponential() {
+        let policy = ExponentialBackoff::new(ms(100), ms(1000), 0.0);
+        assert_eq!((1..7).map(|n| policy.retry_delay(n)).collect::<Vec<_>>(),
+            vec![ms(100), ms(200), ms(400), ms(800), ms(1000), ms(1000)]);
+        assert_eq!(policy.retry_delay(u32::max_value()), ms(1000));
+    }
+
+    #[test]
+    fn jitter() {
+        let policy = ExponentialBackoff::new(ms(100), ms(1000), 0.5);
+        for _ in 0..100 {
+            let delay = policy.retry_delay(10);
+            assert!(delay > ms(500) && delay <= ms(1000), "{:?}", delay);
+            let delay = policy.retry_delay(1);
+            assert!(delay > ms(50) && delay <= ms(100), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected="jitter must be in 0.0..1.0")]
+    fn bad_jitter() {
+        ExponentialBackoff::new(ms(100), ms(1000), 1.0);
+    }
+}
@@ -11,7 +11,9 @@
 //! [`dir-signature`]: https://crates.io/crates/dir-signature
 use std::collections::HashMap;
 use std::fmt;
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{RwLock, Arc};
 
 use failure::Backtrace;
@@ -54,6 +56,20 @@ pub struct InMemoryIndexes {
     indexes: Arc<RwLock<HashMap<ImageId, Arc<[u8]>>>>,
 }
 
+/// A GetIndex implementation that keeps indexes in a directory
+///
+/// Indexes are stored using the same layout as server does, i.e.
+/// `<dir>/ab/abcdef...ds1`. This is useful for long-running processes
+/// which serve many historic images, as indexes survive restarts and don't
+/// occupy memory.
+///
+/// Note: index is read synchronously when requested, as indexes are
+/// usually small.
+#[derive(Debug, Clone)]
+pub struct DiskIndexes {
+    dir: Arc<PathBuf>,
+}
+
 /// Error adding index
 #[derive(Debug, Fail)]
 pub enum IndexError {
@@ -62,6 +78,9 @@ pub enum IndexError {
     #[doc(hidden)]
     #[fail(display="error parsing index")]
     ParseError,
+    /// Error writing index to disk
+    #[fail(display="error writing index {:?}: {}", _0, _1)]
+    Fs(PathBuf, io::Error, Backtrace),
     #[doc(hidden)]
     #[fail(display="index lock was poisoned")]
     LockError(Backtrace),
@@ -76,6 +95,9 @@ pub enum ReadError {
     /// Index not found
     #[fail(display="index {} with not found", _0)]
     NotFound(ImageId),
+    /// Error reading index from disk
+    #[fail(display="error reading index {:?}: {}", _0, _1)]
+    Fs(PathBuf, io::Error, Backtrace),
     #[doc(hidden)]
     #[fail(display="index lock was poisoned")]
     LockError(Backtrace),
@@ -122,3 +144,115 @@ impl GetIndex for InMemoryIndexes {
         }
     }
 }
+
+impl DiskIndexes {
+    /// Open (or create) an index directory
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<DiskIndexes, IndexError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)
+            .map_err(|e| IndexError::Fs(dir.to_path_buf(), e,
+                                        Backtrace::new()))?;
+        Ok(DiskIndexes {
+            dir: Arc::new(dir.to_path_buf()),
+        })
+    }
+    fn index_path(&self, id: &ImageId) -> PathBuf {
+        let hex_id = id.to_string();
+        self.dir.join(&hex_id[..2]).join(format!("{}.ds1", hex_id))
+    }
+    /// Store index and return image id
+    ///
+    /// Storing the same index twice is no-op.
+    pub fn register_index(&self, data: &[u8]) -> Result<ImageId, IndexError> {
+        let image_id = ImageId::from(get_hash(&mut io::Cursor::new(&data))
+            .map_err(|_| IndexError::ParseError)?);
+        let path = self.index_path(&image_id);
+        if path.exists() {
+            return Ok(image_id);
+        }
+        let dir = path.parent().expect("index has a parent dir");
+        let tmp_path = dir.join(format!(".{}.tmp", image_id));
+        fs::create_dir_all(dir)
+            .and_then(|()| File::create(&tmp_path))
+            .and_then(|mut f| f.write_all(data))
+            .and_then(|()| fs::rename(&tmp_path, &path))
+            .map_err(|e| IndexError::Fs(path.clone(), e, Backtrace::new()))?;
+        Ok(image_id)
+    }
+    /// Remove index from the directory
+    ///
+    /// Returns `false` if there was no such index
+    pub fn remove_index(&self, id: &ImageId) -> Result<bool, IndexError> {
+        let path = self.index_path(id);
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(IndexError::Fs(path, e, Backtrace::new())),
+        }
+    }
+}
+
+impl GetIndex for DiskIndexes {
+    type Data = Vec<u8>;
+    type Error = ReadError;
+    type Future = FutureResult<Vec<u8>, ReadError>;
+    fn read_index(&self, id: &ImageId) -> Self::Future {
+        let path = self.index_path(id);
+        let mut buf = Vec::new();
+        match File::open(&path).and_then(|mut f| f.read_to_end(&mut buf)) {
+            Ok(_) => ok(buf),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                err(ReadError::NotFound(id.clone()))
+            }
+            Err(e) => err(ReadError::Fs(path, e, Backtrace::new())),
+        }
+    }
+}
+
+/// Scans directory into index data, as `ciruela upload` does
+#[cfg(test)]
+pub(crate) fn scan_dir(dir: &Path) -> Vec<u8> {
+    use dir_signature::{v1, ScannerConfig, HashType};
+
+    let mut cfg = ScannerConfig::new();
+    cfg.hash(HashType::blake2b_256());
+    cfg.add_dir(dir, "/");
+    let mut buf = Vec::new();
+    v1::scan(&cfg, &mut buf).unwrap();
+    return buf;
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use futures::Future;
+    use tempfile::tempdir;
+    use super::{DiskIndexes, GetIndex, ReadError, scan_dir};
+
+    #[test]
+    fn disk_roundtrip() {
+        let tmp = tempdir().unwrap();
+        let src = tmp.path().join("src");
+        fs::create_dir(&src).unwrap();
+        File::create(src.join("file")).unwrap().write_all(b"hello").unwrap();
+        let data = scan_dir(&src);
+
+        let indexes = DiskIndexes::open(tmp.path().join("indexes")).unwrap();
+        let id = indexes.register_index(&data).unwrap();
+        // storing the same index is no-op
+        assert_eq!(indexes.register_index(&data).unwrap(), id);
+        assert_eq!(indexes.read_index(&id).wait().unwrap(), data);
+
+        // indexes are read from disk after reopen
+        let indexes = DiskIndexes::open(tmp.path().join("indexes")).unwrap();
+        assert_eq!(indexes.read_index(&id).wait().unwrap(), data);
+        assert!(indexes.remove_index(&id).unwrap());
+        assert!(!indexes.remove_index(&id).unwrap());
+        match indexes.read_index(&id).wait() {
+            Err(ReadError::NotFound(ref x)) if *x == id => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
@@ -26,6 +26,7 @@ extern crate serde;
 extern crate serde_bytes;
 extern crate serde_cbor;
 extern crate ssh_keys;
+#[cfg(test)] extern crate tempfile;
 extern crate tk_easyloop;
 extern crate tk_bufstream;
 extern crate tokio_core;
@@ -0,0 +1,78 @@
+use std::time::SystemTime;
+
+use proto::{Signature, SigData, Request, Response};
+use serialize::timestamp;
+use time_util::to_ms;
+use {VPath};
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all="kebab-case")]
+pub enum MaintenanceAction {
+    /// Run cleanup of base dir (or all base dirs with `auto_clean`)
+    Cleanup,
+    /// Run garbage collection of indexes
+    IndexGc,
+    /// Restore image at `path` from the trash
+    Restore,
+}
+
+/// Administrative request, must be signed by one of the master keys
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Maintenance {
+    pub action: MaintenanceAction,
+    pub path: Option<VPath>,
+    #[serde(with="timestamp")]
+    pub timestamp: SystemTime,
+    pub signatures: Vec<Signature>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MaintenanceAck {
+    pub accepted: bool,
+    pub reject_reason: Option<String>,
+}
+
+impl MaintenanceAction {
+    /// Name of the action, same in the protocol and on the command-line
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MaintenanceAction::Cleanup => "cleanup",
+            MaintenanceAction::IndexGc => "index-gc",
+            MaintenanceAction::Restore => "restore",
+        }
+    }
+}
+
+impl Maintenance {
+    /// Data to sign, action name is used in place of the image id
+    ///
+    /// Empty path means all directories.
+    pub fn sig_data(&self) -> SigData {
+        SigData {
+            path: self.path.as_ref()
+                .map(|p| p.as_ref().to_str().expect("path is string"))
+                .unwrap_or(""),
+            image: self.action.name().as_bytes(),
+            timestamp: to_ms(self.timestamp),
+            expires: None,
+            action: None,
+        }
+    }
+}
+
+impl Request for Maintenance {
+    type Response = MaintenanceAck;
+    fn type_name(&self) -> &'static str {
+        return "Maintenance";
+    }
+}
+
+impl Response for MaintenanceAck {
+    fn type_name(&self) -> &'static str {
+        return "Maintenance";
+    }
+    fn static_type_name() -> &'static str {
+        return "Maintenance";
+    }
+}
@@ -4,7 +4,7 @@ use std::time::SystemTime;
 use index::ImageId;
 use machine_id::MachineId;
 use proto::{Signature, SigData, Request, Response};
-use serialize::timestamp;
+use serialize::{timestamp, optional_timestamp};
 // use signature::SignedUpload;
 use time_util::to_ms;
 use {VPath};
@@ -17,6 +17,10 @@ pub struct AppendDir {
     #[serde(with="timestamp")]
     pub timestamp: SystemTime,
     pub signatures: Vec<Signature>,
+    /// Image is removed by cleanup after this time (signed)
+    #[serde(with="optional_timestamp", default,
+            skip_serializing_if="Option::is_none")]
+    pub expires: Option<SystemTime>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -45,12 +49,135 @@ pub struct ReplaceDirAck {
     pub hosts: HashMap<MachineId, String>,
 }
 
+/// Marks directory as exempt from `auto-clean`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Pin {
+    pub path: VPath,
+    #[serde(with="timestamp")]
+    pub timestamp: SystemTime,
+    pub signatures: Vec<Signature>,
+}
+
+/// Removes the mark set by `Pin`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Unpin {
+    pub path: VPath,
+    #[serde(with="timestamp")]
+    pub timestamp: SystemTime,
+    pub signatures: Vec<Signature>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PinAck {
+    pub accepted: bool,
+    pub reject_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnpinAck {
+    pub accepted: bool,
+    pub reject_reason: Option<String>,
+}
+
+/// Last pin or unpin request for the directory, as stored and reconciled
+///
+/// Unpinned state is kept too, so it's not overridden by an older pin from
+/// peers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+// Note everything here, must be stable-serialized
+pub struct PinState {
+    pub pinned: bool,
+    #[serde(with="timestamp")]
+    pub timestamp: SystemTime,
+    pub signatures: Vec<Signature>,
+}
+
+/// Points `current-symlink` of the base dir to the image
+///
+/// The image must be present on the server. Promote is effective until a
+/// newer image is uploaded or another image is promoted.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Promote {
+    pub path: VPath,
+    #[serde(with="timestamp")]
+    pub timestamp: SystemTime,
+    pub signatures: Vec<Signature>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PromoteAck {
+    pub accepted: bool,
+    pub reject_reason: Option<String>,
+}
+
+/// Last promote request for the directory, as stored and reconciled
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+// Note everything here, must be stable-serialized
+pub struct PromoteState {
+    #[serde(with="timestamp")]
+    pub timestamp: SystemTime,
+    pub signatures: Vec<Signature>,
+}
+
+/// Exchanges the image with one of its previous versions
+///
+/// Only for directories with `keep-previous-versions`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Rollback {
+    pub path: VPath,
+    pub image: ImageId,
+    #[serde(with="timestamp")]
+    pub timestamp: SystemTime,
+    /// Signatures of the rollback itself, see `sig_data`
+    pub signatures: Vec<Signature>,
+    /// Signatures of the upload of `image` with the same `timestamp`
+    ///
+    /// Stored as the state of the restored image, so other servers follow
+    /// the rollback as an ordinary replace.
+    pub replace_signatures: Vec<Signature>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RollbackAck {
+    pub accepted: bool,
+    pub reject_reason: Option<String>,
+    pub image: Option<ImageId>,
+}
+
+fn pin_sig_data<'x>(path: &'x VPath, pinned: bool, timestamp: SystemTime)
+    -> SigData<'x>
+{
+    SigData {
+        path: path.as_ref().to_str().expect("path is string"),
+        // image id is never this short, so it can't be confused with upload
+        image: if pinned { &b"pin"[..] } else { &b"unpin"[..] },
+        timestamp: to_ms(timestamp),
+        expires: None,
+        action: None,
+    }
+}
+
+fn promote_sig_data<'x>(path: &'x VPath, timestamp: SystemTime)
+    -> SigData<'x>
+{
+    SigData {
+        path: path.as_ref().to_str().expect("path is string"),
+        // image id is never this short, so it can't be confused with upload
+        image: &b"promote"[..],
+        timestamp: to_ms(timestamp),
+        expires: None,
+        action: None,
+    }
+}
+
 impl AppendDir {
     pub fn sig_data(&self) -> SigData {
         SigData {
             path: self.path.as_ref().to_str().expect("path is string"),
             image: self.image.as_ref(),
             timestamp: to_ms(self.timestamp),
+            expires: self.expires.map(to_ms),
+            action: None,
         }
     }
     /*
@@ -71,6 +198,8 @@ impl ReplaceDir {
             path: self.path.as_ref().to_str().expect("path is string"),
             image: self.image.as_ref(),
             timestamp: to_ms(self.timestamp),
+            expires: None,
+            action: None,
         }
     }
     /*
@@ -86,6 +215,90 @@ impl ReplaceDir {
     */
 }
 
+impl Pin {
+    pub fn sig_data(&self) -> SigData {
+        pin_sig_data(&self.path, true, self.timestamp)
+    }
+}
+
+impl Unpin {
+    pub fn sig_data(&self) -> SigData {
+        pin_sig_data(&self.path, false, self.timestamp)
+    }
+}
+
+impl PinState {
+    pub fn sig_data<'x>(&self, path: &'x VPath) -> SigData<'x> {
+        pin_sig_data(path, self.pinned, self.timestamp)
+    }
+}
+
+impl<'a> From<&'a Pin> for PinState {
+    fn from(cmd: &'a Pin) -> PinState {
+        PinState {
+            pinned: true,
+            timestamp: cmd.timestamp,
+            signatures: cmd.signatures.clone(),
+        }
+    }
+}
+
+impl<'a> From<&'a Unpin> for PinState {
+    fn from(cmd: &'a Unpin) -> PinState {
+        PinState {
+            pinned: false,
+            timestamp: cmd.timestamp,
+            signatures: cmd.signatures.clone(),
+        }
+    }
+}
+
+impl Rollback {
+    /// Signature data of the `ReplaceDir` to the `image` with an action
+    ///
+    /// So the rollback signature can't be replayed as an upload.
+    pub fn sig_data(&self) -> SigData {
+        SigData {
+            path: self.path.as_ref().to_str().expect("path is string"),
+            image: self.image.as_ref(),
+            timestamp: to_ms(self.timestamp),
+            expires: None,
+            action: Some("rollback"),
+        }
+    }
+    /// Signature data of the `ReplaceDir` to the `image`
+    pub fn replace_sig_data(&self) -> SigData {
+        SigData {
+            path: self.path.as_ref().to_str().expect("path is string"),
+            image: self.image.as_ref(),
+            timestamp: to_ms(self.timestamp),
+            expires: None,
+            action: None,
+        }
+    }
+}
+
+impl Promote {
+    pub fn sig_data(&self) -> SigData {
+        promote_sig_data(&self.path, self.timestamp)
+    }
+}
+
+impl PromoteState {
+    pub fn sig_data<'x>(&self, path: &'x VPath) -> SigData<'x> {
+        promote_sig_data(path, self.timestamp)
+    }
+}
+
+impl<'a> From<&'a Promote> for PromoteState {
+    fn from(cmd: &'a Promote) -> PromoteState {
+        PromoteState {
+            timestamp: cmd.timestamp,
+            signatures: cmd.signatures.clone(),
+        }
+    }
+}
+
 impl Request for AppendDir {
     type Response = AppendDirAck;
     fn type_name(&self) -> &'static str {
@@ -117,3 +330,67 @@ impl Response for ReplaceDirAck {
         return "ReplaceDir";
     }
 }
+
+impl Request for Pin {
+    type Response = PinAck;
+    fn type_name(&self) -> &'static str {
+        return "Pin";
+    }
+}
+
+impl Response for PinAck {
+    fn type_name(&self) -> &'static str {
+        return "Pin";
+    }
+    fn static_type_name() -> &'static str {
+        return "Pin";
+    }
+}
+
+impl Request for Unpin {
+    type Response = UnpinAck;
+    fn type_name(&self) -> &'static str {
+        return "Unpin";
+    }
+}
+
+impl Response for UnpinAck {
+    fn type_name(&self) -> &'static str {
+        return "Unpin";
+    }
+    fn static_type_name() -> &'static str {
+        return "Unpin";
+    }
+}
+
+impl Request for Promote {
+    type Response = PromoteAck;
+    fn type_name(&self) -> &'static str {
+        return "Promote";
+    }
+}
+
+impl Response for PromoteAck {
+    fn type_name(&self) -> &'static str {
+        return "Promote";
+    }
+    fn static_type_name() -> &'static str {
+        return "Promote";
+    }
+}
+
+impl Request for Rollback {
+    type Response = RollbackAck;
+    fn type_name(&self) -> &'static str {
+        return "Rollback";
+    }
+}
+
+impl Response for RollbackAck {
+    fn type_name(&self) -> &'static str {
+        return "Rollback";
+    }
+    fn static_type_name() -> &'static str {
+        return "Rollback";
+    }
+}
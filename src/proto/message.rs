@@ -4,6 +4,7 @@ use serde::{Deserialize, Deserializer};
 use serde::de::{Visitor, SeqAccess, Error};
 
 use proto::{dir_commands, index_commands, block_commands, p2p_commands};
+use proto::{admin_commands};
 use proto::{NOTIFICATION, REQUEST, RESPONSE};
 
 
@@ -27,6 +28,11 @@ pub enum RequestType {
     GetIndexAt,
     GetBlock,
     GetBaseDir,
+    Maintenance,
+    Pin,
+    Unpin,
+    Promote,
+    Rollback,
 }
 
 pub enum ResponseType {
@@ -36,6 +42,11 @@ pub enum ResponseType {
     GetIndexAt,
     GetBlock,
     GetBaseDir,
+    Maintenance,
+    Pin,
+    Unpin,
+    Promote,
+    Rollback,
     RequestError,
 }
 
@@ -52,6 +63,11 @@ const REQUEST_TYPES: &'static [&'static str] = &[
     "GetIndexAt",
     "GetBlock",
     "GetBaseDir",
+    "Maintenance",
+    "Pin",
+    "Unpin",
+    "Promote",
+    "Rollback",
     ];
 
 const RESPONSE_TYPES: &'static [&'static str] = &[
@@ -61,6 +77,11 @@ const RESPONSE_TYPES: &'static [&'static str] = &[
     "GetIndexAt",
     "GetBlock",
     "GetBaseDir",
+    "Maintenance",
+    "Pin",
+    "Unpin",
+    "Promote",
+    "Rollback",
     ];
 
 const NOTIFICATION_TYPES: &'static [&'static str] = &[
@@ -76,6 +97,11 @@ pub enum Request {
     GetIndexAt(index_commands::GetIndexAt),
     GetBlock(block_commands::GetBlock),
     GetBaseDir(p2p_commands::GetBaseDir),
+    Maintenance(admin_commands::Maintenance),
+    Pin(dir_commands::Pin),
+    Unpin(dir_commands::Unpin),
+    Promote(dir_commands::Promote),
+    Rollback(dir_commands::Rollback),
 }
 
 pub enum Response {
@@ -85,6 +111,11 @@ pub enum Response {
     GetIndexAt(index_commands::GetIndexAtResponse),
     GetBlock(block_commands::GetBlockResponse),
     GetBaseDir(p2p_commands::GetBaseDirResponse),
+    Maintenance(admin_commands::MaintenanceAck),
+    Pin(dir_commands::PinAck),
+    Unpin(dir_commands::UnpinAck),
+    Promote(dir_commands::PromoteAck),
+    Rollback(dir_commands::RollbackAck),
     Error(String),
 }
 
@@ -119,6 +150,11 @@ impl<'a> Visitor<'a> for RequestTypeVisitor {
             "GetIndexAt" => Ok(RequestType::GetIndexAt),
             "GetBlock" => Ok(RequestType::GetBlock),
             "GetBaseDir" => Ok(RequestType::GetBaseDir),
+            "Maintenance" => Ok(RequestType::Maintenance),
+            "Pin" => Ok(RequestType::Pin),
+            "Unpin" => Ok(RequestType::Unpin),
+            "Promote" => Ok(RequestType::Promote),
+            "Rollback" => Ok(RequestType::Rollback),
             _ => Err(Error::unknown_variant(value, REQUEST_TYPES)),
         }
     }
@@ -140,6 +176,11 @@ impl<'a> Visitor<'a> for ResponseTypeVisitor {
             "GetIndexAt" => Ok(ResponseType::GetIndexAt),
             "GetBlock" => Ok(ResponseType::GetBlock),
             "GetBaseDir" => Ok(ResponseType::GetBaseDir),
+            "Maintenance" => Ok(ResponseType::Maintenance),
+            "Pin" => Ok(ResponseType::Pin),
+            "Unpin" => Ok(ResponseType::Unpin),
+            "Promote" => Ok(ResponseType::Promote),
+            "Rollback" => Ok(ResponseType::Rollback),
             "Error" => Ok(ResponseType::RequestError),
             _ => Err(Error::unknown_variant(value, RESPONSE_TYPES)),
         }
@@ -256,6 +297,26 @@ impl<'a> Visitor<'a> for MessageVisitor {
                         Some(data) => Request::GetBaseDir(data),
                         None => return Err(Error::invalid_length(3, &self)),
                     },
+                    Maintenance => match visitor.next_element()? {
+                        Some(data) => Request::Maintenance(data),
+                        None => return Err(Error::invalid_length(3, &self)),
+                    },
+                    Pin => match visitor.next_element()? {
+                        Some(data) => Request::Pin(data),
+                        None => return Err(Error::invalid_length(3, &self)),
+                    },
+                    Unpin => match visitor.next_element()? {
+                        Some(data) => Request::Unpin(data),
+                        None => return Err(Error::invalid_length(3, &self)),
+                    },
+                    Promote => match visitor.next_element()? {
+                        Some(data) => Request::Promote(data),
+                        None => return Err(Error::invalid_length(3, &self)),
+                    },
+                    Rollback => match visitor.next_element()? {
+                        Some(data) => Request::Rollback(data),
+                        None => return Err(Error::invalid_length(3, &self)),
+                    },
                 };
                 Ok(Message::Request(request_id, data))
             },
@@ -294,6 +355,26 @@ impl<'a> Visitor<'a> for MessageVisitor {
                         Some(data) => Response::GetBaseDir(data),
                         None => return Err(Error::invalid_length(3, &self)),
                     },
+                    Maintenance => match visitor.next_element()? {
+                        Some(data) => Response::Maintenance(data),
+                        None => return Err(Error::invalid_length(3, &self)),
+                    },
+                    Pin => match visitor.next_element()? {
+                        Some(data) => Response::Pin(data),
+                        None => return Err(Error::invalid_length(3, &self)),
+                    },
+                    Unpin => match visitor.next_element()? {
+                        Some(data) => Response::Unpin(data),
+                        None => return Err(Error::invalid_length(3, &self)),
+                    },
+                    Promote => match visitor.next_element()? {
+                        Some(data) => Response::Promote(data),
+                        None => return Err(Error::invalid_length(3, &self)),
+                    },
+                    Rollback => match visitor.next_element()? {
+                        Some(data) => Response::Rollback(data),
+                        None => return Err(Error::invalid_length(3, &self)),
+                    },
                     RequestError => match visitor.next_element()? {
                         Some(data) => Response::Error(data),
                         None => return Err(Error::invalid_length(3, &self)),
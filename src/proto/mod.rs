@@ -10,6 +10,7 @@ mod dir_commands;
 mod index_commands;
 mod block_commands;
 mod p2p_commands;
+mod admin_commands;
 
 pub use self::client::{Client, ClientFuture, Listener};
 pub use self::hash::{Hash, Builder as HashBuilder};
@@ -23,11 +24,16 @@ pub use self::stream_ext::StreamExt;
 
 pub use self::dir_commands::{AppendDir, AppendDirAck};
 pub use self::dir_commands::{ReplaceDir, ReplaceDirAck};
+pub use self::dir_commands::{Pin, PinAck, Unpin, UnpinAck, PinState};
+pub use self::dir_commands::{Promote, PromoteAck, PromoteState};
+pub use self::dir_commands::{Rollback, RollbackAck};
 pub use self::index_commands::{PublishImage, ReceivedImage, AbortedImage};
 pub use self::index_commands::{GetIndex, GetIndexResponse};
 pub use self::index_commands::{GetIndexAt, GetIndexAtResponse};
 pub use self::block_commands::{GetBlock, GetBlockResponse};
 pub use self::p2p_commands::{GetBaseDir, GetBaseDirResponse, BaseDirState};
+pub use self::admin_commands::{Maintenance, MaintenanceAck};
+pub use self::admin_commands::{MaintenanceAction};
 
 // Protocol identifiers
 const NOTIFICATION: u8 = 0;
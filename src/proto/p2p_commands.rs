@@ -2,6 +2,7 @@ use std::collections::BTreeMap;
 
 use proto::{Request, Response, Hash};
 use database::signatures::State;
+use proto::dir_commands::{PinState, PromoteState};
 use {VPath};
 
 
@@ -15,6 +16,10 @@ pub struct GetBaseDirResponse {
     pub config_hash: Hash,
     pub keep_list_hash: Hash,
     pub dirs: BTreeMap<String, State>,
+    #[serde(default)]
+    pub pins: BTreeMap<String, PinState>,
+    #[serde(default)]
+    pub promotes: BTreeMap<String, PromoteState>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -23,6 +28,24 @@ pub struct BaseDirState {
     pub config_hash: Hash,
     pub keep_list_hash: Hash,
     pub dirs: BTreeMap<String, State>,
+    pub pins: BTreeMap<String, PinState>,
+    pub promotes: BTreeMap<String, PromoteState>,
+}
+
+impl BaseDirState {
+    /// Hash that is compared with peers to find out whether to reconcile
+    ///
+    /// Pins and promotes are hashed only if there are any, so the hash is
+    /// the same as of peers that don't know about them yet.
+    pub fn hash(&self) -> Hash {
+        if !self.promotes.is_empty() {
+            Hash::for_object(&(&self.dirs, &self.pins, &self.promotes))
+        } else if !self.pins.is_empty() {
+            Hash::for_object(&(&self.dirs, &self.pins))
+        } else {
+            Hash::for_object(&self.dirs)
+        }
+    }
 }
 
 impl Request for GetBaseDir {
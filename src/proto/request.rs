@@ -13,10 +13,12 @@ use mopa;
 use index::{ImageId};
 use proto::{REQUEST, RESPONSE, NOTIFICATION};
 use proto::message;
-use proto::dir_commands::{AppendDir, ReplaceDir};
+use proto::dir_commands::{AppendDir, ReplaceDir, Pin, Unpin, Promote};
+use proto::dir_commands::{Rollback};
 use proto::index_commands::{GetIndex, GetIndexAt};
 use proto::block_commands::GetBlock;
 use proto::p2p_commands::GetBaseDir;
+use proto::admin_commands::Maintenance;
 
 
 quick_error! {
@@ -168,6 +170,13 @@ pub trait RequestDispatcher {
             R::GetIndexAt(x) => respond::<GetIndexAt, _>(request_id, x, self),
             R::GetBlock(x) => respond::<GetBlock, _>(request_id, x, self),
             R::GetBaseDir(x) => respond::<GetBaseDir, _>(request_id, x, self),
+            R::Maintenance(x) => {
+                respond::<Maintenance, _>(request_id, x, self)
+            }
+            R::Pin(x) => respond::<Pin, _>(request_id, x, self),
+            R::Unpin(x) => respond::<Unpin, _>(request_id, x, self),
+            R::Promote(x) => respond::<Promote, _>(request_id, x, self),
+            R::Rollback(x) => respond::<Rollback, _>(request_id, x, self),
             R::Error(x) => respond_error(request_id, x, self),
         }
     }
@@ -33,15 +33,36 @@ pub struct SigData<'a> {
     pub path: &'a str,
     pub image: &'a [u8],
     pub timestamp: u64,
+    /// Expiration timestamp, signed data is unchanged when it's `None`
+    pub expires: Option<u64>,
+    /// Name of the action signed for the `image` (instead of `expires`),
+    /// `None` for uploads
+    pub action: Option<&'a str>,
+}
+
+fn sig_bytes(src: &SigData) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(100);
+    match (src.expires, src.action) {
+        (_, Some(action)) => {
+            (src.path, Bytes(src.image), src.timestamp, action)
+            .serialize(&mut Cbor::new(&mut buf))
+        }
+        (Some(expires), None) => {
+            (src.path, Bytes(src.image), src.timestamp, expires)
+            .serialize(&mut Cbor::new(&mut buf))
+        }
+        (None, None) => {
+            (src.path, Bytes(src.image), src.timestamp)
+            .serialize(&mut Cbor::new(&mut buf))
+        }
+    }.expect("Can always serialize signature data");
+    return buf;
 }
 
 pub struct Bytes<'a>(&'a [u8]);
 
 pub fn sign(src: SigData, keys: &[PrivateKey]) -> Vec<Signature> {
-    let mut buf = Vec::with_capacity(100);
-    (src.path, Bytes(src.image), src.timestamp)
-        .serialize(&mut Cbor::new(&mut buf))
-        .expect("Can always serialize signature data");
+    let buf = sig_bytes(&src);
     let mut res = Vec::new();
     for key in keys {
         let signature = match *key {
@@ -64,10 +85,7 @@ pub fn sign(src: SigData, keys: &[PrivateKey]) -> Vec<Signature> {
 pub fn verify(src: &SigData, signature: &Signature, keys: &[PublicKey])
     -> bool
 {
-    let mut buf = Vec::with_capacity(100);
-    (src.path, Bytes(src.image), src.timestamp)
-        .serialize(&mut Cbor::new(&mut buf))
-        .expect("Can always serialize signature data");
+    let buf = sig_bytes(src);
     keys.iter().any(|key| {
         match (key, signature) {
             (&PublicKey::Ed25519(ref key), &Signature::SshEd25519(ref sig))
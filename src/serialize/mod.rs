@@ -1,2 +1,3 @@
 pub mod timestamp;
+pub mod optional_timestamp;
 pub mod duration;
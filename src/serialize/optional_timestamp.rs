@@ -0,0 +1,24 @@
+use std::time::{SystemTime};
+
+use serde::{Serializer, Deserializer, Deserialize};
+
+use time_util::{to_ms, from_ms};
+
+
+pub fn serialize<S>(tm: &Option<SystemTime>, ser: S)
+    -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    match *tm {
+        Some(tm) => ser.serialize_some(&to_ms(tm)),
+        None => ser.serialize_none(),
+    }
+}
+
+
+pub fn deserialize<'a, D>(des: D) -> Result<Option<SystemTime>, D::Error>
+    where D: Deserializer<'a>
+{
+    let ms = Option::<u64>::deserialize(des)?;
+    Ok(ms.map(from_ms))
+}
@@ -22,6 +22,8 @@ pub struct SignedUpload {
     pub timestamp: SystemTime,
     #[doc(hidden)]
     pub signatures: Vec<Signature>,
+    #[doc(hidden)]
+    pub expires: Option<SystemTime>,
 }
 
 
@@ -29,16 +31,36 @@ pub struct SignedUpload {
 pub fn sign_upload(path: &VPath, image: &ImageId, timestamp: SystemTime,
     keys: &[PrivateKey])
     -> SignedUpload
+{
+    _sign(path, image, timestamp, None, keys)
+}
+
+/// Prepare a signature for upload which is removed after `expires` time
+///
+/// Expiring uploads can only be appended, not replaced.
+pub fn sign_expiring_upload(path: &VPath, image: &ImageId,
+    timestamp: SystemTime, expires: SystemTime, keys: &[PrivateKey])
+    -> SignedUpload
+{
+    _sign(path, image, timestamp, Some(expires), keys)
+}
+
+fn _sign(path: &VPath, image: &ImageId, timestamp: SystemTime,
+    expires: Option<SystemTime>, keys: &[PrivateKey])
+    -> SignedUpload
 {
     let signatures = sign(SigData {
         path: path.as_ref().to_str().expect("path is string"),
         image: image.as_ref(),
         timestamp: to_ms(timestamp),
+        expires: expires.map(to_ms),
+        action: None,
     }, &keys);
     return SignedUpload {
         path: path.clone(),
         image_id: image.clone(),
         timestamp,
         signatures,
+        expires,
     }
 }
@@ -20,6 +20,10 @@ use serde::de::{Deserialize, Deserializer, Error};
 ///
 /// Type asserts on the presence of the ``key`` and that the path is absolute.
 /// Suffix might be of arbitrary length including zero.
+///
+/// A path with zero-length suffix (level 0) is an image which replaces the
+/// whole directory. Such path is its own base directory, and the image has
+/// an empty name in it.
 #[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Clone)]
 pub struct VPath(Arc<PathBuf>);
 
@@ -49,21 +53,21 @@ impl VPath {
     }
     /// Return parent path relative to a `key`
     ///
-    /// # Panics
-    ///
-    /// Panics if the directory contains only a key (has single component)
+    /// For the path containing only a key (level 0) returns the key itself
     pub fn parent_rel(&self) -> &Path {
-        assert!(self.level() > 0);
-        self.0.strip_prefix("/").ok().and_then(|x| x.parent())
-        .expect("valid virtual path")
+        let path = self.0.strip_prefix("/").expect("valid virtual path");
+        if self.level() == 0 {
+            return path;
+        }
+        path.parent().expect("valid virtual path")
     }
     /// Return virtual path of the directory
     ///
-    /// # Panics
-    ///
-    /// Panics if the directory contains only a key (has single component)
+    /// For the path containing only a key (level 0) returns the path itself
     pub fn parent(&self) -> VPath {
-        assert!(self.level() > 0);
+        if self.level() == 0 {
+            return self.clone();
+        }
         let parent = self.0.parent().expect("valid virtual path");
         VPath(Arc::new(parent.to_path_buf()))
     }
@@ -76,23 +80,29 @@ impl VPath {
     }
     /// The last component of the directory
     ///
-    /// # Panics
-    ///
-    /// Panics if the path has only one component (the key)
+    /// For the path containing only a key (level 0) returns an empty string,
+    /// i.e. the name of the image in it's own base directory
     pub fn final_name(&self) -> &str {
-        assert!(self.level() > 0);
+        if self.level() == 0 {
+            return "";
+        }
         self.0.file_name().and_then(|x| x.to_str())
         .expect("valid virtual path")
     }
     /// Join path to the virtual path
     ///
+    /// Joining an empty path returns the same path, this is consistent with
+    /// `final_name` of the level 0 path.
+    ///
     /// # Panics
     ///
-    /// Panics if suffix is invalid: empty, root or has parent `..` components
+    /// Panics if suffix is invalid: root or has parent `..` components
     pub fn join<P: AsRef<Path>>(&self, path: P) -> VPath {
         use std::path::Component::Normal;
         let path = path.as_ref();
-        assert!(path != Path::new(""));
+        if path == Path::new("") {
+            return self.clone();
+        }
         assert!(path.components().all(|x| matches!(x, Normal(_))));
         VPath(Arc::new(self.0.join(path)))
     }
@@ -126,7 +136,9 @@ impl VPath {
     /// self.parent() == base_dir
     /// ```
     pub fn matches_basedir(&self, base_dir: &VPath) -> bool {
-        assert!(self.level() > 0);
+        if self.level() == 0 {
+            return self == base_dir;
+        }
         let parent = self.0.parent().expect("valid virtual path");
         return parent == base_dir.0.as_path();
     }
@@ -186,3 +198,33 @@ impl fmt::Debug for VPath {
         write!(f, "v{:?}", self.0)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use super::VPath;
+
+    #[test]
+    fn level_one() {
+        let p = VPath::from("/dir/img");
+        assert_eq!(p.level(), 1);
+        assert_eq!(p.key(), "dir");
+        assert_eq!(p.parent(), VPath::from("/dir"));
+        assert_eq!(p.parent_rel(), Path::new("dir"));
+        assert_eq!(p.final_name(), "img");
+        assert_eq!(p.parent().join(p.final_name()), p);
+        assert!(p.matches_basedir(&VPath::from("/dir")));
+    }
+
+    #[test]
+    fn level_zero() {
+        let p = VPath::from("/nginx");
+        assert_eq!(p.level(), 0);
+        assert_eq!(p.parent(), p);
+        assert_eq!(p.parent_rel(), Path::new("nginx"));
+        assert_eq!(p.final_name(), "");
+        assert_eq!(p.suffix(), Path::new(""));
+        assert_eq!(p.parent().join(p.final_name()), p);
+        assert!(p.matches_basedir(&p));
+    }
+}
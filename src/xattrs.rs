@@ -0,0 +1,109 @@
+//! Manifest of extended attributes
+//!
+//! Index format has no place for extended attributes, so client records
+//! them in a regular file in the root of the image. This way they are
+//! covered by the image hash, and daemon applies them on commit.
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+use libc::{self, c_char, c_void};
+
+
+/// Name of the manifest file in the root of the image
+pub const MANIFEST: &'static str = ".ciruela-xattrs";
+
+/// Maps absolute path in the image to attribute names and hex-encoded values
+pub type Manifest = BTreeMap<String, BTreeMap<String, String>>;
+
+
+/// Checks attribute name against `security.capability` or `user.*` pattern
+pub fn matches(pattern: &str, name: &str) -> bool {
+    if pattern.ends_with('*') {
+        name.starts_with(&pattern[..pattern.len()-1])
+    } else {
+        pattern == name
+    }
+}
+
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Lists names of extended attributes, symlinks are not followed
+pub fn list(path: &Path) -> io::Result<Vec<String>> {
+    let cpath = c_string(path.as_os_str().as_bytes())?;
+    let size = unsafe {
+        libc::llistxattr(cpath.as_ptr(), ptr::null_mut(), 0)
+    };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::llistxattr(cpath.as_ptr(),
+            buf.as_mut_ptr() as *mut c_char, buf.len())
+    };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(size as usize);
+    Ok(buf.split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| String::from_utf8(name.to_vec()).ok())
+        .collect())
+}
+
+/// Reads value of extended attribute, symlinks are not followed
+pub fn get(path: &Path, name: &str) -> io::Result<Vec<u8>> {
+    let cpath = c_string(path.as_os_str().as_bytes())?;
+    let cname = c_string(name.as_bytes())?;
+    let size = unsafe {
+        libc::lgetxattr(cpath.as_ptr(), cname.as_ptr(), ptr::null_mut(), 0)
+    };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::lgetxattr(cpath.as_ptr(), cname.as_ptr(),
+            buf.as_mut_ptr() as *mut c_void, buf.len())
+    };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(size as usize);
+    Ok(buf)
+}
+
+/// Sets extended attribute, symlinks are not followed
+pub fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let cpath = c_string(path.as_os_str().as_bytes())?;
+    let cname = c_string(name.as_bytes())?;
+    let rc = unsafe {
+        libc::lsetxattr(cpath.as_ptr(), cname.as_ptr(),
+            value.as_ptr() as *const c_void, value.len(), 0)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::matches;
+
+    #[test]
+    fn patterns() {
+        assert!(matches("security.capability", "security.capability"));
+        assert!(!matches("security.capability", "security.selinux"));
+        assert!(matches("user.*", "user.mime_type"));
+        assert!(!matches("user.*", "trusted.x"));
+        assert!(matches("*", "trusted.x"));
+    }
+}